@@ -0,0 +1,42 @@
+// Run the proxy without the GUI, logging traffic and passing everything through untouched.
+//
+//     cargo run --example headless
+//
+// then point a client at 127.0.0.1:1337. The CA certificate is loaded from (or created in) data/.
+
+use proxy::{ProxyConfig, ProxyState};
+
+#[tokio::main]
+async fn main() {
+    // The library logs through tracing, this prints its warnings alongside the traffic
+    tracing_subscriber::fmt::init();
+    let (server, mut events) = match ProxyConfig::default().build() {
+        Ok(built) => built,
+        Err(e) => return println!("{}", e),
+    };
+    let running = match server.run() {
+        Ok(running) => running,
+        Err(e) => return println!("Unable to bind {}: {}", server.listen_addr(), e),
+    };
+    println!("Listening on {}", server.listen_addr());
+
+    while let Some(event) = events.recv().await {
+        // Notices, and errors from before there was a request, like a failed client handshake, have no id
+        match (event.id, &event.event) {
+            (Some(id), ProxyState::RequestHead(head)) => println!("{} -> {} {}", id, head.method, head.uri),
+            (Some(id), ProxyState::ResponseHead(head)) => println!("{} <- {}", id, head.status),
+            (Some(id), ProxyState::Error(e)) => println!("{} failed: {}", id, e),
+            (None, ProxyState::Error(e)) => println!("{}", e),
+            (_, ProxyState::Msg(msg)) => println!("{}", msg),
+            _ => (),
+        }
+        // The proxy waits on this for heads and chunks, hand back what we got to forward it as is
+        if let Some(callback) = event.callback {
+            let _ = callback.send(event.event);
+        }
+    }
+
+    if let Err(e) = running.await.unwrap() {
+        println!("Proxy stopped: {}", e);
+    }
+}
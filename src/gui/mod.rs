@@ -1,43 +1,321 @@
+use std::time::Duration;
+
 use tokio::sync::mpsc::Receiver;
 
 use eframe::egui::ScrollArea;
 use eframe::{epi, egui};
 use crate::proxy::ProxyEvent;
 
-use super::proxy::ProxyServer;
-use super::store::Store;
+use super::proxy::{Listen, ProxyServer};
+use super::store::{Store, SidebarMode, CaptureMask};
 use tokio::task::JoinHandle;
 
 pub struct ProxyApp {
-    #[allow(dead_code)] // We mostly hold onto this so the future doesn't get cancelled
-    server: JoinHandle<Result<(), hyper::Error>>,
+    server: Option<JoinHandle<Result<(), hyper::Error>>>, // Aborted when we rebind to a different address, None if binding failed
+    proxy: ProxyServer,
     store: Store,
+    replay_base: String,
+    export_path: String,
+    session_path: String,
+    goto_id: String,
+    ca_path: String,
+    listen: String,
+    notice: String,
 }
 
 impl ProxyApp {
     pub fn run(server: ProxyServer, events: Receiver<ProxyEvent>) -> Box<Self> {
         let mut store = Store::new();
-        store.subscribe(events);
+        store.subscribe(events, server.metrics(), server.edit_bodies(), server.rewrite_rules());
+        store.set_proxy(server.clone());
+        // Not being able to bind shouldn't take the window down with it, the port can be changed in settings
+        let (running, mut notice) = match server.run() {
+            Ok(running) => (Some(running), String::new()),
+            Err(e) => (None, format!("Unable to bind {}: {}", server.listen_addr(), e)),
+        };
+        // Runs for as long as the app does, there's no setting to move it
+        if let (Some(Err(e)), Some(addr)) = (server.serve_metrics(), server.metrics_addr()) {
+            notice = format!("{} Unable to bind metrics on {}: {}", notice, addr, e).trim_start().to_string();
+        }
         Box::new(Self {
             store:  store,
-            server: server.run()
+            server: running,
+            listen: server.listen_addr().to_string(),
+            proxy:  server,
+            replay_base: String::new(),
+            export_path: "capture.har".to_string(),
+            session_path: "capture.stain".to_string(),
+            goto_id: String::new(),
+            ca_path: "stain-ca.crt".to_string(),
+            notice,
         })
     }
 }
 
+impl ProxyApp {
+    // The old listener is only torn down once the new one is bound, so a bad address leaves us where we were
+    fn rebind(&mut self) -> String {
+        let addr: Listen = match self.listen.trim().parse() {
+            Ok(addr) => addr,
+            Err(e) => return format!("Invalid listen address {:?}: {}", self.listen, e),
+        };
+        if addr == self.proxy.listen_addr() && self.server.is_some() {
+            return format!("Already listening on {}", addr);
+        }
+        match self.proxy.serve_on(addr.clone()) {
+            Ok(server) => {
+                if let Some(old) = self.server.replace(server) {
+                    old.abort();
+                }
+                format!("Listening on {}", addr)
+            },
+            Err(e) if self.server.is_some() => format!("Unable to bind {}: {}, still listening on {}", addr, e, self.proxy.listen_addr()),
+            Err(e) => format!("Unable to bind {}: {}", addr, e),
+        }
+    }
+}
+
 impl epi::App for ProxyApp {
     fn update(&mut self, ctx: &egui::CtxRef, frame: &epi::Frame) {
         self.store.set_frame(frame.clone());
+        egui::TopBottomPanel::top("Toolbar").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                let mut intercept = self.store.is_intercepting();
+                if ui.checkbox(&mut intercept, "Intercept requests").changed() {
+                    self.store.set_intercept(intercept);
+                }
+                ui.menu_button("Breakpoints", |ui| {
+                    self.store.draw_conditions(ui);
+                });
+                let mut intercept_responses = self.store.is_intercepting_responses();
+                if ui.checkbox(&mut intercept_responses, "Intercept responses").changed() {
+                    self.store.set_intercept_responses(intercept_responses);
+                }
+                let mut intercept_upgrades = self.store.is_intercepting_upgrades();
+                if ui.checkbox(&mut intercept_upgrades, "Intercept upgrade frames").changed() {
+                    self.store.set_intercept_upgrades(intercept_upgrades);
+                }
+                let mut capturing = self.store.is_capturing();
+                if ui.checkbox(&mut capturing, "Capture").changed() {
+                    self.store.set_capturing(capturing);
+                }
+                if ui.button("Clear session").clicked() {
+                    self.store.clear();
+                    self.notice = "Cleared captures".to_string();
+                }
+                if ui.button("Compose").clicked() {
+                    self.store.open_composer();
+                }
+                ui.separator();
+                ui.label("Replay all against:");
+                ui.text_edit_singleline(&mut self.replay_base);
+                if ui.button("Replay").clicked() {
+                    self.notice = match self.replay_base.parse() {
+                        Ok(base) => match self.store.replay_all_against(base) {
+                            Ok(count) => format!("Replaying {} requests", count),
+                            Err(e) => e,
+                        },
+                        Err(e) => format!("Invalid base URL: {}", e),
+                    };
+                }
+                ui.separator();
+                ui.text_edit_singleline(&mut self.export_path);
+                if ui.button("Export HAR").clicked() {
+                    self.notice = match self.store.export_har(&self.export_path) {
+                        Ok(()) => format!("Wrote {}", self.export_path),
+                        Err(e) => format!("Unable to write {}: {}", self.export_path, e),
+                    };
+                }
+                if ui.button("Export .http").clicked() {
+                    self.notice = match self.store.export_http(&self.export_path) {
+                        Ok(count) => format!("Wrote {} requests to {}", count, self.export_path),
+                        Err(e) => format!("Unable to write {}: {}", self.export_path, e),
+                    };
+                }
+                ui.menu_button("Session", |ui| {
+                    ui.text_edit_singleline(&mut self.session_path);
+                    if ui.button("Save session").clicked() {
+                        self.notice = match self.store.save_session(&self.session_path) {
+                            Ok(count) => format!("Saved {} captures to {}", count, self.session_path),
+                            Err(e) => format!("Unable to write {}: {}", self.session_path, e),
+                        };
+                    }
+                    if ui.button("Load session").on_hover_text("Replaces everything captured so far").clicked() {
+                        self.notice = match self.store.load_session(&self.session_path) {
+                            Ok(count) => format!("Loaded {} captures from {}", count, self.session_path),
+                            Err(e) => format!("Unable to load {}: {}", self.session_path, e),
+                        };
+                    }
+                });
+                ui.menu_button("CA certificate", |ui| {
+                    ui.text_edit_singleline(&mut self.ca_path);
+                    let pem = ui.button("Export PEM").clicked();
+                    let der = ui.button("Export DER").clicked();
+                    let export = if pem {
+                        Some(self.proxy.cert_store().export_ca_pem())
+                    } else if der {
+                        Some(self.proxy.cert_store().export_ca_der())
+                    } else {
+                        None
+                    };
+                    if let Some(cert) = export {
+                        self.notice = match std::fs::write(&self.ca_path, cert) {
+                            Ok(()) => format!("Wrote CA certificate to {}", self.ca_path),
+                            Err(e) => format!("Unable to write {}: {}", self.ca_path, e),
+                        };
+                        ui.close_menu();
+                    }
+                });
+                let mut mode = self.store.sidebar_mode();
+                egui::ComboBox::from_id_source("Sidebar mode")
+                    .selected_text(mode.name())
+                    .show_ui(ui, |ui| {
+                        for option in SidebarMode::ALL {
+                            ui.selectable_value(&mut mode, option, option.name());
+                        }
+                    });
+                if mode != self.store.sidebar_mode() {
+                    self.store.set_sidebar_mode(mode);
+                }
+                let mut dedup = self.store.dedup();
+                if ui.checkbox(&mut dedup, "Collapse duplicates").changed() {
+                    self.store.set_dedup(dedup);
+                }
+                let mut slow = self.store.min_latency().is_some();
+                let mut slow_ms = self.store.min_latency().map(|min| min.as_millis() as u64).unwrap_or(1000);
+                let mut changed = ui.checkbox(&mut slow, "Slower than").changed();
+                changed |= ui.add(egui::DragValue::new(&mut slow_ms).clamp_range(0..=600_000).suffix(" ms")).changed();
+                if changed {
+                    self.store.set_min_latency(Some(Duration::from_millis(slow_ms)).filter(|_| slow));
+                }
+                ui.label("Tag");
+                let mut tag_filter = self.store.tag_filter().to_string();
+                if ui.add(egui::TextEdit::singleline(&mut tag_filter).desired_width(80.0)).changed() {
+                    self.store.set_tag_filter(tag_filter);
+                }
+                ui.label("Go to id");
+                let goto = ui.add(egui::TextEdit::singleline(&mut self.goto_id).desired_width(50.0));
+                if goto.lost_focus() && ui.input().key_pressed(egui::Key::Enter) {
+                    self.notice = match self.goto_id.trim().parse() {
+                        Ok(id) if self.store.go_to_id(id) => String::new(),
+                        Ok(id) => format!("No capture with id {}, it may have been evicted or cleared", id),
+                        Err(_) => format!("{:?} isn't a request id", self.goto_id.trim()),
+                    };
+                }
+                ui.menu_button("Rules", |ui| {
+                    self.store.draw_rules(ui);
+                    if ui.button("Save to config").clicked() {
+                        self.notice = match self.store.save_rules(crate::CONFIG_PATH) {
+                            Ok(()) => format!("Saved rules to {}", crate::CONFIG_PATH),
+                            Err(e) => e,
+                        };
+                        ui.close_menu();
+                    }
+                });
+                ui.menu_button("Settings", |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Listen on");
+                        ui.text_edit_singleline(&mut self.listen);
+                    });
+                    if ui.button("Rebind").clicked() {
+                        self.notice = self.rebind();
+                        ui.close_menu();
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label("Keep");
+                        let mut mask = self.store.capture_mask();
+                        for (part, name) in CaptureMask::NAMES {
+                            let mut enabled = mask.contains(part);
+                            if ui.checkbox(&mut enabled, name).changed() {
+                                mask.set(part, enabled);
+                                self.store.set_capture_mask(mask);
+                            }
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Keep at most");
+                        let mut max_mib = self.store.max_body_bytes() / (1024 * 1024);
+                        if ui.add(egui::DragValue::new(&mut max_mib).clamp_range(1..=1024).suffix(" MiB")).changed() {
+                            self.store.set_max_body_bytes(max_mib * 1024 * 1024);
+                        }
+                        ui.label("per body");
+                    });
+                    ui.horizontal(|ui| {
+                        let mut spool = self.store.spool_over().is_some();
+                        let mut spool_mib = self.store.spool_over().unwrap_or(8 * 1024 * 1024) / (1024 * 1024);
+                        let mut changed = ui.checkbox(&mut spool, "Spool bodies over").changed();
+                        changed |= ui.add(egui::DragValue::new(&mut spool_mib).clamp_range(1..=1024).suffix(" MiB")).changed();
+                        ui.label("to disk");
+                        if changed {
+                            self.store.set_spool_over(Some(spool_mib * 1024 * 1024).filter(|_| spool));
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        let mut limit = self.store.max_entries().is_some();
+                        let mut max = self.store.max_entries().unwrap_or(10_000);
+                        let mut changed = ui.checkbox(&mut limit, "Keep at most").changed();
+                        changed |= ui.add(egui::DragValue::new(&mut max).clamp_range(1..=1_000_000)).changed();
+                        ui.label("captures, evicting the oldest");
+                        if changed {
+                            self.store.set_max_entries(Some(max).filter(|_| limit));
+                        }
+                    });
+                });
+                ui.label(&self.notice);
+            });
+        });
+        egui::TopBottomPanel::bottom("Status bar").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if self.server.is_some() {
+                    ui.label(format!("Listening on {}", self.proxy.listen_addr()));
+                } else {
+                    ui.colored_label(egui::Color32::RED, "Not listening, pick another address in Settings");
+                }
+                ui.separator();
+                let (captured, in_flight, errors) = self.store.counts();
+                match self.store.max_entries() {
+                    Some(max) => ui.label(format!("Captured: {} of {}", captured, max)),
+                    None => ui.label(format!("Captured: {}", captured)),
+                };
+                ui.label(format!("~{:.1} MiB in memory", self.store.memory_estimate() as f64 / (1024.0 * 1024.0)));
+                ui.label(format!("In flight: {}", in_flight));
+                ui.label(format!("Errors: {}", errors));
+                ui.separator();
+                ui.label(format!("Open tunnels: {}", self.proxy.open_tunnels()));
+                ui.label(format!("Open upgrades: {}", self.store.open_upgrades()));
+                let dropped = self.proxy.dropped_notices();
+                if dropped > 0 {
+                    ui.separator();
+                    ui.colored_label(egui::Color32::YELLOW, format!("{} notices dropped, the event channel was full", dropped));
+                }
+            });
+        });
         egui::SidePanel::left("Request bar").show( ctx, |ui| {
-            let text_style = egui::TextStyle::Monospace;
-            let font = &ui.fonts()[text_style];
-            let row_height = font.row_height();
-            let char_width = font.glyph_width('w'); // Arbitrarily assuming "w" is one of the wider characters
-            let width = (ui.available_width() / char_width).floor() as usize;
-            let num_rows = self.store.size().unwrap_or(0);
-            ScrollArea::vertical().show_rows(ui, row_height, num_rows, |ui, range| self.store.draw_sidebar(ui, range, width));
+            let row_height = ui.fonts()[egui::TextStyle::Monospace].row_height();
+            let num_rows = self.store.sidebar_rows();
+            if let Some(summary) = self.store.latency_summary() {
+                ui.label(summary);
+                ui.separator();
+            }
+            ScrollArea::vertical().show_rows(ui, row_height, num_rows, |ui, range| {
+                let width = ui.available_width();
+                self.store.draw_sidebar(ui, range, width)
+            });
             ui.allocate_space(ui.available_size());
         });
+        egui::TopBottomPanel::bottom("Log").show(ctx, |ui| {
+            self.store.draw_messages(ui);
+        });
+        if self.store.parked_count() > 0 {
+            egui::TopBottomPanel::bottom("Intercept").resizable(true).show(ctx, |ui| {
+                self.store.draw_intercept(ui);
+            });
+        }
+        if self.store.is_composing() {
+            egui::Window::new("Compose request").default_width(500.0).show(ctx, |ui| {
+                self.store.draw_composer(ui);
+            });
+        }
         egui::CentralPanel::default().show(ctx, |ui| {
             self.store.draw_active(ui);
             ui.allocate_space(ui.available_size());
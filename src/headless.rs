@@ -0,0 +1,101 @@
+use ::proxy::proxy::Receiver;
+use ::proxy::{ProxyEvent, ProxyServer, ProxyState};
+use hyper::http::{HeaderMap, HeaderValue};
+use serde_json::{json, Value};
+
+// One line per event, for following along in a terminal
+fn text_line(event: &ProxyEvent) -> String {
+    let id = event.id.map(|id| id.to_string()).unwrap_or_else(|| "-".to_string());
+    match &event.event {
+        ProxyState::RequestHead(head) => format!("{} -> {} {}", id, head.method, head.uri),
+        ProxyState::RequestPreview(body) => format!("{} -> {} bytes read ahead", id, body.len()),
+        ProxyState::RequestChunk(chunk) => format!("{} -> {} bytes", id, chunk.len()),
+        ProxyState::RequestDone => format!("{} -> done", id),
+        ProxyState::ResponseHead(head) => format!("{} <- {}", id, head.status),
+        ProxyState::ResponseChunk(chunk) => format!("{} <- {} bytes", id, chunk.len()),
+        ProxyState::ResponseDone => format!("{} <- done", id),
+        ProxyState::UpgradeOpen => format!("{} upgraded", id),
+        ProxyState::UpgradeTx { chunk, .. } => format!("{} => {} bytes", id, chunk.len()),
+        ProxyState::UpgradeRx { chunk, .. } => format!("{} <= {} bytes", id, chunk.len()),
+        ProxyState::UpgradeClose => format!("{} upgrade closed", id),
+        ProxyState::Tunnel { host } => format!("{} tunnel to {}", id, host),
+        ProxyState::Mirror { of } => format!("{} mirrors {}", id, of),
+        ProxyState::Redirect { from } => format!("{} follows the redirect of {}", id, from),
+        ProxyState::Tls(info) => format!("{} over {}", id, info),
+        ProxyState::TunnelClose { sent, received } => format!("{} tunnel closed, {} bytes sent, {} bytes received", id, sent, received),
+        ProxyState::Error(e) => format!("{} failed: {}", id, e),
+        ProxyState::Msg(msg) => msg.clone(),
+    }
+}
+
+fn headers(headers: &HeaderMap<HeaderValue>) -> Value {
+    headers.iter()
+        .map(|(name, value)| json!([name.as_str(), String::from_utf8_lossy(value.as_bytes())]))
+        .collect()
+}
+
+// JSON lines, for feeding into something else. Bodies are left out, only their chunk sizes are given.
+fn json_line(event: &ProxyEvent) -> String {
+    let fields = match &event.event {
+        ProxyState::RequestHead(head) => json!({
+            "event": "request_head",
+            "method": head.method.as_str(),
+            "uri": head.uri.to_string(),
+            "version": format!("{:?}", head.version),
+            "headers": headers(&head.headers),
+        }),
+        ProxyState::RequestPreview(body) => json!({ "event": "request_preview", "len": body.len() }),
+        ProxyState::RequestChunk(chunk) => json!({ "event": "request_chunk", "len": chunk.len() }),
+        ProxyState::RequestDone => json!({ "event": "request_done" }),
+        ProxyState::ResponseHead(head) => json!({
+            "event": "response_head",
+            "status": head.status.as_u16(),
+            "version": format!("{:?}", head.version),
+            "headers": headers(&head.headers),
+        }),
+        ProxyState::ResponseChunk(chunk) => json!({ "event": "response_chunk", "len": chunk.len() }),
+        ProxyState::ResponseDone => json!({ "event": "response_done" }),
+        ProxyState::UpgradeOpen => json!({ "event": "upgrade_open" }),
+        ProxyState::UpgradeTx { id, chunk } => json!({ "event": "upgrade_tx", "chunk_id": id, "len": chunk.len() }),
+        ProxyState::UpgradeRx { id, chunk } => json!({ "event": "upgrade_rx", "chunk_id": id, "len": chunk.len() }),
+        ProxyState::UpgradeClose => json!({ "event": "upgrade_close" }),
+        ProxyState::Tunnel { host } => json!({ "event": "tunnel", "host": host }),
+        ProxyState::Mirror { of } => json!({ "event": "mirror", "of": of }),
+        ProxyState::Redirect { from } => json!({ "event": "redirect", "from": from }),
+        ProxyState::Tls(info) => json!({ "event": "tls", "version": info.version, "cipher": info.cipher, "alpn": info.alpn }),
+        ProxyState::TunnelClose { sent, received } => json!({ "event": "tunnel_close", "sent": sent, "received": received }),
+        ProxyState::Error(e) => json!({ "event": "error", "error": e.to_string() }),
+        ProxyState::Msg(msg) => json!({ "event": "msg", "msg": msg }),
+    };
+    let mut line = json!({ "id": event.id });
+    if let (Value::Object(line), Value::Object(fields)) = (&mut line, fields) {
+        line.extend(fields);
+    }
+    line.to_string()
+}
+
+// Serve without a window, printing every event and passing all traffic through untouched
+pub async fn run(server: ProxyServer, mut events: Receiver<ProxyEvent>, json: bool) -> Result<(), String> {
+    let running = server.run().map_err(|e| format!("Unable to bind {}: {}", server.listen_addr(), e))?;
+    tracing::info!("Listening on {}", server.listen_addr());
+    if let (Some(metrics), Some(addr)) = (server.serve_metrics(), server.metrics_addr()) {
+        metrics.map_err(|e| format!("Unable to bind metrics on {}: {}", addr, e))?;
+        tracing::info!("Serving metrics on http://{}/metrics", addr);
+    }
+    let metrics = server.metrics();
+    // Nothing here ever edits a body
+    server.edit_bodies().store(false, crate::ORDERING);
+    while let Some(event) = events.recv().await {
+        metrics.observe(&event);
+        println!("{}", if json { json_line(&event) } else { text_line(&event) });
+        // The proxy waits on this for heads and upgrade frames, sending back what we got forwards it as is
+        if let Some(callback) = event.callback {
+            let _ = callback.send(event.event);
+        }
+    }
+    match running.await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(format!("Proxy stopped: {}", e)),
+        Err(e) => Err(format!("Proxy stopped: {}", e)),
+    }
+}
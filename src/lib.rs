@@ -0,0 +1,17 @@
+// The proxy on its own, without the GUI. The `stain` binary is just a store and an egui front end on top of this.
+//
+// Everything the proxy sees comes out of the receiver handed back by `ProxyConfig::build` as `ProxyEvent`s. Events
+// with a callback hold that part of the traffic until it's answered: send back the same state to pass it on unchanged,
+// or an edited one to tamper with it. Dropping the callback also passes it on unchanged. See examples/headless.rs.
+// Body chunks only come with a callback while `ProxyServer::edit_bodies` is on, which it is until turned off.
+// The metrics served on `metrics_listen` only count events handed to `ProxyServer::metrics().observe`.
+
+pub mod proxy;
+pub mod tls;
+mod util;
+
+pub use util::*;
+pub use proxy::{ProxyConfig, ProxyServer, Frontend, RetryPolicy, ProxyEvent, ProxyState, ProxyError};
+pub use tls::{CertStore, KeyAlgorithm};
+
+pub const ORDERING: std::sync::atomic::Ordering = std::sync::atomic::Ordering::Relaxed;
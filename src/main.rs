@@ -1,19 +1,81 @@
-mod tls;
-mod proxy;
+#[cfg(feature = "gui")]
 mod gui;
+#[cfg(feature = "gui")]
 mod store;
-mod util;
+mod headless;
 
-pub use util::*;
+use ::proxy::{proxy, ORDERING};
+#[cfg(feature = "gui")]
+use ::proxy::Waitpoint;
 
-pub const ORDERING: std::sync::atomic::Ordering = std::sync::atomic::Ordering::Relaxed;
+use tracing_subscriber::EnvFilter;
 
-use eframe;
+// Read from the working directory when it's there, otherwise everything is left at its default
+const CONFIG_PATH: &str = "stain.toml";
+
+const USAGE: &str = "Usage: stain [--headless] [--json]
+
+    --headless  Run the proxy without the GUI, printing each event to stdout
+    --json      With --headless, print events as JSON lines
+    -h, --help  Show this message
+
+Builds without the gui feature always run headless.";
+
+#[derive(Default)]
+struct Args {
+    headless: bool,
+    json: bool,
+}
+
+impl Args {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        for arg in args {
+            match arg.as_str() {
+                "--headless" => parsed.headless = true,
+                "--json" => parsed.json = true,
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0)
+                },
+                other => return Err(format!("Unknown argument {:?}\n\n{}", other, USAGE)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+fn fail(e: String) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(1)
+}
 
 #[tokio::main(worker_threads = 4)]
 async fn main() {
-    let config: proxy::ProxyConfig = Default::default();
-    let (proxy, events) = config.build();
-    let app = gui::ProxyApp::run(proxy, events);
-    eframe::run_native(app, eframe::NativeOptions::default())
+    // RUST_LOG overrides this, e.g. RUST_LOG=debug to follow chunks through the proxy
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_writer(std::io::stderr) // Headless mode prints events on stdout
+        .init();
+    let args = Args::parse(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2)
+    });
+    let config = if std::path::Path::new(CONFIG_PATH).exists() {
+        proxy::ProxyConfig::read_file(CONFIG_PATH)
+    } else {
+        Ok(proxy::ProxyConfig::default())
+    };
+    let mut config = config.unwrap_or_else(|e| fail(e));
+    config.apply_env();
+    config.validate().unwrap_or_else(|e| fail(e));
+    let (proxy, events) = config.build().unwrap_or_else(|e| fail(e));
+    #[cfg(feature = "gui")]
+    if !args.headless {
+        let app = gui::ProxyApp::run(proxy, events);
+        eframe::run_native(app, eframe::NativeOptions::default())
+    }
+    if let Err(e) = headless::run(proxy, events, args.json).await {
+        fail(e)
+    }
 }
@@ -1,10 +1,23 @@
-use std::{pin::Pin, task::Poll, cell::RefCell};
+use std::{pin::Pin, task::Poll, cell::RefCell, time::Duration};
 
 use futures::{lock::Mutex, Future, StreamExt};
 use futures_core::stream::Stream;
 use hyper::{body::Bytes, Body};
-use tokio::sync::mpsc::Sender;
-use crate::proxy::{ProxyEvent, ProxyState};
+use tokio::sync::mpsc::{Sender, error::{SendError, TrySendError}};
+use tokio::time::{sleep, sleep_until, Instant};
+use tracing::{debug, warn};
+use serde::{Deserialize, Serialize};
+use crate::proxy::{ProxyEvent, ProxyState, OneshotSender};
+
+// Slows a body down to simulate a bad network. The default leaves it alone.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Throttle {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_per_sec: Option<u64>,
+    #[serde(rename = "latency_ms", with = "super::repr::optional_millis", skip_serializing_if = "Option::is_none")]
+    pub latency: Option<Duration>, // Extra wait before the first chunk of the body goes out
+}
 
 #[derive(Clone, Debug)]
 enum StreamFork {
@@ -13,47 +26,61 @@ enum StreamFork {
 }
 
 impl StreamFork {
-    async fn send_event(&self, id: u32, chunk: Bytes) -> Bytes {
-        match self {
-            Self::RequestStream(stream) => {
-                let (event, completion) = ProxyEvent::req_chunk(id, &chunk);
-                stream.send( event ).await.unwrap();
-                match completion.await {
-                    Ok(ProxyState::RequestChunk(chunk)) => chunk,
-                    Ok(e) => {
-                        println!("Got unexpected response: {:?}", e);
-                        chunk
-                    }
-                    Err(_) => chunk
-                }
-            },
-            Self::ResponseStream(stream) => {
-                let (event, completion) = ProxyEvent::resp_chunk(id, &chunk);
-                stream.send( event ).await.unwrap();
-                match completion.await {
-                    Ok(ProxyState::ResponseChunk(chunk)) => chunk,
-                    Ok(e) => {
-                        println!("Got unexpected response: {:?}", e);
-                        chunk
-                    }
-                    Err(_) => chunk
-                }
+    // What goes out in place of `chunk`. If the receiver is gone there's nobody left to record or edit it, so it's
+    // forwarded as it came and the traffic keeps flowing without being captured.
+    async fn send_event(&self, id: u32, chunk: Bytes, edit: bool) -> Bytes {
+        match self.exchange(id, &chunk, edit).await {
+            Ok(edited) => edited.unwrap_or(chunk),
+            Err(SendError(_)) => {
+                debug!("Event receiver is gone, passing a chunk of {} through", id);
+                chunk
             }
         }
     }
 
-    fn close(&self, id: u32) {
-        match self {
-            Self::RequestStream(stream) => {
-                stream.try_send(
-                    ProxyEvent::req_done(id)
-                ).unwrap();
-            },
-            Self::ResponseStream(stream) => {
-                stream.try_send(
-                    ProxyEvent::req_done(id)
-                ).unwrap();
+    // Without `edit` nothing is going to change the chunk, so it's only passed on to be recorded rather than waited on.
+    // None means it goes out as it is.
+    async fn exchange(&self, id: u32, chunk: &Bytes, edit: bool) -> Result<Option<Bytes>, SendError<ProxyEvent>> {
+        let stream = match self {
+            Self::RequestStream(stream) | Self::ResponseStream(stream) => stream,
+        };
+        if !edit {
+            let event = match self {
+                Self::RequestStream(_) => ProxyState::RequestChunk(chunk.clone()),
+                Self::ResponseStream(_) => ProxyState::ResponseChunk(chunk.clone()),
+            };
+            stream.send(ProxyEvent::observed(id, event)).await?;
+            return Ok(None);
+        }
+        let (event, completion) = match self {
+            Self::RequestStream(_) => ProxyEvent::req_chunk(id, chunk),
+            Self::ResponseStream(_) => ProxyEvent::resp_chunk(id, chunk),
+        };
+        stream.send(event).await?;
+        Ok(match (self, completion.await) {
+            (Self::RequestStream(_), Ok(ProxyState::RequestChunk(chunk))) => Some(chunk),
+            (Self::ResponseStream(_), Ok(ProxyState::ResponseChunk(chunk))) => Some(chunk),
+            (_, Ok(e)) => {
+                warn!("Got unexpected response: {:?}", e);
+                None
             }
+            // A dropped callback passes the chunk on unchanged
+            (_, Err(_)) => None,
+        })
+    }
+
+    fn close(&self, id: u32) {
+        let (stream, event) = match self {
+            Self::RequestStream(stream) => (stream, ProxyEvent::req_done(id)),
+            Self::ResponseStream(stream) => (stream, ProxyEvent::resp_done(id)),
+        };
+        // We're called from Drop so we can't wait for room. Every chunk before this was already awaited,
+        // so handing the send off to a task when the channel is full can't reorder it.
+        if let Err(TrySendError::Full(event)) = stream.try_send(event) {
+            let stream = stream.clone();
+            tokio::spawn(async move {
+                let _ = stream.send(event).await;
+            });
         }
     }
 }
@@ -73,19 +100,23 @@ impl StreamBody {
 }
 
 impl StreamBody {
-    pub fn stream_request(inner: Body, id: u32, channel: Sender<ProxyEvent>) -> Self {
+    pub fn stream_request(inner: Body, id: u32, channel: Sender<ProxyEvent>, throttle: Throttle, edit: bool) -> Self {
         Self::new( InnerStreamBody{
             inner,
             id,
             stream: StreamFork::RequestStream(channel),
+            throttle,
+            edit,
         })
     }
 
-    pub fn stream_response(inner: Body, id: u32, channel: Sender<ProxyEvent>) -> Self {
+    pub fn stream_response(inner: Body, id: u32, channel: Sender<ProxyEvent>, throttle: Throttle, edit: bool) -> Self {
         Self::new(InnerStreamBody {
             inner,
             id,
             stream: StreamFork::ResponseStream(channel),
+            throttle,
+            edit,
         })
     }
 
@@ -97,9 +128,21 @@ impl StreamBody {
 pub struct InnerStreamBody {
     inner: Body,
     id: u32,
-    stream: StreamFork
+    stream: StreamFork,
+    throttle: Throttle,
+    edit: bool, // Whether each chunk waits on its callback, so it can be changed on the way through
+}
+
+// Where a throttled body is up to: chunks are cut into pieces of about a tenth of a second's worth
+// so the rate stays smooth, and each piece waits until the running total is due.
+struct Pacing {
+    started: Option<Instant>,
+    sent: u64,
+    pending: Bytes,
 }
 
+// Done is sent when the body goes away rather than when its stream ends. Bodies hyper never polls, like the
+// answer to a HEAD or a 304, are dropped as soon as the head is written, so those close straight away too.
 impl Drop for InnerStreamBody {
     fn drop(&mut self) {
         self.stream.close(self.id)
@@ -110,15 +153,157 @@ impl Drop for InnerStreamBody {
 impl InnerStreamBody {
     fn to_stream(self) -> impl Stream<Item = hyper::Result<Bytes>>
     {
-        futures::stream::unfold(self, | mut stream | async move {
-            match stream.inner.next().await {
-                Some(Ok(next)) => {
-                    let bytes = stream.stream.send_event(stream.id, next).await;
-                    Some((Ok(bytes), stream))
-                },
-                Some(Err(e)) => Some((Err(e), stream)),
-                None => None
+        let pacing = Pacing { started: None, sent: 0, pending: Bytes::new() };
+        // Each body is streamed by its own connection's task, so sleeping here only holds up this one
+        futures::stream::unfold((self, pacing), | (mut stream, mut pacing) | async move {
+            if pacing.pending.is_empty() {
+                match stream.inner.next().await {
+                    Some(Ok(next)) => pacing.pending = stream.stream.send_event(stream.id, next, stream.edit).await,
+                    Some(Err(e)) => return Some((Err(e), (stream, pacing))),
+                    None => return None
+                }
             }
+            let started = match pacing.started {
+                Some(started) => started,
+                None => {
+                    if let Some(latency) = stream.throttle.latency {
+                        sleep(latency).await;
+                    }
+                    *pacing.started.insert(Instant::now())
+                }
+            };
+            let bytes = match stream.throttle.bytes_per_sec {
+                Some(rate) if rate > 0 => {
+                    let piece = pacing.pending.split_to(pacing.pending.len().min((rate as usize / 10).max(1)));
+                    pacing.sent += piece.len() as u64;
+                    sleep_until(started + Duration::from_secs_f64(pacing.sent as f64 / rate as f64)).await;
+                    piece
+                },
+                _ => std::mem::take(&mut pacing.pending),
+            };
+            Some((Ok(bytes), (stream, pacing)))
         })
     }
-}
\ No newline at end of file
+}
+
+// Passes a body through untouched while keeping a copy, which is sent on once the body has been read to the end.
+// Past `limit` bytes the copy is given up on, though the body itself carries on. Hyper stops polling a body as soon
+// as it has written the Content-Length, so when that's known the copy is done at that many bytes.
+pub fn tee(body: Body, len: Option<usize>, limit: usize, done: OneshotSender<Result<Bytes, String>>) -> Body {
+    let copy = Some((Vec::new(), done));
+    Body::wrap_stream(futures::stream::unfold((body, copy), move |(mut body, mut copy)| async move {
+        let chunk = body.next().await;
+        let result = match &chunk {
+            Some(Ok(chunk)) => match &mut copy {
+                Some((buf, _)) if buf.len() + chunk.len() > limit => Some(Err(format!("its body is over {} bytes", limit))),
+                Some((buf, _)) => {
+                    buf.extend_from_slice(chunk);
+                    Some(Ok(())).filter(|_| len == Some(buf.len()))
+                },
+                None => None,
+            },
+            Some(Err(e)) => Some(Err(format!("its body failed: {}", e))),
+            None => Some(Ok(())),
+        };
+        if let Some(result) = result {
+            if let Some((buf, done)) = copy.take() {
+                let _ = done.send(result.map(|()| Bytes::from(buf)));
+            }
+        }
+        chunk.map(|chunk| (chunk, (body, copy)))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    fn chunks(count: u8) -> Body {
+        Body::wrap_stream(futures::stream::iter((0..count).map(|i| Ok::<_, std::io::Error>(Bytes::from(vec![b'a' + i; 8])))))
+    }
+
+    async fn receiver_dropped_mid_stream(edit: bool) {
+        let (tx, mut rx) = channel(1);
+        let body = StreamBody::stream_response(chunks(4), 1, tx, Throttle::default(), edit).try_into_body().unwrap();
+        let read = tokio::spawn(hyper::body::to_bytes(body));
+        let first = rx.recv().await.unwrap();
+        if let Some(callback) = first.callback {
+            let _ = callback.send(first.event);
+        }
+        drop(rx);
+        let body = read.await.unwrap().unwrap();
+        assert_eq!(&body[..], b"aaaaaaaabbbbbbbbccccccccdddddddd");
+    }
+
+    #[tokio::test]
+    async fn body_completes_after_receiver_drops() {
+        receiver_dropped_mid_stream(true).await;
+    }
+
+    #[tokio::test]
+    async fn body_completes_after_receiver_drops_without_callbacks() {
+        receiver_dropped_mid_stream(false).await;
+    }
+
+    #[tokio::test]
+    async fn streams_end_with_their_own_done() {
+        for response in [true, false] {
+            let (tx, mut rx) = channel(8);
+            let body = if response {
+                StreamBody::stream_response(chunks(2), 7, tx, Throttle::default(), false)
+            } else {
+                StreamBody::stream_request(chunks(2), 7, tx, Throttle::default(), false)
+            };
+            hyper::body::to_bytes(body.try_into_body().unwrap()).await.unwrap();
+            let mut events = Vec::new();
+            while let Some(event) = rx.recv().await {
+                assert_eq!(event.id, Some(7));
+                events.push(event.event);
+            }
+            assert_eq!(events.len(), 3);
+            if response {
+                assert!(matches!(events[..], [ProxyState::ResponseChunk(_), ProxyState::ResponseChunk(_), ProxyState::ResponseDone]));
+            } else {
+                assert!(matches!(events[..], [ProxyState::RequestChunk(_), ProxyState::RequestChunk(_), ProxyState::RequestDone]));
+            }
+        }
+    }
+
+    // How much the per-chunk callbacks cost on a large download, against recording without them. Run it with
+    // cargo test --release -- --ignored --nocapture callbacks_on_a_large_download
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn callbacks_on_a_large_download() {
+        const CHUNKS: usize = 64 * 1024;
+        const CHUNK_SIZE: usize = 4 * 1024; // 256 MiB in all, in reads about the size a socket hands over
+        for edit in [true, false] {
+            let (tx, mut rx) = channel::<ProxyEvent>(128);
+            // Stands in for the store, answering every callback with the chunk unchanged
+            let store = tokio::spawn(async move {
+                while let Some(event) = rx.recv().await {
+                    if let Some(callback) = event.callback {
+                        let _ = callback.send(event.event);
+                    }
+                }
+            });
+            let chunk = Bytes::from(vec![0u8; CHUNK_SIZE]);
+            let download = Body::wrap_stream(futures::stream::iter((0..CHUNKS).map(move |_| Ok::<_, std::io::Error>(chunk.clone()))));
+            let started = std::time::Instant::now();
+            let mut body = StreamBody::stream_response(download, 1, tx, Throttle::default(), edit).try_into_body().unwrap();
+            let mut len = 0;
+            while let Some(chunk) = body.next().await {
+                len += chunk.unwrap().len();
+            }
+            drop(body);
+            let elapsed = started.elapsed();
+            store.await.unwrap();
+            assert_eq!(len, CHUNKS * CHUNK_SIZE);
+            println!(
+                "{}: {} MiB in {:?}, {:.0} MiB/s",
+                if edit { "with callbacks" } else { "without callbacks" },
+                len >> 20, elapsed, (len >> 20) as f64 / elapsed.as_secs_f64(),
+            );
+        }
+    }
+}
@@ -1,32 +1,168 @@
 use std::convert::Infallible;
+use std::fmt;
 use std::future::Future;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::pin::Pin;
-use std::sync::atomic::AtomicU32;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
+use std::sync::{Arc, Mutex};
 use std::task::Poll;
+use std::time::Duration;
 
-use crate::tls::{CertStore, CertVerifier};
+use crate::tls::{CertStore, CertVerifier, CertOptions, KeyAlgorithm, load_roots};
+use hyper::http::{HeaderMap, HeaderValue, header::{CONTENT_LENGTH, FORWARDED, HOST, UPGRADE, HeaderName}};
 use hyper::http::uri::{Authority, Scheme};
-use hyper::server::conn::{AddrStream, Http};
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream, Http};
 use hyper::service::Service;
-use hyper::upgrade::{self, Upgraded};
-use hyper::{Body, Client, Method, Request, Response, Server, Uri};
-use hyper::body::Bytes;
-use rustls::{ServerConfig, ClientConfig};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use hyper::upgrade;
+use hyper::{Body, Client, Method, Request, Response, Server, StatusCode, Uri, Version};
+use hyper::body::{Bytes, HttpBody};
+use futures::future::BoxFuture;
+use rustls::{ServerConfig, ClientConfig, ProtocolVersion, server::Acceptor};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::task::JoinHandle;
-use tokio_rustls::{server::TlsStream, TlsAcceptor};
-use tokio::{try_join, select};
+use tokio_rustls::{server::TlsStream, LazyConfigAcceptor};
+use tokio::try_join;
+use tracing::{debug, error, warn};
 
-use crate::proxy::ProxyEvent;
+use crate::proxy::{ProxyEvent, ProxyError, ProxyState, TlsInfo, OneshotReciever, oneshot_channel};
+use crate::proxy::request::RequestHead;
+use crate::proxy::response::ResponseHead;
+use crate::proxy::upstream::UpstreamConnector;
+use crate::proxy::body::{self, Throttle};
+use crate::proxy::fault::{self, Fault, FaultInjector, FaultRule, LatencyRule};
+use crate::proxy::metrics::{self, Metrics};
+use crate::proxy::rewrite::RewriteRule;
+use crate::proxy::rewind::Rewind;
+use crate::proxy::socks;
+use crate::proxy::hop;
+use crate::proxy::redirect;
 
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// Override `ca_cert` and `ca_key` from the config, for deploys where the CA comes in as a secret
+const CA_CERT_ENV: &str = "STAIN_CA_CERT";
+const CA_KEY_ENV: &str = "STAIN_CA_KEY";
+
+// Request bodies are held in memory until they can be sent to the mirror, anything bigger isn't mirrored
+const MIRROR_BODY_LIMIT: usize = 16 * 1024 * 1024;
+
+// How clients reach the proxy. Either way each connection ends up in the same tunnel and capture code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Frontend {
+    #[default]
+    Http, // Plain proxy requests, with CONNECT for tunnels
+    Socks5, // Every connection is a tunnel, opened with a SOCKS5 CONNECT
+}
+
+// Where the frontend accepts clients. Written as a socket address, or "unix:" and a path for a Unix socket, which keeps
+// the proxy off the network entirely. Unix sockets only work on Unix, binding one anywhere else fails.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("unix: needs a path after it".to_string()),
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => s.parse().map(Self::Tcp).map_err(|e| format!("{} (expected host:port or unix:/path)", e)),
+        }
+    }
+}
+
+// Another go at requests that couldn't reach the origin at all. Only idempotent methods without a body are retried,
+// since a body has already streamed through the capture by the time the first attempt fails.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_attempts: u32, // Including the first, so 1 never retries
+    #[serde(rename = "backoff_ms", with = "super::repr::millis")]
+    pub backoff: Duration, // Wait before the first retry, doubled for each one after
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { max_attempts: 1, backoff: Duration::from_millis(200) }
+    }
+}
+
+impl RetryPolicy {
+    fn delay(&self, retry: u32) -> Duration {
+        self.backoff.checked_mul(1 << retry.min(16)).unwrap_or(self.backoff)
+    }
+}
+
+// Can be saved to and loaded from a TOML file. Anything missing from the file keeps its default.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct ProxyConfig {
     pub pubkey_path: String,
     pub privkey_path: String,
-    pub listen: SocketAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<String>, // CA cert in PEM, used instead of the files at the paths above when given with the key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_key: Option<String>,
+    #[serde(with = "super::repr::string")]
+    pub listen: Listen,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics_listen: Option<SocketAddr>, // Serve Prometheus metrics at /metrics on this address
+    pub frontend: Frontend,
     pub starting_id: u32,
+    pub max_tunnels: usize,
+    pub ca_not_after_days: u32,
+    pub leaf_not_after_days: u32,
+    pub key_algorithm: KeyAlgorithm,
+    #[serde(with = "super::repr::optional_string", skip_serializing_if = "Option::is_none")]
+    pub upstream_proxy: Option<Uri>, // Send all outbound traffic through this HTTP proxy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_roots: Option<String>, // PEM bundle of CAs to trust for origins on top of the built in ones
+    #[serde(with = "super::repr::optional_string", skip_serializing_if = "Option::is_none")]
+    pub mirror_upstream: Option<Uri>, // Send a copy of every request here as well, its response is captured but never reaches the client
+    pub upgrade_buffer_size: usize, // Read buffer for each direction of an upgraded connection
+    pub mitm_bypass: Vec<String>, // Hosts (and their subdomains) that are tunneled through untouched instead of intercepted
+    pub event_capacity: usize, // Events queued for the store before the proxy has to wait on it
+    pub forwarded_for: bool, // Tell origins who the client is with X-Forwarded-For and Forwarded
+    pub follow_redirects: bool, // Follow 3xx responses on the client's behalf, each hop captured under its own id
+    pub max_redirects: usize, // Past this many the redirect goes back to the client instead
+    // Keep-alive for connections to origins, shared by everything forwarded including mirrored copies and followed
+    // redirects. Bypassed tunnels dial their own connection that closes with the tunnel, so neither applies to them, and
+    // the leaf certs shown to clients are cached by hostname apart from this, whatever happens to the connection onward.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool_max_idle_per_host: Option<usize>, // Idle connections kept per origin, no limit when not given. 0 opens a new one for every request.
+    #[serde(rename = "pool_idle_timeout_ms", with = "super::repr::millis")]
+    pub pool_idle_timeout: Duration, // How long an idle connection is kept before it's closed
+    pub danger_accept_invalid_certs: bool, // Trust origins whose certs don't verify, for debugging broken ones
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fault_seed: Option<u64>, // Fixed seed for reproducible faults, otherwise seeded from the clock
+    // TOML wants plain values before tables, so the table-like options stay at the end
+    pub request_throttle: Throttle, // Pacing for request bodies on their way to the origin
+    pub response_throttle: Throttle, // Pacing for response bodies on their way back to the client
+    pub retry: RetryPolicy,
+    #[serde(skip_serializing_if = "Vec::is_empty")] // An empty list would be a plain value after the tables
+    pub faults: Vec<FaultRule>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub latency: Vec<LatencyRule>, // Extra wait before responses from matching hosts
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rewrite_rules: Vec<RewriteRule>, // Edits to requests, applied in order by whatever handles the events
 }
 
 impl Default for ProxyConfig {
@@ -34,60 +170,364 @@ impl Default for ProxyConfig {
         Self {
             pubkey_path: "data/cert".to_string(),
             privkey_path: "data/key".to_string(),
-            listen: SocketAddr::from(([0, 0, 0, 0], 1337)),
-            starting_id: 1, // Reserve id 0 for events not associated with requests
+            ca_cert: None,
+            ca_key: None,
+            listen: Listen::Tcp(SocketAddr::from(([0, 0, 0, 0], 1337))),
+            metrics_listen: None,
+            frontend: Frontend::default(),
+            starting_id: 1, // Any start works, events that aren't about a request have no id at all
+            max_tunnels: 512,
+            ca_not_after_days: 365,
+            leaf_not_after_days: 365,
+            key_algorithm: KeyAlgorithm::default(),
+            upstream_proxy: None,
+            upstream_roots: None,
+            mirror_upstream: None,
+            upgrade_buffer_size: 16 * 1024,
+            mitm_bypass: Vec::new(),
+            event_capacity: 128,
+            forwarded_for: false,
+            follow_redirects: false,
+            max_redirects: 10,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: Duration::from_secs(90), // hyper's own default
+            danger_accept_invalid_certs: false,
+            fault_seed: None,
+            request_throttle: Throttle::default(),
+            response_throttle: Throttle::default(),
+            retry: RetryPolicy::default(),
+            faults: Vec::new(),
+            latency: Vec::new(),
+            rewrite_rules: Vec::new(),
         }
     }
 }
 
 impl ProxyConfig {
-    pub fn build(self) -> (ProxyServer, Receiver<ProxyEvent>) {
+    // Fails when the CA can't be loaded or created
+    pub fn build(self) -> Result<(ProxyServer, Receiver<ProxyEvent>), String> {
         ProxyServer::new(self)
     }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, String> {
+        let conf = Self::read_file(path)?;
+        conf.validate()?;
+        Ok(conf)
+    }
+
+    // Parsed but not validated, for when more is layered on top before it's checked
+    pub fn read_file(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read config {}: {}", path.display(), e))?;
+        toml::from_str(&text)
+            .map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+
+    pub fn to_file(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        let text = toml::to_string_pretty(self)
+            .map_err(|e| format!("Unable to serialize config: {}", e))?;
+        std::fs::write(path, text)
+            .map_err(|e| format!("Unable to write config {}: {}", path.display(), e))
+    }
+
+    // Whatever is set in the environment wins over the file
+    pub fn apply_env(&mut self) {
+        for (name, value) in [(CA_CERT_ENV, &mut self.ca_cert), (CA_KEY_ENV, &mut self.ca_key)] {
+            if let Ok(env) = std::env::var(name) {
+                *value = Some(env);
+            }
+        }
+    }
+
+    fn cert_options(&self) -> CertOptions {
+        CertOptions {
+            ca_not_after_days: self.ca_not_after_days,
+            leaf_not_after_days: self.leaf_not_after_days,
+            key_algorithm: self.key_algorithm,
+        }
+    }
+
+    // The CA cert and key are created on first run, which needs somewhere to put them, unless they're given inline
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.ca_cert, &self.ca_key) {
+            (Some(cert), Some(key)) => {
+                CertStore::import(cert.as_bytes(), key.as_bytes(), self.cert_options())
+                    .map_err(|e| format!("Unable to use the inline CA: {}", e))?;
+            },
+            (None, None) => {
+                for (what, path) in [("Certificate", &self.pubkey_path), ("Private key", &self.privkey_path)] {
+                    let dir = Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty());
+                    if let Some(dir) = dir.filter(|dir| !dir.is_dir()) {
+                        return Err(format!("{} path {} is in {}, which isn't an existing directory", what, path, dir.display()));
+                    }
+                }
+            },
+            _ => return Err(format!("ca_cert and ca_key ({} and {}) have to be given together", CA_CERT_ENV, CA_KEY_ENV)),
+        }
+        if let Some(path) = &self.upstream_roots {
+            load_roots(path)?;
+        }
+        if let Some(mirror) = self.mirror_upstream.as_ref().filter(|mirror| mirror.scheme().is_none() || mirror.authority().is_none()) {
+            return Err(format!("Mirror upstream {} needs a scheme and host, e.g. http://localhost:8080", mirror));
+        }
+        for (idx, rule) in self.rewrite_rules.iter().enumerate() {
+            if let Some(e) = rule.error() {
+                return Err(format!("Rewrite rule {} has a bad pattern: {}", idx + 1, e));
+            }
+        }
+        Ok(())
+    }
 }
 
+#[derive(Clone)]
 pub struct ProxyServer {
-    listen: SocketAddr,
+    listen: Listen,
+    metrics_listen: Option<SocketAddr>,
+    frontend: Frontend,
     events: Sender<ProxyEvent>,
+    metrics: Arc<Metrics>,
+    dropped_notices: Arc<AtomicUsize>,
+    starting_id: u32,
+    rewrite_rules: Arc<Mutex<Vec<RewriteRule>>>, // From the config, edited live by the store
     core: ProxyCore,
 }
 
 impl ProxyServer {
-    pub fn new(conf: ProxyConfig) -> (Self, Receiver<ProxyEvent>) {
-        let (tx, rx) = channel(128);
+    pub fn new(conf: ProxyConfig) -> Result<(Self, Receiver<ProxyEvent>), String> {
+        let cert_options = conf.cert_options();
+        let cert_store = match (&conf.ca_cert, &conf.ca_key) {
+            (Some(cert), Some(key)) => CertStore::import(cert.as_bytes(), key.as_bytes(), cert_options)
+                .map_err(|e| format!("Unable to use the inline CA: {}", e))?,
+            _ => CertStore::load_or_create(&conf.pubkey_path, &conf.privkey_path, cert_options)?,
+        };
+        let (tx, rx) = channel(conf.event_capacity);
+        let dropped_notices = Arc::new(AtomicUsize::new(0));
         let mut http_connector = hyper::client::HttpConnector::new();
         http_connector.enforce_http(false);
+        // Checked by validate, a bundle that's broken past that point only costs the origins it would have covered
+        let extra_roots = conf.upstream_roots.as_deref().map(load_roots).transpose().unwrap_or_else(|e| {
+            error!("{}", e);
+            None
+        });
         let client_config = ClientConfig::builder()
             .with_safe_defaults()
-            .with_custom_certificate_verifier(Arc::new(CertVerifier::new(tx.clone())))
+            .with_custom_certificate_verifier(Arc::new(CertVerifier::new(tx.clone(), dropped_notices.clone(), conf.danger_accept_invalid_certs, &extra_roots.unwrap_or_default())))
             .with_no_client_auth();
+        let connector = UpstreamConnector::new(http_connector, conf.upstream_proxy);
         let client = hyper_rustls::HttpsConnectorBuilder::new()
             .with_tls_config(client_config)
             .https_or_http()
             .enable_http1()
-            .build();
-        (Self {
+            .enable_http2()
+            .wrap_connector(connector.clone());
+        Ok((Self {
             listen: conf.listen,
+            metrics_listen: conf.metrics_listen,
+            frontend: conf.frontend,
             events: tx.clone(),
+            metrics: Arc::new(Metrics::default()),
+            dropped_notices,
+            starting_id: conf.starting_id,
+            rewrite_rules: Arc::new(Mutex::new(conf.rewrite_rules)),
             core: ProxyCore {
-                cert_store: Arc::new(CertStore::load_or_create(
-                    &conf.pubkey_path,
-                    &conf.privkey_path,
-                )),
+                cert_store: Arc::new(cert_store),
                 channel: tx,
                 id: Arc::new(AtomicU32::new(conf.starting_id)),
                 fallback_host: None,
-                client: Client::builder().build(client)
+                tls: None,
+                client_addr: None,
+                forwarded_for: conf.forwarded_for,
+                mirror_upstream: conf.mirror_upstream,
+                follow_redirects: conf.follow_redirects,
+                max_redirects: conf.max_redirects,
+                client: {
+                    let mut builder = Client::builder();
+                    builder.pool_idle_timeout(conf.pool_idle_timeout);
+                    if let Some(max_idle) = conf.pool_max_idle_per_host {
+                        builder.pool_max_idle_per_host(max_idle);
+                    }
+                    builder.build(client)
+                },
+                connector,
+                mitm_bypass: Arc::new(conf.mitm_bypass.into_iter().map(|host| host.to_ascii_lowercase()).collect()),
+                tunnels: Arc::new(AtomicUsize::new(0)),
+                max_tunnels: conf.max_tunnels,
+                edit_bodies: Arc::new(AtomicBool::new(true)),
+                upgrade_buffer_size: conf.upgrade_buffer_size,
+                request_throttle: conf.request_throttle,
+                response_throttle: conf.response_throttle,
+                retry: conf.retry,
+                faults: Arc::new(FaultInjector::new(conf.faults, conf.fault_seed)),
+                latency: Arc::new(conf.latency),
             },
-        }, rx)
+        }, rx))
+    }
+
+    pub fn cert_store(&self) -> Arc<CertStore> {
+        self.core.cert_store.clone()
+    }
+
+    // The id the next request will get
+    pub fn next_id(&self) -> u32 {
+        self.core.id.load(crate::ORDERING)
+    }
+
+    // Go back to handing out ids from `starting_id`, handing it back, unless an id went out since the next one was `next`.
+    // Anything that already has an id has to be finished, or its events would share an id with a new request's.
+    pub fn reset_ids(&self, next: u32) -> Option<u32> {
+        self.core.id.compare_exchange(next, self.starting_id, crate::ORDERING, crate::ORDERING).ok()?;
+        Some(self.starting_id)
+    }
+
+    // Skip `count` ids so no request is ever given them, handing back the first
+    pub fn reserve_ids(&self, count: u32) -> u32 {
+        self.core.id.fetch_add(count, crate::ORDERING)
     }
 
-    pub fn run(self) -> JoinHandle<Result<(), hyper::Error>> {
-        tokio::spawn(Server::bind(&self.listen).serve(self))
+    // Request events always wait for room in the channel, only notices from places that can't wait get dropped
+    pub fn dropped_notices(&self) -> usize {
+        self.dropped_notices.load(crate::ORDERING)
     }
+
+    pub fn open_tunnels(&self) -> usize {
+        self.core.tunnels.load(crate::ORDERING)
+    }
+
+    // On by default. Whoever answers the callbacks can turn it off while nothing would change a body, chunks then
+    // stream straight through and only show up as events without a callback. Bodies already streaming keep the
+    // setting they started with.
+    pub fn edit_bodies(&self) -> Arc<AtomicBool> {
+        self.core.edit_bodies.clone()
+    }
+
+    // Only counts what's passed to `Metrics::observe` by whoever holds the event receiver
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    // Loaded from the config, the proxy never applies them itself
+    pub fn rewrite_rules(&self) -> Arc<Mutex<Vec<RewriteRule>>> {
+        self.rewrite_rules.clone()
+    }
+
+    // None when no metrics address is configured
+    pub fn serve_metrics(&self) -> Option<Result<JoinHandle<Result<(), hyper::Error>>, hyper::Error>> {
+        let addr = self.metrics_listen?;
+        Some(metrics::serve(addr, self.metrics.clone(), self.core.tunnels.clone()))
+    }
+
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_listen
+    }
+
+    // Fails straight away if the listen address can't be bound, e.g. because the port is taken
+    pub fn run(&self) -> std::io::Result<JoinHandle<Result<(), hyper::Error>>> {
+        self.spawn_on(&self.listen)
+    }
+
+    pub fn listen_addr(&self) -> Listen {
+        self.listen.clone()
+    }
+
+    // Start serving on a new address. On failure (e.g. the port is taken) nothing changes, so the caller
+    // can keep its old listener running; on success it's up to the caller to stop the old one.
+    pub fn serve_on(&mut self, addr: Listen) -> std::io::Result<JoinHandle<Result<(), hyper::Error>>> {
+        let server = self.spawn_on(&addr)?;
+        self.listen = addr;
+        Ok(server)
+    }
+
+    pub fn frontend(&self) -> Frontend {
+        self.frontend
+    }
+
+    fn spawn_on(&self, addr: &Listen) -> std::io::Result<JoinHandle<Result<(), hyper::Error>>> {
+        match addr {
+            Listen::Tcp(addr) => {
+                let listener = std::net::TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                let incoming = AddrIncoming::from_listener(tokio::net::TcpListener::from_std(listener)?)
+                    .map_err(std::io::Error::other)?;
+                Ok(self.spawn_with(incoming))
+            },
+            #[cfg(unix)]
+            Listen::Unix(path) => Ok(self.spawn_with(unix_incoming(bind_unix(path)?))),
+            #[cfg(not(unix))]
+            Listen::Unix(_) => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Unix sockets aren't supported on this platform")),
+        }
+    }
+
+    fn spawn_with<I>(&self, incoming: I) -> JoinHandle<Result<(), hyper::Error>>
+    where
+        I: Accept + Unpin + Send + 'static,
+        I::Conn: Peer + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        I::Error: Into<Box<dyn std::error::Error + Send + Sync>> + fmt::Display + Send,
+    {
+        match self.frontend {
+            Frontend::Http => tokio::spawn(Server::builder(incoming).serve(self.clone())),
+            Frontend::Socks5 => tokio::spawn(self.core.clone().serve_socks(incoming)),
+        }
+    }
+
+    // Push a previously captured request back through the proxy. It gets a fresh id, so it shows up as a new capture.
+    pub fn replay(&self, head: RequestHead, body: Vec<u8>) -> (u32, JoinHandle<Result<(), ProxyError>>) {
+        let core = self.core.clone();
+        let id = core.id.fetch_add(1, crate::ORDERING);
+        (id, tokio::spawn(async move {
+            let req = head.to_request(Body::from(body)).map_err(|e| ProxyError::from(e.to_string()))?;
+            let authority = req.uri().authority().map(|authority| authority.to_string())
+                .ok_or_else(|| format!("Can't replay {} without a host", req.uri()))?;
+            let resp = core.forward(req, authority, id, 0).await?;
+            // Drain the body so the response chunks make it to the store
+            hyper::body::to_bytes(resp.into_body()).await.map_err(|e| ProxyError::from(&e))?;
+            Ok(())
+        }))
+    }
+}
+
+// The client's address, for X-Forwarded-For and the like. Unix socket peers don't have one worth passing on.
+pub trait Peer {
+    fn peer(&self) -> Option<SocketAddr>;
+}
+
+impl Peer for AddrStream {
+    fn peer(&self) -> Option<SocketAddr> {
+        Some(self.remote_addr())
+    }
+}
+
+#[cfg(unix)]
+impl Peer for tokio::net::UnixStream {
+    fn peer(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+// A socket file left behind by a run that didn't clean up is taken over, one something is still listening on isn't
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> std::io::Result<tokio::net::UnixListener> {
+    if matches!(std::os::unix::net::UnixStream::connect(path), Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused) {
+        std::fs::remove_file(path)?;
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+// Clients that gave up before being accepted are skipped, like AddrIncoming does, anything else ends the listener
+#[cfg(unix)]
+fn unix_incoming(listener: tokio::net::UnixListener) -> impl Accept<Conn = tokio::net::UnixStream, Error = std::io::Error> {
+    hyper::server::accept::poll_fn(move |cx| loop {
+        match listener.poll_accept(cx) {
+            Poll::Ready(Ok((conn, _))) => return Poll::Ready(Some(Ok(conn))),
+            Poll::Ready(Err(e)) if matches!(e.kind(), std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::ConnectionReset) => continue,
+            Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+            Poll::Pending => return Poll::Pending,
+        }
+    })
 }
 
-impl<'a> Service<&'a AddrStream> for ProxyServer {
+impl<'a, T: Peer> Service<&'a T> for ProxyServer {
     type Response = ProxyCore;
 
     type Error = Infallible;
@@ -101,8 +541,9 @@ impl<'a> Service<&'a AddrStream> for ProxyServer {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, _req: &'a AddrStream) -> Self::Future {
-        let core = self.core.clone();
+    fn call(&mut self, conn: &'a T) -> Self::Future {
+        let mut core = self.core.clone();
+        core.client_addr = conn.peer();
         let fut = async move { Ok(core) };
         Box::pin(fut)
     }
@@ -114,13 +555,50 @@ pub struct ProxyCore {
     channel: Sender<ProxyEvent>,
     id: Arc<AtomicU32>,
     fallback_host: Option<String>,
-    client: Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>, Body>,
+    tls: Option<TlsInfo>, // Set when this connection reached us over TLS, decides the onward scheme when the request doesn't say
+    client_addr: Option<SocketAddr>, // Peer of the connection being served, replays don't have one
+    forwarded_for: bool,
+    mirror_upstream: Option<Uri>,
+    follow_redirects: bool,
+    max_redirects: usize,
+    client: Client<hyper_rustls::HttpsConnector<UpstreamConnector>, Body>,
+    connector: UpstreamConnector, // Raw connections for bypassed tunnels, still honoring the upstream proxy
+    mitm_bypass: Arc<Vec<String>>,
+    tunnels: Arc<AtomicUsize>,
+    max_tunnels: usize,
+    edit_bodies: Arc<AtomicBool>, // Off, body chunks are only reported instead of each waiting on its callback
+    upgrade_buffer_size: usize,
+    request_throttle: Throttle,
+    response_throttle: Throttle,
+    retry: RetryPolicy,
+    faults: Arc<FaultInjector>,
+    latency: Arc<Vec<LatencyRule>>,
+}
+
+// Counts a live CONNECT tunnel for as long as it's held, even if the tunnel task panics
+struct TunnelGuard(Arc<AtomicUsize>);
+
+impl TunnelGuard {
+    fn try_acquire(tunnels: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        if tunnels.fetch_add(1, crate::ORDERING) >= max {
+            tunnels.fetch_sub(1, crate::ORDERING);
+            None
+        } else {
+            Some(Self(tunnels.clone()))
+        }
+    }
+}
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, crate::ORDERING);
+    }
 }
 
 impl Service<Request<Body>> for ProxyCore {
     type Response = Response<Body>;
 
-    type Error = String;
+    type Error = ProxyError;
 
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
@@ -131,166 +609,778 @@ impl Service<Request<Body>> for ProxyCore {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
         let proxy = self.clone();
         let host = req.uri().host().map(String::from);
         Box::pin(async move {
             if let &Method::CONNECT = req.method() {
+                let guard = match TunnelGuard::try_acquire(&proxy.tunnels, proxy.max_tunnels) {
+                    Some(guard) => guard,
+                    None => {
+                        let _ = proxy.channel.send(ProxyEvent::msg(
+                            format!("Rejecting CONNECT to {:?}, {} tunnels already open", host, proxy.max_tunnels)
+                        )).await;
+                        return Ok(
+                            Response::builder()
+                                .status(503)
+                                .body(hyper::Body::from("Too many open tunnels"))
+                                .unwrap()
+                        )
+                    }
+                };
+                let authority = req.uri().authority().map(|authority| authority.to_string());
                 tokio::spawn(async move {
-                    match upgrade::on(req).await {
-                        Ok(upgraded) => {
-                            proxy.do_tls_upgrade(upgraded, host).await.unwrap();
-                        }
-                        Err(e) => {
-                            eprint!("Error upgrading connection: {}", e);
-                        }
+                    let _guard = guard;
+                    let result = match upgrade::on(req).await {
+                        Ok(upgraded) => match authority {
+                            Some(authority) if proxy.bypasses(host.as_deref()) => proxy.blind_tunnel(upgraded, authority).await,
+                            authority => proxy.intercept_tunnel(upgraded, host.clone(), authority).await,
+                        },
+                        Err(e) => Err(e.into()),
+                    };
+                    if let Err(e) = result {
+                        let _ = proxy.channel.send(ProxyEvent::msg(format!("Tunnel to {:?} failed: {}", host, e))).await;
                     }
                 });
                 Ok(Response::default())
+            } else if let Some(authority) = req.uri().authority().map(|authority| authority.to_string()).or_else(|| proxy.fallback_host.clone()) {
+                // The authority rather than the host, or an origin on any port but the default is never reached
+                let id = proxy.id.fetch_add(1, crate::ORDERING);
+                if let Some(info) = &proxy.tls {
+                    let _ = proxy.channel.send(ProxyEvent::tls(id, info.clone())).await;
+                }
+                proxy.forward(req, authority, id, 0).await
             } else {
-                if let Some(host) = host.or(proxy.fallback_host) {
-                    let id = proxy.id.fetch_add(1, crate::ORDERING);
-                    let mut uri = req.uri().to_owned().into_parts();
-                    uri.authority = Some(Authority::from_maybe_shared(host.clone()).unwrap());
-                    if uri.scheme == None {
-                        uri.scheme = Some(Scheme::HTTPS);
+                Err(ProxyError::from("No SNI or backup host".to_string()))
+            }
+        })
+    }
+}
+
+// Relays one direction of an upgraded connection until the reader is done. Every chunk goes past the store first,
+// which sends back what to write, possibly edited. Only a failed write is an error, the reader going away is the end,
+// passed on by shutting down the writer. The other direction carries on, its side may still have things to say.
+async fn pump<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    chan: &Sender<ProxyEvent>, id: u32, chunk_id: &AtomicU32, outbound: bool, from: &mut R, to: &mut W, buffer_size: usize,
+) -> Result<(), std::io::Error> {
+    let mut buf = vec![0u8; buffer_size];
+    loop {
+        let read = match from.read(&mut buf).await {
+            Ok(0) | Err(_) => return to.shutdown().await,
+            Ok(read) => read,
+        };
+        let bytes = Bytes::copy_from_slice(&buf[..read]);
+        let chunk = chunk_id.fetch_add(1, crate::ORDERING);
+        let (event, completion) = if outbound {
+            ProxyEvent::upgrade_tx(id, chunk, &bytes)
+        } else {
+            ProxyEvent::upgrade_rx(id, chunk, &bytes)
+        };
+        let _ = chan.send(event).await;
+        let bytes = match completion.await {
+            Ok(super::ProxyState::UpgradeTx { chunk, .. }) | Ok(super::ProxyState::UpgradeRx { chunk, .. }) => chunk,
+            Ok(e) => {
+                warn!("Got unexpected result, ignoring: {:?}", e);
+                bytes
+            },
+            Err(_) => bytes,
+        };
+        to.write_all(&bytes).await?;
+    }
+}
+
+// The client picks h2 or HTTP/1.1 per origin through ALPN, but it refuses to send a request marked HTTP/2
+// over an HTTP/1.1 connection. 1.1 works for both, so that's what goes out; the capture keeps the original.
+fn outbound(mut req: Request<Body>) -> Request<Body> {
+    if req.version() == Version::HTTP_2 {
+        *req.version_mut() = Version::HTTP_11;
+    }
+    req
+}
+
+// Names as the RFCs and IANA write them, rustls has its own
+fn tls_info(conn: &rustls::ServerConnection) -> TlsInfo {
+    let version = match conn.protocol_version() {
+        Some(ProtocolVersion::TLSv1_3) => "TLS 1.3".to_string(),
+        Some(ProtocolVersion::TLSv1_2) => "TLS 1.2".to_string(),
+        Some(other) => format!("{:?}", other),
+        None => "unknown version".to_string(),
+    };
+    let cipher = match conn.negotiated_cipher_suite() {
+        Some(suite) => {
+            let name = format!("{:?}", suite.suite());
+            name.strip_prefix("TLS13_").map(|rest| format!("TLS_{}", rest)).unwrap_or(name)
+        },
+        None => "unknown cipher".to_string(),
+    };
+    let alpn = conn.alpn_protocol().map(|alpn| String::from_utf8_lossy(alpn).into_owned());
+    TlsInfo { version, cipher, alpn }
+}
+
+// Added before the request is captured, so the capture shows what the origin got. Hops already listed by
+// a proxy in front of us are kept, with the client appended, as every proxy in the chain is meant to do.
+fn add_forwarded(headers: &mut HeaderMap, client: SocketAddr) {
+    let ip = client.ip().to_canonical();
+    let node = match ip {
+        IpAddr::V4(ip) => format!("for={}", ip),
+        IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+    };
+    for (name, value) in [(HeaderName::from_static("x-forwarded-for"), ip.to_string()), (FORWARDED, node)] {
+        let mut chain: Vec<String> = headers.get_all(&name).iter()
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+            .collect();
+        chain.push(value);
+        if let Ok(value) = HeaderValue::from_str(&chain.join(", ")) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+impl ProxyCore {
+    // Accept errors are already retried inside AddrIncoming, so this only ends if the listener goes away
+    async fn serve_socks<I>(self, mut incoming: I) -> Result<(), hyper::Error>
+    where
+        I: Accept + Unpin,
+        I::Conn: Peer + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        I::Error: fmt::Display + Send,
+    {
+        while let Some(accepted) = futures::future::poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx)).await {
+            let conn = match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    let _ = self.channel.send(ProxyEvent::msg(format!("Failed to accept SOCKS connection: {}", e))).await;
+                    continue;
+                }
+            };
+            let mut proxy = self.clone();
+            proxy.client_addr = conn.peer();
+            tokio::spawn(async move {
+                if let Err(e) = proxy.socks_tunnel(conn).await {
+                    let _ = proxy.channel.send(ProxyEvent::msg(format!("SOCKS connection failed: {}", e))).await;
+                }
+            });
+        }
+        Ok(())
+    }
+
+    // The SOCKS equivalent of a CONNECT request, past the handshake it's treated exactly the same
+    async fn socks_tunnel<T>(&self, mut conn: T) -> Result<(), BoxError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let target = socks::handshake(&mut conn).await?;
+        let guard = match TunnelGuard::try_acquire(&self.tunnels, self.max_tunnels) {
+            Some(guard) => guard,
+            None => {
+                let _ = self.channel.send(ProxyEvent::msg(
+                    format!("Rejecting SOCKS connection to {}, {} tunnels already open", target.authority, self.max_tunnels)
+                )).await;
+                socks::reply(&mut conn, socks::GENERAL_FAILURE).await?;
+                return Ok(());
+            }
+        };
+        let _guard = guard;
+        socks::reply(&mut conn, socks::SUCCEEDED).await?;
+        let host = Some(target.host);
+        let result = if self.bypasses(host.as_deref()) {
+            self.blind_tunnel(conn, target.authority).await
+        } else {
+            self.intercept_tunnel(conn, host.clone(), Some(target.authority)).await
+        };
+        if let Err(e) = result {
+            let _ = self.channel.send(ProxyEvent::msg(format!("Tunnel to {:?} failed: {}", host, e))).await;
+        }
+        Ok(())
+    }
+
+    // `redirects` counts the ones already followed to get to this request
+    async fn forward(self, mut req: Request<Body>, authority: String, id: u32, redirects: usize) -> Result<Response<Body>, ProxyError> {
+        let mut uri = req.uri().to_owned().into_parts();
+        uri.authority = Some(authority.parse::<Authority>().map_err(|e| ProxyError::from(format!("Can't forward to {:?}: {}", authority, e)))?);
+        if uri.scheme == None {
+            uri.scheme = Some(if self.tls.is_some() { Scheme::HTTPS } else { Scheme::HTTP });
+        }
+        let uri = Uri::from_parts(uri).unwrap();
+        *req.uri_mut() = uri;
+        // A followed redirect already carries them over from the first request
+        if let (true, Some(client), 0) = (self.forwarded_for, self.client_addr, redirects) {
+            add_forwarded(req.headers_mut(), client);
+        }
+        let bodyless = req.body().is_end_stream();
+        let (ser_req, req_upgrade) = super::request::Request::from_request(req, id, self.channel.clone(), self.request_throttle, &self.edit_bodies).await;
+        // Kept after the user had their say, so a retry sends what they sent
+        let retry_head = Some(ser_req.head.clone()).filter(|head| {
+            self.retry.max_attempts > 1 && bodyless && matches!(head.method, Method::GET | Method::HEAD | Method::PUT | Method::DELETE)
+        });
+        let sent = Some(ser_req.head.clone()).filter(|_| self.follow_redirects);
+        let offered = hop::upgrade_protocols(&ser_req.head.headers);
+        let onward = outbound(ser_req.into());
+        let origin = onward.uri().host().unwrap_or_default().to_string();
+        if let Some(fault) = self.faults.roll(&origin, onward.uri().path()) {
+            return self.inject(fault, id).await;
+        }
+        // An upgrade can't be copied, the connection it turns into belongs to the client
+        let onward = match &self.mirror_upstream {
+            Some(mirror) if !onward.headers().contains_key(UPGRADE) => self.mirror(onward, mirror, bodyless, id),
+            _ => onward,
+        };
+        let mut result = self.client.request(onward).await;
+        if let Some(head) = retry_head {
+            for retry in 1..self.retry.max_attempts {
+                let e = match &result {
+                    Err(e) if e.is_connect() => e,
+                    _ => break,
+                };
+                let delay = self.retry.delay(retry - 1);
+                let _ = self.channel.send(ProxyEvent::msg(format!(
+                    "Request {} couldn't connect ({}), retry {} of {} in {:?}", id, e, retry, self.retry.max_attempts - 1, delay
+                ))).await;
+                tokio::time::sleep(delay).await;
+                let onward = head.to_request(Body::empty()).map_err(|e| ProxyError::from(e.to_string()))?;
+                result = self.client.request(outbound(onward)).await;
+            }
+        }
+        match result {
+            Err(e) => {
+                let _ = self.channel.send(super::ProxyEvent::err(id, ProxyError::from(&e))).await;
+                Ok(
+                    Response::builder()
+                        .status(500)
+                        .body(hyper::Body::from("Internal Proxy Error"))
+                        .unwrap()
+                )
+            },
+            Ok(resp) => {
+                if let Some((idx, rule)) = fault::latency_for(&self.latency, &origin) {
+                    let _ = self.channel.send(ProxyEvent::msg(format!(
+                        "Holding response {} from {} for {:?}, latency rule {} ({:?})", id, origin, rule.delay, idx + 1, rule.host
+                    ))).await;
+                    tokio::time::sleep(rule.delay).await;
+                }
+                let (resp, resp_upgrade) = super::response::Response::from_response(resp, id, self.channel.clone(), self.response_throttle, &self.edit_bodies).await;
+                if let Some(sent) = sent.filter(|_| resp_upgrade.is_none()) {
+                    match redirect::follow(&sent, !bodyless, &resp.head) {
+                        Ok(Some(_)) if redirects >= self.max_redirects => {
+                            let _ = self.channel.send(ProxyEvent::msg(format!(
+                                "Stopped following redirects at request {} after {}, the client gets this one", id, redirects
+                            ))).await;
+                        },
+                        Ok(Some(next)) => return self.follow(resp, next, id, redirects).await,
+                        Ok(None) => {},
+                        Err(e) => {
+                            let _ = self.channel.send(ProxyEvent::msg(format!("Redirect of request {} wasn't followed, {}", id, e))).await;
+                        },
                     }
-                    let uri = Uri::from_parts(uri).unwrap();
-                    *req.uri_mut() = uri;
-                    let (ser_req, req_upgrade) = super::request::Request::from_request(req, id, proxy.channel.clone()).await;
-                    match proxy.client.request(ser_req.into()).await {
+                }
+                if let Some(resp_upgrade) = resp_upgrade {
+                    // Relaying anything short of an upgrade both sides agreed on would join a client still speaking
+                    // HTTP to an origin speaking something else, so the client gets a 502 and the origin is hung up on
+                    let checked = hop::check_upgrade(offered.as_deref(), resp.head.status, &resp.head.headers)
+                        .and_then(|()| req_upgrade.ok_or_else(|| "Origin switched protocols but the client's connection can't be handed over".to_string()));
+                    let req_upgrade = match checked {
+                        Ok(req_upgrade) => req_upgrade,
                         Err(e) => {
-                            proxy.channel.send(super::ProxyEvent::err(id, e.to_string())).await.unwrap();
-                            Ok(
-                                Response::builder()
-                                    .status(500)
-                                    .body(hyper::Body::from("Internal Proxy Error"))
-                                    .unwrap()
-                            )
+                            let _ = self.channel.send(ProxyEvent::err(id, ProxyError::Other(e.clone()))).await;
+                            return Ok(Response::builder().status(StatusCode::BAD_GATEWAY).body(Body::from(e)).unwrap());
                         },
-                        Ok(resp) => {
-                            let (resp, resp_upgrade) = super::response::Response::from_response(resp, id, proxy.channel.clone()).await;
-                            if let (Some(req_upgrade), Some(resp_upgrade)) = (req_upgrade, resp_upgrade) {
-                                tokio::spawn( async move {
-                                    println!("Both sides trying to upgrade, attempting");
-                                    let chan = proxy.channel.clone();
-                                    let chunk_id = AtomicU32::new(0);
-                                    match try_join!(req_upgrade, resp_upgrade){
-                                        Ok((mut req, mut resp)) => {
-                                            chan.send(super::ProxyEvent::upgrade_open(id)).await.unwrap();
-                                            loop {
-                                                select!{
-                                                    chunk = async {
-                                                        let mut buf: [u8; 512] = [0; 512];
-                                                        if let Ok(read) = req.read(&mut buf).await {
-                                                            if read > 0 {
-                                                                let bytes = Bytes::copy_from_slice(&buf[..read]);
-                                                                let req_id = chunk_id.fetch_add(1, crate::ORDERING);
-                                                                let (event, completion) = super::ProxyEvent::upgrade_tx(id, req_id, &bytes);
-                                                                chan.send(event).await.unwrap();
-                                                                match completion.await {
-                                                                    Ok(super::ProxyState::UpgradeTx{id, chunk}) => Some(chunk),
-                                                                    Ok(e) => {
-                                                                        println!("Got unexpected result, ignoring: {:?}", e);
-                                                                        Some(bytes)
-                                                                    },
-                                                                    Err(_) => {
-                                                                        Some(bytes)
-                                                                    }
-                                                                }
-                                                            } else {
-                                                                None
-                                                            }
-                                                        } else {
-                                                            None
-                                                        }
-                                                    } => {
-                                                        if let Some(bytes) = chunk {
-                                                            resp.write_all(&bytes).await.unwrap()
-                                                        } else {
-                                                            println!("Req disconnected, done");
-                                                            break
-                                                        }
-                                                    },
-                                                    chunk = async {
-                                                        let mut buf: [u8; 512] = [0; 512];
-                                                        if let Ok(read) = resp.read(&mut buf).await{
-                                                            if read > 0 {
-                                                                let bytes = Bytes::copy_from_slice(&buf[..read]);
-                                                                let req_id = chunk_id.fetch_add(1, crate::ORDERING);
-                                                                let (event, completion) = super::ProxyEvent::upgrade_rx(id, req_id, &bytes);
-                                                                chan.send(event).await.unwrap();
-                                                                match completion.await {
-                                                                    Ok(super::ProxyState::UpgradeRx{id, chunk}) => Some(chunk),
-                                                                    Ok(e) => {
-                                                                        println!("Got unexpected result, ignoring: {:?}", e);
-                                                                        Some(bytes)
-                                                                    },
-                                                                    Err(_) => {
-                                                                        Some(bytes)
-                                                                    }
-                                                                }
-                                                            } else {
-                                                                None
-                                                            }
-                                                        } else {
-                                                            None
-                                                        }
-                                                    } => {
-                                                        if let Some(bytes) = chunk {
-                                                            req.write_all(&bytes).await.unwrap()
-                                                        } else {
-                                                            println!("Resp disconnected, done");
-                                                            break
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        Err(e) => {
-                                            eprintln!("Error upgrading: {}", e)
-                                        }
-                                    }
-                                    chan.send(super::ProxyEvent::upgrade_close(id)).await.unwrap();
-                                    println!("Done, closing socket");
-                                });
-                            };
-                            Ok(resp.into())
+                    };
+                    tokio::spawn( async move {
+                        let chan = self.channel.clone();
+                        let chunk_id = AtomicU32::new(0);
+                        match try_join!(req_upgrade, resp_upgrade){
+                            Ok((req, resp)) => {
+                                let _ = chan.send(super::ProxyEvent::upgrade_open(id)).await;
+                                let (mut req_read, mut req_write) = tokio::io::split(req);
+                                let (mut resp_read, mut resp_write) = tokio::io::split(resp);
+                                // Each direction reads, waits on the store and writes in its own loop, so a chunk held
+                                // back by the store only holds up its own direction and isn't lost when the other moves.
+                                // Both run until their reader is done, one side closing doesn't cut off the other's reply.
+                                let result = try_join!(
+                                    pump(&chan, id, &chunk_id, true, &mut req_read, &mut resp_write, self.upgrade_buffer_size),
+                                    pump(&chan, id, &chunk_id, false, &mut resp_read, &mut req_write, self.upgrade_buffer_size),
+                                );
+                                debug!("Upgrade {} done", id);
+                                if let Err(e) = result {
+                                    let _ = chan.send(ProxyEvent::err(id, ProxyError::from(&e))).await;
+                                    // A failed write stops both directions, whichever side is still up gets a proper
+                                    // close instead of the socket just vanishing
+                                    let _ = req_write.shutdown().await;
+                                    let _ = resp_write.shutdown().await;
+                                }
+                            }
+                            Err(e) => {
+                                let _ = chan.send(ProxyEvent::msg(format!("Request {} failed to upgrade: {}", id, e))).await;
+                            }
                         }
-                    }
-                } else {
-                    Err("No SNI or backup host".to_string())
+                        let _ = chan.send(super::ProxyEvent::upgrade_close(id)).await;
+                    });
                 }
+                Ok(resp.into())
             }
+        }
+    }
+
+    // The redirect is read to the end so all of it is captured, then the request it points at goes out under a new id
+    // linked back to this one. Boxed, since forward ends up waiting on itself.
+    fn follow(self, redirect: super::response::Response, next: RequestHead, from: u32, redirects: usize) -> BoxFuture<'static, Result<Response<Body>, ProxyError>> {
+        Box::pin(async move {
+            let redirect: Response<Body> = redirect.into();
+            let _ = hyper::body::to_bytes(redirect.into_body()).await;
+            let id = self.id.fetch_add(1, crate::ORDERING);
+            let _ = self.channel.send(ProxyEvent::redirect(id, from)).await;
+            let authority = next.uri.authority().map(|authority| authority.to_string()).unwrap_or_default();
+            let req = next.to_request(Body::empty()).map_err(|e| ProxyError::from(e.to_string()))?;
+            self.forward(req, authority, id, redirects + 1).await
         })
     }
-}
 
-impl ProxyCore {
-    async fn do_tls_upgrade(&self, conn: Upgraded, fallback_host: Option<String>) -> hyper::Result<()>{
-        let conf = Arc::new(
-            ServerConfig::builder()
-                .with_safe_default_cipher_suites()
-                .with_safe_default_kx_groups()
-                .with_safe_default_protocol_versions()
+    // Sets up a copy of the request for the mirror and hands back the original, with its body teed if it has one.
+    // The copy goes out from its own task once the body has been read, so the client never waits on the mirror.
+    fn mirror(&self, req: Request<Body>, mirror: &Uri, bodyless: bool, of: u32) -> Request<Body> {
+        let mut uri = req.uri().clone().into_parts();
+        let target = mirror.clone().into_parts();
+        uri.scheme = target.scheme;
+        uri.authority = target.authority;
+        let uri = Uri::from_parts(uri).unwrap();
+        let mut headers = req.headers().clone();
+        if let Some(authority) = uri.authority().and_then(|authority| HeaderValue::from_str(authority.as_str()).ok()) {
+            headers.insert(HOST, authority);
+        }
+        let head = RequestHead { method: req.method().clone(), uri, version: req.version(), headers };
+        let (done, copied) = oneshot_channel();
+        let req = if bodyless {
+            let _ = done.send(Ok(Bytes::new()));
+            req
+        } else {
+            let len = req.headers().get(CONTENT_LENGTH).and_then(|len| len.to_str().ok()?.parse().ok());
+            let (parts, body) = req.into_parts();
+            Request::from_parts(parts, body::tee(body, len, MIRROR_BODY_LIMIT, done))
+        };
+        tokio::spawn(self.clone().send_mirror(head, copied, of));
+        req
+    }
+
+    // Captured under its own id, with nothing to intercept or edit since the client isn't waiting on it
+    async fn send_mirror(self, head: RequestHead, copied: OneshotReciever<Result<Bytes, String>>, of: u32) {
+        let body = match copied.await {
+            Ok(Ok(body)) => body,
+            Ok(Err(e)) => {
+                let _ = self.channel.send(ProxyEvent::msg(format!("Request {} wasn't mirrored, {}", of, e))).await;
+                return;
+            },
+            Err(_) => {
+                let _ = self.channel.send(ProxyEvent::msg(format!("Request {} wasn't mirrored, it was dropped before its body was read", of))).await;
+                return;
+            },
+        };
+        let id = self.id.fetch_add(1, crate::ORDERING);
+        let _ = self.channel.send(ProxyEvent::mirror(id, of)).await;
+        let _ = self.channel.send(ProxyEvent::observed(id, ProxyState::RequestHead(head.clone()))).await;
+        if !body.is_empty() {
+            let _ = self.channel.send(ProxyEvent::observed(id, ProxyState::RequestChunk(body.clone()))).await;
+        }
+        let _ = self.channel.send(ProxyEvent::req_done(id)).await;
+        let req = match head.to_request(Body::from(body)) {
+            Ok(req) => req,
+            Err(e) => {
+                let _ = self.channel.send(ProxyEvent::err(id, ProxyError::from(e.to_string()))).await;
+                return;
+            },
+        };
+        let (parts, mut body) = match self.client.request(outbound(req)).await {
+            Ok(resp) => resp.into_parts(),
+            Err(e) => {
+                let _ = self.channel.send(ProxyEvent::err(id, ProxyError::from(&e))).await;
+                return;
+            },
+        };
+        let head = ResponseHead { status: parts.status, version: parts.version, headers: parts.headers };
+        let _ = self.channel.send(ProxyEvent::observed(id, ProxyState::ResponseHead(head))).await;
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => { let _ = self.channel.send(ProxyEvent::observed(id, ProxyState::ResponseChunk(chunk))).await; },
+                Err(e) => {
+                    let _ = self.channel.send(ProxyEvent::err(id, ProxyError::from(&e))).await;
+                    return;
+                },
+            }
+        }
+        let _ = self.channel.send(ProxyEvent::resp_done(id)).await;
+    }
+
+    // Stand in for the origin with a failure. The request has already been captured, so the store sees it fail.
+    async fn inject(&self, fault: Fault, id: u32) -> Result<Response<Body>, ProxyError> {
+        let (err, status) = match fault {
+            Fault::ServerError => (ProxyError::Status(StatusCode::INTERNAL_SERVER_ERROR), StatusCode::INTERNAL_SERVER_ERROR),
+            Fault::Reset => {
+                // An error out of the service makes hyper drop the client connection without a response
+                let err = ProxyError::ConnectionReset("Injected fault".to_string());
+                let _ = self.channel.send(ProxyEvent::err(id, err.clone())).await;
+                return Err(err);
+            },
+            Fault::Hang(duration) => {
+                tokio::time::sleep(duration).await;
+                (ProxyError::Timeout(format!("Injected fault, held for {:?}", duration)), StatusCode::GATEWAY_TIMEOUT)
+            },
+        };
+        let _ = self.channel.send(ProxyEvent::err(id, err)).await;
+        Ok(
+            Response::builder()
+                .status(status)
+                .body(hyper::Body::from("Injected fault"))
                 .unwrap()
-                .with_no_client_auth()
-                .with_cert_resolver(CertStore::build_cert(
-                    &self.cert_store,
-                    fallback_host.to_owned(),
-                )),
-        );
-        let accepted = TlsAcceptor::from(conf).accept(conn).await.unwrap();
+        )
+    }
+
+    fn bypasses(&self, host: Option<&str>) -> bool {
+        let host = match host {
+            Some(host) => host.to_ascii_lowercase(),
+            None => return false,
+        };
+        self.mitm_bypass.iter().any(|bypass| {
+            host == *bypass || host.strip_suffix(bypass.as_str()).map(|sub| sub.ends_with('.')).unwrap_or(false)
+        })
+    }
+
+    // Relay bytes between the client and the origin without looking at them, for hosts that can't be intercepted
+    async fn blind_tunnel<T>(&self, mut conn: T, authority: String) -> Result<(), BoxError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let id = self.id.fetch_add(1, crate::ORDERING);
+        let _ = self.channel.send(ProxyEvent::tunnel(id, authority.clone())).await;
+        let relayed = async {
+            let mut upstream = self.connector.clone().call(format!("http://{}", authority).parse()?).await?;
+            Ok::<_, BoxError>(tokio::io::copy_bidirectional(&mut conn, &mut upstream).await?)
+        };
+        let event = match relayed.await {
+            Ok((sent, received)) => ProxyEvent::tunnel_close(id, sent, received),
+            Err(e) => {
+                let err = e.downcast_ref::<std::io::Error>().map(ProxyError::from).unwrap_or_else(|| ProxyError::from(e.to_string()));
+                ProxyEvent::err(id, err)
+            },
+        };
+        let _ = self.channel.send(event).await;
+        Ok(())
+    }
+
+    // Most tunnels carry TLS, but plain HTTP through CONNECT is legal too. TLS records start with a handshake byte (0x16),
+    // so one byte is enough to tell them apart before handing the connection to the right server.
+    async fn intercept_tunnel<T>(&self, mut conn: T, host: Option<String>, authority: Option<String>) -> Result<(), BoxError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut first = [0u8; 1];
+        if conn.read(&mut first).await? == 0 {
+            return Ok(());
+        }
+        let conn = Rewind::new(conn, Bytes::copy_from_slice(&first));
+        if first[0] == 0x16 {
+            self.do_tls_upgrade(conn, host).await?;
+        } else {
+            let mut service = self.clone();
+            service.fallback_host = authority;
+            Http::new().serve_connection(conn, service).with_upgrades().await?;
+        }
+        Ok(())
+    }
+
+    async fn do_tls_upgrade<T>(&self, conn: T, fallback_host: Option<String>) -> hyper::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut conf = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(CertStore::build_cert(
+                &self.cert_store,
+                fallback_host.to_owned(),
+            ));
+        conf.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let conf = Arc::new(conf);
+        // The ClientHello is read on its own first, so we know the SNI even if the rest of the handshake fails
+        let mut sni = None;
+        let handshake = async {
+            let acceptor = Acceptor::new().map_err(std::io::Error::other)?;
+            let start = LazyConfigAcceptor::new(acceptor, conn).await?;
+            sni = start.client_hello().server_name().map(String::from);
+            start.into_stream(conf).await
+        };
+        let accepted = match handshake.await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Usually the client not trusting our CA. There's no request to tie it to, and nothing more to do for this tunnel.
+                let err = ProxyError::TlsHandshake { host: sni.or(fallback_host), msg: e.to_string() };
+                let _ = self.channel.send(ProxyEvent::conn_err(err)).await;
+                return Ok(());
+            }
+        };
         let mut service = self.clone();
+        let info = tls_info(accepted.get_ref().1);
         service.fallback_host = Self::get_host(&accepted, &fallback_host);
-        Http::new().serve_connection(accepted, service).with_upgrades().await
+        let h2 = accepted.get_ref().1.alpn_protocol() == Some(b"h2");
+        let _ = self.channel.send(ProxyEvent::msg(format!("TLS connected with SNI {:?} ({})", service.fallback_host, info))).await;
+        service.tls = Some(info);
+        // Every stream on an h2 connection is its own call into the service, so each one still gets its own id
+        Http::new().http2_only(h2).serve_connection(accepted, service).with_upgrades().await
     }
 
-    fn get_host(conn: &TlsStream<Upgraded>, fallback_host: &Option<String>) -> Option<String> {
+    fn get_host<T>(conn: &TlsStream<T>, fallback_host: &Option<String>) -> Option<String> {
         conn.get_ref()
             .1
             .sni_hostname()
             .map(String::from)
             .or(fallback_host.to_owned())
     }
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    // A proxy on a free local port with a CA of its own in a fresh temp dir
+    pub(crate) fn test_config(name: &str) -> ProxyConfig {
+        let dir = std::env::temp_dir().join(format!("stain-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        ProxyConfig {
+            pubkey_path: dir.join("cert").to_string_lossy().into_owned(),
+            privkey_path: dir.join("key").to_string_lossy().into_owned(),
+            listen: Listen::Tcp(SocketAddr::from(([127, 0, 0, 1], port))),
+            key_algorithm: KeyAlgorithm::EcdsaP256, // Quick to generate
+            ..ProxyConfig::default()
+        }
+    }
+
+    // Answers every callback with what it was sent and hands on a copy of each event
+    pub(crate) fn pass_through(mut events: Receiver<ProxyEvent>) -> UnboundedReceiver<(Option<u32>, ProxyState)> {
+        let (tx, rx) = unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                let _ = tx.send((event.id, event.event.clone()));
+                if let Some(callback) = event.callback {
+                    let _ = callback.send(event.event);
+                }
+            }
+        });
+        rx
+    }
+
+    pub(crate) fn addr(server: &ProxyServer) -> SocketAddr {
+        match server.listen_addr() {
+            Listen::Tcp(addr) => addr,
+            Listen::Unix(_) => unreachable!(),
+        }
+    }
+
+    // Sends `head` as is and reads back everything up to the end of the response head
+    pub(crate) async fn raw_request(conn: &mut TcpStream, head: &str) -> String {
+        conn.write_all(head.as_bytes()).await.unwrap();
+        let mut read = Vec::new();
+        let mut byte = [0u8; 1];
+        while !read.ends_with(b"\r\n\r\n") && conn.read(&mut byte).await.unwrap() == 1 {
+            read.push(byte[0]);
+        }
+        String::from_utf8(read).unwrap()
+    }
+
+    // An origin that answers the first request it gets with `response`, byte for byte
+    pub(crate) async fn raw_origin(response: impl Into<Vec<u8>>) -> SocketAddr {
+        let response = response.into();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut read = Vec::new();
+            let mut byte = [0u8; 1];
+            while !read.ends_with(b"\r\n\r\n") && conn.read(&mut byte).await.unwrap() == 1 {
+                read.push(byte[0]);
+            }
+            conn.write_all(&response).await.unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn unusable_inline_ca_fails_the_build() {
+        let config = ProxyConfig {
+            ca_cert: Some("not a cert".to_string()),
+            ca_key: Some("not a key".to_string()),
+            ..test_config("inline-ca")
+        };
+        assert!(config.build().err().unwrap().starts_with("Unable to use the inline CA"));
+    }
+
+    #[tokio::test]
+    async fn connect_past_the_limit_gets_503() {
+        let mut config = test_config("tunnels");
+        config.max_tunnels = 1;
+        let (server, events) = config.build().unwrap();
+        let _events = pass_through(events);
+        server.run().unwrap();
+        let connect = "CONNECT 127.0.0.1:9 HTTP/1.1\r\nHost: 127.0.0.1:9\r\n\r\n";
+        let mut first = TcpStream::connect(addr(&server)).await.unwrap();
+        assert!(raw_request(&mut first, connect).await.starts_with("HTTP/1.1 200"));
+        // The first tunnel is still open, waiting on the client to start talking
+        let mut second = TcpStream::connect(addr(&server)).await.unwrap();
+        assert!(raw_request(&mut second, connect).await.starts_with("HTTP/1.1 503"));
+        assert_eq!(server.open_tunnels(), 1);
+        drop(first);
+        for _ in 0..100 {
+            if server.open_tunnels() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.open_tunnels(), 0);
+        let mut third = TcpStream::connect(addr(&server)).await.unwrap();
+        assert!(raw_request(&mut third, connect).await.starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn failed_handshake_has_no_id() {
+        // 0 is a request id like any other
+        let config = ProxyConfig { starting_id: 0, ..test_config("handshake") };
+        let (server, events) = config.build().unwrap();
+        let mut events = pass_through(events);
+        server.run().unwrap();
+        let mut conn = TcpStream::connect(addr(&server)).await.unwrap();
+        let connect = "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n";
+        assert!(raw_request(&mut conn, connect).await.starts_with("HTTP/1.1 200"));
+        // A handshake record with nonsense where the ClientHello should be
+        conn.write_all(&[0x16, 0x03, 0x01, 0x00, 0x04, 0xde, 0xad, 0xbe, 0xef]).await.unwrap();
+        let (id, event) = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match events.recv().await.unwrap() {
+                    (id, ProxyState::Error(e)) => break (id, e),
+                    _ => continue,
+                }
+            }
+        }).await.unwrap();
+        assert_eq!(id, None);
+        assert!(matches!(event, ProxyError::TlsHandshake { host: Some(host), .. } if host == "example.com"));
+        assert_eq!(server.next_id(), 0);
+    }
+
+    #[tokio::test]
+    async fn chunked_responses_stay_chunked() {
+        let (server, events) = test_config("chunked").build().unwrap();
+        let _events = pass_through(events);
+        server.run().unwrap();
+        // Content-Length next to chunked is ignored by the framing, and can't go on to the client with it
+        for origin_head in ["", "Content-Length: 99\r\n"] {
+            let origin = raw_origin(format!(
+                "HTTP/1.1 200 OK\r\n{}Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n", origin_head
+            )).await;
+            let mut conn = TcpStream::connect(addr(&server)).await.unwrap();
+            let head = raw_request(&mut conn, &format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", origin)).await.to_ascii_lowercase();
+            assert!(head.starts_with("http/1.1 200"), "{}", head);
+            assert!(head.contains("transfer-encoding: chunked\r\n"), "{}", head);
+            assert!(!head.contains("content-length"), "{}", head);
+            let mut body = Vec::new();
+            tokio::time::timeout(Duration::from_secs(5), async {
+                let mut buf = [0u8; 64];
+                while !body.ends_with(b"0\r\n\r\n") {
+                    let n = conn.read(&mut buf).await.unwrap();
+                    assert!(n > 0, "Closed before the last chunk");
+                    body.extend_from_slice(&buf[..n]);
+                }
+            }).await.unwrap();
+            // Framed again by hyper, so only what it carries has to match
+            let mut decoded = Vec::new();
+            let mut rest = &body[..];
+            loop {
+                let line = rest.windows(2).position(|window| window == b"\r\n").unwrap();
+                let len = usize::from_str_radix(std::str::from_utf8(&rest[..line]).unwrap().trim(), 16).unwrap();
+                if len == 0 {
+                    break;
+                }
+                decoded.extend_from_slice(&rest[line + 2..line + 2 + len]);
+                rest = &rest[line + 4 + len..];
+            }
+            assert_eq!(decoded, b"hello world");
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_headers_stay_with_the_client() {
+        let (server, events) = test_config("hop").build().unwrap();
+        let _events = pass_through(events);
+        server.run().unwrap();
+        // Tells the client which headers it got, so they can be checked from this side
+        let make = hyper::service::make_service_fn(|_| async {
+            Ok::<_, Infallible>(hyper::service::service_fn(|req: Request<Body>| async move {
+                let mut seen: Vec<&str> = req.headers().keys().map(|name| name.as_str()).collect();
+                seen.sort_unstable();
+                Ok::<_, Infallible>(Response::builder().header("x-seen", seen.join(",")).body(Body::empty()).unwrap())
+            }))
+        });
+        let origin = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make);
+        let origin_addr = origin.local_addr();
+        tokio::spawn(origin);
+        let mut conn = TcpStream::connect(addr(&server)).await.unwrap();
+        let head = raw_request(&mut conn, &format!(
+            "GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\nConnection: keep-alive, X-Secret\r\nKeep-Alive: timeout=5\r\nX-Secret: 1\r\nAccept: */*\r\n\r\n",
+            origin_addr,
+        )).await.to_ascii_lowercase();
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert!(head.contains("x-seen: accept,host\r\n"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn upgrades_relay_past_a_half_close() {
+        let (server, events) = test_config("half-close").build().unwrap();
+        let _events = pass_through(events);
+        server.run().unwrap();
+        // Only answers once the client is done sending, like a request/response protocol over the upgrade
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut read = Vec::new();
+            let mut byte = [0u8; 1];
+            while !read.ends_with(b"\r\n\r\n") && conn.read(&mut byte).await.unwrap() == 1 {
+                read.push(byte[0]);
+            }
+            conn.write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: test\r\n\r\n").await.unwrap();
+            let mut sent = Vec::new();
+            conn.read_to_end(&mut sent).await.unwrap();
+            conn.write_all(&[b"got ", &sent[..]].concat()).await.unwrap();
+        });
+        let mut conn = TcpStream::connect(addr(&server)).await.unwrap();
+        let head = raw_request(&mut conn, &format!(
+            "GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\nConnection: upgrade\r\nUpgrade: test\r\n\r\n", origin
+        )).await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        conn.write_all(b"hello").await.unwrap();
+        conn.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), conn.read_to_end(&mut reply)).await.unwrap().unwrap();
+        assert_eq!(reply, b"got hello");
+    }
+
+    #[tokio::test]
+    async fn switching_protocols_unasked_is_a_bad_gateway() {
+        let (server, events) = test_config("unasked-101").build().unwrap();
+        let mut events = pass_through(events);
+        server.run().unwrap();
+        let origin = raw_origin("HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: h2c\r\n\r\n").await;
+        let mut conn = TcpStream::connect(addr(&server)).await.unwrap();
+        let head = raw_request(&mut conn, &format!("GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n\r\n", origin)).await;
+        assert!(head.starts_with("HTTP/1.1 502"), "{}", head);
+        let error = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match events.recv().await.unwrap() {
+                    (Some(_), ProxyState::Error(e)) => break e,
+                    (_, ProxyState::UpgradeOpen) => panic!("Relayed an upgrade nobody asked for"),
+                    _ => continue,
+                }
+            }
+        }).await.unwrap();
+        assert!(error.to_string().contains("didn't ask to upgrade"), "{}", error);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,187 @@
+use std::error::Error;
+use std::fmt;
+use std::io::ErrorKind;
+
+use hyper::StatusCode;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProxyError {
+    Timeout(String),
+    ConnectionReset(String),
+    ConnectionRefused(String),
+    Dns(String),
+    Status(StatusCode), // Origin answered, but with a 4xx/5xx
+    TlsHandshake { host: Option<String>, msg: String }, // A client gave up on our cert, host is its SNI or else the CONNECT target
+    UpstreamCert(String), // The origin's cert didn't verify
+    Other(String),
+}
+
+impl ProxyError {
+    /// Whether re-sending the same request has a reasonable chance of succeeding
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Timeout(_) | Self::ConnectionReset(_) | Self::ConnectionRefused(_) => true,
+            Self::Status(status) => matches!(
+                *status,
+                StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+            ),
+            Self::Dns(_) | Self::TlsHandshake { .. } | Self::UpstreamCert(_) | Self::Other(_) => false,
+        }
+    }
+
+    fn from_io_kind(kind: ErrorKind, msg: &str) -> Option<Self> {
+        match kind {
+            ErrorKind::TimedOut => Some(Self::Timeout(msg.to_string())),
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => {
+                Some(Self::ConnectionReset(msg.to_string()))
+            }
+            ErrorKind::ConnectionRefused => Some(Self::ConnectionRefused(msg.to_string())),
+            _ => None,
+        }
+    }
+
+    pub fn from_status(status: StatusCode) -> Option<Self> {
+        if status.is_client_error() || status.is_server_error() {
+            Some(Self::Status(status))
+        } else {
+            None
+        }
+    }
+}
+
+// Errors from our verifier come back wrapped in the io::Error the handshake failed with, which the connector wraps again
+fn tls_cause(e: &std::io::Error) -> Option<&rustls::Error> {
+    let inner = e.get_ref()?;
+    inner.downcast_ref::<rustls::Error>().or_else(|| inner.downcast_ref::<std::io::Error>().and_then(tls_cause))
+}
+
+fn is_cert_error(e: &rustls::Error) -> bool {
+    matches!(
+        e,
+        rustls::Error::InvalidCertificateEncoding
+            | rustls::Error::InvalidCertificateSignatureType
+            | rustls::Error::InvalidCertificateSignature
+            | rustls::Error::InvalidCertificateData(_)
+            | rustls::Error::InvalidSct(_)
+    )
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout(msg) => write!(f, "Timed out: {}", msg),
+            Self::ConnectionReset(msg) => write!(f, "Connection reset: {}", msg),
+            Self::ConnectionRefused(msg) => write!(f, "Connection refused: {}", msg),
+            Self::Dns(msg) => write!(f, "DNS lookup failed: {}", msg),
+            Self::Status(status) => write!(f, "Origin returned {}", status),
+            Self::TlsHandshake { host: Some(host), msg } => write!(f, "TLS handshake for {} failed: {}", host, msg),
+            Self::TlsHandshake { host: None, msg } => write!(f, "TLS handshake failed: {}", msg),
+            Self::UpstreamCert(msg) => write!(f, "Origin certificate rejected: {}", msg),
+            Self::Other(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl Error for ProxyError {}
+
+impl From<String> for ProxyError {
+    fn from(msg: String) -> Self {
+        Self::Other(msg)
+    }
+}
+
+impl From<&std::io::Error> for ProxyError {
+    fn from(e: &std::io::Error) -> Self {
+        let msg = e.to_string();
+        Self::from_io_kind(e.kind(), &msg).unwrap_or(Self::Other(msg))
+    }
+}
+
+impl From<&hyper::Error> for ProxyError {
+    fn from(e: &hyper::Error) -> Self {
+        let msg = e.to_string();
+        if e.is_timeout() {
+            return Self::Timeout(msg);
+        }
+        // hyper buries the interesting part (io::Error, dns failure) a few layers down the source chain
+        let mut source: Option<&(dyn Error + 'static)> = e.source();
+        while let Some(cause) = source {
+            if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+                if let Some(tls) = tls_cause(io) {
+                    if is_cert_error(tls) {
+                        return Self::UpstreamCert(tls.to_string());
+                    }
+                }
+                if let Some(e) = Self::from_io_kind(io.kind(), &msg) {
+                    return e;
+                }
+            }
+            if cause.to_string().starts_with("dns error") {
+                return Self::Dns(msg);
+            }
+            source = cause.source();
+        }
+        if e.is_incomplete_message() {
+            Self::ConnectionReset(msg)
+        } else {
+            Self::Other(msg)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retryable_variants() {
+        let retryable = [
+            ProxyError::Timeout(String::new()),
+            ProxyError::ConnectionReset(String::new()),
+            ProxyError::ConnectionRefused(String::new()),
+            ProxyError::Status(StatusCode::BAD_GATEWAY),
+            ProxyError::Status(StatusCode::SERVICE_UNAVAILABLE),
+            ProxyError::Status(StatusCode::GATEWAY_TIMEOUT),
+        ];
+        let terminal = [
+            ProxyError::Dns(String::new()),
+            ProxyError::Status(StatusCode::NOT_FOUND),
+            ProxyError::Status(StatusCode::INTERNAL_SERVER_ERROR),
+            ProxyError::TlsHandshake { host: None, msg: String::new() },
+            ProxyError::UpstreamCert(String::new()),
+            ProxyError::Other(String::new()),
+        ];
+        for e in retryable {
+            assert!(e.is_retryable(), "{:?}", e);
+        }
+        for e in terminal {
+            assert!(!e.is_retryable(), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn io_kinds() {
+        let from_kind = |kind| ProxyError::from(&std::io::Error::from(kind));
+        assert!(matches!(from_kind(ErrorKind::TimedOut), ProxyError::Timeout(_)));
+        assert!(matches!(from_kind(ErrorKind::BrokenPipe), ProxyError::ConnectionReset(_)));
+        assert!(matches!(from_kind(ErrorKind::ConnectionRefused), ProxyError::ConnectionRefused(_)));
+        assert!(matches!(from_kind(ErrorKind::PermissionDenied), ProxyError::Other(_)));
+    }
+
+    #[test]
+    fn only_error_statuses() {
+        assert_eq!(ProxyError::from_status(StatusCode::OK), None);
+        assert_eq!(ProxyError::from_status(StatusCode::FOUND), None);
+        assert_eq!(ProxyError::from_status(StatusCode::FORBIDDEN), Some(ProxyError::Status(StatusCode::FORBIDDEN)));
+    }
+
+    #[tokio::test]
+    async fn refused_connection_is_retryable() {
+        // Nothing listens on a port that was just freed
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let e = hyper::Client::new().get(format!("http://127.0.0.1:{}/", port).parse().unwrap()).await.unwrap_err();
+        let e = ProxyError::from(&e);
+        assert!(matches!(e, ProxyError::ConnectionRefused(_)), "{:?}", e);
+        assert!(e.is_retryable());
+    }
+}
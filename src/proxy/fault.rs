@@ -0,0 +1,173 @@
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+// Deliberately fail some of the requests going to matching hosts/paths. Rates are probabilities between 0 and 1,
+// rolled once per request, so they shouldn't add up to more than 1.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaultRule {
+    pub host: String, // Glob, * and ? wildcards. Empty matches anything.
+    pub path: String,
+    pub error_rate: f64, // Answer with a 500 without contacting the origin
+    pub reset_rate: f64, // Drop the client connection without answering
+    pub hang_rate: f64, // Sit on the request for `hang_for`, then give up with a 504
+    #[serde(rename = "hang_for_ms", with = "super::repr::millis")]
+    pub hang_for: Duration,
+}
+
+impl Default for FaultRule {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            path: String::new(),
+            error_rate: 0.0,
+            reset_rate: 0.0,
+            hang_rate: 0.0,
+            hang_for: Duration::from_secs(30),
+        }
+    }
+}
+
+// Holds back responses from matching hosts before their head goes to the client, to see how the client copes with a
+// slow dependency. Comes on top of any response throttle, which only paces the body after the head.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LatencyRule {
+    pub host: String, // Glob, * and ? wildcards. Empty matches anything.
+    #[serde(rename = "delay_ms", with = "super::repr::millis")]
+    pub delay: Duration,
+}
+
+// The first rule matching the host decides, later ones are ignored
+pub fn latency_for<'a>(rules: &'a [LatencyRule], host: &str) -> Option<(usize, &'a LatencyRule)> {
+    rules.iter().enumerate().find(|(_, rule)| glob(&rule.host, host))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    ServerError,
+    Reset,
+    Hang(Duration),
+}
+
+pub struct FaultInjector {
+    rules: Vec<FaultRule>,
+    state: Mutex<u64>, // splitmix64, so the same seed fails the same requests in the same order
+}
+
+impl FaultInjector {
+    pub fn new(rules: Vec<FaultRule>, seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_nanos() as u64).unwrap_or_default()
+        });
+        Self { rules, state: Mutex::new(seed) }
+    }
+
+    fn next_f64(&self) -> f64 {
+        let mut state = self.state.lock().unwrap();
+        *state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // The first rule matching the request decides, later ones are ignored
+    pub fn roll(&self, host: &str, path: &str) -> Option<Fault> {
+        let rule = self.rules.iter().find(|rule| glob(&rule.host, host) && glob(&rule.path, path))?;
+        let roll = self.next_f64();
+        if roll < rule.error_rate {
+            Some(Fault::ServerError)
+        } else if roll < rule.error_rate + rule.reset_rate {
+            Some(Fault::Reset)
+        } else if roll < rule.error_rate + rule.reset_rate + rule.hang_rate {
+            Some(Fault::Hang(rule.hang_for))
+        } else {
+            None
+        }
+    }
+}
+
+pub fn glob(pattern: &str, text: &str) -> bool {
+    if pattern.is_empty() {
+        return true;
+    }
+    let (pattern, text): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // Where to pick up again if what followed the last * didn't pan out
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule() -> FaultRule {
+        FaultRule {
+            host: "*.example.com".to_string(),
+            path: "/api/*".to_string(),
+            error_rate: 0.2,
+            reset_rate: 0.2,
+            hang_rate: 0.2,
+            hang_for: Duration::from_millis(10),
+        }
+    }
+
+    fn rolls(seed: u64) -> Vec<Option<Fault>> {
+        let faults = FaultInjector::new(vec![rule()], Some(seed));
+        (0..200).map(|_| faults.roll("www.example.com", "/api/users")).collect()
+    }
+
+    #[test]
+    fn same_seed_same_faults() {
+        let first = rolls(42);
+        assert_eq!(first, rolls(42));
+        assert_ne!(first, rolls(43));
+        // Each outcome turns up at roughly its rate
+        for fault in [Some(Fault::ServerError), Some(Fault::Reset), Some(Fault::Hang(Duration::from_millis(10))), None] {
+            assert!(first.iter().filter(|&&roll| roll == fault).count() > 20, "{:?} rarely rolled", fault);
+        }
+    }
+
+    #[test]
+    fn only_matching_requests_roll() {
+        let faults = FaultInjector::new(vec![FaultRule { error_rate: 1.0, ..rule() }], Some(1));
+        assert_eq!(faults.roll("www.example.com", "/api/users"), Some(Fault::ServerError));
+        assert_eq!(faults.roll("example.com", "/api/users"), None);
+        assert_eq!(faults.roll("www.example.com", "/static/app.js"), None);
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob("", "anything"));
+        assert!(glob("a*c", "abbbc"));
+        assert!(glob("a?c", "abc"));
+        assert!(glob("*.example.com", "a.b.example.com"));
+        assert!(!glob("a?c", "ac"));
+        assert!(!glob("*.example.com", "example.com"));
+    }
+}
@@ -0,0 +1,150 @@
+use hyper::StatusCode;
+use hyper::http::{HeaderMap, HeaderValue, header::{self, HeaderName}};
+
+// RFC 7230 6.1, plus Proxy-Connection which older clients still send in place of Connection
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+fn connection_names(headers: &HeaderMap<HeaderValue>) -> Vec<HeaderName> {
+    headers.get_all(header::CONNECTION).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect()
+}
+
+// The headers of a captured head that are meant for the next hop rather than this one. Everything above goes,
+// along with anything the Connection header names. The body is already de-chunked by the time it's sent on and
+// its length isn't known up front, so hyper frames it again for whichever protocol the other side speaks. Any other
+// transfer codings are still on the body, so they stay, and hyper adds chunked after them. Since Content-Length
+// means nothing next to Transfer-Encoding (RFC 7230 3.3.3), and hyper refuses to write both, it's dropped.
+//
+// Two more exceptions: "TE: trailers" stays, gRPC over h2 won't work without it, and an upgrade keeps
+// "Connection: upgrade" and its Upgrade header, or the WebSocket handshake never reaches the other side.
+pub fn forwardable(headers: &HeaderMap<HeaderValue>) -> HeaderMap<HeaderValue> {
+    let named = connection_names(headers);
+    let upgrade = named.contains(&header::UPGRADE) && headers.contains_key(header::UPGRADE);
+    let trailers = headers.get_all(header::TE).iter().all(|value| value.as_bytes().eq_ignore_ascii_case(b"trailers"));
+
+    let mut forwarded = headers.clone();
+    if forwarded.contains_key(header::TRANSFER_ENCODING) {
+        forwarded.remove(header::CONTENT_LENGTH);
+    }
+    for name in HOP_BY_HOP {
+        if !(name == "te" && trailers) {
+            forwarded.remove(name);
+        }
+    }
+    let codings: Vec<&str> = headers.get_all(header::TRANSFER_ENCODING).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim())
+        .filter(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("chunked"))
+        .collect();
+    if !codings.is_empty() {
+        if let Ok(codings) = HeaderValue::from_str(&codings.join(", ")) {
+            forwarded.insert(header::TRANSFER_ENCODING, codings);
+        }
+    }
+    for name in &named {
+        forwarded.remove(name);
+    }
+    if upgrade {
+        forwarded.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        for value in headers.get_all(header::UPGRADE) {
+            forwarded.append(header::UPGRADE, value.clone());
+        }
+    }
+    forwarded
+}
+
+// The protocols named in Upgrade, lowercased, as long as Connection says to act on them (RFC 7230 6.7)
+pub fn upgrade_protocols(headers: &HeaderMap<HeaderValue>) -> Option<Vec<String>> {
+    if !connection_names(headers).contains(&header::UPGRADE) {
+        return None;
+    }
+    let protocols: Vec<String> = headers.get_all(header::UPGRADE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|protocol| protocol.trim().to_ascii_lowercase())
+        .filter(|protocol| !protocol.is_empty())
+        .collect();
+    Some(protocols).filter(|protocols| !protocols.is_empty())
+}
+
+// Whether a response that hands over the connection is one we can relay. `offered` is what the request asked to
+// upgrade to, if it asked at all. The origin has to answer 101 and switch to one of those.
+pub fn check_upgrade(offered: Option<&[String]>, status: StatusCode, headers: &HeaderMap<HeaderValue>) -> Result<(), String> {
+    if status != StatusCode::SWITCHING_PROTOCOLS {
+        return Err(format!("Origin handed over the connection with {}, only 101 switches protocols", status));
+    }
+    let offered = offered.ok_or_else(|| "Origin switched protocols on a request that didn't ask to upgrade".to_string())?;
+    let chosen = upgrade_protocols(headers)
+        .ok_or_else(|| "Origin answered 101 without Connection: upgrade and an Upgrade header".to_string())?;
+    match chosen.iter().find(|protocol| !offered.contains(protocol)) {
+        Some(protocol) => Err(format!("Origin switched to {}, the request only offered {}", protocol, offered.join(", "))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap<HeaderValue> {
+        pairs.iter().map(|(name, value)| (HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap())).collect()
+    }
+
+    #[test]
+    fn only_chunked_is_taken_off_the_codings() {
+        let forwarded = forwardable(&headers(&[("transfer-encoding", "gzip, chunked"), ("content-length", "10")]));
+        assert_eq!(forwarded.get(header::TRANSFER_ENCODING).unwrap(), "gzip");
+        assert!(forwarded.get(header::CONTENT_LENGTH).is_none());
+        let forwarded = forwardable(&headers(&[("transfer-encoding", "chunked"), ("content-length", "10")]));
+        assert!(forwarded.get(header::TRANSFER_ENCODING).is_none());
+        assert!(forwarded.get(header::CONTENT_LENGTH).is_none());
+    }
+
+    #[test]
+    fn connection_and_what_it_names_go() {
+        let forwarded = forwardable(&headers(&[
+            ("connection", "keep-alive, x-secret"),
+            ("keep-alive", "timeout=5"),
+            ("x-secret", "1"),
+            ("proxy-authorization", "Basic Zm9vOmJhcg=="),
+            ("te", "trailers"),
+            ("accept", "*/*"),
+        ]));
+        let mut names: Vec<&str> = forwarded.keys().map(|name| name.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["accept", "te"]);
+    }
+
+    #[test]
+    fn upgrades_keep_their_headers() {
+        let forwarded = forwardable(&headers(&[("connection", "keep-alive, Upgrade"), ("upgrade", "websocket")]));
+        assert_eq!(forwarded.get(header::CONNECTION).unwrap(), "upgrade");
+        assert_eq!(forwarded.get(header::UPGRADE).unwrap(), "websocket");
+    }
+
+    #[test]
+    fn upgrades_have_to_be_agreed_on() {
+        let offered = vec!["websocket".to_string()];
+        let switched = headers(&[("connection", "Upgrade"), ("upgrade", "WebSocket")]);
+        assert_eq!(check_upgrade(Some(&offered), StatusCode::SWITCHING_PROTOCOLS, &switched), Ok(()));
+        assert!(check_upgrade(None, StatusCode::SWITCHING_PROTOCOLS, &switched).is_err());
+        assert!(check_upgrade(Some(&offered), StatusCode::OK, &switched).is_err());
+        let h2c = headers(&[("connection", "upgrade"), ("upgrade", "h2c")]);
+        assert!(check_upgrade(Some(&offered), StatusCode::SWITCHING_PROTOCOLS, &h2c).is_err());
+        assert!(check_upgrade(Some(&offered), StatusCode::SWITCHING_PROTOCOLS, &headers(&[("upgrade", "websocket")])).is_err());
+    }
+}
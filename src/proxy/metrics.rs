@@ -0,0 +1,85 @@
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use tokio::task::JoinHandle;
+
+use super::{ProxyEvent, ProxyState};
+
+// Counters over everything the proxy has seen since it started. Events only reach whoever holds the receiver, so
+// it's up to them to pass each one to `observe` on the way through.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: AtomicU64,
+    responses: [AtomicU64; 5], // By status class, 1xx to 5xx
+    errors: AtomicU64,
+    bytes_out: AtomicU64, // Client to origin, bodies, upgrades and tunnels alike
+    bytes_in: AtomicU64, // Origin to client
+}
+
+impl Metrics {
+    pub fn observe(&self, event: &ProxyEvent) {
+        let add = |counter: &AtomicU64, n: u64| { counter.fetch_add(n, crate::ORDERING); };
+        match &event.event {
+            ProxyState::RequestHead(_) => add(&self.requests, 1),
+            ProxyState::ResponseHead(head) => {
+                if let Some(class) = self.responses.get((head.status.as_u16() / 100) as usize - 1) {
+                    add(class, 1);
+                }
+            },
+            ProxyState::Error(_) => add(&self.errors, 1),
+            ProxyState::RequestChunk(chunk) | ProxyState::UpgradeTx { chunk, .. } => add(&self.bytes_out, chunk.len() as u64),
+            ProxyState::ResponseChunk(chunk) | ProxyState::UpgradeRx { chunk, .. } => add(&self.bytes_in, chunk.len() as u64),
+            ProxyState::TunnelClose { sent, received } => {
+                add(&self.bytes_out, *sent);
+                add(&self.bytes_in, *received);
+            },
+            _ => {},
+        }
+    }
+
+    // Prometheus text exposition format
+    fn render(&self, tunnels: usize) -> String {
+        let load = |counter: &AtomicU64| counter.load(crate::ORDERING);
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+        metric("stain_requests_total", "counter", "Requests seen, including mirror copies and replays.", &[(String::new(), load(&self.requests))]);
+        let classes: Vec<_> = self.responses.iter().enumerate()
+            .map(|(idx, count)| (format!("{{class=\"{}xx\"}}", idx + 1), load(count)))
+            .collect();
+        metric("stain_responses_total", "counter", "Responses from origins by status class.", &classes);
+        metric("stain_errors_total", "counter", "Requests, tunnels and handshakes that failed.", &[(String::new(), load(&self.errors))]);
+        metric("stain_tunnels_active", "gauge", "CONNECT and SOCKS tunnels open right now.", &[(String::new(), tunnels as u64)]);
+        metric("stain_bytes_out_total", "counter", "Bytes sent on to origins.", &[(String::new(), load(&self.bytes_out))]);
+        metric("stain_bytes_in_total", "counter", "Bytes received from origins.", &[(String::new(), load(&self.bytes_in))]);
+        out
+    }
+}
+
+// A plain HTTP listener of its own, so scraping never goes through the proxy
+pub fn serve(addr: SocketAddr, metrics: Arc<Metrics>, tunnels: Arc<AtomicUsize>) -> Result<JoinHandle<Result<(), hyper::Error>>, hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let (metrics, tunnels) = (metrics.clone(), tunnels.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let resp = match (req.method(), req.uri().path()) {
+                    (&Method::GET, "/metrics") => Response::builder()
+                        .header("content-type", "text/plain; version=0.0.4")
+                        .body(Body::from(metrics.render(tunnels.load(crate::ORDERING)))),
+                    _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("Metrics are at /metrics")),
+                };
+                async move { resp }
+            }))
+        }
+    });
+    Ok(tokio::spawn(Server::try_bind(&addr)?.serve(make_service)))
+}
@@ -3,19 +3,49 @@
 pub mod request;
 pub mod response;
 pub mod body;
+pub mod error;
 mod core;
+mod upstream;
+mod rewind;
+mod socks;
+mod hop;
+mod redirect;
+pub mod fault;
+pub mod rewrite;
+pub mod metrics;
+pub(crate) mod repr;
 
 pub use tokio::sync::mpsc::{Sender, Receiver};
 pub use tokio::sync::oneshot::{Sender as OneshotSender, Receiver as OneshotReciever, channel as oneshot_channel};
 pub use self::core::*;
+pub use self::error::ProxyError;
 
 use hyper::body::Bytes;
 use request::RequestHead;
 use response::ResponseHead;
 
+// What was negotiated with a client whose TLS we terminated
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsInfo {
+    pub version: String, // e.g. "TLS 1.3"
+    pub cipher: String, // IANA name, e.g. TLS_AES_256_GCM_SHA384
+    pub alpn: Option<String>,
+}
+
+impl std::fmt::Display for TlsInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}, {}", self.version, self.cipher)?;
+        if let Some(alpn) = &self.alpn {
+            write!(f, ", ALPN {}", alpn)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ProxyState {
     RequestHead(RequestHead),
+    RequestPreview(Bytes), // A small body read in full before its head is sent, so it can be edited with it. It still streams through as chunks after.
     RequestChunk(Bytes),
     RequestDone,
     ResponseHead(ResponseHead),
@@ -25,13 +55,18 @@ pub enum ProxyState {
     UpgradeTx{id: u32, chunk: Bytes},
     UpgradeRx{id: u32, chunk: Bytes},
     UpgradeClose,
-    Error(String), // Something has gone wrong affecting a state machine
+    Tunnel{host: String}, // A CONNECT we're relaying blind, nothing else will follow for this id but the close
+    Mirror{of: u32}, // This id is a copy of request `of` sent to the mirror upstream, its request and response follow without callbacks
+    Redirect{from: u32}, // This id is the redirect of request `from` being followed, the client only gets the last response of the chain
+    Tls(TlsInfo), // The request came in on a connection we terminated TLS for, sent ahead of its head
+    TunnelClose{sent: u64, received: u64},
+    Error(ProxyError), // Something has gone wrong affecting a state machine
     Msg(String),   // Non-state changing alerts
 }
 
 #[derive(Debug)]
 pub struct ProxyEvent {
-    pub id: u32,
+    pub id: Option<u32>, // None for events that aren't about any request, like notices and failed client handshakes
     pub event: ProxyState,
     pub callback: Option<OneshotSender<ProxyState>>
 }
@@ -41,7 +76,7 @@ impl ProxyEvent {
         let (tx, rx) = oneshot_channel();
         (
             Self {
-                id,
+                id: Some(id),
                 event: ProxyState::RequestHead(head.clone()),
                 callback: Some(tx)
             },
@@ -49,11 +84,19 @@ impl ProxyEvent {
         )
     }
 
+    pub fn req_preview(id: u32, body: &Bytes) -> Self {
+        Self {
+            id: Some(id),
+            event: ProxyState::RequestPreview(body.clone()),
+            callback: None
+        }
+    }
+
     pub fn req_chunk(id: u32, chunk: &Bytes) -> (Self, OneshotReciever<ProxyState>) {
         let (tx, rx) = oneshot_channel();
         (
             Self {
-                id,
+                id: Some(id),
                 event: ProxyState::RequestChunk(chunk.clone()),
                 callback: Some(tx)
             },
@@ -63,7 +106,7 @@ impl ProxyEvent {
 
     pub fn req_done(id: u32) -> Self {
         Self {
-            id,
+            id: Some(id),
             event: ProxyState::RequestDone,
             callback: None
         }
@@ -73,7 +116,7 @@ impl ProxyEvent {
         let (tx, rx) = oneshot_channel();
         (
             Self {
-                id,
+                id: Some(id),
                 event: ProxyState::ResponseHead(head.clone()),
                 callback: Some(tx)
             },
@@ -85,7 +128,7 @@ impl ProxyEvent {
         let (tx, rx) = oneshot_channel();
         (
             Self {
-                id,
+                id: Some(id),
                 event: ProxyState::ResponseChunk(chunk.clone()),
                 callback: Some(tx)
             },
@@ -95,7 +138,7 @@ impl ProxyEvent {
 
     pub fn resp_done(id: u32) -> Self {
         Self {
-            id,
+            id: Some(id),
             event: ProxyState::ResponseDone,
             callback: None
         }
@@ -103,7 +146,7 @@ impl ProxyEvent {
 
     pub fn upgrade_open(id: u32) -> Self {
         Self {
-            id,
+            id: Some(id),
             event: ProxyState::UpgradeOpen,
             callback: None
         }
@@ -113,7 +156,7 @@ impl ProxyEvent {
         let (tx, rx) = oneshot_channel();
         (
             Self {
-                id: req_id,
+                id: Some(req_id),
                 event: ProxyState::UpgradeTx{id, chunk: chunk.clone()},
                 callback: Some(tx)
             },
@@ -125,7 +168,7 @@ impl ProxyEvent {
         let (tx, rx) = oneshot_channel();
         (
             Self {
-                id: req_id,
+                id: Some(req_id),
                 event: ProxyState::UpgradeRx{id, chunk: chunk.clone()},
                 callback: Some(tx)
             },
@@ -135,23 +178,81 @@ impl ProxyEvent {
 
     pub fn upgrade_close(id: u32) -> Self {
         Self {
-            id,
+            id: Some(id),
             event: ProxyState::UpgradeClose,
             callback: None
         }
     }
 
-    pub fn err(id: u32, msg: String) -> Self {
+    pub fn tunnel(id: u32, host: String) -> Self {
+        Self {
+            id: Some(id),
+            event: ProxyState::Tunnel{host},
+            callback: None
+        }
+    }
+
+    pub fn tunnel_close(id: u32, sent: u64, received: u64) -> Self {
+        Self {
+            id: Some(id),
+            event: ProxyState::TunnelClose{sent, received},
+            callback: None
+        }
+    }
+
+    pub fn mirror(id: u32, of: u32) -> Self {
+        Self {
+            id: Some(id),
+            event: ProxyState::Mirror{of},
+            callback: None
+        }
+    }
+
+    pub fn redirect(id: u32, from: u32) -> Self {
+        Self {
+            id: Some(id),
+            event: ProxyState::Redirect{from},
+            callback: None
+        }
+    }
+
+    pub fn tls(id: u32, info: TlsInfo) -> Self {
+        Self {
+            id: Some(id),
+            event: ProxyState::Tls(info),
+            callback: None
+        }
+    }
+
+    // For traffic nothing is waiting on, so there's nothing to send back
+    pub fn observed(id: u32, event: ProxyState) -> Self {
+        Self {
+            id: Some(id),
+            event,
+            callback: None
+        }
+    }
+
+    pub fn err(id: u32, err: ProxyError) -> Self {
+        Self {
+            id: Some(id),
+            event: ProxyState::Error(err),
+            callback: None
+        }
+    }
+
+    // Fails something that never got as far as a request, e.g. a client handshake
+    pub fn conn_err(err: ProxyError) -> Self {
         Self {
-            id,
-            event: ProxyState::Error(msg),
+            id: None,
+            event: ProxyState::Error(err),
             callback: None
         }
     }
 
     pub fn msg(msg: String) -> Self {
         Self {
-            id: 0,
+            id: None,
             event: ProxyState::Msg(msg),
             callback: None
         }
@@ -0,0 +1,83 @@
+use hyper::{Method, StatusCode, Uri};
+use hyper::http::{HeaderValue, header};
+
+use super::request::RequestHead;
+use super::response::ResponseHead;
+
+// Sent along to the same origin only, like browsers do
+const CREDENTIALS: [header::HeaderName; 3] = [header::AUTHORIZATION, header::COOKIE, header::PROXY_AUTHORIZATION];
+
+// What went with a body that a follow up turned into a GET no longer has
+const BODY_HEADERS: [header::HeaderName; 4] = [header::CONTENT_LENGTH, header::CONTENT_TYPE, header::CONTENT_ENCODING, header::TRANSFER_ENCODING];
+
+// RFC 3986 3.1, a scheme has to come before anything that could be part of a path
+fn has_scheme(location: &str) -> bool {
+    match location.split_once(':') {
+        Some((scheme, _)) => scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.')),
+        None => false,
+    }
+}
+
+// Location against the URI it came back for (RFC 3986 5.2). Dot segments are left for the origin to deal with.
+fn resolve(base: &Uri, location: &str) -> Result<Uri, String> {
+    let location = location.split('#').next().unwrap_or_default().trim();
+    let scheme = base.scheme_str().unwrap_or("http");
+    let authority = base.authority().map(|authority| authority.as_str()).unwrap_or_default();
+    let resolved = if location.is_empty() {
+        base.to_string()
+    } else if location.starts_with("//") {
+        format!("{}:{}", scheme, location)
+    } else if location.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, location)
+    } else if has_scheme(location) {
+        location.to_string()
+    } else if location.starts_with('?') {
+        format!("{}://{}{}{}", scheme, authority, base.path(), location)
+    } else {
+        let dir = &base.path()[..base.path().rfind('/').map(|slash| slash + 1).unwrap_or(0)];
+        format!("{}://{}{}{}", scheme, authority, dir, location)
+    };
+    let uri: Uri = resolved.parse().map_err(|e| format!("its Location {:?} isn't a URI: {}", location, e))?;
+    match uri.scheme_str() {
+        Some("http") | Some("https") if uri.authority().is_some() => Ok(uri),
+        _ => Err(format!("its Location {:?} isn't an http(s) URL", location)),
+    }
+}
+
+// The request to send in place of `sent` when `resp` redirects it, or None when it isn't a redirect. A body can't be
+// sent twice since it streamed on the first time, so a redirect that keeps the method for one is an error instead.
+pub fn follow(sent: &RequestHead, had_body: bool, resp: &ResponseHead) -> Result<Option<RequestHead>, String> {
+    let to_get = match resp.status {
+        // Clients have always turned a POST into a GET for these, which RFC 9110 15.4.2 and 15.4.3 now allow
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND => sent.method == Method::POST,
+        StatusCode::SEE_OTHER => sent.method != Method::HEAD,
+        StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT => false,
+        _ => return Ok(None),
+    };
+    let location = match resp.headers.get(header::LOCATION).map(|location| location.to_str()) {
+        Some(Ok(location)) => location,
+        Some(Err(_)) => return Err("its Location isn't valid text".to_string()),
+        None => return Ok(None),
+    };
+    if had_body && !to_get {
+        return Err(format!("{} {} would have to send its body again", resp.status.as_u16(), sent.method));
+    }
+    let uri = resolve(&sent.uri, location)?;
+    let mut next = RequestHead { method: sent.method.clone(), uri, version: sent.version, headers: sent.headers.clone() };
+    if to_get {
+        next.method = Method::GET;
+        for name in BODY_HEADERS {
+            next.headers.remove(name);
+        }
+    }
+    if (next.uri.scheme(), next.uri.authority()) != (sent.uri.scheme(), sent.uri.authority()) {
+        for name in CREDENTIALS {
+            next.headers.remove(name);
+        }
+    }
+    if let Some(host) = next.uri.authority().and_then(|authority| HeaderValue::from_str(authority.as_str()).ok()) {
+        next.headers.insert(header::HOST, host);
+    }
+    Ok(Some(next))
+}
@@ -0,0 +1,191 @@
+// Serde representations for types that don't implement serde themselves, or whose default form reads badly in a config
+// file. Method, URI and version go out as strings and headers as a list of name/value pairs, so repeated headers keep their
+// order. Header names come out lowercase since that's how HeaderMap stores them; values that aren't valid UTF-8 are
+// written lossily and won't round trip exactly.
+
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+use hyper::http::{HeaderMap, HeaderValue, Version, header::HeaderName};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
+
+// Anything with a Display/FromStr pair that round trips, i.e. Method and Uri
+pub mod string {
+    use super::*;
+
+    pub fn serialize<T: Display, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+    }
+}
+
+pub mod optional_string {
+    use super::*;
+
+    pub fn serialize<T: Display, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => serializer.collect_str(value),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(deserializer)?.map(|value| value.parse().map_err(D::Error::custom)).transpose()
+    }
+}
+
+// Whole milliseconds, rather than serde's {secs, nanos}
+pub mod millis {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(deserializer)?))
+    }
+}
+
+pub mod optional_millis {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => serializer.serialize_u64(duration.as_millis() as u64),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_millis))
+    }
+}
+
+// StatusCode displays with its reason phrase, so it's kept as the bare number instead
+pub mod status {
+    use super::*;
+    use hyper::StatusCode;
+
+    pub fn serialize<S: Serializer>(status: &StatusCode, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(status.as_u16())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<StatusCode, D::Error> {
+        StatusCode::from_u16(u16::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+pub mod version {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(version: &Version, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{:?}", version))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Version, D::Error> {
+        match String::deserialize(deserializer)?.as_str() {
+            "HTTP/0.9" => Ok(Version::HTTP_09),
+            "HTTP/1.0" => Ok(Version::HTTP_10),
+            "HTTP/1.1" => Ok(Version::HTTP_11),
+            "HTTP/2.0" => Ok(Version::HTTP_2),
+            "HTTP/3.0" => Ok(Version::HTTP_3),
+            other => Err(D::Error::custom(format!("Unknown HTTP version {:?}", other))),
+        }
+    }
+}
+
+pub mod headers {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Header {
+        name: String,
+        value: String,
+    }
+
+    pub fn serialize<S: Serializer>(headers: &HeaderMap<HeaderValue>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(headers.iter().map(|(name, value)| Header {
+            name: name.to_string(),
+            value: String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HeaderMap<HeaderValue>, D::Error> {
+        let mut headers = HeaderMap::new();
+        for Header { name, value } in Vec::<Header>::deserialize(deserializer)? {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).map_err(D::Error::custom)?,
+                HeaderValue::from_str(&value).map_err(D::Error::custom)?,
+            );
+        }
+        Ok(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::proxy::{request::RequestHead, response::ResponseHead};
+
+    fn headers() -> HeaderMap<HeaderValue> {
+        let mut headers = HeaderMap::new();
+        headers.append("accept", HeaderValue::from_static("text/html"));
+        headers.append("cookie", HeaderValue::from_static("a=1"));
+        headers.append("cookie", HeaderValue::from_static("b=2"));
+        headers
+    }
+
+    #[test]
+    fn request_head_round_trip() {
+        let head = RequestHead {
+            method: Method::PATCH,
+            uri: "http://example.com/a?b=c".parse().unwrap(),
+            version: Version::HTTP_11,
+            headers: headers(),
+        };
+        let value = serde_json::to_value(&head).unwrap();
+        assert_eq!(value, json!({
+            "method": "PATCH",
+            "uri": "http://example.com/a?b=c",
+            "version": "HTTP/1.1",
+            "headers": [
+                {"name": "accept", "value": "text/html"},
+                {"name": "cookie", "value": "a=1"},
+                {"name": "cookie", "value": "b=2"},
+            ],
+        }));
+        assert_eq!(serde_json::from_value::<RequestHead>(value).unwrap(), head);
+    }
+
+    #[test]
+    fn response_head_round_trip() {
+        let head = ResponseHead { status: StatusCode::NOT_FOUND, version: Version::HTTP_2, headers: headers() };
+        let text = serde_json::to_string(&head).unwrap();
+        assert!(text.contains(r#""status":404"#));
+        assert_eq!(serde_json::from_str::<ResponseHead>(&text).unwrap(), head);
+    }
+
+    #[test]
+    fn unknown_version() {
+        let text = r#"{"status": 200, "version": "HTTP/4", "headers": []}"#;
+        assert!(serde_json::from_str::<ResponseHead>(text).is_err());
+    }
+}
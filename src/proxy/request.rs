@@ -1,13 +1,21 @@
-use hyper::{http::{Method, Uri, Version, HeaderMap, HeaderValue}, Body, upgrade::OnUpgrade};
-use crate::proxy::body::StreamBody;
+use std::sync::atomic::AtomicBool;
+
+use hyper::{http::{Method, Uri, Version, HeaderMap, HeaderValue, header::CONTENT_LENGTH}, Body, body::Bytes, upgrade::OnUpgrade};
+use crate::proxy::body::{StreamBody, Throttle};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
 
 use super::{ProxyEvent, Sender, ProxyState};
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct RequestHead {
+    #[serde(with = "super::repr::string")]
     pub method: Method,
+    #[serde(with = "super::repr::string")]
     pub uri: Uri,
+    #[serde(with = "super::repr::version")]
     pub version: Version,
+    #[serde(with = "super::repr::headers")]
     pub headers: HeaderMap<HeaderValue>,
 }
 
@@ -17,49 +25,74 @@ pub struct Request {
     pub body: StreamBody,
 }
 
+// Bodies up to this size, with a Content-Length saying so, are read before the head goes out. Anything bigger
+// streams as it comes so an upload's progress can still be followed.
+const PREVIEW_LIMIT: u64 = 64 * 1024;
+
 impl Request {
-    pub async fn from_request(req: hyper::Request<Body>, id: u32, channel: Sender<ProxyEvent>) -> (Self, Option<OnUpgrade>) {
-        let (mut parts, body) = req.into_parts();
+    pub async fn from_request(req: hyper::Request<Body>, id: u32, channel: Sender<ProxyEvent>, throttle: Throttle, edit_bodies: &AtomicBool) -> (Self, Option<OnUpgrade>) {
+        let (mut parts, mut body) = req.into_parts();
         let head = RequestHead {
                 method:  parts.method,
                 uri:     parts.uri,
                 version: parts.version,
                 headers: parts.headers,
         };
+        let previewed = head.headers.get(CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+            .filter(|len| *len > 0 && *len <= PREVIEW_LIMIT);
+        if previewed.is_some() {
+            body = match hyper::body::to_bytes(body).await {
+                Ok(bytes) => {
+                    let _ = channel.send(ProxyEvent::req_preview(id, &bytes)).await;
+                    Body::from(bytes)
+                },
+                // Handed on as the body, so the request fails the same way it would have while streaming
+                Err(e) => Body::wrap_stream(futures::stream::once(async { Err::<Bytes, _>(e) })),
+            };
+        }
         let (event, completion) = ProxyEvent::req_head(id, &head);
-        channel.send(event).await.unwrap();
+        // If the receiver is gone the callback goes with the event, and the head is sent on as it came
+        let _ = channel.send(event).await;
         let head = match completion.await {
             Ok(ProxyState::RequestHead(head)) => head,
             Ok(e) => {
-                println!("Got unexpected result {:?}", e);
+                warn!("Got unexpected result {:?}", e);
                 head
             }
             Err(_) => {
-                println!("Dropped sender");
+                debug!("Dropped sender");
                 head
             }
         };
+        // Read once the head is answered, whatever answered it has had its chance to say the body may be edited
         (Self {
             head,
-            body: StreamBody::stream_request(body, id,  channel),
+            body: StreamBody::stream_request(body, id,  channel, throttle, edit_bodies.load(crate::ORDERING)),
         },
         parts.extensions.remove()
         )
     }
 }
 
-impl Into<hyper::Request<Body>> for Request {
-    fn into(self) -> hyper::Request<Body> {
+impl RequestHead {
+    pub fn to_request(&self, body: Body) -> hyper::http::Result<hyper::Request<Body>> {
         let req = hyper::Request::builder()
-            .method(self.head.method)
-            .uri(self.head.uri)
-            .version(self.head.version);
-        let req = self.head.headers.iter().fold(
+            .method(self.method.clone())
+            .uri(self.uri.clone())
+            .version(self.version);
+        let req = super::hop::forwardable(&self.headers).iter().fold(
             req,
             | req, (name, item) | req.header(name, item)
         );
-        req
-            .body(self.body.try_into_body().expect("Body was previously consumed"))
+        req.body(body)
+    }
+}
+
+impl Into<hyper::Request<Body>> for Request {
+    fn into(self) -> hyper::Request<Body> {
+        self.head
+            .to_request(self.body.try_into_body().expect("Body was previously consumed"))
             .unwrap()
     }
 }
\ No newline at end of file
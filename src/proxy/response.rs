@@ -1,12 +1,19 @@
+use std::sync::atomic::AtomicBool;
+
 use hyper::{http::{Version, HeaderMap, HeaderValue}, Body, StatusCode, upgrade::OnUpgrade};
-use crate::proxy::body::StreamBody;
+use crate::proxy::body::{StreamBody, Throttle};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
 use super::{ProxyEvent, Sender, ProxyState};
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ResponseHead {
+    #[serde(with = "super::repr::status")]
     pub status: StatusCode,
+    #[serde(with = "super::repr::version")]
     pub version: Version,
+    #[serde(with = "super::repr::headers")]
     pub headers: HeaderMap<HeaderValue>,
 }
 
@@ -17,7 +24,7 @@ pub struct Response {
 }
 
 impl Response {
-    pub async fn from_response(resp: hyper::Response<Body>, id: u32, channel: Sender<ProxyEvent>) -> (Self, Option<OnUpgrade>) {
+    pub async fn from_response(resp: hyper::Response<Body>, id: u32, channel: Sender<ProxyEvent>, throttle: Throttle, edit_bodies: &AtomicBool) -> (Self, Option<OnUpgrade>) {
         let (mut parts, body) = resp.into_parts();
         let head = ResponseHead {
                 status:  parts.status,
@@ -25,18 +32,19 @@ impl Response {
                 headers: parts.headers,
         };
         let (event, completion) = ProxyEvent::resp_head(id, &head);
-        channel.send(event).await.unwrap();
+        // If the receiver is gone the callback goes with the event, and the head is sent on as it came
+        let _ = channel.send(event).await;
         let head = match completion.await {
             Ok(ProxyState::ResponseHead(head)) => head,
             Ok(e) => {
-                println!("Got unexpected result {:?}", e);
+                warn!("Got unexpected result {:?}", e);
                 head
             }
             Err(_) => head
         };
         (Self {
             head,
-            body: StreamBody::stream_response(body, id,  channel)
+            body: StreamBody::stream_response(body, id,  channel, throttle, edit_bodies.load(crate::ORDERING))
         },
         parts.extensions.remove()
         )
@@ -48,7 +56,7 @@ impl Into<hyper::Response<Body>> for Response {
         let resp = hyper::Response::builder()
             .status(self.head.status)
             .version(self.head.version);
-        let resp = self.head.headers.iter().fold(
+        let resp = super::hop::forwardable(&self.head.headers).iter().fold(
             resp,
             | req, (name, item) | req.header(name, item)
         );
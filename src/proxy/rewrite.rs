@@ -0,0 +1,179 @@
+use std::sync::OnceLock;
+
+use hyper::http::{HeaderValue, header::{HeaderName, HOST}};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::request::RequestHead;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RewriteAction {
+    SetHeader { name: String, value: String },
+    ReplaceBody { find: String, replace: String },
+    RewriteUri { pattern: String, replacement: String }, // Regex replace over the whole URI, $1 style groups allowed
+}
+
+impl RewriteAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::SetHeader { .. } => "Set header",
+            Self::ReplaceBody { .. } => "Replace in body",
+            Self::RewriteUri { .. } => "Rewrite URI",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Matcher {
+    host: Option<Regex>,
+    path: Option<Regex>,
+    header: Option<Regex>,
+    uri: Option<Regex>, // Only set for RewriteUri
+}
+
+// Automatic edit applied to every request it matches. Empty patterns match anything. The proxy only holds these for
+// whatever subscribes to its events, it doesn't apply them itself.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RewriteRule {
+    pub enabled: bool,
+    pub host: String,
+    pub path: String,
+    pub header: String, // Matched against each header as "name: value"
+    pub action: RewriteAction,
+    #[serde(skip)]
+    matcher: OnceLock<Result<Matcher, String>>, // Compiled on first use, so loaded rules don't need a step of their own
+}
+
+impl Default for RewriteRule {
+    fn default() -> Self {
+        Self::new(RewriteAction::SetHeader { name: String::new(), value: String::new() })
+    }
+}
+
+fn compile(pattern: &str) -> Result<Option<Regex>, String> {
+    if pattern.is_empty() {
+        Ok(None)
+    } else {
+        Regex::new(pattern).map(Some).map_err(|e| e.to_string())
+    }
+}
+
+impl RewriteRule {
+    pub fn new(action: RewriteAction) -> Self {
+        Self {
+            enabled: true,
+            host: String::new(),
+            path: String::new(),
+            header: String::new(),
+            action,
+            matcher: OnceLock::new(),
+        }
+    }
+
+    // Has to be called after any of the patterns are edited
+    pub fn recompile(&mut self) {
+        self.matcher = OnceLock::new();
+    }
+
+    fn matcher(&self) -> &Result<Matcher, String> {
+        self.matcher.get_or_init(|| Ok(Matcher {
+            host: compile(&self.host)?,
+            path: compile(&self.path)?,
+            header: compile(&self.header)?,
+            uri: match &self.action {
+                RewriteAction::RewriteUri { pattern, .. } => compile(pattern)?,
+                _ => None,
+            },
+        }))
+    }
+
+    // Why the rule never matches, if one of its patterns isn't a valid regex
+    pub fn error(&self) -> Option<&str> {
+        self.matcher().as_ref().err().map(String::as_str)
+    }
+
+    pub fn matches(&self, head: &RequestHead) -> bool {
+        let matcher = match (self.matcher(), self.enabled) {
+            (Ok(matcher), true) => matcher,
+            _ => return false,
+        };
+        let host = head.uri.host()
+            .or_else(|| head.headers.get(HOST).and_then(|host| host.to_str().ok()))
+            .unwrap_or_default();
+        matcher.host.as_ref().map(|re| re.is_match(host)).unwrap_or(true)
+            && matcher.path.as_ref().map(|re| re.is_match(head.uri.path())).unwrap_or(true)
+            && matcher.header.as_ref().map(|re| {
+                head.headers.iter().any(|(name, value)| {
+                    re.is_match(&format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes())))
+                })
+            }).unwrap_or(true)
+    }
+
+    // Edit the head in place, handing back what to find and replace it with in the request's chunks if that's what
+    // this rule does
+    pub fn apply(&self, head: &mut RequestHead) -> Option<(Vec<u8>, Vec<u8>)> {
+        match &self.action {
+            RewriteAction::SetHeader { name, value } => {
+                if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.trim().as_bytes()), HeaderValue::from_str(value)) {
+                    head.headers.insert(name, value);
+                }
+                None
+            },
+            RewriteAction::ReplaceBody { find, replace } if !find.is_empty() => {
+                Some((find.clone().into_bytes(), replace.clone().into_bytes()))
+            },
+            RewriteAction::ReplaceBody { .. } => None,
+            RewriteAction::RewriteUri { replacement, .. } => {
+                if let Some(re) = self.matcher().as_ref().ok().and_then(|matcher| matcher.uri.as_ref()) {
+                    if let Ok(uri) = re.replace_all(&head.uri.to_string(), replacement.as_str()).parse() {
+                        head.uri = uri;
+                    }
+                }
+                None
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::ProxyConfig;
+
+    #[test]
+    fn rules_survive_the_config_file() {
+        let config = ProxyConfig {
+            rewrite_rules: vec![
+                RewriteRule { host: "^api\\.".to_string(), ..RewriteRule::new(RewriteAction::SetHeader { name: "x-a".to_string(), value: "1".to_string() }) },
+                RewriteRule { enabled: false, ..RewriteRule::new(RewriteAction::ReplaceBody { find: "a".to_string(), replace: "b".to_string() }) },
+            ],
+            ..Default::default()
+        };
+        let loaded: ProxyConfig = toml::from_str(&toml::to_string_pretty(&config).unwrap()).unwrap();
+        assert_eq!(loaded.rewrite_rules.len(), 2);
+        assert_eq!(loaded.rewrite_rules[0].action, config.rewrite_rules[0].action);
+        assert!(!loaded.rewrite_rules[1].enabled);
+        // Loaded rules compile on their own
+        let head = RequestHead {
+            method: hyper::Method::GET,
+            uri: "http://api.example.com/".parse().unwrap(),
+            version: hyper::Version::HTTP_11,
+            headers: Default::default(),
+        };
+        assert!(loaded.rewrite_rules[0].matches(&head));
+        assert!(!loaded.rewrite_rules[1].matches(&head));
+    }
+
+    #[test]
+    fn bad_patterns_fail_validation() {
+        let config = ProxyConfig {
+            pubkey_path: "cert".to_string(), // Next to wherever the tests run, so only the rule can fail
+            privkey_path: "key".to_string(),
+            rewrite_rules: vec![RewriteRule { path: "(".to_string(), ..RewriteRule::default() }],
+            ..Default::default()
+        };
+        assert!(config.validate().unwrap_err().contains("Rewrite rule 1"));
+    }
+}
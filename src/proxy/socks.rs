@@ -0,0 +1,135 @@
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use hyper::http::uri::Authority;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Reply codes from RFC 1928
+pub const SUCCEEDED: u8 = 0x00;
+pub const GENERAL_FAILURE: u8 = 0x01;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+
+// Where the client asked to connect: the bare host, for SNI and bypass matching, and host:port
+pub struct Target {
+    pub host: String,
+    pub authority: String,
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+// Runs the SOCKS5 greeting and request, up to but not including the reply, so the caller can still refuse.
+// Only CONNECT without authentication is supported, which is all a client pointed at a local proxy needs.
+pub async fn handshake<T: AsyncRead + AsyncWrite + Unpin>(conn: &mut T) -> Result<Target, Error> {
+    let mut greeting = [0u8; 2];
+    conn.read_exact(&mut greeting).await?;
+    if greeting[0] != VERSION {
+        return Err(invalid(format!("Not a SOCKS5 client, version byte {:#04x}", greeting[0])));
+    }
+    let mut methods = vec![0u8; greeting[1] as usize];
+    conn.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTH) {
+        conn.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(Error::new(ErrorKind::PermissionDenied, "SOCKS client requires authentication"));
+    }
+    conn.write_all(&[VERSION, NO_AUTH]).await?;
+
+    let mut request = [0u8; 4];
+    conn.read_exact(&mut request).await?;
+    if request[0] != VERSION {
+        return Err(invalid(format!("Bad SOCKS request version {:#04x}", request[0])));
+    }
+    if request[1] != CONNECT {
+        reply(conn, COMMAND_NOT_SUPPORTED).await?;
+        return Err(Error::new(ErrorKind::Unsupported, format!("Unsupported SOCKS command {:#04x}", request[1])));
+    }
+    let (host, bracketed) = match request[3] {
+        0x01 => {
+            let mut addr = [0u8; 4];
+            conn.read_exact(&mut addr).await?;
+            let host = Ipv4Addr::from(addr).to_string();
+            (host.clone(), host)
+        },
+        0x03 => {
+            let mut len = [0u8; 1];
+            conn.read_exact(&mut len).await?;
+            let mut name = vec![0u8; len[0] as usize];
+            conn.read_exact(&mut name).await?;
+            // Anything that isn't UTF-8 can't be a host either, which the check below turns away
+            let host = String::from_utf8_lossy(&name).into_owned();
+            (host.clone(), host)
+        },
+        0x04 => {
+            let mut addr = [0u8; 16];
+            conn.read_exact(&mut addr).await?;
+            let host = Ipv6Addr::from(addr).to_string();
+            let bracketed = format!("[{}]", host);
+            (host, bracketed)
+        },
+        other => {
+            reply(conn, ADDRESS_NOT_SUPPORTED).await?;
+            return Err(invalid(format!("Unsupported SOCKS address type {:#04x}", other)));
+        },
+    };
+    let mut port = [0u8; 2];
+    conn.read_exact(&mut port).await?;
+    let authority = format!("{}:{}", bracketed, u16::from_be_bytes(port));
+    // A domain name can be any bytes at all, and the authority ends up in the URI of every request through the tunnel.
+    // Anything that doesn't come back out as the same host, like "x/y" or "user@host", is refused here.
+    match authority.parse::<Authority>() {
+        Ok(parsed) if !host.is_empty() && parsed.host() == bracketed => Ok(Target { host, authority }),
+        _ => {
+            reply(conn, ADDRESS_NOT_SUPPORTED).await?;
+            Err(invalid(format!("SOCKS target {:?} isn't a valid host", host)))
+        },
+    }
+}
+
+// We never connect before replying, so there's no real bound address to give back
+pub async fn reply<T: AsyncWrite + Unpin>(conn: &mut T, code: u8) -> Result<(), Error> {
+    conn.write_all(&[VERSION, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the handshake against a client sending a CONNECT to `name` on port 443, handing back the target and
+    // whatever the handshake wrote back after the method choice
+    async fn connect_to(name: &[u8]) -> (Result<Target, Error>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let mut request = vec![VERSION, 1, NO_AUTH, VERSION, CONNECT, 0x00, 0x03, name.len() as u8];
+        request.extend_from_slice(name);
+        request.extend_from_slice(&443u16.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let target = handshake(&mut server).await;
+        drop(server);
+        let mut written = Vec::new();
+        client.read_to_end(&mut written).await.unwrap();
+        (target, written[2..].to_vec())
+    }
+
+    #[tokio::test]
+    async fn domain_targets() {
+        let (target, written) = connect_to(b"example.com").await;
+        let target = target.unwrap();
+        assert_eq!((target.host.as_str(), target.authority.as_str()), ("example.com", "example.com:443"));
+        assert!(written.is_empty());
+    }
+
+    #[tokio::test]
+    async fn domains_that_arent_hosts_are_refused() {
+        for name in [&b"x/y"[..], b"a b", b"user@example.com", b"", b"\xff"] {
+            let (target, written) = connect_to(name).await;
+            assert!(target.is_err(), "{:?}", name);
+            assert_eq!(written.get(1), Some(&ADDRESS_NOT_SUPPORTED), "{:?}", name);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use hyper::client::HttpConnector;
+use hyper::http::uri::Scheme;
+use hyper::service::Service;
+use hyper::Uri;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// Connects straight to the origin, or through a CONNECT tunnel on an upstream HTTP proxy when one is set.
+// Everything goes through the tunnel, plain http included, so the TLS layer on top doesn't need to know about the upstream.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    http: HttpConnector,
+    proxy: Option<Uri>,
+}
+
+impl UpstreamConnector {
+    pub fn new(http: HttpConnector, proxy: Option<Uri>) -> Self {
+        Self { http, proxy }
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = TcpStream;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        match self.proxy.clone() {
+            None => {
+                let connecting = self.http.call(dst);
+                Box::pin(async move { Ok(connecting.await?) })
+            }
+            Some(proxy) => {
+                let connecting = self.http.call(proxy);
+                Box::pin(async move {
+                    let mut stream = connecting.await?;
+                    tunnel(&mut stream, &dst).await?;
+                    Ok(stream)
+                })
+            }
+        }
+    }
+}
+
+async fn tunnel(stream: &mut TcpStream, dst: &Uri) -> Result<(), Error> {
+    let host = dst.host()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("No host to tunnel to in {}", dst)))?;
+    let port = dst.port_u16()
+        .unwrap_or(if dst.scheme() == Some(&Scheme::HTTP) { 80 } else { 443 });
+    let target = format!("{}:{}", host, port);
+    stream.write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target).as_bytes()).await?;
+
+    // The client always speaks first once the tunnel is up, so nothing past the response head can arrive yet
+    let mut head = Vec::new();
+    let mut buf = [0u8; 512];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > 8192 {
+            return Err(Error::new(ErrorKind::InvalidData, "Upstream proxy response head too large"));
+        }
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Upstream proxy closed the connection during CONNECT"));
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+    let status_line = String::from_utf8_lossy(head.split(|&byte| byte == b'\n').next().unwrap_or_default()).trim().to_string();
+    match status_line.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("Upstream proxy refused CONNECT to {}: {}", target, status_line)
+        )),
+    }
+}
@@ -0,0 +1,69 @@
+use eframe::egui::{Ui, TextEdit};
+use hyper::http::header::HOST;
+
+use crate::proxy::fault::glob;
+use crate::proxy::request::RequestHead;
+
+// Narrows request interception down to what matches. Patterns are globs with * and ? wildcards, empty ones
+// match anything.
+pub struct InterceptCondition {
+    pub enabled: bool,
+    pub host: String,
+    pub path: String,
+    pub method: String, // Exact, case doesn't matter
+}
+
+impl InterceptCondition {
+    fn new() -> Self {
+        Self { enabled: true, host: String::new(), path: String::new(), method: String::new() }
+    }
+
+    fn matches(&self, head: &RequestHead) -> bool {
+        let host = head.uri.host()
+            .or_else(|| head.headers.get(HOST).and_then(|host| host.to_str().ok()))
+            .unwrap_or_default();
+        let method = self.method.trim();
+        glob(&self.host, host)
+            && glob(&self.path, head.uri.path())
+            && (method.is_empty() || method.eq_ignore_ascii_case(head.method.as_str()))
+    }
+
+    fn draw(&mut self, ui: &mut Ui, idx: usize) -> bool {
+        let mut removed = false;
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.enabled, format!("Breakpoint {}", idx + 1));
+            ui.label("Method");
+            ui.add(TextEdit::singleline(&mut self.method).desired_width(60.0));
+            for (label, pattern) in [("Host", &mut self.host), ("Path", &mut self.path)] {
+                ui.label(label);
+                ui.add(TextEdit::singleline(pattern).desired_width(140.0));
+            }
+            removed = ui.small_button("x").clicked();
+        });
+        removed
+    }
+}
+
+// With nothing enabled every request is parked, same as before there were conditions
+pub fn should_park(conditions: &[InterceptCondition], head: &RequestHead) -> bool {
+    let mut enabled = conditions.iter().filter(|condition| condition.enabled).peekable();
+    enabled.peek().is_none() || enabled.any(|condition| condition.matches(head))
+}
+
+pub fn draw_conditions(ui: &mut Ui, conditions: &mut Vec<InterceptCondition>) {
+    let mut removed = None;
+    for (idx, condition) in conditions.iter_mut().enumerate() {
+        if condition.draw(ui, idx) {
+            removed = Some(idx);
+        }
+    }
+    if let Some(idx) = removed {
+        conditions.remove(idx);
+    }
+    if !conditions.iter().any(|condition| condition.enabled) {
+        ui.label("No breakpoints enabled, every request is intercepted");
+    }
+    if ui.button("Add breakpoint").clicked() {
+        conditions.push(InterceptCondition::new());
+    }
+}
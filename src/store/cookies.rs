@@ -0,0 +1,84 @@
+use eframe::egui::{Ui, Grid, Label, RichText};
+use hyper::http::{HeaderMap, HeaderValue, header::{COOKIE, SET_COOKIE, HeaderName}};
+
+// One row of the cookie table. Cookies the client sent only have a name and value, the rest comes with Set-Cookie.
+pub struct Cookie {
+    sent: bool,
+    name: String,
+    value: String,
+    domain: String,
+    path: String,
+    expires: String, // Max-Age when given, it wins over Expires (RFC 6265 5.3)
+    flags: Vec<String>,
+}
+
+impl Cookie {
+    fn new(sent: bool, pair: &str) -> Self {
+        let (name, value) = pair.split_once('=').unwrap_or(("", pair));
+        Self {
+            sent,
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+            domain: String::new(),
+            path: String::new(),
+            expires: String::new(),
+            flags: Vec::new(),
+        }
+    }
+}
+
+fn values<'a>(headers: &'a HeaderMap<HeaderValue>, name: &HeaderName) -> impl Iterator<Item = String> + 'a {
+    headers.get_all(name).iter().map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+}
+
+// Cookie headers from the request, then Set-Cookie from the response, in the order they were sent. Nothing is
+// validated, whatever is there is shown the way it came in.
+pub fn parse(request: &HeaderMap<HeaderValue>, response: Option<&HeaderMap<HeaderValue>>) -> Vec<Cookie> {
+    let mut cookies: Vec<Cookie> = values(request, &COOKIE)
+        .flat_map(|header| header.split(';').map(str::to_string).collect::<Vec<_>>())
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| Cookie::new(true, &pair))
+        .collect();
+    for header in response.into_iter().flat_map(|headers| values(headers, &SET_COOKIE)) {
+        let mut attributes = header.split(';');
+        let mut cookie = Cookie::new(false, attributes.next().unwrap_or_default());
+        let mut expires = None;
+        for attribute in attributes {
+            let (name, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim().to_string();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "domain" => cookie.domain = value,
+                "path" => cookie.path = value,
+                "max-age" => cookie.expires = format!("Max-Age {}", value),
+                "expires" => expires = Some(value),
+                "" => {},
+                _ if value.is_empty() => cookie.flags.push(name.trim().to_string()),
+                _ => cookie.flags.push(format!("{}={}", name.trim(), value)),
+            }
+        }
+        if cookie.expires.is_empty() {
+            cookie.expires = expires.unwrap_or_else(|| "Session".to_string());
+        }
+        cookies.push(cookie);
+    }
+    cookies
+}
+
+pub fn draw_cookies(ui: &mut Ui, id: usize, cookies: &[Cookie]) {
+    Grid::new(("cookies", id)).num_columns(7).striped(true).show(ui, |ui| {
+        for heading in ["", "Name", "Value", "Domain", "Path", "Expires", "Flags"] {
+            ui.strong(heading);
+        }
+        ui.end_row();
+        for cookie in cookies {
+            ui.label(if cookie.sent { "Sent" } else { "Set" });
+            ui.label(RichText::new(&cookie.name).monospace().strong());
+            ui.add(Label::new(RichText::new(&cookie.value).monospace()).wrap(true));
+            ui.label(&cookie.domain);
+            ui.label(&cookie.path);
+            ui.label(&cookie.expires);
+            ui.label(cookie.flags.join(", "));
+            ui.end_row();
+        }
+    });
+}
@@ -0,0 +1,181 @@
+use eframe::egui::{Ui, RichText, CollapsingHeader};
+use hyper::http::{HeaderMap, HeaderValue};
+
+use super::{StatusColors, StoredPair};
+use super::spool::BodyBuffer;
+use super::view::{self, BodyKind};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DiffLine {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+// Past this the middle of the diff is shown as removed then added instead of lined up, the table grows as the product
+const LCS_LIMIT: usize = 4_000_000;
+
+// Same cut off as the body views, a diff of megabytes of text is no easier to read than the text itself
+const TEXT_LIMIT: usize = 64 * 1024;
+
+// Line diff by longest common subsequence, after trimming what's shared at either end
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (old_mid, new_mid) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    let mut lines: Vec<DiffLine> = old[..prefix].iter().map(|line| DiffLine::Same(line.to_string())).collect();
+    if old_mid.len() * new_mid.len() > LCS_LIMIT {
+        lines.extend(old_mid.iter().map(|line| DiffLine::Removed(line.to_string())));
+        lines.extend(new_mid.iter().map(|line| DiffLine::Added(line.to_string())));
+    } else {
+        // lengths[i][j] is the LCS of old_mid[i..] and new_mid[j..]
+        let width = new_mid.len() + 1;
+        let mut lengths = vec![0u32; (old_mid.len() + 1) * width];
+        for i in (0..old_mid.len()).rev() {
+            for j in (0..new_mid.len()).rev() {
+                lengths[i * width + j] = if old_mid[i] == new_mid[j] {
+                    lengths[(i + 1) * width + j + 1] + 1
+                } else {
+                    lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < old_mid.len() && j < new_mid.len() {
+            if old_mid[i] == new_mid[j] {
+                lines.push(DiffLine::Same(old_mid[i].to_string()));
+                i += 1;
+                j += 1;
+            } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+                lines.push(DiffLine::Removed(old_mid[i].to_string()));
+                i += 1;
+            } else {
+                lines.push(DiffLine::Added(new_mid[j].to_string()));
+                j += 1;
+            }
+        }
+        lines.extend(old_mid[i..].iter().map(|line| DiffLine::Removed(line.to_string())));
+        lines.extend(new_mid[j..].iter().map(|line| DiffLine::Added(line.to_string())));
+    }
+    lines.extend(old[old.len() - suffix..].iter().map(|line| DiffLine::Same(line.to_string())));
+    lines
+}
+
+fn header_text(headers: &HeaderMap<HeaderValue>) -> String {
+    headers.iter()
+        .map(|(name, value)| format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes())))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Bodies are compared the way they're shown: decoded, and pretty printed where we know how
+fn body_text(headers: &HeaderMap<HeaderValue>, body: &BodyBuffer) -> Result<String, String> {
+    let raw = body.read().map_err(|e| format!("unable to read body: {}", e))?;
+    let decoded = view::decode(headers, &raw)?;
+    let body = decoded.as_deref().unwrap_or(&raw);
+    let kind = BodyKind::detect(headers, body);
+    if let Some(pretty) = view::pretty(kind, body) {
+        return Ok(truncate(pretty));
+    }
+    match std::str::from_utf8(body) {
+        Ok(text) if !matches!(kind, BodyKind::Image | BodyKind::Binary) => Ok(truncate(text.to_string())),
+        _ => Err(format!("binary, {} bytes", body.len())),
+    }
+}
+
+fn truncate(mut text: String) -> String {
+    if text.len() > TEXT_LIMIT {
+        let mut end = TEXT_LIMIT;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+    text
+}
+
+fn diff_bodies(old: Option<(&HeaderMap<HeaderValue>, &BodyBuffer)>, new: Option<(&HeaderMap<HeaderValue>, &BodyBuffer)>) -> Result<Vec<DiffLine>, String> {
+    let old = old.map(|(headers, body)| body_text(headers, body)).transpose()?.unwrap_or_default();
+    let new = new.map(|(headers, body)| body_text(headers, body)).transpose()?.unwrap_or_default();
+    Ok(diff_lines(&old, &new))
+}
+
+// Everything that's compared between two captures, worked out once rather than every frame
+pub struct Comparison {
+    pub key: [usize; 6], // Both cache indices and the body lengths it was made from, responses may still be streaming in
+    sections: Vec<(&'static str, Result<Vec<DiffLine>, String>)>,
+}
+
+impl Comparison {
+    pub fn key(old_idx: usize, old: &StoredPair, new_idx: usize, new: &StoredPair) -> [usize; 6] {
+        let lens = |pair: &StoredPair| (
+            pair.request.as_ref().map(|req| req.body.len()).unwrap_or(0),
+            pair.response.as_ref().map(|resp| resp.body.len() + 1).unwrap_or(0),
+        );
+        let (old_req, old_resp) = lens(old);
+        let (new_req, new_resp) = lens(new);
+        [old_idx, new_idx, old_req, new_req, old_resp, new_resp]
+    }
+
+    pub fn new(old_idx: usize, old: &StoredPair, new_idx: usize, new: &StoredPair) -> Self {
+        let old_req = old.request.as_ref();
+        let new_req = new.request.as_ref();
+        let old_resp = old.response.as_ref();
+        let new_resp = new.response.as_ref();
+        let request_line = |pair: &StoredPair| pair.request.as_ref()
+            .map(|req| format!("{} {}", req.head.method, req.head.uri))
+            .unwrap_or_default();
+        let status_line = |pair: &StoredPair| pair.response.as_ref()
+            .map(|resp| resp.head.status.to_string())
+            .unwrap_or_default();
+        let heads = |old: Option<&HeaderMap<HeaderValue>>, new: Option<&HeaderMap<HeaderValue>>| {
+            Ok(diff_lines(&old.map(header_text).unwrap_or_default(), &new.map(header_text).unwrap_or_default()))
+        };
+        Self {
+            key: Self::key(old_idx, old, new_idx, new),
+            sections: vec![
+                ("Request line", Ok(diff_lines(&request_line(old), &request_line(new)))),
+                ("Request headers", heads(old_req.map(|req| &req.head.headers), new_req.map(|req| &req.head.headers))),
+                ("Request body", diff_bodies(
+                    old_req.map(|req| (&req.head.headers, &req.body)),
+                    new_req.map(|req| (&req.head.headers, &req.body)),
+                )),
+                ("Status", Ok(diff_lines(&status_line(old), &status_line(new)))),
+                ("Response headers", heads(old_resp.map(|resp| &resp.head.headers), new_resp.map(|resp| &resp.head.headers))),
+                ("Response body", diff_bodies(
+                    old_resp.map(|resp| (&resp.head.headers, &resp.body)),
+                    new_resp.map(|resp| (&resp.head.headers, &resp.body)),
+                )),
+            ],
+        }
+    }
+
+    // Unified, one collapsible section per part, with the ones that differ opened up
+    pub fn draw(&self, ui: &mut Ui) {
+        let colors = StatusColors::for_visuals(ui.visuals());
+        for (name, lines) in &self.sections {
+            let changed = match lines {
+                Ok(lines) => lines.iter().filter(|line| !matches!(line, DiffLine::Same(_))).count(),
+                Err(_) => 0,
+            };
+            let title = match lines {
+                Ok(_) if changed == 0 => format!("{} (identical)", name),
+                Ok(_) => format!("{} ({} lines differ)", name, changed),
+                Err(e) => format!("{} (not compared: {})", name, e),
+            };
+            CollapsingHeader::new(title).id_source(("diff", *name)).default_open(changed > 0).show(ui, |ui| {
+                for line in lines.iter().flatten() {
+                    let text = match line {
+                        DiffLine::Same(text) => RichText::new(format!("  {}", text)),
+                        DiffLine::Removed(text) => RichText::new(format!("- {}", text)).color(colors.failure),
+                        DiffLine::Added(text) => RichText::new(format!("+ {}", text)).color(colors.success),
+                    };
+                    ui.label(text.monospace());
+                }
+            });
+        }
+    }
+}
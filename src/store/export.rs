@@ -0,0 +1,190 @@
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use hyper::Uri;
+use hyper::http::{HeaderMap, HeaderValue, header::{CONTENT_LENGTH, CONTENT_TYPE}};
+
+use super::{StoredRequest, StoredResponse};
+
+pub fn to_curl(req: &StoredRequest) -> String {
+    let mut cmd = format!("curl -X {} {}", shell_quote(req.head.method.as_str().as_bytes()), shell_quote(req.head.uri.to_string().as_bytes()));
+    for (name, value) in req.head.headers.iter() {
+        let header = [name.as_str().as_bytes(), b": ", value.as_bytes()].concat();
+        cmd.push_str(" \\\n  -H ");
+        cmd.push_str(&shell_quote(&header));
+    }
+    if !req.body.is_empty() {
+        cmd.push_str(" \\\n  --data-binary ");
+        cmd.push_str(&shell_quote(&req.body.read().unwrap_or_default()));
+    }
+    cmd
+}
+
+// Both heads written out HTTP/1 style for pasting elsewhere, whatever version was actually spoken
+pub fn raw_heads(req: &StoredRequest, resp: Option<&StoredResponse>) -> String {
+    let mut raw = format!("{} {} {:?}\n", req.head.method, req.head.uri, req.head.version);
+    push_headers(&mut raw, &req.head.headers);
+    if let Some(resp) = resp {
+        raw.push_str(&format!("\n{:?} {}\n", resp.head.version, resp.head.status));
+        push_headers(&mut raw, &resp.head.headers);
+    }
+    raw
+}
+
+// The .http format read by the VS Code REST Client and JetBrains' HTTP client, requests separated by ###.
+// Content-Length is left for the client to work out, so the body can be edited in the file. Bodies that
+// aren't text are replaced with a comment, the format has nowhere to put raw bytes.
+pub fn to_http_file<'a>(reqs: impl IntoIterator<Item = &'a StoredRequest>) -> String {
+    let mut file = String::new();
+    for req in reqs {
+        if !file.is_empty() {
+            file.push_str("\n###\n\n");
+        }
+        file.push_str(&format!("{} {}\n", req.head.method, req.head.uri));
+        for (name, value) in req.head.headers.iter().filter(|(name, _)| *name != CONTENT_LENGTH) {
+            file.push_str(&format!("{}: {}\n", name, String::from_utf8_lossy(value.as_bytes())));
+        }
+        if req.body.is_empty() {
+            continue;
+        }
+        file.push('\n');
+        let body = req.body.read();
+        match body.as_deref().map(std::str::from_utf8) {
+            Ok(Ok(text)) => {
+                file.push_str(text);
+                if !text.ends_with('\n') {
+                    file.push('\n');
+                }
+                if req.truncated {
+                    file.push_str(&format!("# Body truncated, only the first {} bytes were kept\n", req.body.len()));
+                }
+            },
+            Ok(Err(_)) => file.push_str(&format!("# Binary body of {} bytes left out\n", req.body.len())),
+            Err(e) => file.push_str(&format!("# Body left out, unable to read it: {}\n", e)),
+        }
+    }
+    file
+}
+
+fn push_headers(raw: &mut String, headers: &HeaderMap<HeaderValue>) {
+    for (name, value) in headers.iter() {
+        raw.push_str(&format!("{}: {}\n", name, String::from_utf8_lossy(value.as_bytes())));
+    }
+}
+
+// Last path segment of the URI, with an extension guessed from the Content-Type if it doesn't already have one
+pub fn suggest_filename(uri: &Uri, headers: &HeaderMap<HeaderValue>) -> String {
+    let name = uri.path().rsplit('/').next()
+        .filter(|name| !name.is_empty())
+        .map(|name| name.replace(|c: char| !(c.is_ascii_alphanumeric() || "._-".contains(c)), "_"))
+        .unwrap_or_else(|| "body".to_string());
+    if name.contains('.') {
+        return name;
+    }
+    let mime = headers.get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let extension = match mime.as_str() {
+        "application/json" => "json",
+        "text/html" => "html",
+        "text/plain" => "txt",
+        "text/css" => "css",
+        "text/javascript" | "application/javascript" => "js",
+        "application/xml" | "text/xml" => "xml",
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "application/pdf" => "pdf",
+        "application/zip" => "zip",
+        _ => "bin",
+    };
+    format!("{}.{}", name, extension)
+}
+
+// Writes `bytes` to `path`, or next to it as name-1.ext, name-2.ext and so on if that's taken. Never replaces a file.
+pub fn save_body(path: &str, bytes: &[u8]) -> std::io::Result<PathBuf> {
+    let path = Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+    let mut candidate = path.to_path_buf();
+    for n in 1.. {
+        match OpenOptions::new().write(true).create_new(true).open(&candidate) {
+            Ok(mut file) => {
+                file.write_all(bytes)?;
+                return Ok(candidate);
+            },
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => candidate.set_file_name(format!("{}-{}{}", stem, n, extension)),
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+// Plain single quotes when the bytes are printable, otherwise fall back to $'...' so quotes,
+// newlines and binary all survive a paste into a POSIX-ish shell
+fn shell_quote(bytes: &[u8]) -> String {
+    if bytes.iter().all(|&byte| byte.is_ascii_graphic() || byte == b' ') {
+        format!("'{}'", String::from_utf8_lossy(bytes).replace('\'', "'\\''"))
+    } else {
+        let mut quoted = String::from("$'");
+        for &byte in bytes {
+            match byte {
+                b'\\' => quoted.push_str("\\\\"),
+                b'\'' => quoted.push_str("\\'"),
+                b'\n' => quoted.push_str("\\n"),
+                b'\r' => quoted.push_str("\\r"),
+                b'\t' => quoted.push_str("\\t"),
+                byte if byte.is_ascii_graphic() || byte == b' ' => quoted.push(byte as char),
+                byte => quoted.push_str(&format!("\\x{:02x}", byte)),
+            }
+        }
+        quoted.push('\'');
+        quoted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content_type(mime: &'static str) -> HeaderMap<HeaderValue> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(mime));
+        headers
+    }
+
+    #[test]
+    fn suggestions_follow_each_content_type() {
+        let uri: Uri = "https://example.com/api/avatar".parse().unwrap();
+        assert_eq!(suggest_filename(&uri, &content_type("application/json; charset=utf-8")), "avatar.json");
+        assert_eq!(suggest_filename(&uri, &content_type("image/png")), "avatar.png");
+        assert_eq!(suggest_filename(&uri, &HeaderMap::new()), "avatar.bin");
+        let uri: Uri = "https://example.com/files/report.pdf?download=1".parse().unwrap();
+        assert_eq!(suggest_filename(&uri, &content_type("application/octet-stream")), "report.pdf");
+        let uri: Uri = "https://example.com/".parse().unwrap();
+        assert_eq!(suggest_filename(&uri, &content_type("text/html")), "body.html");
+    }
+
+    #[test]
+    fn saving_never_overwrites() {
+        let dir = std::env::temp_dir().join(format!("stain-export-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("body.json");
+        let path = path.to_str().unwrap();
+        assert_eq!(save_body(path, b"first").unwrap(), dir.join("body.json"));
+        assert_eq!(save_body(path, b"second").unwrap(), dir.join("body-1.json"));
+        assert_eq!(save_body(path, b"third").unwrap(), dir.join("body-2.json"));
+        assert_eq!(std::fs::read(dir.join("body.json")).unwrap(), b"first");
+        assert_eq!(std::fs::read(dir.join("body-2.json")).unwrap(), b"third");
+        let bare = dir.join("body");
+        save_body(bare.to_str().unwrap(), b"").unwrap();
+        assert_eq!(save_body(bare.to_str().unwrap(), b"").unwrap(), dir.join("body-1"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
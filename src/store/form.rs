@@ -0,0 +1,284 @@
+use eframe::egui::{Ui, Grid, TextEdit, Color32};
+use hyper::http::{HeaderMap, HeaderValue, header::CONTENT_TYPE};
+
+use super::intercept::draw_headers;
+use super::view::{percent_decode, BodyKind};
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            b' ' => "+".to_string(),
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn parse_urlencoded(text: &str) -> Vec<(String, String)> {
+    text.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (percent_decode(key), percent_decode(value)),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+pub struct Part {
+    headers: Vec<(String, String)>,
+    body: Result<String, Vec<u8>>, // Text parts can be edited, anything else goes out as it came in
+}
+
+// RFC 7578. The preamble and epilogue are dropped, nothing reads them.
+fn parse_multipart(body: &[u8], boundary: &str) -> Option<Vec<Part>> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut rest = &body[find(body, &delimiter)? + delimiter.len()..];
+    let next = format!("\r\n--{}", boundary).into_bytes();
+    let mut parts = Vec::new();
+    loop {
+        if rest.starts_with(b"--") {
+            return Some(parts);
+        }
+        rest = rest.strip_prefix(b"\r\n")?;
+        let end = find(rest, &next)?;
+        let part = &rest[..end];
+        rest = &rest[end + next.len()..];
+        let (head, body) = match find(part, b"\r\n\r\n") {
+            Some(split) => (&part[..split], &part[split + 4..]),
+            None if part.starts_with(b"\r\n") => (&part[..0], &part[2..]),
+            None => return None,
+        };
+        let headers = std::str::from_utf8(head).ok()?
+            .split("\r\n")
+            .filter(|line| !line.is_empty())
+            .map(|line| line.split_once(':').map(|(name, value)| (name.trim().to_string(), value.trim().to_string())))
+            .collect::<Option<Vec<_>>>()?;
+        let body = String::from_utf8(body.to_vec()).map_err(|e| e.into_bytes());
+        parts.push(Part { headers, body });
+    }
+}
+
+fn boundary(headers: &HeaderMap<HeaderValue>) -> Option<String> {
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+fn check_json(text: &str) -> Result<(), String> {
+    serde_json::from_str::<serde::de::IgnoredAny>(text)
+        .map(|_| ())
+        .map_err(|e| format!("Body isn't valid JSON: {}", e))
+}
+
+// Re-indents valid JSON, or takes out all the whitespace between tokens when `pretty` is off. Keys stay in the order
+// they came in and numbers keep their exact text, which going through serde_json::Value wouldn't guarantee.
+fn reformat_json(text: &str, pretty: bool) -> String {
+    let mut out = String::with_capacity(text.len());
+    let newline = |out: &mut String, depth: usize| {
+        if pretty {
+            out.push('\n');
+            out.push_str(&"  ".repeat(depth));
+        }
+    };
+    let (mut depth, mut in_string, mut escaped) = (0, false, false);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {},
+            }
+            continue;
+        }
+        match c {
+            '"' => {
+                in_string = true;
+                out.push(c);
+            },
+            '{' | '[' => {
+                out.push(c);
+                while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+                    chars.next();
+                }
+                // Empty ones stay on the one line
+                if let Some(close) = chars.next_if(|c| matches!(c, '}' | ']')) {
+                    out.push(close);
+                } else {
+                    depth += 1;
+                    newline(&mut out, depth);
+                }
+            },
+            '}' | ']' => {
+                depth -= 1;
+                newline(&mut out, depth);
+                out.push(c);
+            },
+            ',' => {
+                out.push(c);
+                newline(&mut out, depth);
+            },
+            ':' => out.push_str(if pretty { ": " } else { ":" }),
+            c if c.is_whitespace() => {},
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// Editor for a body read ahead of its intercepted request, picked by Content-Type
+pub enum BodyEditor {
+    Form(Vec<(String, String)>),
+    Multipart { boundary: String, parts: Vec<Part> },
+    Json { text: String, pretty: bool, original: String }, // Pretty printed it's checked and minified on the way out, otherwise it goes as typed
+    Raw(String),
+    Binary(Vec<u8>),
+}
+
+impl BodyEditor {
+    pub fn new(headers: &HeaderMap<HeaderValue>, body: &[u8]) -> Self {
+        let content_type = headers.get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        if let Some(boundary) = boundary(headers) {
+            if let Some(parts) = parse_multipart(body, &boundary) {
+                return Self::Multipart { boundary, parts };
+            }
+        }
+        match String::from_utf8(body.to_vec()) {
+            Ok(text) if BodyKind::detect(headers, body) == BodyKind::Json && check_json(&text).is_ok() => {
+                Self::Json { text: reformat_json(&text, true), pretty: true, original: text }
+            },
+            Ok(text) if content_type == "application/x-www-form-urlencoded" => Self::Form(parse_urlencoded(&text)),
+            Ok(text) => Self::Raw(text),
+            Err(e) => Self::Binary(e.into_bytes()),
+        }
+    }
+
+    // Fails on a pretty printed JSON body that no longer parses, rather than sending it on broken
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        Ok(match self {
+            Self::Form(fields) => fields.iter()
+                .filter(|(key, _)| !key.is_empty())
+                .map(|(key, value)| format!("{}={}", percent_encode(key), percent_encode(value)))
+                .collect::<Vec<_>>()
+                .join("&")
+                .into_bytes(),
+            Self::Multipart { boundary, parts } => {
+                let mut out = Vec::new();
+                for part in parts {
+                    out.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+                    for (name, value) in part.headers.iter().filter(|(name, _)| !name.trim().is_empty()) {
+                        out.extend_from_slice(format!("{}: {}\r\n", name.trim(), value).as_bytes());
+                    }
+                    out.extend_from_slice(b"\r\n");
+                    match &part.body {
+                        Ok(text) => out.extend_from_slice(text.as_bytes()),
+                        Err(bytes) => out.extend_from_slice(bytes),
+                    }
+                    out.extend_from_slice(b"\r\n");
+                }
+                out.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+                out
+            },
+            Self::Json { text, pretty: true, original } => {
+                check_json(text)?;
+                let minified = reformat_json(text, false);
+                // Untouched, the original goes out byte for byte
+                if minified == reformat_json(original, false) {
+                    original.clone().into_bytes()
+                } else {
+                    minified.into_bytes()
+                }
+            },
+            Self::Json { text, pretty: false, .. } | Self::Raw(text) => text.clone().into_bytes(),
+            Self::Binary(bytes) => bytes.clone(),
+        })
+    }
+
+    pub fn draw(&mut self, ui: &mut Ui, id: u32) {
+        match self {
+            Self::Form(fields) => {
+                ui.label("Form fields");
+                let mut removed = None;
+                Grid::new(("form fields", id)).num_columns(3).show(ui, |ui| {
+                    for (idx, (key, value)) in fields.iter_mut().enumerate() {
+                        ui.text_edit_singleline(key);
+                        ui.add(TextEdit::singleline(value).desired_width(f32::INFINITY));
+                        if ui.small_button("x").clicked() {
+                            removed = Some(idx);
+                        }
+                        ui.end_row();
+                    }
+                });
+                if let Some(idx) = removed {
+                    fields.remove(idx);
+                }
+                if ui.small_button("Add field").clicked() {
+                    fields.push((String::new(), String::new()));
+                }
+            },
+            Self::Multipart { parts, .. } => {
+                let mut removed = None;
+                for (idx, part) in parts.iter_mut().enumerate() {
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Part {}", idx + 1));
+                        if ui.small_button("Remove part").clicked() {
+                            removed = Some(idx);
+                        }
+                    });
+                    draw_headers(ui, ("multipart headers", id * 1000 + idx as u32), &mut part.headers);
+                    match &mut part.body {
+                        Ok(text) => { ui.add(TextEdit::multiline(text).code_editor().desired_width(f32::INFINITY)); },
+                        Err(bytes) => { ui.label(format!("{} bytes of binary, sent as is", bytes.len())); },
+                    }
+                }
+                if let Some(idx) = removed {
+                    parts.remove(idx);
+                }
+                if ui.small_button("Add part").clicked() {
+                    parts.push(Part {
+                        headers: vec![("Content-Disposition".to_string(), "form-data; name=\"\"".to_string())],
+                        body: Ok(String::new()),
+                    });
+                }
+            },
+            Self::Json { text, pretty, original } => {
+                if ui.checkbox(pretty, "Pretty print JSON").changed() {
+                    match (*pretty, check_json(text)) {
+                        (true, Ok(())) => *text = reformat_json(text, true),
+                        // Can't indent what doesn't parse, the error below says why
+                        (true, Err(_)) => *pretty = false,
+                        (false, Ok(())) if reformat_json(text, false) == reformat_json(original, false) => *text = original.clone(),
+                        (false, Ok(())) => *text = reformat_json(text, false),
+                        (false, Err(_)) => {},
+                    }
+                }
+                ui.add(TextEdit::multiline(text).code_editor().desired_width(f32::INFINITY));
+                if let Err(e) = check_json(text) {
+                    ui.colored_label(if *pretty { Color32::RED } else { Color32::YELLOW }, e);
+                }
+            },
+            Self::Raw(text) => {
+                ui.label("Body");
+                ui.add(TextEdit::multiline(text).code_editor().desired_width(f32::INFINITY));
+            },
+            Self::Binary(bytes) => { ui.label(format!("{} bytes of binary body, sent as is", bytes.len())); },
+        }
+    }
+}
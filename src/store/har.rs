@@ -0,0 +1,169 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::http::{HeaderMap, HeaderValue, header::CONTENT_TYPE};
+use serde_json::{json, Value};
+
+use super::{StoredPair, StoredResult};
+
+// Build a HAR 1.2 log out of the captured pairs. Incomplete captures are still emitted with whatever we have.
+pub fn to_har(pairs: &[StoredPair]) -> Value {
+    let now = format_time(SystemTime::now());
+    let entries: Vec<Value> = pairs.iter()
+        .filter(|pair| pair.request.is_some())
+        .map(|pair| entry(pair, &now))
+        .collect();
+    json!({
+        "log": {
+            "version": "1.2",
+            "creator": { "name": "stain", "version": env!("CARGO_PKG_VERSION") },
+            "entries": entries,
+        }
+    })
+}
+
+fn entry(pair: &StoredPair, started: &str) -> Value {
+    let req = pair.request.as_ref().expect("Only called for pairs with a request");
+    let mut problems = Vec::new();
+    match &req.status {
+        StoredResult::Pending => problems.push("request body incomplete".to_string()),
+        StoredResult::Error(e) => problems.push(format!("request failed: {}", e)),
+        StoredResult::Ok => {},
+    }
+    if req.truncated {
+        problems.push("request body truncated".to_string());
+    }
+    let mut request = json!({
+        "method": req.head.method.as_str(),
+        "url": req.head.uri.to_string(),
+        "httpVersion": format!("{:?}", req.head.version),
+        "cookies": [],
+        "headers": headers(&req.head.headers),
+        "queryString": query_string(req.head.uri.query()),
+        "headersSize": -1,
+        "bodySize": req.body.len(),
+    });
+    if !req.body.is_empty() {
+        let (text, encoding) = encode_body(&req.body.read().unwrap_or_default());
+        request["postData"] = json!({ "mimeType": mime_type(&req.head.headers), "text": text });
+        if let Some(encoding) = encoding {
+            request["postData"]["encoding"] = json!(encoding);
+        }
+    }
+    let response = match &pair.response {
+        Some(resp) => {
+            match &resp.status {
+                StoredResult::Pending => problems.push("response body incomplete".to_string()),
+                StoredResult::Error(e) => problems.push(format!("response failed: {}", e)),
+                StoredResult::Ok => {},
+            }
+            if resp.truncated {
+                problems.push("response body truncated".to_string());
+            }
+            let (text, encoding) = encode_body(&resp.body.read().unwrap_or_default());
+            let mut content = json!({
+                "size": resp.body.len(),
+                "mimeType": mime_type(&resp.head.headers),
+                "text": text,
+            });
+            if let Some(encoding) = encoding {
+                content["encoding"] = json!(encoding);
+            }
+            json!({
+                "status": resp.head.status.as_u16(),
+                "statusText": resp.head.status.canonical_reason().unwrap_or(""),
+                "httpVersion": format!("{:?}", resp.head.version),
+                "cookies": [],
+                "headers": headers(&resp.head.headers),
+                "content": content,
+                "redirectURL": resp.head.headers.get("location").and_then(|value| value.to_str().ok()).unwrap_or(""),
+                "headersSize": -1,
+                "bodySize": resp.body.len(),
+            })
+        },
+        None => {
+            problems.push("no response captured".to_string());
+            json!({
+                "status": 0,
+                "statusText": "",
+                "httpVersion": "",
+                "cookies": [],
+                "headers": [],
+                "content": { "size": 0, "mimeType": "" },
+                "redirectURL": "",
+                "headersSize": -1,
+                "bodySize": -1,
+            })
+        }
+    };
+    // HAR wants -1 for timings that don't apply, and we only know wait and receive
+    let wait = pair.time_to_first_byte().map(|ttfb| ttfb.as_secs_f64() * 1000.0);
+    let total = pair.elapsed().map(|elapsed| elapsed.as_secs_f64() * 1000.0);
+    let receive = match (wait, total) {
+        (Some(wait), Some(total)) => total - wait,
+        _ => -1.0,
+    };
+    let mut entry = json!({
+        "startedDateTime": started,
+        "time": total.or(wait).unwrap_or(0.0),
+        "request": request,
+        "response": response,
+        "cache": {},
+        "timings": { "send": 0, "wait": wait.unwrap_or(-1.0), "receive": receive },
+    });
+    if !problems.is_empty() {
+        entry["comment"] = json!(format!("Incomplete capture: {}", problems.join(", ")));
+    }
+    entry
+}
+
+fn headers(headers: &HeaderMap<HeaderValue>) -> Vec<Value> {
+    headers.iter()
+        .map(|(name, value)| json!({ "name": name.as_str(), "value": String::from_utf8_lossy(value.as_bytes()) }))
+        .collect()
+}
+
+fn query_string(query: Option<&str>) -> Vec<Value> {
+    query.unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            json!({ "name": name, "value": value })
+        })
+        .collect()
+}
+
+fn mime_type(headers: &HeaderMap<HeaderValue>) -> String {
+    headers.get(CONTENT_TYPE)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        .unwrap_or_default()
+}
+
+// HAR text has to be a string, so anything that isn't UTF-8 goes in as base64
+fn encode_body(body: &[u8]) -> (String, Option<&'static str>) {
+    match std::str::from_utf8(body) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => (base64::encode(body), Some("base64")),
+    }
+}
+
+// RFC 3339 in UTC, which is what HAR's ISO 8601 dates expect
+fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60, since_epoch.subsec_millis()
+    )
+}
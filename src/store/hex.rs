@@ -0,0 +1,118 @@
+use std::ops::RangeInclusive;
+
+use eframe::egui::{Ui, Label, RichText, Sense, ScrollArea, TextStyle, Color32};
+
+use super::view::PreviewKey;
+
+const ROW_BYTES: usize = 16;
+
+const HEIGHT: f32 = 400.0;
+
+fn printable(byte: u8) -> char {
+    if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }
+}
+
+// Offset, hex and ASCII columns with the byte under the pointer lit up in both. Only the rows on screen are laid
+// out, so there's no need to cut the body short like the text views do.
+pub struct HexView {
+    hovered: Option<(PreviewKey, usize)>,
+    selection: Option<(PreviewKey, usize, usize)>, // Where the selection was started and where it was extended to
+}
+
+impl HexView {
+    pub fn new() -> Self {
+        Self { hovered: None, selection: None }
+    }
+
+    pub fn forget(&mut self) {
+        self.hovered = None;
+        self.selection = None;
+    }
+
+    fn selected(&self, key: PreviewKey) -> Option<RangeInclusive<usize>> {
+        self.selection
+            .filter(|(selected, _, _)| *selected == key)
+            .map(|(_, anchor, end)| anchor.min(end)..=anchor.max(end))
+    }
+
+    pub fn show(&mut self, ui: &mut Ui, key: PreviewKey, body: &[u8]) {
+        let selected = self.selected(key).filter(|range| *range.end() < body.len());
+        ui.horizontal(|ui| {
+            match &selected {
+                Some(range) => {
+                    let bytes = &body[range.clone()];
+                    ui.label(format!("Selected {:#x}..={:#x}, {} bytes", range.start(), range.end(), bytes.len()));
+                    if ui.button("Copy hex").clicked() {
+                        ui.output().copied_text = bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ");
+                    }
+                    if ui.button("Copy ASCII").clicked() {
+                        ui.output().copied_text = bytes.iter().map(|&byte| printable(byte)).collect();
+                    }
+                    if ui.button("Clear").clicked() {
+                        self.selection = None;
+                    }
+                },
+                None => { ui.label("Click a byte to select it, shift click to extend"); },
+            }
+        });
+        let hovered = self.hovered.filter(|(hovered, _)| *hovered == key).map(|(_, offset)| offset);
+        let cursor = ui.visuals().widgets.hovered.bg_fill;
+        let highlight = ui.visuals().selection.bg_fill;
+        let background = |offset: usize| {
+            if hovered == Some(offset) {
+                cursor
+            } else if selected.as_ref().map(|range| range.contains(&offset)).unwrap_or(false) {
+                highlight
+            } else {
+                Color32::TRANSPARENT
+            }
+        };
+        let mut now_hovered = None;
+        let mut clicked = None;
+        let row_height = ui.fonts()[TextStyle::Monospace].row_height();
+        let num_rows = body.len().div_ceil(ROW_BYTES);
+        ScrollArea::vertical().id_source(("hex", key)).max_height(HEIGHT).show_rows(ui, row_height, num_rows, |ui, rows| {
+            for row in rows {
+                let start = row * ROW_BYTES;
+                let chunk = &body[start..body.len().min(start + ROW_BYTES)];
+                ui.horizontal(|ui| {
+                    ui.spacing_mut().item_spacing.x = 0.0;
+                    ui.label(RichText::new(format!("{:08x}  ", start)).monospace().weak());
+                    let mut cell = |ui: &mut Ui, offset: usize, text: String| {
+                        let response = ui.add(Label::new(RichText::new(text).monospace().background_color(background(offset))).sense(Sense::click()));
+                        if response.hovered() {
+                            now_hovered = Some(offset);
+                        }
+                        if response.clicked() {
+                            clicked = Some((offset, ui.input().modifiers.shift));
+                        }
+                    };
+                    for col in 0..ROW_BYTES {
+                        match chunk.get(col) {
+                            Some(byte) => cell(ui, start + col, format!("{:02x}", byte)),
+                            None => { ui.label(RichText::new("  ").monospace()); },
+                        }
+                        ui.label(RichText::new(if col == ROW_BYTES / 2 - 1 { "  " } else { " " }).monospace());
+                    }
+                    ui.label(RichText::new(" |").monospace());
+                    for (col, byte) in chunk.iter().enumerate() {
+                        cell(ui, start + col, printable(*byte).to_string());
+                    }
+                    ui.label(RichText::new("|").monospace());
+                });
+            }
+        });
+        // Request and response bodies share this, only let go of the pointer if it was over this one
+        match now_hovered {
+            Some(offset) => self.hovered = Some((key, offset)),
+            None if hovered.is_some() => self.hovered = None,
+            None => {},
+        }
+        if let Some((offset, extend)) = clicked {
+            self.selection = match self.selection {
+                Some((selected, anchor, _)) if extend && selected == key => Some((key, anchor, offset)),
+                _ => Some((key, offset, offset)),
+            };
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use eframe::egui::{Ui, Grid, TextEdit, Color32};
+use hyper::http::{HeaderMap, HeaderValue, header::{HeaderName, CONTENT_LENGTH}};
+use hyper::{Method, StatusCode, Uri};
+use hyper::body::Bytes;
+
+use crate::proxy::{OneshotSender, ProxyState};
+use crate::proxy::request::RequestHead;
+use crate::proxy::response::ResponseHead;
+use crate::Waitpoint;
+use super::form::BodyEditor;
+
+// Holds a proxy callback until the GUI decides what to send back. A task waits on the waitpoint and
+// fires the callback, so the GUI side never has to be async.
+struct Breakpoint {
+    resumed: Waitpoint,
+    decision: Arc<Mutex<Option<ProxyState>>>, // None lets the proxy carry on with the original
+}
+
+impl Breakpoint {
+    fn new(callback: OneshotSender<ProxyState>) -> Self {
+        let resumed = Waitpoint::new();
+        let decision = Arc::new(Mutex::new(None));
+        tokio::spawn({
+            let resumed = resumed.clone();
+            let decision = decision.clone();
+            async move {
+                resumed.await;
+                let state = decision.lock().unwrap().take();
+                if let Some(state) = state {
+                    // The client may have hung up while we were waiting, nothing left to do in that case
+                    let _ = callback.send(state);
+                }
+            }
+        });
+        Self { resumed, decision }
+    }
+
+    fn release(&self, state: ProxyState) {
+        *self.decision.lock().unwrap() = Some(state);
+        self.resumed.complete();
+    }
+}
+
+// Never leave the proxy waiting on something that's gone from the GUI
+impl Drop for Breakpoint {
+    fn drop(&mut self) {
+        self.resumed.complete();
+    }
+}
+
+pub enum ParkedAction {
+    None,
+    Resume,
+    ResumeUnmodified,
+}
+
+pub fn editable_headers(headers: &HeaderMap<HeaderValue>) -> Vec<(String, String)> {
+    headers.iter()
+        .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+        .collect()
+}
+
+pub fn parse_headers(headers: &[(String, String)]) -> Result<HeaderMap<HeaderValue>, String> {
+    let mut parsed = HeaderMap::new();
+    for (name, value) in headers.iter().filter(|(name, _)| !name.trim().is_empty()) {
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| format!("Invalid header name {:?}", name))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| format!("Invalid value for header {}", name))?;
+        parsed.append(name, value);
+    }
+    Ok(parsed)
+}
+
+pub fn draw_headers(ui: &mut Ui, id: (&str, u32), headers: &mut Vec<(String, String)>) {
+    ui.label("Headers");
+    let mut removed = None;
+    Grid::new(id).num_columns(3).show(ui, |ui| {
+        for (idx, (name, value)) in headers.iter_mut().enumerate() {
+            ui.text_edit_singleline(name);
+            ui.add(TextEdit::singleline(value).desired_width(f32::INFINITY));
+            if ui.small_button("x").clicked() {
+                removed = Some(idx);
+            }
+            ui.end_row();
+        }
+    });
+    if let Some(idx) = removed {
+        headers.remove(idx);
+    }
+    if ui.small_button("Add header").clicked() {
+        headers.push((String::new(), String::new()));
+    }
+}
+
+fn draw_actions(ui: &mut Ui, error: &Option<String>) -> ParkedAction {
+    let mut action = ParkedAction::None;
+    if let Some(error) = error {
+        ui.colored_label(Color32::RED, error);
+    }
+    ui.horizontal(|ui| {
+        if ui.button("Resume").clicked() {
+            action = ParkedAction::Resume;
+        }
+        if ui.button("Resume unmodified").clicked() {
+            action = ParkedAction::ResumeUnmodified;
+        }
+    });
+    action
+}
+
+// A request head held back from the proxy until the user resumes it
+pub struct ParkedRequest {
+    pub id: u32,
+    original: RequestHead,
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+    body: Option<(Bytes, BodyEditor)>, // Only there if the proxy read the body ahead of the head
+    error: Option<String>,
+    breakpoint: Breakpoint,
+}
+
+impl ParkedRequest {
+    pub fn new(id: u32, head: RequestHead, body: Option<Bytes>, callback: OneshotSender<ProxyState>) -> Self {
+        Self {
+            id,
+            method: head.method.to_string(),
+            uri: head.uri.to_string(),
+            headers: editable_headers(&head.headers),
+            body: body.map(|body| {
+                let editor = BodyEditor::new(&head.headers, &body);
+                (body, editor)
+            }),
+            error: None,
+            original: head,
+            breakpoint: Breakpoint::new(callback),
+        }
+    }
+
+    fn edited(&self) -> Result<(RequestHead, Option<Bytes>), String> {
+        let method = Method::from_bytes(self.method.trim().as_bytes())
+            .map_err(|_| format!("Invalid method {:?}", self.method))?;
+        let uri: Uri = self.uri.trim().parse()
+            .map_err(|e| format!("Invalid URI: {}", e))?;
+        let mut headers = parse_headers(&self.headers)?;
+        let body = match &self.body {
+            Some((original, editor)) => Some(Bytes::from(editor.encode()?)).filter(|body| original != body),
+            None => None,
+        };
+        if let Some(body) = &body {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        }
+        Ok((RequestHead { method, uri, version: self.original.version, headers }, body))
+    }
+
+    // Hand the head back to the proxy. An edited body is registered first, so it's there to swap in when the
+    // original comes through as a chunk. Returns false and stays parked if the edits don't parse.
+    pub fn resume(&mut self, bodies: &Mutex<HashMap<u32, Bytes>>) -> bool {
+        match self.edited() {
+            Ok((head, body)) => {
+                if let Some(body) = body {
+                    bodies.lock().unwrap().insert(self.id, body);
+                }
+                self.breakpoint.release(ProxyState::RequestHead(head));
+                true
+            },
+            Err(e) => {
+                self.error = Some(e);
+                false
+            }
+        }
+    }
+
+    pub fn resume_unmodified(self) {
+        self.breakpoint.release(ProxyState::RequestHead(self.original.clone()));
+    }
+
+    pub fn draw(&mut self, ui: &mut Ui) -> ParkedAction {
+        Grid::new(("parked", self.id)).num_columns(2).show(ui, |ui| {
+            ui.label("Method");
+            ui.text_edit_singleline(&mut self.method);
+            ui.end_row();
+            ui.label("URI");
+            ui.add(TextEdit::singleline(&mut self.uri).desired_width(f32::INFINITY));
+            ui.end_row();
+        });
+        draw_headers(ui, ("parked headers", self.id), &mut self.headers);
+        if let Some((_, editor)) = &mut self.body {
+            editor.draw(ui, self.id);
+        }
+        draw_actions(ui, &self.error)
+    }
+}
+
+// Find/replace applied to each chunk of an edited response body as it streams through
+pub type BodyRewrite = (Vec<u8>, Vec<u8>);
+
+// A response head held back from the client until the user resumes it
+pub struct ParkedResponse {
+    pub id: u32,
+    original: ResponseHead,
+    status: String,
+    headers: Vec<(String, String)>,
+    find: String,
+    replace: String,
+    error: Option<String>,
+    breakpoint: Breakpoint,
+}
+
+impl ParkedResponse {
+    pub fn new(id: u32, head: ResponseHead, callback: OneshotSender<ProxyState>) -> Self {
+        Self {
+            id,
+            status: head.status.as_u16().to_string(),
+            headers: editable_headers(&head.headers),
+            find: String::new(),
+            replace: String::new(),
+            error: None,
+            original: head,
+            breakpoint: Breakpoint::new(callback),
+        }
+    }
+
+    fn edited(&self) -> Result<(ResponseHead, Option<BodyRewrite>), String> {
+        let status = self.status.trim().parse::<u16>().ok()
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .ok_or_else(|| format!("Invalid status {:?}", self.status))?;
+        let mut headers = parse_headers(&self.headers)?;
+        let rewrite = Some((self.find.clone().into_bytes(), self.replace.clone().into_bytes()))
+            .filter(|(find, _)| !find.is_empty());
+        // We can't know the new length up front, so let hyper fall back to chunked encoding
+        if matches!(&rewrite, Some((find, replace)) if find.len() != replace.len()) {
+            headers.remove(CONTENT_LENGTH);
+        }
+        Ok((ResponseHead { status, version: self.original.version, headers }, rewrite))
+    }
+
+    // Hand the head back to the proxy. The body rewrite is registered first so no chunk can slip past it.
+    // Returns false and stays parked if the edits don't parse.
+    pub fn resume(&mut self, rewrites: &Mutex<HashMap<u32, BodyRewrite>>) -> bool {
+        match self.edited() {
+            Ok((head, rewrite)) => {
+                if let Some(rewrite) = rewrite {
+                    rewrites.lock().unwrap().insert(self.id, rewrite);
+                }
+                self.breakpoint.release(ProxyState::ResponseHead(head));
+                true
+            },
+            Err(e) => {
+                self.error = Some(e);
+                false
+            }
+        }
+    }
+
+    pub fn resume_unmodified(self) {
+        self.breakpoint.release(ProxyState::ResponseHead(self.original.clone()));
+    }
+
+    pub fn draw(&mut self, ui: &mut Ui) -> ParkedAction {
+        Grid::new(("parked response", self.id)).num_columns(2).show(ui, |ui| {
+            ui.label("Status");
+            ui.text_edit_singleline(&mut self.status);
+            ui.end_row();
+        });
+        draw_headers(ui, ("parked response headers", self.id), &mut self.headers);
+        Grid::new(("parked response body", self.id)).num_columns(2).show(ui, |ui| {
+            ui.label("Replace in body");
+            ui.add(TextEdit::singleline(&mut self.find).desired_width(f32::INFINITY));
+            ui.end_row();
+            ui.label("With");
+            ui.add(TextEdit::singleline(&mut self.replace).desired_width(f32::INFINITY));
+            ui.end_row();
+        });
+        draw_actions(ui, &self.error)
+    }
+}
+
+// Matches are only found within a single chunk, one split across two chunks is left alone
+pub fn rewrite_chunk(chunk: &[u8], (find, replace): &BodyRewrite) -> Vec<u8> {
+    let mut out = Vec::with_capacity(chunk.len());
+    let mut rest = chunk;
+    while let Some(pos) = rest.windows(find.len()).position(|window| window == &find[..]) {
+        out.extend_from_slice(&rest[..pos]);
+        out.extend_from_slice(replace);
+        rest = &rest[pos + find.len()..];
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+fn hex_text(bytes: &[u8]) -> String {
+    bytes.chunks(16)
+        .map(|row| row.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("Odd number of hex digits".to_string());
+    }
+    digits.chunks(2)
+        .map(|pair| {
+            let pair: String = pair.iter().collect();
+            u8::from_str_radix(&pair, 16).map_err(|_| format!("Invalid hex byte {:?}", pair))
+        })
+        .collect()
+}
+
+// A chunk read off one side of an upgraded connection, held back from the other side until the user resumes it.
+// Chunks are whatever a single read returned, not protocol messages, so edits go out byte for byte.
+pub struct ParkedFrame {
+    pub id: u32,
+    chunk_id: u32,
+    outbound: bool, // Client to origin
+    original: Bytes,
+    text: String,
+    hex: bool, // Edited as hex, for chunks that aren't UTF-8
+    error: Option<String>,
+    breakpoint: Breakpoint,
+}
+
+impl ParkedFrame {
+    pub fn new(id: u32, chunk_id: u32, outbound: bool, chunk: Bytes, callback: OneshotSender<ProxyState>) -> Self {
+        let (text, hex) = match std::str::from_utf8(&chunk) {
+            Ok(text) => (text.to_string(), false),
+            Err(_) => (hex_text(&chunk), true),
+        };
+        Self { id, chunk_id, outbound, original: chunk, text, hex, error: None, breakpoint: Breakpoint::new(callback) }
+    }
+
+    fn state(&self, chunk: Bytes) -> ProxyState {
+        if self.outbound {
+            ProxyState::UpgradeTx { id: self.chunk_id, chunk }
+        } else {
+            ProxyState::UpgradeRx { id: self.chunk_id, chunk }
+        }
+    }
+
+    // Hand the chunk back to the proxy to write to the other side. Returns false and stays parked if the hex doesn't parse.
+    pub fn resume(&mut self) -> bool {
+        let edited = if self.hex { parse_hex(&self.text) } else { Ok(self.text.clone().into_bytes()) };
+        match edited {
+            Ok(chunk) => {
+                self.breakpoint.release(self.state(Bytes::from(chunk)));
+                true
+            },
+            Err(e) => {
+                self.error = Some(e);
+                false
+            }
+        }
+    }
+
+    pub fn resume_unmodified(self) {
+        self.breakpoint.release(self.state(self.original.clone()));
+    }
+
+    pub fn title(&self) -> String {
+        let direction = if self.outbound { "client -> origin" } else { "origin -> client" };
+        format!("chunk #{} of {}, {}, {} bytes", self.chunk_id, self.id, direction, self.original.len())
+    }
+
+    pub fn draw(&mut self, ui: &mut Ui) -> ParkedAction {
+        ui.label(if self.hex { "Edit as hex, whitespace is ignored" } else { "Edit as text" });
+        ui.label("Sent as is on the upgraded connection. For WebSocket that means the frame header's length has to match what you send.");
+        ui.add(TextEdit::multiline(&mut self.text).code_editor().desired_width(f32::INFINITY));
+        draw_actions(ui, &self.error)
+    }
+}
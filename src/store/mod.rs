@@ -1,51 +1,223 @@
-use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::ops::Range;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicUsize};
+use std::time::{Duration, Instant};
 
-use eframe::egui::{Ui, Label, RichText, Sense};
+use eframe::egui::{Ui, Label, RichText, Sense, ScrollArea, CollapsingHeader, Color32, Visuals, Grid, ProgressBar, TextStyle};
+use hyper::Uri;
+use hyper::body::Bytes;
+use hyper::http::{HeaderMap, HeaderValue, header::{CONTENT_LENGTH, HOST, UPGRADE}};
+use hyper::http::uri::{Authority, Scheme};
 use tokio::sync::mpsc::Receiver;
 use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
 
 use super::proxy::request::RequestHead;
 use super::proxy::response::ResponseHead;
-use super::proxy::ProxyEvent;
+use super::proxy::{ProxyEvent, ProxyError, ProxyServer, ProxyState, TlsInfo};
+use super::proxy::metrics::Metrics;
+use super::proxy::ProxyConfig;
 
 mod storable;
+mod view;
+mod intercept;
+mod rewrite;
+mod spool;
+mod har;
+mod export;
+mod diff;
+mod resend;
+mod hex;
+mod websocket;
+mod form;
+mod condition;
+mod session;
+mod cookies;
+
+use view::BodyView;
+use diff::Comparison;
+use intercept::{ParkedRequest, ParkedResponse, ParkedFrame, ParkedAction, BodyRewrite};
+use super::proxy::rewrite::RewriteRule;
+use condition::InterceptCondition;
+use resend::{ResendEditor, ResendAction};
+use spool::BodyBuffer;
+use websocket::WebSocket;
 
 #[derive(PartialEq, Clone)]
 struct StoredRequest {
     head: RequestHead,
-    body: Vec<u8>,
+    body: BodyBuffer,
+    truncated: bool, // Body went past the store's cap and only the start of it was kept
+    started: Instant,
     last_chunk_id: u32,
-    status: StoredResult
+    status: StoredResult,
+    body_hash: Option<u64>, // Set once the body is complete, used to spot repeated requests
+    uploaded: usize, // Body bytes that have gone through so far, kept or not
 }
 
 #[derive(PartialEq, Clone)]
 struct StoredResponse {
     head: ResponseHead,
-    body: Vec<u8>,
+    body: BodyBuffer,
+    truncated: bool, // Body went past the store's cap and only the start of it was kept
+    first_byte: Instant, // When the head arrived
+    finished: Option<Instant>,
     last_chunk_id: u32,
     status: StoredResult
 }
 
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum UpgradeDirection {
+    Tx, // Client -> origin
+    Rx, // Origin -> client
+}
+
+#[derive(PartialEq, Clone)]
+struct UpgradeFrame {
+    direction: UpgradeDirection,
+    chunk_id: u32,
+    bytes: Vec<u8>,
+}
+
 #[derive(PartialEq, Clone)]
 struct StoredPair {
+    id: u32, // Assigned by the proxy, the one its messages and the log refer to. 0 for slots nothing has landed in.
     request: Option<StoredRequest>,
     response: Option<StoredResponse>,
+    upgrade: Vec<UpgradeFrame>, // Sorted by chunk id, tx and rx share the same counter
+    websocket: Option<Box<WebSocket>>, // Messages parsed out of the upgrade, when it's a WebSocket. Anything that won't parse lands in `upgrade`.
+    tunnel: Option<StoredTunnel>, // Set instead of request/response for connections we relayed without intercepting
+    tag: Option<String>, // Set by the user, never sent anywhere
+    note: String,
+    mirror_of: Option<u32>, // Id of the request this is a copy of, when it went to the mirror upstream
+    redirect_from: Option<u32>, // Id of the request whose redirect the proxy followed to send this one
+    tls: Option<TlsInfo>, // What the client's connection negotiated, when we terminated its TLS
+}
+
+#[derive(PartialEq, Clone)]
+struct StoredTunnel {
+    host: String,
+    started: Instant,
+    closed: Option<(u64, u64)>, // Bytes client -> origin and origin -> client, once the tunnel is done
+    error: Option<ProxyError>,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum SidebarMode {
+    Captured,
+    Status, // Lowest status first, anything without a response yet at the end
+    Latency, // Slowest first, unfinished at the end
+    Host,
+}
+
+impl SidebarMode {
+    pub const ALL: [Self; 4] = [Self::Captured, Self::Status, Self::Latency, Self::Host];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Captured => "Capture order",
+            Self::Status => "By status",
+            Self::Latency => "By latency",
+            Self::Host => "Grouped by host",
+        }
+    }
+}
+
+// Which parts of the traffic get kept. Whatever's left out is still passed along, it just isn't stored.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct CaptureMask(u8);
+
+impl CaptureMask {
+    pub const REQUEST_HEADS: Self = Self(1); // Without these only the request line is kept, the headers are dropped
+    pub const REQUEST_BODIES: Self = Self(1 << 1);
+    pub const RESPONSE_HEADS: Self = Self(1 << 2); // Likewise, the status stays
+    pub const RESPONSE_BODIES: Self = Self(1 << 3);
+    pub const UPGRADES: Self = Self(1 << 4);
+    pub const ALL: Self = Self(0b11111);
+
+    pub const NAMES: [(Self, &'static str); 5] = [
+        (Self::REQUEST_HEADS, "Request headers"),
+        (Self::REQUEST_BODIES, "Request bodies"),
+        (Self::RESPONSE_HEADS, "Response headers"),
+        (Self::RESPONSE_BODIES, "Response bodies"),
+        (Self::UPGRADES, "Upgrade frames"),
+    ];
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn set(&mut self, other: Self, enabled: bool) {
+        if enabled {
+            self.0 |= other.0;
+        } else {
+            self.0 &= !other.0;
+        }
+    }
+}
+
+enum SidebarRow {
+    Pair(usize), // Cache index
+    Host { host: String, count: usize },
+    Duplicates { first: usize, count: usize }, // Run of identical requests, shown as its first capture
+}
+
+// Consecutive requests with the same method, URI and body fold into one row. Unfinished requests never do,
+// since their body could still turn out different.
+fn push_rows(rows: &mut Vec<SidebarRow>, cache: &[StoredPair], pairs: Vec<usize>, dedup: bool, expanded: &HashSet<usize>) {
+    if !dedup {
+        rows.extend(pairs.into_iter().map(SidebarRow::Pair));
+        return;
+    }
+    let key = |idx: usize| cache[idx].request.as_ref()
+        .and_then(|req| Some((&req.head.method, &req.head.uri, req.body_hash?)));
+    let mut pairs = pairs.into_iter().peekable();
+    while let Some(first) = pairs.next() {
+        let mut run = vec![first];
+        if key(first).is_some() {
+            while let Some(&next) = pairs.peek() {
+                if key(next) != key(first) {
+                    break;
+                }
+                run.push(next);
+                pairs.next();
+            }
+        }
+        if run.len() == 1 {
+            rows.push(SidebarRow::Pair(first));
+        } else {
+            rows.push(SidebarRow::Duplicates { first, count: run.len() });
+            if expanded.contains(&first) {
+                rows.extend(run.into_iter().map(SidebarRow::Pair));
+            }
+        }
+    }
 }
 
 #[derive(PartialEq, Clone, Debug)]
 enum StoredResult {
     Pending,
     Ok,
-    Error(String)
+    Error(ProxyError)
 }
 
 impl Default for StoredPair {
     fn default() -> Self {
         Self {
+            id: 0,
             request: None,
             response: None,
+            upgrade: Vec::new(),
+            websocket: None,
+            tunnel: None,
+            tag: None,
+            note: String::new(),
+            mirror_of: None,
+            redirect_from: None,
+            tls: None,
         }
     }
 }
@@ -58,18 +230,328 @@ impl StoredPair{
     fn resp_mut(&mut self) -> Option<&mut StoredResponse> {
         self.response.as_mut()
     }
+
+    fn is_websocket(&self) -> bool {
+        let upgrade = |headers: &HeaderMap<HeaderValue>| headers.get(UPGRADE)
+            .map(|value| value.as_bytes().eq_ignore_ascii_case(b"websocket"))
+            .unwrap_or(false);
+        self.request.as_ref().map(|req| upgrade(&req.head.headers)).unwrap_or(false)
+            || self.response.as_ref().map(|resp| upgrade(&resp.head.headers)).unwrap_or(false)
+    }
+
+    fn push_upgrade(&mut self, direction: UpgradeDirection, chunk_id: u32, chunk: &[u8]) {
+        if self.websocket.is_none() && self.upgrade.is_empty() && self.is_websocket() {
+            self.websocket = Some(Box::default());
+        }
+        let chunk = match &mut self.websocket {
+            Some(websocket) => match websocket.feed(direction == UpgradeDirection::Tx, chunk) {
+                Some(rest) => rest,
+                None => return,
+            },
+            None => chunk.to_vec(),
+        };
+        // Tx and rx are pumped concurrently so they can land slightly out of order
+        let pos = self.upgrade.partition_point(|frame| frame.chunk_id < chunk_id);
+        self.upgrade.insert(pos, UpgradeFrame { direction, chunk_id, bytes: chunk });
+    }
+
+    fn host(&self) -> String {
+        match (&self.request, &self.tunnel) {
+            (Some(req), _) => req.head.uri.host()
+                .or_else(|| req.head.headers.get(HOST).and_then(|host| host.to_str().ok()))
+                .unwrap_or_default()
+                .to_string(),
+            // Tunnels are recorded by authority, leave the port off so they group with the host's requests
+            (None, Some(tunnel)) => tunnel.host.parse::<Authority>()
+                .map(|authority| authority.host().to_string())
+                .unwrap_or_else(|_| tunnel.host.clone()),
+            (None, None) => String::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.request.is_none() && self.tunnel.is_none()
+    }
+
+    // Nothing more is coming in for it, upgrades aside
+    fn settled(&self) -> bool {
+        match (&self.request, &self.tunnel) {
+            (Some(_), _) => match self.status() {
+                (StoredResult::Error(_), _) | (_, StoredResult::Error(_)) => true,
+                (req, resp) => req != StoredResult::Pending && resp != StoredResult::Pending,
+            },
+            (None, Some(tunnel)) => tunnel.closed.is_some() || tunnel.error.is_some(),
+            (None, None) => false,
+        }
+    }
+
+    // Roughly what the pair holds in memory: heads, bodies that haven't been spooled, and upgrade frames
+    fn memory_size(&self) -> usize {
+        let headers = |headers: &HeaderMap<HeaderValue>| headers.iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum::<usize>();
+        let body = |body: &BodyBuffer| if body.is_spooled() { 0 } else { body.len() };
+        let req = self.request.as_ref()
+            .map(|req| req.head.uri.to_string().len() + headers(&req.head.headers) + body(&req.body))
+            .unwrap_or(0);
+        let resp = self.response.as_ref()
+            .map(|resp| headers(&resp.head.headers) + body(&resp.body))
+            .unwrap_or(0);
+        let websocket = self.websocket.as_ref().map(|websocket| websocket.memory_size()).unwrap_or(0);
+        std::mem::size_of::<Self>() + req + resp + websocket + self.upgrade.iter().map(|frame| frame.bytes.len()).sum::<usize>()
+    }
+
+    // Time from the request head arriving to the response head arriving
+    fn time_to_first_byte(&self) -> Option<Duration> {
+        Some(self.response.as_ref()?.first_byte.duration_since(self.request.as_ref()?.started))
+    }
+
+    // Time from the request head arriving to the end of the response body, None until it finishes
+    fn elapsed(&self) -> Option<Duration> {
+        Some(self.response.as_ref()?.finished?.duration_since(self.request.as_ref()?.started))
+    }
+
+    fn tagged(&self, filter: &str) -> bool {
+        self.tag.as_ref().map(|tag| tag.to_lowercase().contains(&filter.to_lowercase())).unwrap_or(false)
+    }
+
+    // Requests still waiting count once they've been going longer than `min`, they're slow already
+    fn slower_than(&self, min: Duration) -> bool {
+        match (self.elapsed(), &self.request) {
+            (Some(elapsed), _) => elapsed > min,
+            (None, Some(req)) => self.error().is_none() && req.started.elapsed() > min,
+            (None, None) => false,
+        }
+    }
+
+    fn status(&self) -> (StoredResult, StoredResult) {
+        (
+            self.request.as_ref().map(|request| request.status.clone()).unwrap_or(StoredResult::Pending),
+            self.response.as_ref().map(|response| response.status.clone()).unwrap_or(StoredResult::Pending),
+        )
+    }
+
+    // Errors raised by the proxy itself win over whatever status the origin sent back
+    fn error(&self) -> Option<ProxyError> {
+        let req_status = self.request.as_ref().map(|req| &req.status);
+        let resp_status = self.response.as_ref().map(|resp| &resp.status);
+        match (req_status, resp_status) {
+            (_, Some(StoredResult::Error(e))) | (Some(StoredResult::Error(e)), _) => Some(e.clone()),
+            _ => self.response.as_ref().and_then(|resp| ProxyError::from_status(resp.head.status))
+        }
+    }
 }
 
-struct InnerStore {
-    cache: RefCell<Vec<StoredPair>>
+// Sidebar tints by outcome, picked to stay readable on both the dark and light themes
+struct StatusColors {
+    success: Color32,
+    redirect: Color32,
+    failure: Color32,
+    pending: Color32,
+    error: Color32, // The proxy itself failed, as opposed to the origin answering with an error status
+}
+
+impl StatusColors {
+    fn for_visuals(visuals: &Visuals) -> Self {
+        if visuals.dark_mode {
+            Self {
+                success: Color32::from_rgb(110, 200, 110),
+                redirect: Color32::from_rgb(220, 200, 90),
+                failure: Color32::from_rgb(235, 95, 95),
+                pending: visuals.weak_text_color(),
+                error: Color32::from_rgb(200, 130, 255),
+            }
+        } else {
+            Self {
+                success: Color32::from_rgb(20, 130, 20),
+                redirect: Color32::from_rgb(160, 115, 0),
+                failure: Color32::from_rgb(190, 30, 30),
+                pending: visuals.weak_text_color(),
+                error: Color32::from_rgb(130, 40, 190),
+            }
+        }
+    }
+
+    fn pick(&self, status: Option<(StoredResult, StoredResult)>, response: Option<&StoredResponse>) -> Option<Color32> {
+        if let Some((StoredResult::Error(_), _) | (_, StoredResult::Error(_))) = status {
+            return Some(self.error);
+        }
+        let status = match response {
+            Some(resp) => resp.head.status,
+            None => return Some(self.pending),
+        };
+        if status.is_success() {
+            Some(self.success)
+        } else if status.is_redirection() {
+            Some(self.redirect)
+        } else if status.is_client_error() || status.is_server_error() {
+            Some(self.failure)
+        } else {
+            None
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        format!("{}ms", duration.as_millis())
+    } else {
+        format!("{:.2}s", duration.as_secs_f64())
+    }
+}
+
+// Method and path, with the path cut short if it has to be, then the latency out at the right edge. Widths are measured
+// rather than counted in chars, since the theme can put any font behind the monospace style.
+fn sidebar_line(method: &str, path: &str, latency: &str, width: f32, measure: impl Fn(&str) -> f32) -> String {
+    let full = format!("{} {}", method, path);
+    let spare = width - measure(&format!("{} {}", full, latency));
+    if spare >= 0.0 {
+        let padding = (spare / measure(" ").max(1.0)).floor() as usize;
+        return format!("{}{} {}", full, " ".repeat(padding), latency);
+    }
+    // Even with none of the path left it might not fit, the label clips whatever's over
+    let cut = |end: usize| format!("{} {}... {}", method, &path[..end], latency);
+    let ends: Vec<usize> = std::iter::once(0).chain(path.char_indices().skip(1).map(|(idx, _)| idx)).collect();
+    let fitting = ends.partition_point(|&end| measure(&cut(end)) <= width);
+    cut(ends[fitting.saturating_sub(1)])
+}
+
+// Empty out the oldest captures past `max`, going past any that are retained. Dropping the bodies is what frees the
+// memory, spool files included. `base` is the id at index 0.
+fn evict_oldest(cache: &mut [StoredPair], max: usize, base: u32, retained: &HashSet<u32>) {
+    let live = cache.iter().filter(|pair| !pair.is_empty()).count();
+    let mut over = live.saturating_sub(max);
+    for (idx, pair) in cache.iter_mut().enumerate() {
+        if over == 0 {
+            break;
+        }
+        if !pair.is_empty() && !retained.contains(&(base + idx as u32)) {
+            *pair = StoredPair::default();
+            over -= 1;
+        }
+    }
+}
+
+// Progress through a request body that's still coming in, as a fraction when the client said how long it would be
+fn draw_upload_progress(ui: &mut Ui, req: &StoredRequest) {
+    let total = req.head.headers.get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .filter(|total| *total > 0);
+    match total {
+        Some(total) => {
+            let fraction = req.uploaded as f32 / total as f32;
+            let text = format!("Uploading, {} of {} bytes ({:.0}%)", req.uploaded, total, fraction * 100.0);
+            ui.add(ProgressBar::new(fraction.min(1.0)).text(text));
+        },
+        None => { ui.label(format!("Uploading, {} bytes so far", req.uploaded)); },
+    }
+}
+
+// One row per value, so repeated headers show up as many times as they were sent
+fn draw_headers(ui: &mut Ui, id: (usize, bool), headers: &HeaderMap<HeaderValue>) {
+    Grid::new(("headers", id)).num_columns(2).striped(true).show(ui, |ui| {
+        for (name, value) in headers.iter() {
+            ui.label(RichText::new(name.as_str()).monospace().strong());
+            ui.add(Label::new(RichText::new(String::from_utf8_lossy(value.as_bytes())).monospace()).wrap(true));
+            ui.end_row();
+        }
+    });
+}
+
+// Replays always point back at the first capture, not at whichever replay was re-sent
+fn replay_from(proxy: &ProxyServer, replays: &mut HashMap<usize, usize>, id_base: u32, idx: usize, req: &StoredRequest) {
+    let original = replays.get(&idx).copied().unwrap_or(idx);
+    let (id, _) = proxy.replay(req.head.clone(), req.body.read().map(|body| body.into_owned()).unwrap_or_default());
+    replays.insert((id - id_base) as usize, original);
+}
+
+// Ids of every request in the redirect chain `pair` is part of, first to last. Looked up by id rather than slot, since
+// a loaded session doesn't line them up. Hops that were evicted or never captured end the chain where they'd be.
+fn redirect_chain(cache: &[StoredPair], pair: &StoredPair) -> Vec<u32> {
+    let find = |id: u32| cache.iter().find(|pair| pair.id == id && !pair.is_empty());
+    let mut chain = vec![pair.id];
+    let mut current = pair;
+    // A session file could claim a loop, which would otherwise never end
+    while let Some(from) = current.redirect_from.and_then(find).filter(|from| !chain.contains(&from.id)) {
+        chain.insert(0, from.id);
+        current = from;
+    }
+    let mut last = pair.id;
+    while let Some(next) = cache.iter().find(|pair| pair.redirect_from == Some(last) && !chain.contains(&pair.id)) {
+        chain.push(next.id);
+        last = next.id;
+    }
+    chain
+}
+
+// Keep at most `max` bytes of a body. The proxy streams every chunk on to the client regardless, this only caps our copy.
+fn append_capped(body: &mut BodyBuffer, truncated: &mut bool, chunk: &[u8], max: usize, spool_over: usize) {
+    let room = max.saturating_sub(body.len());
+    if chunk.len() > room {
+        *truncated = true;
+    }
+    if let Err(e) = body.append(&chunk[..chunk.len().min(room)], spool_over) {
+        // Whatever made it to disk so far is still readable, the rest is lost
+        error!("Unable to spool body to disk: {}", e);
+        *truncated = true;
+    }
 }
 
-unsafe impl Sync for InnerStore {}
+// Oldest notices are dropped past this, a noisy upstream shouldn't grow the log forever
+const MAX_MESSAGES: usize = 1000;
+
+// The subscriber takes the write lock for each event it records and the GUI takes read locks while drawing, so neither
+// side ever skips its work because the other is busy. To keep that deadlock free:
+// - the cache lock is taken first, before any of the store's mutexes, and never while holding one of them
+// - nothing awaits or asks for a repaint with it held, the subscriber repaints after letting go
+// - a thread holding a read lock doesn't take another one, a waiting writer would block the second read forever
+struct InnerStore {
+    cache: RwLock<Vec<StoredPair>>
+}
 
 pub struct Store {
     store: Arc<InnerStore>,
     frame: Arc<Mutex<Option<eframe::epi::Frame>>>, // Store a frame so we can request a repaint with an update
-    active: Option<usize>,
+    proxy: Option<ProxyServer>, // Handle used to re-send captured requests
+    replays: HashMap<usize, usize>, // Cache index of a replayed capture -> index of the capture it replays
+    max_entries: Arc<Mutex<Option<usize>>>, // Past this many captures the oldest are evicted, None keeps everything
+    kept: HashSet<usize>, // Captures the user asked to keep, never evicted
+    retained: Arc<Mutex<HashSet<u32>>>, // Ids of the captures eviction leaves alone: kept, pinned or open in a tab
+    tabs: Vec<usize>, // Cache index shown in each open tab, a plain click in the sidebar swaps out the current one
+    active: usize, // Position in `tabs` of the one on screen
+    pinned: Option<usize>, // Capture the active one gets compared against
+    resend: Option<ResendEditor>,
+    composer: Option<ResendEditor>, // A request being written from scratch, not tied to any capture
+    comparison: Option<Comparison>,
+    rows: Vec<SidebarRow>,
+    sidebar_mode: SidebarMode,
+    collapsed: HashSet<String>, // Hosts folded away when grouping by host
+    dedup: bool,
+    min_latency: Option<Duration>, // Only list pairs slower than this
+    tag_filter: String, // Only list pairs with a tag containing this, ignoring case
+    latency_summary: Option<(Duration, Duration, usize)>, // p50, p95 and how many finished pairs they're over, for the listed rows
+    expanded: HashSet<usize>, // Duplicate runs opened up, by the cache index of their first capture
+    id_base: Arc<AtomicU32>, // Request id stored at cache index 0, moved past everything already issued on clear
+    body_view: BodyView,
+    save_paths: (Option<(usize, bool)>, String, String), // Where to save the active pair's request and response bodies, each suggested from its own head
+    save_notice: String,
+    intercept: Arc<AtomicBool>,
+    capturing: Arc<AtomicBool>, // When off, traffic is still forwarded but new pairs aren't recorded
+    capture_mask: Arc<AtomicU8>,
+    max_body_bytes: Arc<AtomicUsize>, // Per body cap on what we keep in memory
+    spool_over: Arc<AtomicUsize>, // Bodies bigger than this move to a temp file
+    parked: Arc<Mutex<Vec<ParkedRequest>>>, // Request heads waiting on the user before being sent upstream
+    intercept_responses: Arc<AtomicBool>,
+    parked_responses: Arc<Mutex<Vec<ParkedResponse>>>, // Response heads waiting on the user before reaching the client
+    intercept_upgrades: Arc<AtomicBool>,
+    parked_frames: Arc<Mutex<Vec<ParkedFrame>>>, // Upgrade chunks waiting on the user before being written to the other side
+    body_rewrites: Arc<Mutex<HashMap<u32, BodyRewrite>>>, // Edits to apply to response chunks, by request id
+    request_bodies: Arc<Mutex<HashMap<u32, Bytes>>>, // Edited bodies of intercepted requests, by request id
+    rules: Arc<Mutex<Vec<RewriteRule>>>, // Applied in order to every request before interception sees it
+    conditions: Arc<Mutex<Vec<InterceptCondition>>>, // Which requests get parked while intercepting
+    messages: Arc<Mutex<Vec<String>>>, // Notices from the proxy that aren't tied to a request
+    open_upgrades: Arc<AtomicUsize>, // Counted whether or not the request was captured
     pub job: Option<JoinHandle<()>>
 }
 
@@ -77,187 +559,1207 @@ impl Store {
     pub fn new() -> Self {
         Self{
             store: Arc::new(InnerStore{
-                cache: RefCell::new(Vec::new())
+                cache: RwLock::new(Vec::new())
             }),
             job: None,
-            active: None,
+            proxy: None,
+            replays: HashMap::new(),
+            max_entries: Arc::new(Mutex::new(None)),
+            kept: HashSet::new(),
+            retained: Arc::new(Mutex::new(HashSet::new())),
+            tabs: Vec::new(),
+            active: 0,
+            pinned: None,
+            resend: None,
+            composer: None,
+            comparison: None,
+            rows: Vec::new(),
+            sidebar_mode: SidebarMode::Captured,
+            collapsed: HashSet::new(),
+            dedup: false,
+            min_latency: None,
+            tag_filter: String::new(),
+            latency_summary: None,
+            expanded: HashSet::new(),
+            id_base: Arc::new(AtomicU32::new(1)),
+            body_view: BodyView::new(),
+            save_paths: (None, String::new(), String::new()),
+            save_notice: String::new(),
+            intercept: Arc::new(AtomicBool::new(false)),
+            capturing: Arc::new(AtomicBool::new(true)),
+            capture_mask: Arc::new(AtomicU8::new(CaptureMask::ALL.0)),
+            max_body_bytes: Arc::new(AtomicUsize::new(16 * 1024 * 1024)),
+            spool_over: Arc::new(AtomicUsize::new(usize::MAX)),
+            parked: Arc::new(Mutex::new(Vec::new())),
+            intercept_responses: Arc::new(AtomicBool::new(false)),
+            parked_responses: Arc::new(Mutex::new(Vec::new())),
+            intercept_upgrades: Arc::new(AtomicBool::new(false)),
+            parked_frames: Arc::new(Mutex::new(Vec::new())),
+            body_rewrites: Arc::new(Mutex::new(HashMap::new())),
+            request_bodies: Arc::new(Mutex::new(HashMap::new())),
+            rules: Arc::new(Mutex::new(Vec::new())),
+            conditions: Arc::new(Mutex::new(Vec::new())),
+            messages: Arc::new(Mutex::new(Vec::new())),
+            open_upgrades: Arc::new(AtomicUsize::new(0)),
             frame: Arc::new(Mutex::new(None))
         }
     }
 
-    pub fn size(&self) -> Option<usize> {
-        self.store.cache.try_borrow().map(|store| store.len()).ok()
-    }
-
     pub fn set_frame(&self, frame: eframe::epi::Frame) {
         self.frame.lock().unwrap().replace(frame);
     }
 
+    pub fn set_intercept(&self, enabled: bool) {
+        self.intercept.store(enabled, crate::ORDERING);
+        if !enabled {
+            // Don't leave anything hanging once the user stops intercepting
+            self.parked.lock().unwrap().drain(..).for_each(ParkedRequest::resume_unmodified);
+        }
+    }
+
+    pub fn is_intercepting(&self) -> bool {
+        self.intercept.load(crate::ORDERING)
+    }
+
+    pub fn set_capturing(&self, enabled: bool) {
+        self.capturing.store(enabled, crate::ORDERING);
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.capturing.load(crate::ORDERING)
+    }
+
+    pub fn set_capture_mask(&self, mask: CaptureMask) {
+        self.capture_mask.store(mask.0, crate::ORDERING);
+    }
+
+    pub fn capture_mask(&self) -> CaptureMask {
+        CaptureMask(self.capture_mask.load(crate::ORDERING))
+    }
+
+    pub fn set_max_body_bytes(&self, max: usize) {
+        self.max_body_bytes.store(max, crate::ORDERING);
+    }
+
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes.load(crate::ORDERING)
+    }
+
+    // None keeps every body in memory
+    pub fn set_spool_over(&self, threshold: Option<usize>) {
+        self.spool_over.store(threshold.unwrap_or(usize::MAX), crate::ORDERING);
+    }
+
+    pub fn spool_over(&self) -> Option<usize> {
+        Some(self.spool_over.load(crate::ORDERING)).filter(|threshold| *threshold != usize::MAX)
+    }
+
+    pub fn set_intercept_responses(&self, enabled: bool) {
+        self.intercept_responses.store(enabled, crate::ORDERING);
+        if !enabled {
+            self.parked_responses.lock().unwrap().drain(..).for_each(ParkedResponse::resume_unmodified);
+        }
+    }
+
+    pub fn is_intercepting_responses(&self) -> bool {
+        self.intercept_responses.load(crate::ORDERING)
+    }
+
+    pub fn set_intercept_upgrades(&self, enabled: bool) {
+        self.intercept_upgrades.store(enabled, crate::ORDERING);
+        if !enabled {
+            self.parked_frames.lock().unwrap().drain(..).for_each(ParkedFrame::resume_unmodified);
+        }
+    }
+
+    pub fn is_intercepting_upgrades(&self) -> bool {
+        self.intercept_upgrades.load(crate::ORDERING)
+    }
+
+    pub fn parked_count(&self) -> usize {
+        self.parked.lock().unwrap().len() + self.parked_responses.lock().unwrap().len() + self.parked_frames.lock().unwrap().len()
+    }
+
+    // Captured pairs, how many of those are still in flight and how many failed, recounted every time it's asked for
+    pub fn counts(&self) -> (usize, usize, usize) {
+        let cache = self.store.cache.read().unwrap();
+        let mut counts = (0, 0, 0);
+        for pair in cache.iter() {
+            if let Some(tunnel) = &pair.tunnel {
+                counts.0 += 1;
+                match (&tunnel.error, tunnel.closed) {
+                    (Some(_), _) => counts.2 += 1,
+                    (None, None) => counts.1 += 1,
+                    (None, Some(_)) => {},
+                }
+            } else if pair.request.is_some() {
+                counts.0 += 1;
+                if pair.error().is_some() {
+                    counts.2 += 1;
+                } else if pair.response.as_ref().and_then(|resp| resp.finished).is_none() {
+                    counts.1 += 1;
+                }
+            }
+        }
+        counts
+    }
+
+    pub fn memory_estimate(&self) -> usize {
+        self.store.cache.read().unwrap().iter().map(StoredPair::memory_size).sum()
+    }
+
+    // Applies to what's already captured straight away, not only from the next capture on
+    pub fn set_max_entries(&mut self, max: Option<usize>) {
+        *self.max_entries.lock().unwrap() = max;
+        self.sync_retained();
+        if let Some(max) = max {
+            let base = self.id_base.load(crate::ORDERING);
+            evict_oldest(&mut self.store.cache.write().unwrap(), max, base, &self.retained.lock().unwrap());
+        }
+    }
+
+    pub fn max_entries(&self) -> Option<usize> {
+        *self.max_entries.lock().unwrap()
+    }
+
+    // Hands the subscriber what's kept, pinned or open in a tab by id, which unlike a cache index doesn't shift
+    fn sync_retained(&self) {
+        let base = self.id_base.load(crate::ORDERING);
+        *self.retained.lock().unwrap() = self.kept.iter()
+            .chain(&self.tabs)
+            .chain(&self.pinned)
+            .map(|&idx| base + idx as u32)
+            .collect();
+    }
+
+    // Eviction empties slots as captures come in. Empty slots at the front are dropped here and the id base moved past
+    // them, the same way clear works, which shifts every cache index we hold by that much. A head that still hasn't shown
+    // up for one of those slots is ignored when it does. Emptied slots behind one that's retained stay until it goes,
+    // since cache indexes follow ids, but they don't hold anything.
+    fn drop_evicted(&mut self) {
+        let shift = {
+            let mut cache = self.store.cache.write().unwrap();
+            let shift = cache.iter().take_while(|pair| pair.is_empty()).count();
+            cache.drain(..shift);
+            self.id_base.fetch_add(shift as u32, crate::ORDERING);
+            shift
+        };
+        if shift == 0 {
+            return;
+        }
+        let remap = |idx: usize| idx.checked_sub(shift);
+        self.tabs = self.tabs.iter().filter_map(|&idx| remap(idx)).collect();
+        self.active = self.active.min(self.tabs.len().saturating_sub(1));
+        self.pinned = self.pinned.and_then(remap);
+        self.resend = self.resend.take().and_then(|mut editor| {
+            editor.source = Some(remap(editor.source?)?);
+            Some(editor)
+        });
+        self.kept = self.kept.iter().filter_map(|&idx| remap(idx)).collect();
+        self.expanded = self.expanded.iter().filter_map(|&idx| remap(idx)).collect();
+        self.replays = self.replays.iter()
+            .filter_map(|(&replay, &original)| Some((remap(replay)?, remap(original)?)))
+            .collect();
+        self.save_paths.0 = self.save_paths.0.and_then(|(idx, resp)| Some((remap(idx)?, resp)));
+        self.comparison = None;
+        self.body_view.forget_cached(self.frame.lock().unwrap().as_ref());
+    }
+
+    pub fn open_upgrades(&self) -> usize {
+        self.open_upgrades.load(crate::ORDERING)
+    }
+
+    pub fn draw_messages(&self, ui: &mut Ui) {
+        let messages = self.messages.lock().unwrap();
+        CollapsingHeader::new(format!("Log ({})", messages.len())).show(ui, |ui| {
+            ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+                for msg in messages.iter() {
+                    ui.label(RichText::new(msg).monospace());
+                }
+            });
+        });
+    }
+
+    // Editor for the oldest parked request, or response once no requests are waiting
+    fn draw_intercepted_frame(&self, ui: &mut Ui) {
+        let mut parked = self.parked_frames.lock().unwrap();
+        if parked.is_empty() {
+            return;
+        }
+        ui.heading(format!("Intercepted {} ({} waiting)", parked[0].title(), parked.len()));
+        match parked[0].draw(ui) {
+            ParkedAction::None => {},
+            ParkedAction::Resume => {
+                if parked[0].resume() {
+                    parked.remove(0);
+                }
+            },
+            ParkedAction::ResumeUnmodified => parked.remove(0).resume_unmodified(),
+        }
+    }
+
+    pub fn draw_intercept(&self, ui: &mut Ui) {
+        let mut parked = self.parked.lock().unwrap();
+        if parked.is_empty() {
+            drop(parked);
+            self.draw_intercepted_response(ui);
+            return;
+        }
+        ui.heading(format!("Intercepted request {} ({} waiting)", parked[0].id, parked.len()));
+        match parked[0].draw(ui) {
+            ParkedAction::None => {},
+            ParkedAction::Resume => {
+                if parked[0].resume(&self.request_bodies) {
+                    parked.remove(0);
+                }
+            },
+            ParkedAction::ResumeUnmodified => parked.remove(0).resume_unmodified(),
+        }
+    }
+
+    pub fn open_composer(&mut self) {
+        if self.composer.is_none() {
+            self.composer = Some(ResendEditor::blank());
+        }
+    }
+
+    pub fn is_composing(&self) -> bool {
+        self.composer.is_some()
+    }
+
+    // Sent like a replay, so it gets a fresh id and shows up as a capture of its own
+    pub fn draw_composer(&mut self, ui: &mut Ui) {
+        let editor = match &mut self.composer {
+            Some(editor) => editor,
+            None => return,
+        };
+        match editor.draw(ui) {
+            ResendAction::Send(head, body) => {
+                if let Some(proxy) = &self.proxy {
+                    let (id, _) = proxy.replay(*head, body);
+                    editor.notice = format!("Sent as capture #{}", id - self.id_base.load(crate::ORDERING) + 1);
+                }
+            },
+            ResendAction::Close => self.composer = None,
+            ResendAction::None => {},
+        }
+    }
+
+    pub fn draw_rules(&self, ui: &mut Ui) {
+        rewrite::draw_rules(ui, &mut self.rules.lock().unwrap());
+    }
+
+    // Write the rules into the config at `path`, keeping everything else in it as it is on disk
+    pub fn save_rules(&self, path: &str) -> Result<(), String> {
+        let mut config = if std::path::Path::new(path).exists() {
+            ProxyConfig::read_file(path)?
+        } else {
+            ProxyConfig::default()
+        };
+        config.rewrite_rules = self.rules.lock().unwrap().clone();
+        config.to_file(path)
+    }
+
+    pub fn draw_conditions(&self, ui: &mut Ui) {
+        condition::draw_conditions(ui, &mut self.conditions.lock().unwrap());
+    }
+
+    fn draw_intercepted_response(&self, ui: &mut Ui) {
+        let mut parked = self.parked_responses.lock().unwrap();
+        if parked.is_empty() {
+            drop(parked);
+            self.draw_intercepted_frame(ui);
+            return;
+        }
+        ui.heading(format!("Intercepted response {} ({} waiting)", parked[0].id, parked.len()));
+        match parked[0].draw(ui) {
+            ParkedAction::None => {},
+            ParkedAction::Resume => {
+                if parked[0].resume(&self.body_rewrites) {
+                    parked.remove(0);
+                }
+            },
+            ParkedAction::ResumeUnmodified => parked.remove(0).resume_unmodified(),
+        }
+    }
+
+    pub fn set_proxy(&mut self, proxy: ProxyServer) {
+        self.proxy = Some(proxy);
+    }
+
+    // Re-send every captured request with its scheme/authority swapped for `base`'s, prefixing `base`'s path if it has one.
+    // The new captures are remembered as replays of the originals so they can be compared.
+    pub fn replay_all_against(&mut self, base: Uri) -> Result<usize, String> {
+        let proxy = self.proxy.as_ref().ok_or("No proxy to replay through")?;
+        let authority = base.authority().ok_or_else(|| format!("{} has no host", base))?.clone();
+        let scheme = base.scheme().cloned().unwrap_or(Scheme::HTTPS);
+        let prefix = base.path().trim_end_matches('/');
+        let captured: Vec<(usize, StoredRequest)> = self.store.cache
+            .read()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| !self.replays.contains_key(idx))
+            .filter_map(|(idx, pair)| pair.request.clone().map(|req| (idx, req)))
+            .collect();
+        let count = captured.len();
+        for (idx, req) in captured {
+            let mut head = req.head;
+            let path = head.uri.path_and_query().map(|path| path.as_str()).unwrap_or("/");
+            head.uri = Uri::builder()
+                .scheme(scheme.clone())
+                .authority(authority.clone())
+                .path_and_query(format!("{}{}", prefix, path))
+                .build()
+                .map_err(|e| e.to_string())?;
+            if head.headers.contains_key(HOST) {
+                head.headers.insert(HOST, authority.as_str().parse().map_err(|_| format!("Bad host {}", authority))?);
+            }
+            let (id, _) = proxy.replay(head, req.body.read().map(|body| body.into_owned()).unwrap_or_default());
+            self.replays.insert((id - self.id_base.load(crate::ORDERING)) as usize, idx);
+        }
+        Ok(count)
+    }
+
+    // Drop every capture. Ids the proxy already handed out, including requests still in flight, end up below the new
+    // base and are ignored by the subscriber, so the next request lands in slot 0 without colliding with a stale one.
+    pub fn clear(&mut self) {
+        {
+            let mut cache = self.store.cache.write().unwrap();
+            if let Some(proxy) = &self.proxy {
+                // Ids start over when everything that was given one is recorded here and done. Anything still going
+                // would share its id with a new request, so then the new captures start past every id handed out.
+                let next = proxy.next_id();
+                let settled = next.checked_sub(self.id_base.load(crate::ORDERING)) == Some(cache.len() as u32)
+                    && cache.iter().all(StoredPair::settled)
+                    && self.open_upgrades() == 0
+                    && proxy.open_tunnels() == 0;
+                let base = settled.then(|| proxy.reset_ids(next)).flatten().unwrap_or(next);
+                self.id_base.store(base, crate::ORDERING);
+            }
+            cache.clear();
+        }
+        self.tabs.clear();
+        self.active = 0;
+        self.pinned = None;
+        self.resend = None;
+        self.kept.clear();
+        self.comparison = None;
+        self.rows.clear();
+        self.expanded.clear();
+        self.replays.clear();
+        self.sync_retained();
+        self.body_view.forget_cached(self.frame.lock().unwrap().as_ref());
+    }
+
+    pub fn export_har(&self, path: &str) -> std::io::Result<()> {
+        let har = har::to_har(&self.store.cache.read().unwrap());
+        serde_json::to_writer_pretty(std::fs::File::create(path)?, &har)?;
+        Ok(())
+    }
+
+    // Everything captured so far, in our own format. Returns how many captures went in.
+    pub fn save_session(&self, path: &str) -> std::io::Result<usize> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let cache = self.store.cache.read().unwrap();
+        futures::executor::block_on(session::write(futures::io::AllowStdIo::new(file), &cache))?;
+        Ok(cache.iter().filter(|pair| !pair.is_empty()).count())
+    }
+
+    // Replaces the current captures with the ones in a session file. Their slots take ids the proxy will never hand
+    // out, so live traffic carries on after them without landing on one.
+    pub fn load_session(&mut self, path: &str) -> std::io::Result<usize> {
+        let file = std::fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        let spool_over = self.spool_over.load(crate::ORDERING);
+        let reader = futures::io::AllowStdIo::new(std::io::BufReader::new(file));
+        let pairs = futures::executor::block_on(session::read(reader, len, spool_over))?;
+        self.clear();
+        let count = pairs.iter().filter(|pair| !pair.is_empty()).count();
+        let mut cache = self.store.cache.write().unwrap();
+        if let Some(proxy) = &self.proxy {
+            self.id_base.store(proxy.reserve_ids(pairs.len() as u32), crate::ORDERING);
+        }
+        *cache = pairs;
+        Ok(count)
+    }
+
+    // Every request listed in the sidebar, in the order shown, as one .http file. Returns how many went in.
+    pub fn export_http(&self, path: &str) -> std::io::Result<usize> {
+        let (count, file) = {
+            let cache = self.store.cache.read().unwrap();
+            let reqs: Vec<&StoredRequest> = self.rows.iter()
+                .filter_map(|row| match row {
+                    SidebarRow::Pair(idx) | SidebarRow::Duplicates { first: idx, .. } => cache.get(*idx)?.request.as_ref(),
+                    SidebarRow::Host { .. } => None,
+                })
+                .collect();
+            (reqs.len(), export::to_http_file(reqs))
+        };
+        std::fs::write(path, file)?;
+        Ok(count)
+    }
+
     fn get_status(&self, idx: usize) -> Option<(StoredResult, StoredResult)> {
-        if let Ok(store) = self.store.cache.try_borrow() {
-            store.get(idx).map(
-                | pair | (
-                    pair.request.as_ref().map( |request | request.status.clone() ).unwrap_or( StoredResult::Pending ),
-                    pair.response.as_ref().map( |response | response.status.clone() ).unwrap_or( StoredResult::Pending )
-                )
-            )
+        self.store.cache.read().unwrap().get(idx).map(StoredPair::status)
+    }
+
+    // Show a capture, in a new tab or in place of the current one. Captures already open just get switched to.
+    fn select(&mut self, idx: usize, new_tab: bool) {
+        if let Some(open) = self.tabs.iter().position(|tab| *tab == idx) {
+            self.active = open;
+        } else if new_tab || self.tabs.is_empty() {
+            self.tabs.push(idx);
+            self.active = self.tabs.len() - 1;
         } else {
-            None
+            self.tabs[self.active] = idx;
+        }
+        self.sync_retained();
+    }
+
+    // Open the capture with the id the proxy gave it, as long as it's still around. Returns whether it was.
+    pub fn go_to_id(&mut self, id: u32) -> bool {
+        let found = self.store.cache.read().unwrap().iter().position(|pair| pair.id == id && !pair.is_empty());
+        if let Some(idx) = found {
+            self.select(idx, false);
+        }
+        found.is_some()
+    }
+
+    fn close_tab(&mut self, tab: usize) {
+        self.tabs.remove(tab);
+        if self.active > tab || self.active >= self.tabs.len() {
+            self.active = self.active.saturating_sub(1);
+        }
+    }
+
+    // Only there once a second capture is open, so a single selection looks the way it always did
+    fn draw_tabs(&mut self, ui: &mut Ui) {
+        if self.tabs.len() < 2 {
+            return;
+        }
+        let titles: Vec<String> = match self.store.cache.read() {
+            Ok(cache) => self.tabs.iter().map(|&idx| {
+                let title = match cache.get(idx) {
+                    Some(StoredPair { request: Some(req), .. }) => format!("{} {}", req.head.method, req.head.uri.path()),
+                    Some(StoredPair { tunnel: Some(tunnel), .. }) => format!("TUNNEL {}", tunnel.host),
+                    _ => String::new(),
+                };
+                format!("#{} {:.24}", idx + 1, title)
+            }).collect(),
+            Err(_) => return,
+        };
+        let mut closed = None;
+        ScrollArea::horizontal().id_source("capture tabs").show(ui, |ui| {
+            ui.horizontal(|ui| {
+                for (tab, title) in titles.into_iter().enumerate() {
+                    let label = ui.selectable_label(tab == self.active, title);
+                    if label.clicked() {
+                        self.active = tab;
+                    }
+                    if ui.small_button("x").clicked() || label.middle_clicked() {
+                        closed = Some(tab);
+                    }
+                }
+            });
+        });
+        if let Some(tab) = closed {
+            self.close_tab(tab);
         }
+        ui.separator();
     }
 
-    pub fn draw_active(&self, ui: &mut Ui) {
-        if let Some(idx) = self.active {
+    pub fn draw_active(&mut self, ui: &mut Ui) {
+        self.draw_tabs(ui);
+        if let Some(&idx) = self.tabs.get(self.active) {
             ui.heading(format!("{:?}", self.get_status(idx)));
-            if let Some(cache) = self.store.cache.try_borrow().ok() {
+            if let Some(original) = self.replays.get(&idx) {
+                ui.label(format!("Replay of capture #{}", original + 1));
+            }
+            let store = self.store.clone();
+            let id_base = self.id_base.load(crate::ORDERING);
+            let mut annotated = None;
+            let mut goto = None;
+            if let Ok(cache) = store.cache.read() {
                 if let Some(pair) = cache.get(idx) {
+                    ui.label(format!("Request id {}", pair.id));
                     if let Some(req) = &pair.request {
                         if let Some(resp ) = &pair.response {
                             ui.heading(format!("{}: {} {}", resp.head.status, req.head.method, req.head.uri));
                         } else {
                             ui.heading(format!("PENDING: {} {}", req.head.method, req.head.uri));
                         }
+                        match (pair.time_to_first_byte(), pair.elapsed()) {
+                            (Some(ttfb), Some(elapsed)) => {
+                                ui.label(format!("First byte after {}, finished after {}", format_duration(ttfb), format_duration(elapsed)));
+                            },
+                            (Some(ttfb), None) => {
+                                ui.label(format!("First byte after {}, still receiving", format_duration(ttfb)));
+                            },
+                            _ => {},
+                        }
+                        if req.status == StoredResult::Pending && req.uploaded > 0 {
+                            draw_upload_progress(ui, req);
+                        }
+                        ui.horizontal(|ui| {
+                            if ui.button("Copy as curl").clicked() {
+                                ui.output().copied_text = export::to_curl(req);
+                            }
+                            if ui.button("Copy raw heads").clicked() {
+                                ui.output().copied_text = export::raw_heads(req, pair.response.as_ref());
+                            }
+                            if ui.button("Copy as .http").clicked() {
+                                ui.output().copied_text = export::to_http_file([req]);
+                            }
+                            if let Some(proxy) = &self.proxy {
+                                if ui.button("Replay").clicked() {
+                                    replay_from(proxy, &mut self.replays, id_base, idx, req);
+                                }
+                                if ui.button("Edit and resend").clicked() {
+                                    let body = req.body.read().map(|body| body.into_owned()).unwrap_or_default();
+                                    self.resend = Some(ResendEditor::new(idx, &req.head, body, req.truncated));
+                                }
+                            }
+                            if self.pinned == Some(idx) {
+                                if ui.button("Unpin").clicked() {
+                                    self.pinned = None;
+                                }
+                            } else if ui.button("Pin for compare").clicked() {
+                                self.pinned = Some(idx);
+                            }
+                            if self.kept.contains(&idx) {
+                                if ui.button("Stop keeping").clicked() {
+                                    self.kept.remove(&idx);
+                                }
+                            } else if ui.button("Keep").on_hover_text("Never evict this capture").clicked() {
+                                self.kept.insert(idx);
+                            }
+                        });
+                        if let Some(info) = &pair.tls {
+                            ui.label(format!("Client connection: {}", info));
+                        }
+                        if let Some(of) = pair.mirror_of {
+                            ui.label(format!("Copy of request {} sent to the mirror upstream, the client never saw this response", of));
+                        }
+                        let chain = redirect_chain(&cache, pair);
+                        if chain.len() > 1 {
+                            ui.horizontal(|ui| {
+                                ui.label("Redirects followed, the client only saw the last response:");
+                                for (hop, id) in chain.iter().enumerate() {
+                                    if hop > 0 {
+                                        ui.label("->");
+                                    }
+                                    if *id == pair.id {
+                                        ui.strong(id.to_string());
+                                    } else if ui.small_button(id.to_string()).clicked() {
+                                        goto = Some(*id);
+                                    }
+                                }
+                            });
+                        }
+                        // Edited on copies, the cache is only read locked while drawing
+                        let mut tag = pair.tag.clone().unwrap_or_default();
+                        let mut note = pair.note.clone();
+                        Grid::new(("annotation", idx)).num_columns(2).show(ui, |ui| {
+                            ui.label("Tag");
+                            let tag_changed = ui.text_edit_singleline(&mut tag).changed();
+                            ui.end_row();
+                            ui.label("Note");
+                            let note_changed = ui.text_edit_multiline(&mut note).changed();
+                            ui.end_row();
+                            if tag_changed || note_changed {
+                                annotated = Some((idx, tag, note));
+                            }
+                        });
+                        // The request's path is suggested once per selection, the response's again when it arrives
+                        if self.save_paths.0.map(|(at, _)| at) != Some(idx) {
+                            self.save_paths.1 = export::suggest_filename(&req.head.uri, &req.head.headers);
+                            self.save_notice.clear();
+                        }
+                        if self.save_paths.0 != Some((idx, pair.response.is_some())) {
+                            self.save_paths.2 = pair.response.as_ref()
+                                .map(|resp| export::suggest_filename(&req.head.uri, &resp.head.headers))
+                                .unwrap_or_default();
+                            self.save_paths.0 = Some((idx, pair.response.is_some()));
+                        }
+                        let mut saving = None;
+                        ui.horizontal(|ui| {
+                            ui.text_edit_singleline(&mut self.save_paths.1);
+                            if ui.button("Save request body").clicked() {
+                                saving = Some((&self.save_paths.1, &req.body, req.truncated));
+                            }
+                        });
+                        if let Some(resp) = &pair.response {
+                            ui.horizontal(|ui| {
+                                ui.text_edit_singleline(&mut self.save_paths.2);
+                                if ui.button("Save response body").clicked() {
+                                    saving = Some((&self.save_paths.2, &resp.body, resp.truncated));
+                                }
+                            });
+                        }
+                        if let Some((path, body, truncated)) = saving {
+                            self.save_notice = match body.read().and_then(|bytes| export::save_body(path, &bytes)) {
+                                Ok(path) if truncated => format!("Wrote {} bytes to {}, incomplete since the body was truncated", body.len(), path.display()),
+                                Ok(path) => format!("Wrote {} bytes to {}", body.len(), path.display()),
+                                Err(e) => format!("Unable to write {}: {}", path, e),
+                            };
+                        }
+                        if !self.save_notice.is_empty() {
+                            ui.label(&self.save_notice);
+                        }
+                        if let Some(err) = pair.error() {
+                            ui.horizontal(|ui| {
+                                if err.is_retryable() {
+                                    ui.label(format!("{} (retryable)", err));
+                                    if let Some(proxy) = &self.proxy {
+                                        if ui.button("Retry").clicked() {
+                                            replay_from(proxy, &mut self.replays, id_base, idx, req);
+                                        }
+                                    }
+                                } else {
+                                    ui.label(err.to_string());
+                                }
+                            });
+                        }
+                        let pinned = self.pinned
+                            .filter(|pinned| *pinned != idx)
+                            .and_then(|pinned| Some((pinned, cache.get(pinned).filter(|pinned| pinned.request.is_some())?)));
+                        if let Some((pinned, pinned_pair)) = pinned {
+                            let key = Comparison::key(pinned, pinned_pair, idx, pair);
+                            if self.comparison.as_ref().map(|comparison| comparison.key != key).unwrap_or(true) {
+                                self.comparison = Some(Comparison::new(pinned, pinned_pair, idx, pair));
+                            }
+                        }
+                        let frame = self.frame.lock().unwrap().clone();
+                        // A new search opens both bodies, matches in a collapsed one would be counted but never seen
+                        let searched = self.body_view.draw_search(ui).then_some(true);
+                        ScrollArea::vertical().show(ui, |ui| {
+                            let mut closed = false;
+                            if let Some(editor) = self.resend.as_mut().filter(|editor| editor.source == Some(idx)) {
+                                CollapsingHeader::new("Edit and resend").default_open(true).show(ui, |ui| {
+                                    match editor.draw(ui) {
+                                        ResendAction::Send(head, body) => {
+                                            if let Some(proxy) = &self.proxy {
+                                                let original = self.replays.get(&idx).copied().unwrap_or(idx);
+                                                let (id, _) = proxy.replay(*head, body);
+                                                let new_idx = (id - id_base) as usize;
+                                                self.replays.insert(new_idx, original);
+                                                editor.notice = format!("Sent as capture #{}", new_idx + 1);
+                                            }
+                                        },
+                                        ResendAction::Close => closed = true,
+                                        ResendAction::None => {},
+                                    }
+                                });
+                            }
+                            if closed {
+                                self.resend = None;
+                            }
+                            if let (Some((pinned, _)), Some(comparison)) = (pinned, &self.comparison) {
+                                CollapsingHeader::new(format!("Compared with pinned capture #{}", pinned + 1))
+                                    .default_open(true)
+                                    .show(ui, |ui| comparison.draw(ui));
+                            }
+                            CollapsingHeader::new("Request headers").show(ui, |ui| draw_headers(ui, (idx, false), &req.head.headers));
+                            if let Some(resp) = &pair.response {
+                                CollapsingHeader::new("Response headers").show(ui, |ui| draw_headers(ui, (idx, true), &resp.head.headers));
+                            }
+                            let cookies = cookies::parse(&req.head.headers, pair.response.as_ref().map(|resp| &resp.head.headers));
+                            if !cookies.is_empty() {
+                                CollapsingHeader::new(format!("Cookies ({})", cookies.len())).show(ui, |ui| cookies::draw_cookies(ui, idx, &cookies));
+                            }
+                            CollapsingHeader::new("Request body").open(searched).show(ui, |ui| {
+                                if req.truncated {
+                                    ui.colored_label(Color32::YELLOW, format!("Truncated, only the first {} bytes were kept", req.body.len()));
+                                }
+                                self.body_view.show_buffer(ui, frame.as_ref(), (idx, false), &req.head.headers, &req.body)
+                            });
+                            if let Some(resp) = &pair.response {
+                                CollapsingHeader::new("Response body").default_open(true).open(searched).show(ui, |ui| {
+                                    if resp.truncated {
+                                        ui.colored_label(Color32::YELLOW, format!("Truncated, only the first {} bytes were kept", resp.body.len()));
+                                    }
+                                    self.body_view.show_buffer(ui, frame.as_ref(), (idx, true), &resp.head.headers, &resp.body)
+                                });
+                            }
+                            if let Some(websocket) = &pair.websocket {
+                                CollapsingHeader::new(format!("WebSocket messages ({})", websocket.messages.len()))
+                                    .default_open(true)
+                                    .show(ui, |ui| websocket.draw(ui));
+                            }
+                            if !pair.upgrade.is_empty() {
+                                CollapsingHeader::new(format!("Upgrade frames ({})", pair.upgrade.len())).show(ui, |ui| {
+                                    for upgrade in &pair.upgrade {
+                                        let arrow = match upgrade.direction {
+                                            UpgradeDirection::Tx => "->",
+                                            UpgradeDirection::Rx => "<-",
+                                        };
+                                        let text = match std::str::from_utf8(&upgrade.bytes) {
+                                            Ok(text) => text.to_string(),
+                                            Err(_) => view::hex_dump(&upgrade.bytes),
+                                        };
+                                        ui.label(RichText::new(format!("{} #{} {}", arrow, upgrade.chunk_id, text)).monospace());
+                                    }
+                                });
+                            }
+                        });
+                    } else if let Some(tunnel) = &pair.tunnel {
+                        ui.heading(format!("TUNNEL {}", tunnel.host));
+                        ui.label("Relayed without interception, the host is on the MITM bypass list");
+                        match (&tunnel.error, tunnel.closed) {
+                            (Some(e), _) => { ui.colored_label(Color32::RED, e.to_string()); },
+                            (None, Some((sent, received))) => {
+                                ui.label(format!("Closed, {} bytes sent, {} bytes received", sent, received));
+                            },
+                            (None, None) => {
+                                ui.label(format!("Open for {}", format_duration(tunnel.started.elapsed())));
+                            },
+                        }
                     }
                 }
+            };
+            if let Some((idx, tag, note)) = annotated {
+                if let Some(pair) = store.cache.write().unwrap().get_mut(idx) {
+                    pair.tag = Some(tag).filter(|tag| !tag.trim().is_empty());
+                    pair.note = note;
+                }
+            }
+            if let Some(id) = goto {
+                self.go_to_id(id);
             }
         }
     }
 
-    pub fn draw_sidebar(&mut self, ui: &mut Ui, mut range: Range<usize>, line_width: usize) {
-        if let Ok(cache ) =  self.store.cache.try_borrow() {
-            // Clamp range to actual size of vec. Don't worry about neg idx bc it's unsigned anyways.
-            if range.start > cache.len() {
-                range.start = cache.len() - 1;
-            }
-            if range.end > cache.len() {
-                range.end = cache.len();
-            }
-            if range.start > range.end {
-                range.end = range.start
+    pub fn set_sidebar_mode(&mut self, mode: SidebarMode) {
+        self.sidebar_mode = mode;
+    }
+
+    pub fn sidebar_mode(&self) -> SidebarMode {
+        self.sidebar_mode
+    }
+
+    pub fn set_dedup(&mut self, dedup: bool) {
+        self.dedup = dedup;
+    }
+
+    pub fn dedup(&self) -> bool {
+        self.dedup
+    }
+
+    pub fn set_min_latency(&mut self, min: Option<Duration>) {
+        self.min_latency = min;
+    }
+
+    pub fn min_latency(&self) -> Option<Duration> {
+        self.min_latency
+    }
+
+    pub fn set_tag_filter(&mut self, filter: String) {
+        self.tag_filter = filter;
+    }
+
+    pub fn tag_filter(&self) -> &str {
+        &self.tag_filter
+    }
+
+    pub fn latency_summary(&self) -> Option<String> {
+        let (p50, p95, count) = self.latency_summary?;
+        Some(format!("p50 {}, p95 {} over {} finished", format_duration(p50), format_duration(p95), count))
+    }
+
+    // Rebuild the sidebar rows. Placeholder slots for ids we haven't seen a request for are skipped.
+    // Rows only ever point at cache indices, so the selection is the same whatever order they're shown in.
+    pub fn sidebar_rows(&mut self) -> usize {
+        self.sync_retained();
+        self.drop_evicted();
+        if let Ok(cache) = self.store.cache.read() {
+            let mut pairs: Vec<usize> = cache.iter()
+                .enumerate()
+                .filter(|(_, pair)| pair.request.is_some() || pair.tunnel.is_some())
+                .filter(|(_, pair)| self.min_latency.map(|min| pair.slower_than(min)).unwrap_or(true))
+                .filter(|(_, pair)| self.tag_filter.trim().is_empty() || pair.tagged(self.tag_filter.trim()))
+                .map(|(idx, _)| idx)
+                .collect();
+            // Nearest rank, over whatever the filter let through
+            let mut latencies: Vec<Duration> = pairs.iter().filter_map(|&idx| cache[idx].elapsed()).collect();
+            latencies.sort();
+            let percentile = |p: usize| latencies[(latencies.len() * p).div_ceil(100).max(1) - 1];
+            self.latency_summary = Some(latencies.len())
+                .filter(|count| *count > 0)
+                .map(|count| (percentile(50), percentile(95), count));
+            // Stable sorts, so ties stay in capture order
+            match self.sidebar_mode {
+                SidebarMode::Captured => {},
+                SidebarMode::Status => pairs.sort_by_key(|&idx| {
+                    cache[idx].response.as_ref().map(|resp| resp.head.status.as_u16()).unwrap_or(u16::MAX)
+                }),
+                SidebarMode::Latency => pairs.sort_by_key(|&idx| std::cmp::Reverse(cache[idx].elapsed())),
+                SidebarMode::Host => {
+                    let mut hosts: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+                    for idx in pairs {
+                        hosts.entry(cache[idx].host()).or_default().push(idx);
+                    }
+                    self.rows.clear();
+                    for (host, pairs) in hosts {
+                        let collapsed = self.collapsed.contains(&host);
+                        self.rows.push(SidebarRow::Host { count: pairs.len(), host });
+                        if !collapsed {
+                            push_rows(&mut self.rows, &cache, pairs, self.dedup, &self.expanded);
+                        }
+                    }
+                    return self.rows.len();
+                },
             }
-            let start = range.start;
-            cache[range].iter().enumerate().for_each( | (idx, pair) | {
-                if let Some(req) = &pair.request {
-                    let method: &str = &req.head.method.to_string();
-                    let method_len = method.len();
-                    let path = req.head.uri.path();
-                    let path_len = path.len();
-                    let total = method_len + path_len + 1;
-                    let text = if total > line_width {
-                        format!("{} {:.*}...", method, line_width - method_len - 5, path)
+            self.rows.clear();
+            push_rows(&mut self.rows, &cache, pairs, self.dedup, &self.expanded);
+        }
+        self.rows.len()
+    }
+
+    pub fn draw_sidebar(&mut self, ui: &mut Ui, range: Range<usize>, line_width: f32) {
+        let store = self.store.clone();
+        let ctx = ui.ctx().clone();
+        let measure = |text: &str| ctx.fonts().layout_no_wrap(text.to_string(), TextStyle::Monospace, Color32::TEMPORARY_COLOR).size().x;
+        if let Ok(cache) = store.cache.read() {
+            let range = range.start.min(self.rows.len())..range.end.min(self.rows.len());
+            let mut toggled = None;
+            let mut opened = None;
+            let mut selected = None; // Middle or ctrl click opens a new tab
+            let colors = StatusColors::for_visuals(ui.visuals());
+            for row in &self.rows[range] {
+                let (idx, run) = match row {
+                    SidebarRow::Pair(idx) => (*idx, None),
+                    SidebarRow::Duplicates { first, count } => (*first, Some(*count)),
+                    SidebarRow::Host { host, count } => {
+                        let marker = if self.collapsed.contains(host) { "+" } else { "-" };
+                        let text = format!("{} {} ({})", marker, if host.is_empty() { "(no host)" } else { host }, count);
+                        let label = ui.add(Label::new(RichText::from(text).monospace().strong()).wrap(false).sense(Sense::click()));
+                        if label.clicked() {
+                            toggled = Some(host.clone());
+                        }
+                        continue;
+                    },
+                };
+                if let Some((pair, tunnel)) = cache.get(idx).and_then(|pair| Some((pair, pair.tunnel.as_ref()?))) {
+                    let mut text = RichText::from(format!("{} TUNNEL {}", pair.id, tunnel.host)).monospace().italics();
+                    if tunnel.error.is_some() {
+                        text = text.color(colors.error);
+                    }
+                    let label = ui.add(Label::new(text).wrap(false).sense(Sense::click()));
+                    if label.clicked() || label.middle_clicked() {
+                        selected = Some((idx, label.middle_clicked() || ui.input().modifiers.command));
+                    }
+                } else if let Some((pair, req)) = cache.get(idx).and_then(|pair| Some((pair, pair.request.as_ref()?))) {
+                    // Latency goes at the end of the line, the path gets cut short to make room for it
+                    let latency = pair.elapsed().map(format_duration).unwrap_or_default();
+                    let mut method = if self.replays.contains_key(&idx) {
+                        format!("[R] {}", req.head.method)
+                    } else if pair.mirror_of.is_some() {
+                        format!("[M] {}", req.head.method)
                     } else {
-                        format!("{} {}", method, path)
+                        req.head.method.to_string()
                     };
-                    let label = ui.add(Label::new(RichText::from(text).monospace()).wrap(false).sense(Sense::click()));
-                    if label.clicked() {
-                        self.active = Some(idx + start)
+                    if self.pinned == Some(idx) {
+                        method = format!("[P] {}", method);
+                    }
+                    if self.kept.contains(&idx) {
+                        method = format!("[K] {}", method);
+                    }
+                    if let Some(tag) = &pair.tag {
+                        method = format!("{} #{}", method, tag.trim());
+                    }
+                    method = format!("{} {}", pair.id, method);
+                    if let Some(count) = run {
+                        let marker = if self.expanded.contains(&idx) { "-" } else { "+" };
+                        method = format!("{} x{} {}", marker, count, method);
+                    }
+                    let text = sidebar_line(&method, req.head.uri.path(), &latency, line_width, measure);
+                    let mut text = RichText::from(text).monospace();
+                    if let Some(color) = colors.pick(Some(pair.status()), pair.response.as_ref()) {
+                        text = text.color(color);
+                    }
+                    let label = ui.add(Label::new(text).wrap(false).sense(Sense::click()));
+                    if label.clicked() || label.middle_clicked() {
+                        selected = Some((idx, label.middle_clicked() || ui.input().modifiers.command));
+                        if run.is_some() {
+                            opened = Some(idx);
+                        }
                     }
-                } else {
-                    ui.label(format!("???"));
                 }
-            });
+            }
+            if let Some((idx, new_tab)) = selected {
+                self.select(idx, new_tab);
+            }
+            if let Some(first) = opened {
+                if !self.expanded.remove(&first) {
+                    self.expanded.insert(first);
+                }
+            }
+            if let Some(host) = toggled {
+                if !self.collapsed.remove(&host) {
+                    self.collapsed.insert(host);
+                }
+            }
             ui.allocate_space(ui.available_size());
-        }
+        };
     }
 
-    pub fn subscribe(&mut self, mut channel: Receiver<ProxyEvent>) {
+    // `rules` are the proxy's rewrite rules, shared so edits here go straight to the requests that follow
+    pub fn subscribe(&mut self, mut channel: Receiver<ProxyEvent>, metrics: Arc<Metrics>, edit_bodies: Arc<AtomicBool>, rules: Arc<Mutex<Vec<RewriteRule>>>) {
+        self.rules = rules;
         let store = self.store.clone();
         let frame = self.frame.clone();
+        let intercept = self.intercept.clone();
+        let capturing = self.capturing.clone();
+        let capture_mask = self.capture_mask.clone();
+        let max_body_bytes = self.max_body_bytes.clone();
+        let spool_over = self.spool_over.clone();
+        let id_base = self.id_base.clone();
+        let parked = self.parked.clone();
+        let intercept_responses = self.intercept_responses.clone();
+        let parked_responses = self.parked_responses.clone();
+        let intercept_upgrades = self.intercept_upgrades.clone();
+        let parked_frames = self.parked_frames.clone();
+        let body_rewrites = self.body_rewrites.clone();
+        let request_bodies = self.request_bodies.clone();
+        let rules = self.rules.clone();
+        let conditions = self.conditions.clone();
+        let messages = self.messages.clone();
+        let open_upgrades = self.open_upgrades.clone();
+        let max_entries = self.max_entries.clone();
+        let retained = self.retained.clone();
         self.job = Some(tokio::spawn(
             async move {
+                let mut request_rewrites = HashMap::new(); // Body rewrites picked up by rules, by request id
+                let mut upgraded = HashSet::new(); // Ids with an upgrade open, a failed upgrade gets a close without an open
+                let mut orphan_chunks: HashMap<u32, Vec<Bytes>> = HashMap::new(); // Request chunks that beat their head here, by request id
+                let mut previews: HashMap<u32, Bytes> = HashMap::new(); // Request bodies read ahead, until their head is parked
+                let mut mirrors: HashMap<u32, u32> = HashMap::new(); // Mirror copies whose head hasn't come in yet, to the id they copy
+                let mut redirects: HashMap<u32, u32> = HashMap::new(); // Followed redirects whose head hasn't come in yet, to the id redirected
+                let mut handshakes: HashMap<u32, TlsInfo> = HashMap::new(); // Client TLS of requests whose head hasn't come in yet
                 loop {
                     let mut repaint = false;
                     match channel.recv().await {
-                        Some(ProxyEvent{id, event, callback}) => {
-                            if let Ok(mut store_mut) = store.cache.try_borrow_mut() {
-                                if id > 0 {
-                                    let id = (id - 1) as usize;
+                        Some(proxy_event) => {
+                            metrics.observe(&proxy_event);
+                            let ProxyEvent{id, event, callback} = proxy_event;
+                            // Errors that aren't tied to a request, e.g. a failed TLS handshake, are notices too
+                            let notice = match &event {
+                                ProxyState::Msg(msg) => Some(msg.clone()),
+                                ProxyState::Error(e) if id.is_none() => Some(e.to_string()),
+                                _ => None,
+                            };
+                            if let Some(notice) = notice {
+                                let mut messages = messages.lock().unwrap();
+                                if messages.len() >= MAX_MESSAGES {
+                                    messages.remove(0);
+                                }
+                                messages.push(notice);
+                                repaint = true;
+                            }
+                            // Nothing to record or answer for those
+                            let id = match id {
+                                Some(id) => id,
+                                None => {
+                                    if let Some(frame) = frame.lock().unwrap().as_ref() {
+                                        frame.request_repaint()
+                                    }
+                                    continue;
+                                },
+                            };
+                            match &event {
+                                ProxyState::UpgradeOpen => { upgraded.insert(id); },
+                                ProxyState::UpgradeClose => { upgraded.remove(&id); },
+                                ProxyState::Mirror { of } => { mirrors.insert(id, *of); },
+                                ProxyState::Redirect { from } => { redirects.insert(id, *from); },
+                                ProxyState::Tls(info) => { handshakes.insert(id, info.clone()); },
+                                _ => {},
+                            }
+                            let (mirror_of, redirect_from, tls) = match &event {
+                                ProxyState::RequestHead(_) => (mirrors.remove(&id), redirects.remove(&id), handshakes.remove(&id)),
+                                _ => (None, None, None),
+                            };
+                            if upgraded.len() != open_upgrades.load(crate::ORDERING) {
+                                open_upgrades.store(upgraded.len(), crate::ORDERING);
+                                repaint = true;
+                            }
+                            // Whether a pair is recorded is decided by its head alone. A pair whose head came in
+                            // while capturing is recorded to the end even if capture is paused midway, and one whose
+                            // head came in while paused has no slot, so its later events fall through below.
+                            let record = capturing.load(crate::ORDERING)
+                                || !matches!(event, ProxyState::RequestHead(_) | ProxyState::Tunnel { .. });
+                            if let Some(mut store_mut) = record.then(|| store.cache.write().unwrap()) {
+                                let base = id_base.load(crate::ORDERING);
+                                if id >= base {
+                                    let request_id = id;
+                                    let id = (id - base) as usize;
                                     let len = store_mut.len();
+                                    let mask = CaptureMask(capture_mask.load(crate::ORDERING));
                                     match &event {
+                                        crate::proxy::ProxyState::RequestChunk(chunk) if !mask.contains(CaptureMask::REQUEST_BODIES) => {
+                                            if let Some(req) = store_mut.get_mut(id).and_then(|pair| pair.req_mut()) {
+                                                req.uploaded += chunk.len();
+                                                repaint = true;
+                                            }
+                                        },
+                                        crate::proxy::ProxyState::ResponseChunk(_) if !mask.contains(CaptureMask::RESPONSE_BODIES) => {},
+                                        crate::proxy::ProxyState::UpgradeTx { .. } | crate::proxy::ProxyState::UpgradeRx { .. }
+                                            if !mask.contains(CaptureMask::UPGRADES) => {},
                                         crate::proxy::ProxyState::RequestHead(head) => {
-                                            repaint = true;
+                                            let head = &if mask.contains(CaptureMask::REQUEST_HEADS) {
+                                                head.clone()
+                                            } else {
+                                                RequestHead { headers: HeaderMap::new(), ..head.clone() }
+                                            };
                                             match std::cmp::Ord::cmp(&len, &id) {
                                                 std::cmp::Ordering::Equal => {
+                                                        repaint = true;
                                                         store_mut.push(StoredPair{
+                                                            id: request_id,
                                                             request: Some(StoredRequest {
                                                                 head: head.clone(),
-                                                                body: Vec::new(),
+                                                                body: BodyBuffer::default(),
+                                                                truncated: false,
+                                                                started: Instant::now(),
                                                                 last_chunk_id: 0,
-                                                                status: StoredResult::Pending
+                                                                status: StoredResult::Pending,
+                                                                body_hash: None,
+                                                                uploaded: 0,
                                                             }),
                                                             response: None,
+                                                            upgrade: Vec::new(),
+                                                            websocket: None,
+                                                            tunnel: None,
+                                                            tag: None,
+                                                            note: String::new(),
+                                                            mirror_of: None,
+                                                            redirect_from: None,
+                                                            tls: None,
                                                         })
                                                 }
                                                 std::cmp::Ordering::Less => {
-                                                    println!("Missing requests, have {} but id is {}", len, id);
+                                                    repaint = true;
+                                                    warn!("Missing requests, have {} but id is {}", len, id);
                                                     for _ in len..id {
                                                         store_mut.push(Default::default());
                                                     }
                                                     store_mut.push(StoredPair{
+                                                        id: request_id,
                                                         request: Some(StoredRequest {
                                                             head: head.clone(),
-                                                            body: Vec::new(),
+                                                            body: BodyBuffer::default(),
+                                                            truncated: false,
+                                                            started: Instant::now(),
                                                             last_chunk_id: 0,
-                                                            status: StoredResult::Pending
+                                                            status: StoredResult::Pending,
+                                                            body_hash: None,
+                                                            uploaded: 0,
                                                         }),
                                                         response: None,
+                                                        upgrade: Vec::new(),
+                                                        websocket: None,
+                                                        tunnel: None,
+                                                        tag: None,
+                                                        note: String::new(),
+                                                        mirror_of: None,
+                                                        redirect_from: None,
+                                                        tls: None,
                                                     });
                                                 }
+                                                // Heads don't always arrive in id order, an earlier one fills the placeholder a later one left
                                                 std::cmp::Ordering::Greater => {
-                                                    println!("Too many requests, have {} but id is {}", len, id);
                                                     if let Some(slot) = store_mut.get_mut(id) {
-                                                        if None == slot.request {
-                                                            println!("Slot is empty, filling");
+                                                        if None == slot.request && slot.tunnel.is_none() {
+                                                            repaint = true;
+                                                            slot.id = request_id;
                                                             slot.request = Some(StoredRequest {
                                                                 head: head.clone(),
-                                                                body: Vec::new(),
+                                                                body: BodyBuffer::default(),
+                                                                truncated: false,
+                                                                started: Instant::now(),
                                                                 last_chunk_id: 0,
-                                                                status: StoredResult::Pending
+                                                                status: StoredResult::Pending,
+                                                                body_hash: None,
+                                                                uploaded: 0,
                                                             })
+                                                        } else {
+                                                            warn!("Slot {} already filled, dropping head", id);
                                                         }
                                                     }
                                                 },
                                             }
+                                            if let (Some(of), Some(pair)) = (mirror_of, store_mut.get_mut(id)) {
+                                                pair.mirror_of = Some(of);
+                                            }
+                                            if let (Some(from), Some(pair)) = (redirect_from, store_mut.get_mut(id)) {
+                                                pair.redirect_from = Some(from);
+                                            }
+                                            if let (Some(info), Some(pair)) = (tls, store_mut.get_mut(id)) {
+                                                pair.tls = Some(info);
+                                            }
+                                            // Anything that got here ahead of the head goes in first, in the order it came
+                                            if let Some(chunks) = orphan_chunks.remove(&request_id) {
+                                                if let Some(req) = store_mut.get_mut(id).and_then(|pair| pair.req_mut()) {
+                                                    for chunk in chunks {
+                                                        req.uploaded += chunk.len();
+                                                        append_capped(&mut req.body, &mut req.truncated, &chunk, max_body_bytes.load(crate::ORDERING), spool_over.load(crate::ORDERING));
+                                                    }
+                                                }
+                                            }
                                         },
                                         crate::proxy::ProxyState::RequestChunk ( chunk ) => {
-                                            if let Some(pair) = store_mut.get_mut(id as usize) {
-                                                    if let Some(req) = pair.req_mut() {
-                                                        req.body.extend_from_slice(&chunk);
-                                                    } else {
-                                                        println!("Got chunk for {} but request empty", id)
-                                                    }
-                                            } else {
-                                                println!("Got chunk for {} but index empty", id)
+                                            match store_mut.get_mut(id).and_then(|pair| pair.req_mut()) {
+                                                Some(req) => {
+                                                    req.uploaded += chunk.len();
+                                                    append_capped(&mut req.body, &mut req.truncated, chunk, max_body_bytes.load(crate::ORDERING), spool_over.load(crate::ORDERING));
+                                                    repaint = true;
+                                                },
+                                                // Hold on to it until the head shows up
+                                                None => orphan_chunks.entry(request_id).or_default().push(chunk.clone()),
                                             }
                                         },
                                         crate::proxy::ProxyState::RequestDone => {
-                                            if let Some(pair) = store_mut.get_mut(id as usize) {
-                                                if let Some(req) = pair.req_mut() {
-                                                    req.status = StoredResult::Ok;
-                                                } else {
-                                                    println!("Request {} done but nothing stored????", id)
-                                                }
+                                            if let Some(req) = store_mut.get_mut(id).and_then(|pair| pair.req_mut()) {
+                                                req.status = StoredResult::Ok;
+                                                req.body_hash = req.body.read().ok().map(|body| {
+                                                    let mut hasher = DefaultHasher::new();
+                                                    body.hash(&mut hasher);
+                                                    hasher.finish()
+                                                });
+                                            } else {
+                                                // Most likely the head was skipped while capture was paused, so it's never coming
+                                                orphan_chunks.remove(&request_id);
+                                                warn!("Request {} done but nothing stored", id)
                                             }
                                         },
                                         crate::proxy::ProxyState::ResponseHead( head ) => {
+                                            let head = &if mask.contains(CaptureMask::RESPONSE_HEADS) {
+                                                head.clone()
+                                            } else {
+                                                ResponseHead { headers: HeaderMap::new(), ..head.clone() }
+                                            };
                                             if let Some(pair) = store_mut.get_mut(id as usize) {
-                                                if pair.response == None {
+                                                // Placeholder slots, e.g. for a head skipped while paused, never get a response
+                                                if pair.response == None && pair.request.is_some() {
                                                     pair.response = Some(StoredResponse {
                                                         head: head.clone(),
-                                                        body: Vec::new(),
+                                                        body: BodyBuffer::default(),
+                                                        truncated: false,
+                                                        first_byte: Instant::now(),
+                                                        finished: None,
                                                         last_chunk_id: 0,
                                                         status: StoredResult::Pending
                                                     })
                                                 }
                                             } else {
-                                                println!("Missing response {}, wtf???", id);
+                                                warn!("Missing response {}", id);
                                             }
                                         },
                                         crate::proxy::ProxyState::ResponseChunk ( chunk ) => {
                                             store_mut.get_mut(id as usize)
                                                 .map(|pair| {
                                                     if let Some(resp) = pair.resp_mut() {
-                                                        resp.body.extend_from_slice(&chunk)
+                                                        append_capped(&mut resp.body, &mut resp.truncated, chunk, max_body_bytes.load(crate::ORDERING), spool_over.load(crate::ORDERING))
                                                     }
                                             });
 
@@ -266,38 +1768,125 @@ impl Store {
                                             if let Some(pair) = store_mut.get_mut(id as usize) {
                                                 if let Some(resp) = pair.resp_mut() {
                                                     resp.status = StoredResult::Ok;
+                                                    resp.finished = Some(Instant::now());
                                                 } else {
-                                                    println!("Response {} done but nothing stored????", id)
+                                                    warn!("Response {} done but nothing stored", id)
                                                 }
                                             }
 
                                         },
-                                        crate::proxy::ProxyState::UpgradeTx { id, chunk } => {
-
+                                        crate::proxy::ProxyState::UpgradeTx { id: chunk_id, chunk } => {
+                                            if let Some(pair) = store_mut.get_mut(id) {
+                                                pair.push_upgrade(UpgradeDirection::Tx, *chunk_id, chunk);
+                                                repaint = true;
+                                            }
                                         },
-                                        crate::proxy::ProxyState::UpgradeRx { id, chunk } => {
-
+                                        crate::proxy::ProxyState::UpgradeRx { id: chunk_id, chunk } => {
+                                            if let Some(pair) = store_mut.get_mut(id) {
+                                                pair.push_upgrade(UpgradeDirection::Rx, *chunk_id, chunk);
+                                                repaint = true;
+                                            }
                                         }
+                                        crate::proxy::ProxyState::Tunnel { host } => {
+                                            if len <= id {
+                                                store_mut.resize_with(id + 1, Default::default);
+                                            }
+                                            store_mut[id].id = request_id;
+                                            store_mut[id].tunnel = Some(StoredTunnel {
+                                                host: host.clone(),
+                                                started: Instant::now(),
+                                                closed: None,
+                                                error: None,
+                                            });
+                                            repaint = true;
+                                        },
+                                        crate::proxy::ProxyState::TunnelClose { sent, received } => {
+                                            if let Some(tunnel) = store_mut.get_mut(id).and_then(|pair| pair.tunnel.as_mut()) {
+                                                tunnel.closed = Some((*sent, *received));
+                                                repaint = true;
+                                            }
+                                        },
                                         crate::proxy::ProxyState::Error(e) => {
                                             if let Some(pair) = store_mut.get_mut(id) {
-                                                if let Some( resp ) = pair.resp_mut() {
-                                                    println!("Got error with stored rx: {}", id);
+                                                if let Some(tunnel) = pair.tunnel.as_mut() {
+                                                    tunnel.error = Some(e.clone());
+                                                } else if let Some( resp ) = pair.resp_mut() {
+                                                    debug!("Got error with stored rx: {}", id);
                                                     resp.status = StoredResult::Error(e.clone())
                                                 } else if let Some( req ) = pair.req_mut() {
-                                                    println!("Got error with stored tx: {}", id);
+                                                    debug!("Got error with stored tx: {}", id);
                                                     req.status = StoredResult::Error(e.clone())
                                                 } else {
-                                                    println!("Got error for {} but neither req or resp", id);
+                                                    warn!("Got error for {} but neither req or resp", id);
                                                 }
                                             }
                                         }
                                         _ => {} // None of the other enums do things with requests
                                     }
+                                    // Here rather than when drawing, so the limit holds even with nothing on screen
+                                    if matches!(event, ProxyState::RequestHead(_) | ProxyState::Tunnel { .. }) {
+                                        if let Some(max) = *max_entries.lock().unwrap() {
+                                            evict_oldest(&mut store_mut, max, base, &retained.lock().unwrap());
+                                        }
+                                    }
                                 }
                             }
-                            // Intercept/edit logic will go here
+                            match &event {
+                                ProxyState::ResponseDone => { body_rewrites.lock().unwrap().remove(&id); },
+                                ProxyState::RequestDone => { request_bodies.lock().unwrap().remove(&id); },
+                                ProxyState::RequestPreview(body) => { previews.insert(id, body.clone()); },
+                                _ => {},
+                            }
+                            let event = rewrite::apply(&rules.lock().unwrap(), &mut request_rewrites, id, event);
+                            // Taken whether or not the head gets parked, so nothing is left behind
+                            let preview = match &event {
+                                ProxyState::RequestHead(_) => previews.remove(&id),
+                                _ => None,
+                            };
+                            // Bodies only wait on a callback per chunk while something could change them. Anything parked or
+                            // already holding edits keeps that on, a resumed head or one still streaming may need it.
+                            if matches!(event, ProxyState::RequestHead(_) | ProxyState::ResponseHead(_)) {
+                                let editing = intercept.load(crate::ORDERING)
+                                    || intercept_responses.load(crate::ORDERING)
+                                    || !request_rewrites.is_empty()
+                                    || !request_bodies.lock().unwrap().is_empty()
+                                    || !body_rewrites.lock().unwrap().is_empty()
+                                    || !parked.lock().unwrap().is_empty()
+                                    || !parked_responses.lock().unwrap().is_empty();
+                                edit_bodies.store(editing, crate::ORDERING);
+                            }
                             if let Some(callback) = callback {
-                                callback.send(event).unwrap();
+                                match event {
+                                    ProxyState::RequestHead(head) if intercept.load(crate::ORDERING) && condition::should_park(&conditions.lock().unwrap(), &head) => {
+                                        parked.lock().unwrap().push(ParkedRequest::new(id, head, preview, callback));
+                                        repaint = true;
+                                    },
+                                    ProxyState::ResponseHead(head) if intercept_responses.load(crate::ORDERING) => {
+                                        parked_responses.lock().unwrap().push(ParkedResponse::new(id, head, callback));
+                                        repaint = true;
+                                    },
+                                    ProxyState::UpgradeTx { id: chunk_id, chunk } if intercept_upgrades.load(crate::ORDERING) => {
+                                        parked_frames.lock().unwrap().push(ParkedFrame::new(id, chunk_id, true, chunk, callback));
+                                        repaint = true;
+                                    },
+                                    ProxyState::UpgradeRx { id: chunk_id, chunk } if intercept_upgrades.load(crate::ORDERING) => {
+                                        parked_frames.lock().unwrap().push(ParkedFrame::new(id, chunk_id, false, chunk, callback));
+                                        repaint = true;
+                                    },
+                                    // The edited body goes out in place of the first chunk, anything after it is dropped
+                                    ProxyState::RequestChunk(chunk) if request_bodies.lock().unwrap().contains_key(&id) => {
+                                        let body = request_bodies.lock().unwrap().get_mut(&id).map(std::mem::take).unwrap_or(chunk);
+                                        let _ = callback.send(ProxyState::RequestChunk(body));
+                                    },
+                                    ProxyState::ResponseChunk(chunk) => {
+                                        let rewritten = body_rewrites.lock().unwrap()
+                                            .get(&id)
+                                            .map(|rewrite| Bytes::from(intercept::rewrite_chunk(&chunk, rewrite)));
+                                        let _ = callback.send(ProxyState::ResponseChunk(rewritten.unwrap_or(chunk)));
+                                    },
+                                    // Nobody is waiting any more when the client hung up in the meantime
+                                    event => { let _ = callback.send(event); },
+                                }
                             };
                         },
                         None => break
@@ -311,4 +1900,287 @@ impl Store {
             }
         ))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use hyper::{Body, Method, Request, Response, Server, StatusCode, Version};
+    use hyper::service::{make_service_fn, service_fn};
+    use crate::proxy::{Listen, ProxyConfig};
+
+    // A proxy with a CA of its own in a fresh temp dir. Nothing is listening, requests go in through replay.
+    fn test_proxy(name: &str) -> (ProxyServer, Receiver<ProxyEvent>) {
+        let dir = std::env::temp_dir().join(format!("stain-store-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        ProxyConfig {
+            pubkey_path: dir.join("cert").to_string_lossy().into_owned(),
+            privkey_path: dir.join("key").to_string_lossy().into_owned(),
+            listen: Listen::Tcp(SocketAddr::from(([127, 0, 0, 1], 0))),
+            key_algorithm: ::proxy::KeyAlgorithm::EcdsaP256,
+            ..ProxyConfig::default()
+        }.build().unwrap()
+    }
+
+    // A store recording everything that goes through its own proxy
+    fn subscribed(name: &str) -> (Store, ProxyServer) {
+        let (server, events) = test_proxy(name);
+        let mut store = Store::new();
+        store.subscribe(events, server.metrics(), server.edit_bodies(), server.rewrite_rules());
+        store.set_proxy(server.clone());
+        (store, server)
+    }
+
+    // An origin answering every request with `body`
+    fn stub(body: &'static str) -> SocketAddr {
+        let make = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_: Request<Body>| async move {
+                Ok::<_, Infallible>(Response::new(Body::from(body)))
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn get(uri: String) -> RequestHead {
+        RequestHead { method: Method::GET, uri: uri.parse().unwrap(), version: Version::HTTP_11, headers: HeaderMap::new() }
+    }
+
+    fn response_body(pair: &StoredPair) -> Option<Vec<u8>> {
+        let resp = pair.response.as_ref().filter(|resp| resp.status == StoredResult::Ok)?;
+        Some(resp.body.read().unwrap().into_owned())
+    }
+
+    // Gives the subscriber up to five seconds to get the cache to where `done` says
+    async fn wait_for(store: &Store, done: impl Fn(&[StoredPair]) -> bool) {
+        for _ in 0..500 {
+            if done(&store.store.cache.read().unwrap()) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Timed out waiting on the store");
+    }
+
+    #[tokio::test]
+    async fn replay_all_against_a_new_host() {
+        let (mut store, server) = subscribed("replay-all");
+        let (old, new) = (stub("old"), stub("new"));
+        server.replay(get(format!("http://{}/one", old)), Vec::new());
+        server.replay(get(format!("http://{}/two", old)), Vec::new());
+        wait_for(&store, |cache| cache.iter().filter(|pair| response_body(pair).is_some()).count() == 2).await;
+        assert_eq!(store.replay_all_against(format!("http://{}", new).parse().unwrap()), Ok(2));
+        wait_for(&store, |cache| cache.iter().filter(|pair| response_body(pair).is_some()).count() == 4).await;
+        let cache = store.store.cache.read().unwrap();
+        let mut replays: Vec<(usize, usize)> = store.replays.iter().map(|(&replay, &original)| (replay, original)).collect();
+        replays.sort_unstable();
+        assert_eq!(replays, vec![(2, 0), (3, 1)]);
+        for (replay, original) in replays {
+            let (replay, original) = (&cache[replay], &cache[original]);
+            assert_eq!(response_body(original).unwrap(), b"old");
+            assert_eq!(response_body(replay).unwrap(), b"new");
+            assert_eq!(replay.request.as_ref().unwrap().head.uri.path(), original.request.as_ref().unwrap().head.uri.path());
+            assert_eq!(replay.response.as_ref().unwrap().head.status, StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn clear_starts_ids_over() {
+        let (mut store, server) = subscribed("clear");
+        let origin = stub("body");
+        server.replay(get(format!("http://{}/one", origin)), Vec::new());
+        server.replay(get(format!("http://{}/two", origin)), Vec::new());
+        wait_for(&store, |cache| cache.iter().filter(|pair| response_body(pair).is_some()).count() == 2).await;
+        store.clear();
+        assert_eq!(server.next_id(), 1);
+        let (id, _) = server.replay(get(format!("http://{}/three", origin)), Vec::new());
+        assert_eq!(id, 1);
+        wait_for(&store, |cache| cache.first().and_then(response_body).is_some()).await;
+        let cache = store.store.cache.read().unwrap();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache[0].request.as_ref().unwrap().head.uri.path(), "/three");
+    }
+
+    #[tokio::test]
+    async fn clear_with_a_request_in_flight() {
+        let (mut store, server) = subscribed("clear-in-flight");
+        let make = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_: Request<Body>| async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                Ok::<_, Infallible>(Response::new(Body::from("late")))
+            }))
+        });
+        let slow = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make);
+        let origin = slow.local_addr();
+        tokio::spawn(slow);
+        let (_, replay) = server.replay(get(format!("http://{}/slow", origin)), Vec::new());
+        wait_for(&store, |cache| cache.first().is_some_and(|pair| pair.request.is_some())).await;
+        store.clear();
+        // Request 1 is still waiting on its response, so the ids carry on past it
+        assert_eq!(server.next_id(), 2);
+        replay.await.unwrap().unwrap();
+        let origin = stub("body");
+        let (id, _) = server.replay(get(format!("http://{}/next", origin)), Vec::new());
+        assert_eq!(id, 2);
+        wait_for(&store, |cache| cache.first().and_then(response_body).is_some()).await;
+        let cache = store.store.cache.read().unwrap();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(response_body(&cache[0]).unwrap(), b"body");
+    }
+
+    #[tokio::test]
+    async fn sidebar_rows_skip_gaps() {
+        let (mut store, server) = subscribed("sidebar-gap");
+        let origin = stub("body");
+        server.replay(get(format!("http://{}/1", origin)), Vec::new());
+        wait_for(&store, |cache| cache.first().and_then(response_body).is_some()).await;
+        // An id nothing is captured under leaves an empty slot between the two
+        server.reserve_ids(1);
+        server.replay(get(format!("http://{}/3", origin)), Vec::new());
+        wait_for(&store, |cache| cache.len() == 3 && cache.last().and_then(response_body).is_some()).await;
+        assert!(store.store.cache.read().unwrap()[1].is_empty());
+        assert_eq!(store.sidebar_rows(), 2);
+        // The second row on screen is the request after the gap, not the gap
+        assert!(matches!(store.rows[1], SidebarRow::Pair(2)));
+        let cache = store.store.cache.read().unwrap();
+        assert_eq!(cache[2].request.as_ref().unwrap().head.uri.path(), "/3");
+    }
+
+    #[tokio::test]
+    async fn head_response_finishes() {
+        let (store, server) = subscribed("head");
+        let origin = stub("not sent for a HEAD");
+        let head = RequestHead { method: Method::HEAD, ..get(format!("http://{}/", origin)) };
+        server.replay(head, Vec::new());
+        wait_for(&store, |cache| cache.first().and_then(|pair| pair.response.as_ref()).is_some_and(|resp| resp.status == StoredResult::Ok)).await;
+        let cache = store.store.cache.read().unwrap();
+        assert!(response_body(&cache[0]).unwrap().is_empty());
+    }
+
+    // A store fed events straight from the test, in whatever order it likes
+    fn fed() -> (Store, tokio::sync::mpsc::Sender<ProxyEvent>) {
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let mut store = Store::new();
+        store.subscribe(rx, Arc::new(Metrics::default()), Arc::new(AtomicBool::new(false)), Arc::default());
+        (store, tx)
+    }
+
+    #[tokio::test]
+    async fn heads_out_of_order() {
+        let (mut store, events) = fed();
+        for id in [3, 1, 2] {
+            let (event, _) = ProxyEvent::req_head(id, &get(format!("http://example.com/{}", id)));
+            events.send(event).await.unwrap();
+        }
+        wait_for(&store, |cache| cache.len() == 3 && cache.iter().all(|pair| pair.request.is_some())).await;
+        assert_eq!(live(&store.store.cache.read().unwrap()), ["/1", "/2", "/3"]);
+        assert_eq!(store.sidebar_rows(), 3);
+    }
+
+    #[tokio::test]
+    async fn chunks_ahead_of_their_head() {
+        let (store, events) = fed();
+        events.send(ProxyEvent::observed(1, ProxyState::RequestChunk(Bytes::from_static(b"early ")))).await.unwrap();
+        events.send(ProxyEvent::observed(1, ProxyState::RequestChunk(Bytes::from_static(b"chunks ")))).await.unwrap();
+        let (head, _) = ProxyEvent::req_head(1, &get("http://example.com/".to_string()));
+        events.send(head).await.unwrap();
+        events.send(ProxyEvent::observed(1, ProxyState::RequestChunk(Bytes::from_static(b"then more")))).await.unwrap();
+        events.send(ProxyEvent::req_done(1)).await.unwrap();
+        wait_for(&store, |cache| cache.first().and_then(|pair| pair.request.as_ref()).is_some_and(|req| req.status == StoredResult::Ok)).await;
+        let cache = store.store.cache.read().unwrap();
+        let req = cache[0].request.as_ref().unwrap();
+        assert_eq!(&req.body.read().unwrap()[..], b"early chunks then more");
+    }
+
+    fn live(cache: &[StoredPair]) -> Vec<String> {
+        cache.iter()
+            .filter_map(|pair| Some(pair.request.as_ref()?.head.uri.path().to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn evicts_as_captures_come_in() {
+        let (mut store, server) = subscribed("evict");
+        store.set_max_entries(Some(2));
+        let origin = stub("body");
+        for path in ["/1", "/2", "/3", "/4"] {
+            server.replay(get(format!("http://{}{}", origin, path)), Vec::new());
+        }
+        // Without anything drawing the sidebar
+        wait_for(&store, |cache| cache.len() == 4 && live(cache).len() == 2 && cache.iter().all(|pair| pair.is_empty() || response_body(pair).is_some())).await;
+        assert_eq!(live(&store.store.cache.read().unwrap()), ["/3", "/4"]);
+    }
+
+    #[tokio::test]
+    async fn eviction_goes_past_retained_captures() {
+        let (mut store, server) = subscribed("evict-retained");
+        let origin = stub("body");
+        for path in ["/1", "/2", "/3"] {
+            server.replay(get(format!("http://{}{}", origin, path)), Vec::new());
+            wait_for(&store, |cache| cache.last().and_then(response_body).is_some()).await;
+        }
+        store.kept.insert(0);
+        store.select(1, false);
+        store.set_max_entries(Some(3));
+        server.replay(get(format!("http://{}/4", origin)), Vec::new());
+        server.replay(get(format!("http://{}/5", origin)), Vec::new());
+        wait_for(&store, |cache| cache.len() == 5 && cache.iter().all(|pair| pair.is_empty() || response_body(pair).is_some())).await;
+        assert_eq!(live(&store.store.cache.read().unwrap()), ["/1", "/2", "/5"]);
+        // The kept one at the front holds the emptied slots behind it in place
+        assert_eq!(store.sidebar_rows(), 3);
+        assert_eq!(store.store.cache.read().unwrap().len(), 5);
+        store.kept.clear();
+        store.set_max_entries(Some(1));
+        store.sidebar_rows();
+        assert_eq!(live(&store.store.cache.read().unwrap()), ["/2"]);
+        assert_eq!(store.store.cache.read().unwrap().len(), 4);
+        assert_eq!(store.tabs, [0]);
+    }
+
+    fn monospace(text: &str) -> f32 {
+        text.chars().count() as f32 * 8.0
+    }
+
+    // Narrow i, wide w and m, like a proportional font
+    fn proportional(text: &str) -> f32 {
+        text.chars().map(|c| match c {
+            'i' | 'l' | '.' | ' ' => 3.0,
+            'm' | 'w' | 'M' | 'W' => 12.0,
+            _ => 7.0,
+        }).sum()
+    }
+
+    #[test]
+    fn sidebar_line_pads_to_the_edge() {
+        let line = sidebar_line("GET", "/", "12ms", 160.0, monospace);
+        assert_eq!(line, format!("GET /{}12ms", " ".repeat(11)));
+        assert_eq!(monospace(&line), 160.0);
+    }
+
+    #[test]
+    fn sidebar_line_cuts_the_path() {
+        let line = sidebar_line("GET", "/a/long/path/to/somewhere", "1s", 160.0, monospace);
+        assert_eq!(line, "GET /a/long/pa... 1s");
+        assert!(monospace(&line) <= 160.0);
+        let line = sidebar_line("GET", "/wwwwwwwwwwiiiiiiiiii", "1s", 120.0, proportional);
+        assert!(line.starts_with("GET /w") && line.ends_with("... 1s"));
+        assert!(proportional(&line) <= 120.0);
+        // Never cut inside a char
+        let line = sidebar_line("GET", "/\u{65e5}\u{672c}\u{8a9e}\u{65e5}\u{672c}\u{8a9e}", "1s", 100.0, monospace);
+        assert_eq!(line, "GET /\u{65e5}... 1s");
+    }
+
+    #[test]
+    fn sidebar_line_with_no_room() {
+        let method = "- x12 [R] PROPFIND".repeat(10);
+        for width in [-10.0, 0.0, 8.0, 100.0] {
+            assert_eq!(sidebar_line(&method, "/path", "1s", width, monospace), format!("{} ... 1s", method));
+            assert_eq!(sidebar_line(&method, "/path", "1s", width, proportional), format!("{} ... 1s", method));
+        }
+        assert_eq!(sidebar_line("GET", "", "", 0.0, monospace), "GET ... ");
+    }
+}
@@ -0,0 +1,176 @@
+use eframe::egui::{Ui, Grid, TextEdit, Color32};
+use hyper::http::{HeaderValue, header::{CONTENT_LENGTH, CONTENT_TYPE, HOST}};
+use hyper::{Method, Uri, Version};
+
+use crate::proxy::request::RequestHead;
+use super::intercept::{editable_headers, parse_headers, draw_headers};
+
+pub enum ResendAction {
+    None,
+    Send(Box<RequestHead>, Vec<u8>),
+    Close,
+}
+
+// Bodies that aren't text can't go through a text box, they're sent as captured
+enum EditableBody {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+// A copy of a captured request the user can change before sending it again as a new capture, or one written from
+// scratch. Either way it goes out through the proxy and is captured like any other.
+pub struct ResendEditor {
+    pub source: Option<usize>, // Cache index of the capture it was opened from, None when composed from scratch
+    method: String,
+    uri: String,
+    version: Version,
+    headers: Vec<(String, String)>,
+    body: EditableBody,
+    truncated: bool,
+    error: Option<String>,
+    pub notice: String,
+}
+
+impl ResendEditor {
+    pub fn new(source: usize, head: &RequestHead, body: Vec<u8>, truncated: bool) -> Self {
+        Self {
+            source: Some(source),
+            method: head.method.to_string(),
+            uri: head.uri.to_string(),
+            version: head.version,
+            headers: editable_headers(&head.headers),
+            body: match String::from_utf8(body) {
+                Ok(text) => EditableBody::Text(text),
+                Err(e) => EditableBody::Binary(e.into_bytes()),
+            },
+            truncated,
+            error: None,
+            notice: String::new(),
+        }
+    }
+
+    pub fn blank() -> Self {
+        Self {
+            source: None,
+            method: "GET".to_string(),
+            uri: "https://".to_string(),
+            version: Version::HTTP_11,
+            headers: vec![(String::new(), String::new())],
+            body: EditableBody::Text(String::new()),
+            truncated: false,
+            error: None,
+            notice: String::new(),
+        }
+    }
+
+    fn edited(&self) -> Result<(RequestHead, Vec<u8>), String> {
+        let method = Method::from_bytes(self.method.trim().as_bytes())
+            .map_err(|_| format!("Invalid method {:?}", self.method))?;
+        let uri: Uri = self.uri.trim().parse()
+            .map_err(|e| format!("Invalid URI: {}", e))?;
+        // Replays are sent straight to the origin, there's no connection to fall back on for the host
+        if uri.scheme().is_none() || uri.authority().is_none() {
+            return Err(format!("{} needs a scheme and host, e.g. https://example.com/", uri));
+        }
+        if !matches!(uri.scheme_str(), Some("http") | Some("https")) {
+            return Err(format!("Only http and https can be sent, not {}", uri.scheme_str().unwrap_or_default()));
+        }
+        let mut headers = parse_headers(&self.headers)?;
+        let body = match &self.body {
+            EditableBody::Text(text) => text.clone().into_bytes(),
+            EditableBody::Binary(bytes) => bytes.clone(),
+        };
+        // A stale length would have the origin waiting on bytes that never come, or cut the body short
+        if headers.contains_key(CONTENT_LENGTH) {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        }
+        Ok((RequestHead { method, uri, version: self.version, headers }, body))
+    }
+
+    // Things that will go out as asked but are probably not what was meant
+    fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let headers: Vec<(String, &str)> = self.headers.iter()
+            .filter(|(name, _)| !name.trim().is_empty())
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.as_str()))
+            .collect();
+        for (name, value) in &headers {
+            if name.contains(':') || name.contains(' ') {
+                warnings.push(format!("Header name {:?} looks like a whole line, the value goes in the second box", name));
+            } else if value.trim().is_empty() {
+                warnings.push(format!("Header {} has no value", name));
+            } else if value.trim() != *value {
+                warnings.push(format!("Header {} has spaces around its value", name));
+            }
+        }
+        let count = |wanted: &str| headers.iter().filter(|(name, _)| name == wanted).count();
+        for name in [HOST.as_str(), CONTENT_TYPE.as_str()] {
+            if count(name) > 1 {
+                warnings.push(format!("{} is given {} times", name, count(name)));
+            }
+        }
+        if let (Ok(uri), Some((_, host))) = (self.uri.trim().parse::<Uri>(), headers.iter().find(|(name, _)| name == HOST.as_str())) {
+            if uri.authority().map(|authority| !authority.as_str().eq_ignore_ascii_case(host.trim())).unwrap_or(false) {
+                warnings.push(format!("Host {} doesn't match the URI, the request still goes to {}", host.trim(), uri.authority().unwrap()));
+            }
+        }
+        let has_body = match &self.body {
+            EditableBody::Text(text) => !text.is_empty(),
+            EditableBody::Binary(bytes) => !bytes.is_empty(),
+        };
+        if has_body && matches!(self.method.trim().to_ascii_uppercase().as_str(), "GET" | "HEAD") {
+            warnings.push(format!("{} with a body, many servers ignore or reject it", self.method.trim()));
+        }
+        if has_body && count(CONTENT_TYPE.as_str()) == 0 {
+            warnings.push("Body without a Content-Type".to_string());
+        }
+        warnings
+    }
+
+    pub fn draw(&mut self, ui: &mut Ui) -> ResendAction {
+        Grid::new(("resend", self.source)).num_columns(2).show(ui, |ui| {
+            ui.label("Method");
+            ui.text_edit_singleline(&mut self.method);
+            ui.end_row();
+            ui.label("URI");
+            ui.add(TextEdit::singleline(&mut self.uri).desired_width(f32::INFINITY));
+            ui.end_row();
+        });
+        draw_headers(ui, ("resend headers", self.source.map(|source| source as u32).unwrap_or(u32::MAX)), &mut self.headers);
+        ui.label("Body");
+        if self.truncated {
+            ui.colored_label(Color32::YELLOW, "The captured body was truncated, only what was kept will be sent");
+        }
+        match &mut self.body {
+            EditableBody::Text(text) => {
+                ui.add(TextEdit::multiline(text).code_editor().desired_width(f32::INFINITY));
+            },
+            EditableBody::Binary(bytes) => {
+                ui.label(format!("{} bytes of binary, sent as captured", bytes.len()));
+            },
+        }
+        for warning in self.warnings() {
+            ui.colored_label(Color32::YELLOW, warning);
+        }
+        if let Some(error) = &self.error {
+            ui.colored_label(Color32::RED, error);
+        }
+        let mut action = ResendAction::None;
+        ui.horizontal(|ui| {
+            if ui.button("Send").clicked() {
+                match self.edited() {
+                    Ok((head, body)) => {
+                        self.error = None;
+                        action = ResendAction::Send(Box::new(head), body);
+                    },
+                    Err(e) => self.error = Some(e),
+                }
+            }
+            if ui.button("Close").clicked() {
+                action = ResendAction::Close;
+            }
+            ui.label(&self.notice);
+        });
+        action
+    }
+}
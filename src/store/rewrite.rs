@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+
+use eframe::egui::{Ui, TextEdit, Color32};
+use hyper::body::Bytes;
+use hyper::http::header::CONTENT_LENGTH;
+
+use crate::proxy::ProxyState;
+use crate::proxy::rewrite::{RewriteAction, RewriteRule};
+use super::intercept::{BodyRewrite, rewrite_chunk};
+
+fn draw_rule(ui: &mut Ui, rule: &mut RewriteRule, idx: usize) -> bool {
+    let mut changed = false;
+    let mut removed = false;
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut rule.enabled, format!("Rule {}", idx + 1));
+        for (label, pattern) in [("Host", &mut rule.host), ("Path", &mut rule.path), ("Header", &mut rule.header)] {
+            ui.label(label);
+            changed |= ui.add(TextEdit::singleline(pattern).desired_width(120.0)).changed();
+        }
+        removed = ui.small_button("x").clicked();
+    });
+    ui.horizontal(|ui| {
+        let kinds = [
+            RewriteAction::SetHeader { name: String::new(), value: String::new() },
+            RewriteAction::ReplaceBody { find: String::new(), replace: String::new() },
+            RewriteAction::RewriteUri { pattern: String::new(), replacement: String::new() },
+        ];
+        for kind in kinds {
+            let selected = std::mem::discriminant(&kind) == std::mem::discriminant(&rule.action);
+            if ui.selectable_label(selected, kind.name()).clicked() && !selected {
+                rule.action = kind;
+                changed = true;
+            }
+        }
+    });
+    ui.horizontal(|ui| {
+        let (first, second, labels) = match &mut rule.action {
+            RewriteAction::SetHeader { name, value } => (name, value, ("Name", "Value")),
+            RewriteAction::ReplaceBody { find, replace } => (find, replace, ("Find", "Replace with")),
+            RewriteAction::RewriteUri { pattern, replacement } => (pattern, replacement, ("Pattern", "Replacement")),
+        };
+        ui.label(labels.0);
+        changed |= ui.add(TextEdit::singleline(first).desired_width(160.0)).changed();
+        ui.label(labels.1);
+        changed |= ui.add(TextEdit::singleline(second).desired_width(160.0)).changed();
+    });
+    if changed {
+        rule.recompile();
+    }
+    if let Some(e) = rule.error() {
+        ui.colored_label(Color32::RED, e);
+    }
+    removed
+}
+
+// Run the rules over an event before it goes back to the proxy. Heads are edited in place, and body rewrites
+// picked up from them are remembered by id in `pending` until that request's body is done.
+pub fn apply(rules: &[RewriteRule], pending: &mut HashMap<u32, Vec<BodyRewrite>>, id: u32, event: ProxyState) -> ProxyState {
+    match event {
+        ProxyState::RequestHead(mut head) => {
+            let mut body = Vec::new();
+            // In order, so a later rule sees what earlier ones did to the head
+            for rule in rules {
+                if rule.matches(&head) {
+                    body.extend(rule.apply(&mut head));
+                }
+            }
+            if !body.is_empty() {
+                // We can't know the new length up front, so let hyper fall back to chunked encoding
+                if body.iter().any(|(find, replace)| find.len() != replace.len()) {
+                    head.headers.remove(CONTENT_LENGTH);
+                }
+                pending.insert(id, body);
+            }
+            ProxyState::RequestHead(head)
+        },
+        ProxyState::RequestChunk(chunk) => match pending.get(&id) {
+            Some(rewrites) => ProxyState::RequestChunk(Bytes::from(
+                rewrites.iter().fold(chunk.to_vec(), |chunk, rewrite| rewrite_chunk(&chunk, rewrite))
+            )),
+            None => ProxyState::RequestChunk(chunk),
+        },
+        ProxyState::RequestDone => {
+            pending.remove(&id);
+            ProxyState::RequestDone
+        },
+        event => event,
+    }
+}
+
+pub fn draw_rules(ui: &mut Ui, rules: &mut Vec<RewriteRule>) {
+    let mut removed = None;
+    for (idx, rule) in rules.iter_mut().enumerate() {
+        if draw_rule(ui, rule, idx) {
+            removed = Some(idx);
+        }
+        ui.separator();
+    }
+    if let Some(idx) = removed {
+        rules.remove(idx);
+    }
+    if ui.button("Add rule").clicked() {
+        rules.push(RewriteRule::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Method, Version};
+    use hyper::http::HeaderMap;
+    use crate::proxy::request::RequestHead;
+
+    fn request(uri: &str) -> RequestHead {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, "11".parse().unwrap());
+        RequestHead { method: Method::POST, uri: uri.parse().unwrap(), version: Version::HTTP_11, headers }
+    }
+
+    fn rule(host: &str, action: RewriteAction) -> RewriteRule {
+        let mut rule = RewriteRule::new(action);
+        rule.host = host.to_string();
+        rule
+    }
+
+    fn sent_head(event: ProxyState) -> RequestHead {
+        match event {
+            ProxyState::RequestHead(head) => head,
+            other => panic!("expected a head, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn injects_headers_into_matching_requests() {
+        let rules = [
+            rule("^api\\.", RewriteAction::SetHeader { name: "x-injected".to_string(), value: "1".to_string() }),
+            rule("", RewriteAction::SetHeader { name: "x-injected".to_string(), value: "2".to_string() }),
+            rule("^www\\.", RewriteAction::SetHeader { name: "x-other".to_string(), value: "1".to_string() }),
+        ];
+        let mut pending = HashMap::new();
+        let head = sent_head(apply(&rules, &mut pending, 1, ProxyState::RequestHead(request("http://api.example.com/"))));
+        // Both matching rules ran, in order, and the one for another host didn't
+        assert_eq!(head.headers.get("x-injected").unwrap(), "2");
+        assert!(head.headers.get("x-other").is_none());
+        assert!(pending.is_empty());
+
+        let mut disabled = rules.clone();
+        disabled[1].enabled = false;
+        let head = sent_head(apply(&disabled, &mut pending, 2, ProxyState::RequestHead(request("http://api.example.com/"))));
+        assert_eq!(head.headers.get("x-injected").unwrap(), "1");
+    }
+
+    #[test]
+    fn substitutes_in_the_body_until_it_is_done() {
+        let rules = [rule("", RewriteAction::ReplaceBody { find: "secret".to_string(), replace: "[redacted]".to_string() })];
+        let mut pending = HashMap::new();
+        let head = sent_head(apply(&rules, &mut pending, 1, ProxyState::RequestHead(request("http://example.com/"))));
+        // The new length isn't known up front
+        assert!(head.headers.get(CONTENT_LENGTH).is_none());
+        for (id, expected) in [(1, &b"a [redacted] b"[..]), (2, &b"a secret b"[..])] {
+            match apply(&rules, &mut pending, id, ProxyState::RequestChunk(Bytes::from_static(b"a secret b"))) {
+                ProxyState::RequestChunk(chunk) => assert_eq!(&chunk[..], expected),
+                other => panic!("expected a chunk, got {:?}", other),
+            }
+        }
+        apply(&rules, &mut pending, 1, ProxyState::RequestDone);
+        assert!(pending.is_empty());
+    }
+}
@@ -0,0 +1,278 @@
+use std::borrow::Cow;
+use std::io::IoSlice;
+use std::time::{Duration, Instant};
+
+use futures::{AsyncRead, AsyncWrite};
+use futures::{AsyncReadExt, AsyncWriteExt};
+use hyper::StatusCode;
+
+use crate::proxy::{ProxyError, TlsInfo};
+use super::storable::{self, Storable, Fields, put, put_u32, put_u64, put_option, put_request, put_response, invalid};
+use super::spool::BodyBuffer;
+use super::websocket::WebSocket;
+use super::{StoredPair, StoredRequest, StoredResponse, StoredResult, StoredTunnel, UpgradeFrame, UpgradeDirection};
+
+const MAGIC: &[u8; 8] = b"STAINSES";
+
+// Bumped whenever the layout of the pack changes, older files are refused rather than misread
+pub const VERSION: u32 = 4;
+
+// The file is the magic, the version as a little endian u32, then the pack through to the end
+pub struct SessionHeader {
+    pub len: usize,
+    pub spool_over: usize, // Not in the file, bodies loaded back past this go to disk like captured ones would
+}
+
+// Every slot of the cache, evicted ones included, so indices line up the same once loaded. Instants can't be written
+// out as they are, each one goes in as how long before `at` it was and comes back that long before the time of loading.
+pub struct Session<'a> {
+    pub pairs: Cow<'a, [StoredPair]>,
+    at: Instant,
+}
+
+fn put_instant(buf: &mut Vec<u8>, at: Instant, instant: &Instant) {
+    put_u64(buf, at.saturating_duration_since(*instant).as_nanos() as u64);
+}
+
+fn take_instant(fields: &mut Fields, at: Instant) -> std::io::Result<Instant> {
+    let before = Duration::from_nanos(fields.u64()?);
+    Ok(at.checked_sub(before).unwrap_or(at))
+}
+
+fn put_body(buf: &mut Vec<u8>, body: &BodyBuffer) {
+    // A spool file that's gone missing leaves the body empty, same as it would show in the GUI
+    put(buf, &body.read().unwrap_or_default());
+}
+
+fn put_error(buf: &mut Vec<u8>, e: &ProxyError) {
+    let (kind, msg) = match e {
+        ProxyError::Timeout(msg) => (0, msg),
+        ProxyError::ConnectionReset(msg) => (1, msg),
+        ProxyError::ConnectionRefused(msg) => (2, msg),
+        ProxyError::Dns(msg) => (3, msg),
+        ProxyError::Status(status) => {
+            put(buf, &[4]);
+            put(buf, &status.as_u16().to_le_bytes());
+            return;
+        },
+        ProxyError::TlsHandshake { host, msg } => {
+            put(buf, &[5]);
+            put_option(buf, host.as_ref(), |buf, host| put(buf, host.as_bytes()));
+            put(buf, msg.as_bytes());
+            return;
+        },
+        ProxyError::UpstreamCert(msg) => (6, msg),
+        ProxyError::Other(msg) => (7, msg),
+    };
+    put(buf, &[kind]);
+    put(buf, msg.as_bytes());
+}
+
+fn take_error(fields: &mut Fields) -> std::io::Result<ProxyError> {
+    Ok(match fields.u8()? {
+        0 => ProxyError::Timeout(fields.string()?),
+        1 => ProxyError::ConnectionReset(fields.string()?),
+        2 => ProxyError::ConnectionRefused(fields.string()?),
+        3 => ProxyError::Dns(fields.string()?),
+        4 => {
+            let status = u16::from_le_bytes(fields.take()?.try_into().map_err(|_| invalid("Bad error status".to_string()))?);
+            ProxyError::Status(StatusCode::from_u16(status).map_err(|e| invalid(format!("Bad error status {}: {}", status, e)))?)
+        },
+        5 => ProxyError::TlsHandshake { host: fields.option(Fields::string)?, msg: fields.string()? },
+        6 => ProxyError::UpstreamCert(fields.string()?),
+        7 => ProxyError::Other(fields.string()?),
+        other => return Err(invalid(format!("Unknown error kind {}", other))),
+    })
+}
+
+fn put_result(buf: &mut Vec<u8>, result: &StoredResult) {
+    match result {
+        StoredResult::Pending => put(buf, &[0]),
+        StoredResult::Ok => put(buf, &[1]),
+        StoredResult::Error(e) => {
+            put(buf, &[2]);
+            put_error(buf, e);
+        },
+    }
+}
+
+fn take_result(fields: &mut Fields) -> std::io::Result<StoredResult> {
+    match fields.u8()? {
+        0 => Ok(StoredResult::Pending),
+        1 => Ok(StoredResult::Ok),
+        2 => take_error(fields).map(StoredResult::Error),
+        other => Err(invalid(format!("Unknown result {}", other))),
+    }
+}
+
+fn put_pair(buf: &mut Vec<u8>, at: Instant, pair: &StoredPair) {
+    put_u32(buf, pair.id);
+    put_option(buf, pair.request.as_ref(), |buf, req: &StoredRequest| {
+        put_request(buf, &req.head);
+        put_body(buf, &req.body);
+        put(buf, &[req.truncated as u8]);
+        put_instant(buf, at, &req.started);
+        put_u32(buf, req.last_chunk_id);
+        put_result(buf, &req.status);
+        put_option(buf, req.body_hash.as_ref(), |buf, hash| put_u64(buf, *hash));
+        put_u64(buf, req.uploaded as u64);
+    });
+    put_option(buf, pair.response.as_ref(), |buf, resp: &StoredResponse| {
+        put_response(buf, &resp.head);
+        put_body(buf, &resp.body);
+        put(buf, &[resp.truncated as u8]);
+        put_instant(buf, at, &resp.first_byte);
+        put_option(buf, resp.finished.as_ref(), |buf, finished| put_instant(buf, at, finished));
+        put_u32(buf, resp.last_chunk_id);
+        put_result(buf, &resp.status);
+    });
+    put_u32(buf, pair.upgrade.len() as u32);
+    for frame in &pair.upgrade {
+        put(buf, &[(frame.direction == UpgradeDirection::Rx) as u8]);
+        put_u32(buf, frame.chunk_id);
+        put(buf, &frame.bytes);
+    }
+    put_option(buf, pair.websocket.as_ref(), |buf, websocket| {
+        let mut pack = Vec::new();
+        websocket.pack(&mut pack);
+        put(buf, &pack);
+    });
+    put_option(buf, pair.tunnel.as_ref(), |buf, tunnel: &StoredTunnel| {
+        put(buf, tunnel.host.as_bytes());
+        put_instant(buf, at, &tunnel.started);
+        put_option(buf, tunnel.closed.as_ref(), |buf, (sent, received)| {
+            put_u64(buf, *sent);
+            put_u64(buf, *received);
+        });
+        put_option(buf, tunnel.error.as_ref(), put_error);
+    });
+    put_option(buf, pair.tag.as_ref(), |buf, tag| put(buf, tag.as_bytes()));
+    put(buf, pair.note.as_bytes());
+    put_option(buf, pair.mirror_of.as_ref(), |buf, of| put_u32(buf, *of));
+    put_option(buf, pair.redirect_from.as_ref(), |buf, from| put_u32(buf, *from));
+    put_option(buf, pair.tls.as_ref(), |buf, tls: &TlsInfo| {
+        put(buf, tls.version.as_bytes());
+        put(buf, tls.cipher.as_bytes());
+        put_option(buf, tls.alpn.as_ref(), |buf, alpn| put(buf, alpn.as_bytes()));
+    });
+}
+
+fn take_flag(fields: &mut Fields) -> std::io::Result<bool> {
+    match fields.u8()? {
+        0 => Ok(false),
+        1 => Ok(true),
+        other => Err(invalid(format!("Bad flag {}", other))),
+    }
+}
+
+fn take_pair(fields: &mut Fields, at: Instant, spool_over: usize) -> std::io::Result<StoredPair> {
+    let id = fields.u32()?;
+    let body = |fields: &mut Fields| BodyBuffer::from_bytes(fields.take()?.to_vec(), spool_over);
+    let request = fields.option(|fields| Ok(StoredRequest {
+        head: fields.request()?,
+        body: body(fields)?,
+        truncated: take_flag(fields)?,
+        started: take_instant(fields, at)?,
+        last_chunk_id: fields.u32()?,
+        status: take_result(fields)?,
+        body_hash: fields.option(Fields::u64)?,
+        uploaded: fields.u64()? as usize,
+    }))?;
+    let response = fields.option(|fields| Ok(StoredResponse {
+        head: fields.response()?,
+        body: body(fields)?,
+        truncated: take_flag(fields)?,
+        first_byte: take_instant(fields, at)?,
+        finished: fields.option(|fields| take_instant(fields, at))?,
+        last_chunk_id: fields.u32()?,
+        status: take_result(fields)?,
+    }))?;
+    let upgrade = (0..fields.u32()?)
+        .map(|_| Ok(UpgradeFrame {
+            direction: if take_flag(fields)? { UpgradeDirection::Rx } else { UpgradeDirection::Tx },
+            chunk_id: fields.u32()?,
+            bytes: fields.take()?.to_vec(),
+        }))
+        .collect::<std::io::Result<_>>()?;
+    let websocket = fields.option(|fields| {
+        let pack = fields.take()?;
+        WebSocket::unpack(&pack.len(), pack).map(Box::new)
+    })?;
+    let tunnel = fields.option(|fields| Ok(StoredTunnel {
+        host: fields.string()?,
+        started: take_instant(fields, at)?,
+        closed: fields.option(|fields| Ok((fields.u64()?, fields.u64()?)))?,
+        error: fields.option(take_error)?,
+    }))?;
+    Ok(StoredPair {
+        id,
+        request,
+        response,
+        upgrade,
+        websocket,
+        tunnel,
+        tag: fields.option(Fields::string)?,
+        note: fields.string()?,
+        mirror_of: fields.option(Fields::u32)?,
+        redirect_from: fields.option(Fields::u32)?,
+        tls: fields.option(|fields| Ok(TlsInfo {
+            version: fields.string()?,
+            cipher: fields.string()?,
+            alpn: fields.option(Fields::string)?,
+        }))?,
+    })
+}
+
+impl<'a> Storable for Session<'a> {
+    type Header = SessionHeader;
+
+    fn pack<'b>(&self, buf: &'b mut Vec<u8>) -> (SessionHeader, Vec<IoSlice<'b>>) {
+        buf.clear();
+        put_u32(buf, self.pairs.len() as u32);
+        for pair in self.pairs.iter() {
+            put_pair(buf, self.at, pair);
+        }
+        (SessionHeader { len: buf.len(), spool_over: usize::MAX }, vec![IoSlice::new(buf)])
+    }
+
+    fn pack_size(header: &SessionHeader) -> usize {
+        header.len
+    }
+
+    // Even an empty session has its pair count
+    fn has_body(_header: &SessionHeader) -> bool {
+        true
+    }
+
+    fn unpack(hdr: &SessionHeader, pack: &[u8]) -> std::io::Result<Self> {
+        let at = Instant::now();
+        let mut fields = Fields(pack);
+        let pairs = (0..fields.u32()?)
+            .map(|_| take_pair(&mut fields, at, hdr.spool_over))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        fields.finish()?;
+        Ok(Self { pairs: Cow::Owned(pairs), at })
+    }
+}
+
+pub async fn write<F: AsyncWrite + Unpin>(mut f: F, pairs: &[StoredPair]) -> std::io::Result<()> {
+    f.write_all(MAGIC).await?;
+    f.write_all(&VERSION.to_le_bytes()).await?;
+    storable::write_stored(&mut f, Session { pairs: Cow::Borrowed(pairs), at: Instant::now() }).await?;
+    f.flush().await
+}
+
+// `len` is the size of the whole file, the pack is whatever follows the version
+pub async fn read<F: AsyncRead + Unpin>(mut f: F, len: u64, spool_over: usize) -> std::io::Result<Vec<StoredPair>> {
+    let mut start = [0u8; 12];
+    f.read_exact(&mut start).await.map_err(|_| invalid("Too short to be a session file".to_string()))?;
+    if &start[..8] != MAGIC {
+        return Err(invalid("Not a session file".to_string()));
+    }
+    let version = u32::from_le_bytes(start[8..].try_into().unwrap());
+    if version != VERSION {
+        return Err(invalid(format!("Session file is format version {}, this build only reads version {}", version, VERSION)));
+    }
+    let header = SessionHeader { len: (len - start.len() as u64) as usize, spool_over };
+    storable::read_stored::<_, Session>(f, &header).await.map(|session| session.pairs.into_owned())
+}
@@ -0,0 +1,153 @@
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+
+static SPOOL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Temp file holding a body, removed once the last capture referencing it is gone. Only the path is held, the file is
+// opened for each write or read, so a long session with many spooled bodies doesn't run out of file descriptors.
+#[derive(Debug)]
+struct SpoolFile {
+    path: PathBuf,
+}
+
+impl SpoolFile {
+    fn create(contents: &[u8]) -> std::io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "stain-{}-{}.body",
+            std::process::id(),
+            SPOOL_COUNTER.fetch_add(1, crate::ORDERING)
+        ));
+        File::create(&path)?.write_all(contents)?;
+        Ok(Self { path })
+    }
+
+    fn append(&self, chunk: &[u8]) -> std::io::Result<()> {
+        OpenOptions::new().append(true).open(&self.path)?.write_all(chunk)
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// A captured body, kept in memory until it grows past the spool threshold and on disk after that
+#[derive(Clone, Debug, Default)]
+pub struct BodyBuffer {
+    memory: Vec<u8>,
+    spool: Option<Arc<SpoolFile>>,
+    len: usize,
+}
+
+impl PartialEq for BodyBuffer {
+    fn eq(&self, other: &Self) -> bool {
+        let same_spool = match (&self.spool, &other.spool) {
+            (Some(a), Some(b)) => a.path == b.path,
+            (None, None) => true,
+            _ => false,
+        };
+        self.len == other.len && same_spool && self.memory == other.memory
+    }
+}
+
+impl BodyBuffer {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_spooled(&self) -> bool {
+        self.spool.is_some()
+    }
+
+    // A whole body at once, as when it's loaded back from a session file
+    pub fn from_bytes(body: Vec<u8>, spool_over: usize) -> std::io::Result<Self> {
+        let mut buffer = Self { len: body.len(), memory: body, spool: None };
+        if buffer.len > spool_over {
+            buffer.move_to_disk()?;
+        }
+        Ok(buffer)
+    }
+
+    fn move_to_disk(&mut self) -> std::io::Result<()> {
+        let spool = SpoolFile::create(&self.memory)?;
+        self.memory = Vec::new();
+        self.spool = Some(Arc::new(spool));
+        Ok(())
+    }
+
+    // Anything already in memory moves to disk in one go the first time we cross `spool_over`
+    pub fn append(&mut self, chunk: &[u8], spool_over: usize) -> std::io::Result<()> {
+        if self.spool.is_none() && self.len + chunk.len() > spool_over {
+            self.move_to_disk()?;
+        }
+        match &self.spool {
+            Some(spool) => spool.append(chunk)?,
+            None => self.memory.extend_from_slice(chunk),
+        }
+        self.len += chunk.len();
+        Ok(())
+    }
+
+    pub fn read(&self) -> std::io::Result<Cow<'_, [u8]>> {
+        match &self.spool {
+            None => Ok(Cow::Borrowed(&self.memory)),
+            Some(spool) => {
+                let mut buf = Vec::with_capacity(self.len);
+                File::open(&spool.path)?.take(self.len as u64).read_to_end(&mut buf)?;
+                Ok(Cow::Owned(buf))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Whether this process has `path` open
+    fn is_open(path: &std::path::Path) -> bool {
+        std::fs::read_dir("/proc/self/fd").unwrap()
+            .filter_map(|fd| std::fs::read_link(fd.ok()?.path()).ok())
+            .any(|target| target == path)
+    }
+
+    #[test]
+    fn spools_past_the_threshold() {
+        let mut body = BodyBuffer::default();
+        body.append(b"0123", 8).unwrap();
+        assert!(!body.is_spooled());
+        body.append(b"4567", 8).unwrap();
+        assert!(!body.is_spooled());
+        body.append(b"89", 8).unwrap();
+        assert!(body.is_spooled());
+        body.append(b"ab", 8).unwrap();
+        assert_eq!(body.len(), 12);
+        assert_eq!(&*body.read().unwrap(), b"0123456789ab");
+    }
+
+    #[test]
+    fn spool_files_are_closed_between_uses() {
+        let mut body = BodyBuffer::from_bytes(b"spooled straight away".to_vec(), 0).unwrap();
+        let path = body.spool.as_ref().unwrap().path.clone();
+        body.append(b", then some more", 0).unwrap();
+        assert_eq!(&*body.read().unwrap(), b"spooled straight away, then some more");
+        if cfg!(target_os = "linux") {
+            assert!(!is_open(&path));
+        }
+        // Shared between clones, gone with the last one
+        let copy = body.clone();
+        drop(body);
+        assert!(path.exists());
+        drop(copy);
+        assert!(!path.exists());
+    }
+}
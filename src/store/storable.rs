@@ -3,29 +3,39 @@ use std::io::ErrorKind;
 
 use futures::{AsyncRead, AsyncWrite};
 use futures::{AsyncReadExt, AsyncWriteExt};
+use hyper::{Method, StatusCode, Version};
+use hyper::http::{HeaderMap, HeaderValue, header::HeaderName};
 
-pub trait Storable {
+use crate::proxy::request::RequestHead;
+use crate::proxy::response::ResponseHead;
+
+pub trait Storable: Sized {
     type Header: Sized;
 
-    fn pack(&self) -> (Self::Header, &[IoSlice]);
+    // Serializes into `buf`, the caller owns it so the slices handed back can borrow from it
+    fn pack<'a>(&self, buf: &'a mut Vec<u8>) -> (Self::Header, Vec<IoSlice<'a>>);
     fn pack_size(header: &Self::Header) -> usize;
-    fn unpack(hdr: &Self::Header, pack: &[u8]) -> Self;
+    fn has_body(header: &Self::Header) -> bool; // What the header claims, independent of pack_size
+    fn unpack(hdr: &Self::Header, pack: &[u8]) -> std::io::Result<Self>;
 }
 
 pub async fn read_stored<F: AsyncRead + Unpin, S: Storable>(mut f: F, header: &S::Header) -> std::io::Result<S> {
     let size = S::pack_size(header);
-    let mut buf: Vec<u8> = Vec::with_capacity(size);
-    let buf_slice: &mut [u8] = buf.as_mut_slice();
-    match f.read_exact(buf_slice).await {
-        Ok(()) => Ok(S::unpack(header, buf_slice)),
+    if size == 0 && S::has_body(header) {
+        return Err(std::io::Error::new(ErrorKind::InvalidData, "Header claims a body but packed size is 0"));
+    }
+    let mut buf: Vec<u8> = vec![0u8; size];
+    match f.read_exact(&mut buf).await {
+        Ok(()) => S::unpack(header, &buf),
         Err(e) => Err(e),
     }
 }
 
 pub async fn write_stored<F: AsyncWrite + Unpin, S: Storable>(mut f: F, store: S) -> std::io::Result<S::Header> {
-    let (header, pack) = store.pack();
+    let mut buf = Vec::new();
+    let (header, pack) = store.pack(&mut buf);
     let len: usize = pack.iter().map(|slice| slice.len()).sum();
-    match f.write_vectored(pack).await {
+    match f.write_vectored(&pack).await {
         Ok(bytes_written) => {
             if bytes_written < len {
                 Err(std::io::Error::new(ErrorKind::UnexpectedEof, format!("Not enough bytes written, wanted {} got {}", len, bytes_written)))
@@ -35,4 +45,328 @@ pub async fn write_stored<F: AsyncWrite + Unpin, S: Storable>(mut f: F, store: S
         },
         Err(e) => Err(e),
     }
-}
\ No newline at end of file
+}
+
+// Everything about a head that's fixed size. The URI and headers follow in the pack, each field as a
+// little endian u32 length and then its bytes, with names and values alternating.
+pub struct RequestHeader {
+    pub method: Method,
+    pub version: Version,
+    pub header_count: u32,
+    pub len: usize, // Bytes in the pack
+}
+
+pub struct ResponseHeader {
+    pub status: StatusCode,
+    pub version: Version,
+    pub header_count: u32,
+    pub len: usize,
+}
+
+pub fn put(buf: &mut Vec<u8>, field: &[u8]) {
+    buf.extend_from_slice(&(field.len() as u32).to_le_bytes());
+    buf.extend_from_slice(field);
+}
+
+pub fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    put(buf, &value.to_le_bytes());
+}
+
+pub fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    put(buf, &value.to_le_bytes());
+}
+
+// A presence byte, then the value if there is one
+pub fn put_option<T>(buf: &mut Vec<u8>, value: Option<&T>, put_value: impl FnOnce(&mut Vec<u8>, &T)) {
+    put(buf, &[value.is_some() as u8]);
+    if let Some(value) = value {
+        put_value(buf, value);
+    }
+}
+
+// One entry per value, so repeated headers come back as many times as they went in
+fn put_headers(buf: &mut Vec<u8>, headers: &HeaderMap<HeaderValue>) -> u32 {
+    for (name, value) in headers {
+        put(buf, name.as_str().as_bytes());
+        put(buf, value.as_bytes());
+    }
+    headers.len() as u32
+}
+
+const VERSIONS: [Version; 5] = [Version::HTTP_09, Version::HTTP_10, Version::HTTP_11, Version::HTTP_2, Version::HTTP_3];
+
+fn version_byte(version: Version) -> u8 {
+    VERSIONS.iter().position(|known| *known == version).unwrap_or(2) as u8
+}
+
+// A head nested inside some other pack, its header's fields ahead of its own pack
+pub fn put_request(buf: &mut Vec<u8>, head: &RequestHead) {
+    let mut pack = Vec::new();
+    let (header, _) = head.pack(&mut pack);
+    put(buf, header.method.as_str().as_bytes());
+    put(buf, &[version_byte(header.version)]);
+    put_u32(buf, header.header_count);
+    put(buf, &pack);
+}
+
+pub fn put_response(buf: &mut Vec<u8>, head: &ResponseHead) {
+    let mut pack = Vec::new();
+    let (header, _) = head.pack(&mut pack);
+    put(buf, &header.status.as_u16().to_le_bytes());
+    put(buf, &[version_byte(header.version)]);
+    put_u32(buf, header.header_count);
+    put(buf, &pack);
+}
+
+pub fn invalid(msg: String) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+pub struct Fields<'a>(pub &'a [u8]);
+
+impl<'a> Fields<'a> {
+    pub fn take(&mut self) -> std::io::Result<&'a [u8]> {
+        let (len, rest) = match self.0 {
+            [a, b, c, d, rest @ ..] => (u32::from_le_bytes([*a, *b, *c, *d]) as usize, rest),
+            _ => return Err(invalid("Pack ends in the middle of a field length".to_string())),
+        };
+        if rest.len() < len {
+            return Err(invalid(format!("Field wants {} bytes, only {} left", len, rest.len())));
+        }
+        let (field, rest) = rest.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn fixed<const N: usize>(&mut self) -> std::io::Result<[u8; N]> {
+        let field = self.take()?;
+        field.try_into().map_err(|_| invalid(format!("Expected a {} byte field, got {}", N, field.len())))
+    }
+
+    pub fn u8(&mut self) -> std::io::Result<u8> {
+        self.fixed::<1>().map(|[byte]| byte)
+    }
+
+    pub fn u32(&mut self) -> std::io::Result<u32> {
+        self.fixed().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> std::io::Result<u64> {
+        self.fixed().map(u64::from_le_bytes)
+    }
+
+    pub fn string(&mut self) -> std::io::Result<String> {
+        String::from_utf8(self.take()?.to_vec()).map_err(|e| invalid(format!("Field isn't UTF-8: {}", e)))
+    }
+
+    pub fn option<T>(&mut self, take_value: impl FnOnce(&mut Self) -> std::io::Result<T>) -> std::io::Result<Option<T>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => take_value(self).map(Some),
+            other => Err(invalid(format!("Bad presence byte {}", other))),
+        }
+    }
+
+    fn version(&mut self) -> std::io::Result<Version> {
+        let byte = self.u8()?;
+        VERSIONS.get(byte as usize).copied().ok_or_else(|| invalid(format!("Unknown HTTP version {}", byte)))
+    }
+
+    pub fn request(&mut self) -> std::io::Result<RequestHead> {
+        let method = Method::from_bytes(self.take()?).map_err(|e| invalid(format!("Bad method: {}", e)))?;
+        let version = self.version()?;
+        let header_count = self.u32()?;
+        let pack = self.take()?;
+        RequestHead::unpack(&RequestHeader { method, version, header_count, len: pack.len() }, pack)
+    }
+
+    pub fn response(&mut self) -> std::io::Result<ResponseHead> {
+        let status = u16::from_le_bytes(self.fixed()?);
+        let status = StatusCode::from_u16(status).map_err(|e| invalid(format!("Bad status {}: {}", status, e)))?;
+        let version = self.version()?;
+        let header_count = self.u32()?;
+        let pack = self.take()?;
+        ResponseHead::unpack(&ResponseHeader { status, version, header_count, len: pack.len() }, pack)
+    }
+
+    fn headers(&mut self, count: u32) -> std::io::Result<HeaderMap<HeaderValue>> {
+        let mut headers = HeaderMap::new();
+        for _ in 0..count {
+            let name = HeaderName::from_bytes(self.take()?).map_err(|e| invalid(format!("Bad header name: {}", e)))?;
+            let value = HeaderValue::from_bytes(self.take()?).map_err(|e| invalid(format!("Bad header value: {}", e)))?;
+            headers.append(name, value);
+        }
+        Ok(headers)
+    }
+
+    // pack_size is taken from the header, so anything left over means the two disagree
+    pub fn finish(self) -> std::io::Result<()> {
+        match self.0.len() {
+            0 => Ok(()),
+            left => Err(invalid(format!("{} bytes left over after unpacking", left))),
+        }
+    }
+}
+
+impl Storable for RequestHead {
+    type Header = RequestHeader;
+
+    fn pack<'a>(&self, buf: &'a mut Vec<u8>) -> (RequestHeader, Vec<IoSlice<'a>>) {
+        buf.clear();
+        put(buf, self.uri.to_string().as_bytes());
+        let header_count = put_headers(buf, &self.headers);
+        let header = RequestHeader { method: self.method.clone(), version: self.version, header_count, len: buf.len() };
+        (header, vec![IoSlice::new(buf)])
+    }
+
+    fn pack_size(header: &RequestHeader) -> usize {
+        header.len
+    }
+
+    // There's always a URI, even if it's only "/"
+    fn has_body(_header: &RequestHeader) -> bool {
+        true
+    }
+
+    fn unpack(hdr: &RequestHeader, pack: &[u8]) -> std::io::Result<Self> {
+        let mut fields = Fields(pack);
+        let uri = fields.take()?;
+        let uri = std::str::from_utf8(uri).ok()
+            .and_then(|uri| uri.parse().ok())
+            .ok_or_else(|| invalid(format!("Bad URI {:?}", String::from_utf8_lossy(uri))))?;
+        let headers = fields.headers(hdr.header_count)?;
+        fields.finish()?;
+        Ok(Self { method: hdr.method.clone(), uri, version: hdr.version, headers })
+    }
+}
+
+impl Storable for ResponseHead {
+    type Header = ResponseHeader;
+
+    fn pack<'a>(&self, buf: &'a mut Vec<u8>) -> (ResponseHeader, Vec<IoSlice<'a>>) {
+        buf.clear();
+        let header_count = put_headers(buf, &self.headers);
+        let header = ResponseHeader { status: self.status, version: self.version, header_count, len: buf.len() };
+        (header, vec![IoSlice::new(buf)])
+    }
+
+    fn pack_size(header: &ResponseHeader) -> usize {
+        header.len
+    }
+
+    fn has_body(header: &ResponseHeader) -> bool {
+        header.header_count > 0
+    }
+
+    fn unpack(hdr: &ResponseHeader, pack: &[u8]) -> std::io::Result<Self> {
+        let mut fields = Fields(pack);
+        let headers = fields.headers(hdr.header_count)?;
+        fields.finish()?;
+        Ok(Self { status: hdr.status, version: hdr.version, headers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::io::Cursor;
+
+    // Bytes as they are, with a header that can claim a body whatever the size says
+    struct Blob(Vec<u8>);
+
+    struct BlobHeader {
+        len: usize,
+        claims_body: bool,
+    }
+
+    impl Storable for Blob {
+        type Header = BlobHeader;
+
+        fn pack<'a>(&self, buf: &'a mut Vec<u8>) -> (BlobHeader, Vec<IoSlice<'a>>) {
+            buf.clear();
+            buf.extend_from_slice(&self.0);
+            (BlobHeader { len: buf.len(), claims_body: !buf.is_empty() }, vec![IoSlice::new(buf)])
+        }
+
+        fn pack_size(header: &BlobHeader) -> usize {
+            header.len
+        }
+
+        fn has_body(header: &BlobHeader) -> bool {
+            header.claims_body
+        }
+
+        fn unpack(_hdr: &BlobHeader, pack: &[u8]) -> std::io::Result<Self> {
+            Ok(Self(pack.to_vec()))
+        }
+    }
+
+    #[test]
+    fn read_back_what_was_written() {
+        let payload = b"a known payload\0with a NUL in it".to_vec();
+        let mut file = Cursor::new(Vec::new());
+        let header = block_on(write_stored(&mut file, Blob(payload.clone()))).unwrap();
+        assert_eq!(header.len, payload.len());
+        file.set_position(0);
+        let read: Blob = block_on(read_stored(&mut file, &header)).unwrap();
+        assert_eq!(read.0, payload);
+    }
+
+    #[test]
+    fn empty_pack_claiming_a_body() {
+        let header = BlobHeader { len: 0, claims_body: true };
+        let read = block_on(read_stored::<_, Blob>(Cursor::new(b"ignored".to_vec()), &header));
+        assert_eq!(read.err().map(|e| e.kind()), Some(ErrorKind::InvalidData));
+        let header = BlobHeader { len: 0, claims_body: false };
+        assert!(block_on(read_stored::<_, Blob>(Cursor::new(Vec::new()), &header)).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn short_file() {
+        let header = BlobHeader { len: 8, claims_body: true };
+        let read = block_on(read_stored::<_, Blob>(Cursor::new(b"four".to_vec()), &header));
+        assert_eq!(read.err().map(|e| e.kind()), Some(ErrorKind::UnexpectedEof));
+    }
+
+    fn round_trip<S: Storable>(stored: S) -> S {
+        let mut file = Cursor::new(Vec::new());
+        let header = block_on(write_stored(&mut file, stored)).unwrap();
+        file.set_position(0);
+        block_on(read_stored(&mut file, &header)).unwrap()
+    }
+
+    fn headers() -> HeaderMap<HeaderValue> {
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", HeaderValue::from_static("a=1"));
+        headers.append("set-cookie", HeaderValue::from_static("b=2"));
+        headers.append("x-empty", HeaderValue::from_static(""));
+        // obs-text, which isn't ASCII and isn't necessarily UTF-8 either
+        headers.append("x-name", HeaderValue::from_bytes("Zo\u{eb} \u{65e5}\u{672c}".as_bytes()).unwrap());
+        headers.append("x-name", HeaderValue::from_bytes(b"caf\xe9").unwrap());
+        headers
+    }
+
+    #[test]
+    fn request_head_round_trip() {
+        let head = RequestHead {
+            method: Method::from_bytes(b"PROPFIND").unwrap(),
+            uri: "https://example.com/a%20b?q=1&q=2".parse().unwrap(),
+            version: Version::HTTP_2,
+            headers: headers(),
+        };
+        let read = round_trip(head.clone());
+        assert_eq!(read, head);
+        let cookies: Vec<_> = read.headers.get_all("set-cookie").iter().collect();
+        assert_eq!(cookies, ["a=1", "b=2"]);
+        assert_eq!(read.headers.get_all("x-name").iter().next_back().unwrap().as_bytes(), b"caf\xe9");
+    }
+
+    #[test]
+    fn response_head_round_trip() {
+        let head = ResponseHead { status: StatusCode::IM_A_TEAPOT, version: Version::HTTP_10, headers: headers() };
+        assert_eq!(round_trip(head.clone()), head);
+        let bare = ResponseHead { status: StatusCode::NO_CONTENT, version: Version::HTTP_11, headers: HeaderMap::new() };
+        assert_eq!(round_trip(bare.clone()), bare);
+    }
+}
@@ -0,0 +1,594 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use eframe::egui::{Ui, RichText, TextureId, Vec2, Color32, CollapsingHeader, Label, TextEdit, TextStyle, Align, Key};
+use eframe::egui::text::{LayoutJob, TextFormat};
+use eframe::egui::epaint::text::cursor::CCursor;
+use eframe::epi;
+use super::spool::BodyBuffer;
+use super::hex::HexView;
+use hyper::http::{HeaderMap, HeaderValue, header::{CONTENT_ENCODING, CONTENT_TYPE}};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyTab {
+    Pretty,
+    Tree,
+    Frames,
+    Raw,
+    Hex,
+    Preview,
+}
+
+impl BodyTab {
+    const ALL: [BodyTab; 6] = [BodyTab::Pretty, BodyTab::Tree, BodyTab::Frames, BodyTab::Raw, BodyTab::Hex, BodyTab::Preview];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Pretty => "Pretty",
+            Self::Tree => "Tree",
+            Self::Frames => "Frames",
+            Self::Raw => "Raw",
+            Self::Hex => "Hex",
+            Self::Preview => "Preview",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyKind {
+    Json,
+    Html,
+    Form,
+    Grpc,
+    Text,
+    Image,
+    Binary,
+}
+
+impl BodyKind {
+    // Trust the Content-Type when it's specific, otherwise look at the bytes themselves
+    pub fn detect(headers: &HeaderMap<HeaderValue>, body: &[u8]) -> Self {
+        let content_type = headers.get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        match content_type.as_str() {
+            "application/json" => Self::Json,
+            "text/html" => Self::Html,
+            "application/x-www-form-urlencoded" => Self::Form,
+            t if t == "application/grpc" || t.starts_with("application/grpc+") => Self::Grpc,
+            t if t.ends_with("+json") => Self::Json,
+            t if t.starts_with("image/") => Self::Image,
+            t if t.starts_with("text/") || t.ends_with("xml") || t.ends_with("javascript") => Self::Text,
+            _ => Self::sniff(body),
+        }
+    }
+
+    fn sniff(body: &[u8]) -> Self {
+        if image::guess_format(body).is_ok() {
+            return Self::Image;
+        }
+        match std::str::from_utf8(body) {
+            Ok(text) => {
+                let text = text.trim_start();
+                if (text.starts_with('{') || text.starts_with('['))
+                    && serde_json::from_str::<serde_json::Value>(text).is_ok() {
+                    Self::Json
+                } else if text.len() >= 5 && (text[..5].eq_ignore_ascii_case("<html") || text[..5].eq_ignore_ascii_case("<!doc")) {
+                    Self::Html
+                } else {
+                    Self::Text
+                }
+            }
+            Err(_) => Self::Binary,
+        }
+    }
+
+    pub fn default_tab(&self) -> BodyTab {
+        match self {
+            Self::Json | Self::Html | Self::Form => BodyTab::Pretty,
+            Self::Text => BodyTab::Raw,
+            Self::Grpc => BodyTab::Frames,
+            Self::Image => BodyTab::Preview,
+            Self::Binary => BodyTab::Hex,
+        }
+    }
+
+    pub fn supports(&self, tab: BodyTab) -> bool {
+        match tab {
+            BodyTab::Pretty => matches!(self, Self::Json | Self::Html | Self::Form),
+            BodyTab::Tree => *self == Self::Json,
+            BodyTab::Frames => *self == Self::Grpc,
+            BodyTab::Preview => *self == Self::Image,
+            BodyTab::Raw | BodyTab::Hex => true,
+        }
+    }
+}
+
+pub fn pretty(kind: BodyKind, body: &[u8]) -> Option<String> {
+    match kind {
+        BodyKind::Json => serde_json::from_slice::<serde_json::Value>(body)
+            .ok()
+            .and_then(|value| serde_json::to_string_pretty(&value).ok()),
+        BodyKind::Form => std::str::from_utf8(body).ok().map(|form| {
+            form.split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    format!("{} = {}", percent_decode(name), percent_decode(value))
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }),
+        // Not a real formatter, but one tag per line beats a single minified line
+        BodyKind::Html => std::str::from_utf8(body).ok().map(|html| html.replace("><", ">\n<")),
+        _ => None,
+    }
+}
+
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let escaped = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match escaped {
+                    Some(byte) => {
+                        out.push(byte);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Upper bound on a decoded body, so a small compressed bomb can't take the UI down with it
+const DECODE_LIMIT: u64 = 64 * 1024 * 1024;
+
+// Undo Content-Encoding for display. Ok(None) means the body isn't encoded and can be shown as is.
+pub fn decode(headers: &HeaderMap<HeaderValue>, body: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let encodings: Vec<String> = headers.get_all(CONTENT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|encoding| encoding.trim().to_ascii_lowercase())
+        .filter(|encoding| !encoding.is_empty() && encoding != "identity")
+        .collect();
+    if encodings.is_empty() {
+        return Ok(None);
+    }
+    let mut decoded = body.to_vec();
+    // Encodings are listed in the order they were applied, so peel them off back to front
+    for encoding in encodings.iter().rev() {
+        let reader: Box<dyn Read> = match encoding.as_str() {
+            "gzip" | "x-gzip" => Box::new(flate2::read::GzDecoder::new(&decoded[..])),
+            // Supposed to be zlib wrapped, but plenty of servers send raw deflate
+            "deflate" if decoded.first().map(|byte| byte & 0x0f == 8).unwrap_or(false) =>
+                Box::new(flate2::read::ZlibDecoder::new(&decoded[..])),
+            "deflate" => Box::new(flate2::read::DeflateDecoder::new(&decoded[..])),
+            "br" => Box::new(brotli_decompressor::Decompressor::new(&decoded[..], 4096)),
+            other => return Err(format!("unsupported encoding {:?}", other)),
+        };
+        let mut out = Vec::new();
+        reader.take(DECODE_LIMIT).read_to_end(&mut out).map_err(|e| format!("{} decoding failed: {}", encoding, e))?;
+        decoded = out;
+    }
+    Ok(Some(decoded))
+}
+
+pub fn hex_dump(body: &[u8]) -> String {
+    body.chunks(16)
+        .enumerate()
+        .map(|(row, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let ascii: String = chunk.iter()
+                .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+                .collect();
+            format!("{:08x}  {:<47}  |{}|", row * 16, hex.join(" "), ascii)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub struct GrpcFrame<'a> {
+    pub compressed: bool, // Compressed with whatever grpc-encoding says
+    pub message: &'a [u8],
+}
+
+// gRPC messages each go out as a compressed flag byte and a big endian u32 length, then the message itself.
+// Whatever's left that doesn't make a whole frame comes back as the second half, the body may still be streaming in.
+pub fn grpc_frames(body: &[u8]) -> (Vec<GrpcFrame<'_>>, &[u8]) {
+    let mut frames = Vec::new();
+    let mut rest = body;
+    while let [flag, a, b, c, d, tail @ ..] = rest {
+        let len = u32::from_be_bytes([*a, *b, *c, *d]) as usize;
+        if tail.len() < len {
+            break;
+        }
+        frames.push(GrpcFrame { compressed: *flag != 0, message: &tail[..len] });
+        rest = &tail[len..];
+    }
+    (frames, rest)
+}
+
+// Children listed under one opened node, past that a huge array would stall the UI as badly as the raw text
+const TREE_LIMIT: usize = 1000;
+
+// Objects and arrays open up on click, hovering a node shows its path from the root
+fn show_json(ui: &mut Ui, path: &str, name: &str, value: &serde_json::Value) {
+    type Children<'a> = Box<dyn Iterator<Item = (String, String, &'a serde_json::Value)> + 'a>;
+    let (summary, len, children): (String, usize, Children) = match value {
+        serde_json::Value::Object(map) => (format!("{} {{{}}}", name, map.len()), map.len(), Box::new(map.iter().map(|(key, child)| {
+            let path = if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                format!("{}.{}", path, key)
+            } else {
+                format!("{}[{:?}]", path, key)
+            };
+            (key.clone(), path, child)
+        }))),
+        serde_json::Value::Array(items) => (format!("{} [{}]", name, items.len()), items.len(), Box::new(items.iter().enumerate().map(|(idx, child)| {
+            (idx.to_string(), format!("{}[{}]", path, idx), child)
+        }))),
+        scalar => {
+            ui.label(RichText::new(format!("{}: {}", name, scalar)).monospace()).on_hover_text(path);
+            return;
+        }
+    };
+    CollapsingHeader::new(RichText::new(summary).monospace())
+        .id_source(path)
+        .show(ui, |ui| {
+            for (name, path, child) in children.take(TREE_LIMIT) {
+                show_json(ui, &path, &name, child);
+            }
+            if len > TREE_LIMIT {
+                ui.label(format!("... {} more", len - TREE_LIMIT));
+            }
+        })
+        .header_response
+        .on_hover_text(path);
+}
+
+// Frame level only for now, each message is shown as hex until there's a protobuf decoder
+fn show_grpc(ui: &mut Ui, key: PreviewKey, headers: &HeaderMap<HeaderValue>, body: &[u8]) {
+    let encoding = headers.get("grpc-encoding").and_then(|value| value.to_str().ok()).unwrap_or("unknown encoding");
+    let (frames, rest) = grpc_frames(body);
+    ui.label(format!("{} messages", frames.len()));
+    for (idx, frame) in frames.iter().enumerate().take(TREE_LIMIT) {
+        let compressed = if frame.compressed { format!(", compressed ({})", encoding) } else { String::new() };
+        CollapsingHeader::new(RichText::new(format!("#{} {} bytes{}", idx + 1, frame.message.len(), compressed)).monospace())
+            .id_source(("grpc frame", key, idx))
+            .show(ui, |ui| {
+                let shown = &frame.message[..frame.message.len().min(RENDER_LIMIT)];
+                ui.label(RichText::new(hex_dump(shown)).monospace());
+                if shown.len() < frame.message.len() {
+                    ui.label(format!("... {} more bytes", frame.message.len() - shown.len()));
+                }
+            });
+    }
+    if frames.len() > TREE_LIMIT {
+        ui.label(format!("... {} more messages", frames.len() - TREE_LIMIT));
+    }
+    if !rest.is_empty() {
+        ui.colored_label(Color32::YELLOW, format!("{} bytes at the end don't make up a whole frame", rest.len()));
+    }
+}
+
+// Anything past this is left out of the text views, laying out megabytes of monospace text every frame locks up the UI
+const RENDER_LIMIT: usize = 64 * 1024;
+
+const THUMBNAIL_HEIGHT: f32 = 320.0;
+
+// Identifies which body a preview texture was decoded from: (cache index, is response)
+pub type PreviewKey = (usize, bool);
+
+type Decoded = Result<Option<Vec<u8>>, String>;
+
+type Parsed = Result<serde_json::Value, String>;
+
+// Find in body. Matches are numbered in the order the text views draw them, request before response, so the total
+// is only known once a frame has gone by.
+struct Search {
+    query: String,
+    current: usize,
+    total: usize, // Counted over the last frame
+    counted: usize, // Counted so far this frame
+    scroll: bool, // Bring the current match on screen next time it's drawn
+}
+
+impl Search {
+    // Case only matters for non-ASCII, lowering ASCII keeps byte offsets lined up with the original
+    fn matches(&self, text: &str) -> Vec<usize> {
+        let needle = self.query.to_ascii_lowercase();
+        text.to_ascii_lowercase().match_indices(&needle).map(|(start, _)| start).collect()
+    }
+}
+
+pub struct BodyView {
+    chosen: Option<BodyTab>, // Last tab the user picked, reused whenever the body supports it
+    preview: Option<(PreviewKey, Option<(TextureId, Vec2)>)>,
+    decoded: HashMap<PreviewKey, (usize, Decoded)>, // Decompressed copies along with the body length they were made from, it may still be streaming in
+    spooled: HashMap<PreviewKey, (usize, Vec<u8>)>, // Bodies read back from disk, same deal
+    parsed: HashMap<PreviewKey, (usize, Parsed)>, // JSON trees, parsed from the decoded body
+    hex: HexView,
+    search: Search,
+}
+
+impl BodyView {
+    pub fn new() -> Self {
+        Self {
+            chosen: None,
+            preview: None,
+            decoded: HashMap::new(),
+            spooled: HashMap::new(),
+            parsed: HashMap::new(),
+            hex: HexView::new(),
+            search: Search { query: String::new(), current: 0, total: 0, counted: 0, scroll: false },
+        }
+    }
+
+    // Goes ahead of the bodies each frame. True when the query was just changed, so collapsed bodies can be opened up.
+    pub fn draw_search(&mut self, ui: &mut Ui) -> bool {
+        let search = &mut self.search;
+        search.total = search.counted;
+        search.counted = 0;
+        if search.current >= search.total {
+            search.current = 0;
+        }
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            ui.label("Find in body");
+            let field = ui.add(TextEdit::singleline(&mut search.query).desired_width(200.0));
+            if field.changed() {
+                search.current = 0;
+                search.scroll = true;
+                changed = true;
+            }
+            let mut step = None;
+            if field.lost_focus() && ui.input().key_pressed(Key::Enter) {
+                step = Some(true);
+                field.request_focus();
+            }
+            if ui.small_button("Prev").clicked() {
+                step = Some(false);
+            }
+            if ui.small_button("Next").clicked() {
+                step = Some(true);
+            }
+            if let (Some(forward), true) = (step, search.total > 0) {
+                search.current = if forward { (search.current + 1) % search.total } else { (search.current + search.total - 1) % search.total };
+                search.scroll = true;
+            }
+            if search.query.is_empty() {
+                return;
+            }
+            match search.total {
+                0 => ui.label("No matches"),
+                total => ui.label(format!("{} of {}", search.current + 1, total)),
+            };
+        });
+        changed
+    }
+
+    pub fn show_buffer(&mut self, ui: &mut Ui, frame: Option<&epi::Frame>, key: PreviewKey, headers: &HeaderMap<HeaderValue>, body: &BodyBuffer) {
+        if !body.is_spooled() {
+            self.spooled.remove(&key);
+            return self.show(ui, frame, key, headers, &body.read().unwrap_or_default());
+        }
+        self.spooled.retain(|cached, _| cached.0 == key.0);
+        let bytes = match self.spooled.remove(&key) {
+            Some((len, bytes)) if len == body.len() => bytes,
+            _ => match body.read() {
+                Ok(bytes) => bytes.into_owned(),
+                Err(e) => {
+                    ui.colored_label(Color32::RED, format!("Unable to read spooled body: {}", e));
+                    return;
+                }
+            },
+        };
+        ui.label(format!("{} bytes, spooled to disk", body.len()));
+        self.show(ui, frame, key, headers, &bytes);
+        self.spooled.insert(key, (body.len(), bytes));
+    }
+
+    pub fn show(&mut self, ui: &mut Ui, frame: Option<&epi::Frame>, key: PreviewKey, headers: &HeaderMap<HeaderValue>, body: &[u8]) {
+        if body.is_empty() {
+            ui.label("(empty body)");
+            return;
+        }
+        // Request and response of the active capture are both on screen, anything else can go
+        self.decoded.retain(|cached, _| cached.0 == key.0);
+        let decoded = match self.decoded.remove(&key) {
+            Some((len, decoded)) if len == body.len() => decoded,
+            _ => decode(headers, body),
+        };
+        match &decoded {
+            Ok(Some(decoded)) => self.show_decoded(ui, frame, key, headers, decoded),
+            Ok(None) => self.show_decoded(ui, frame, key, headers, body),
+            Err(e) => {
+                // The header lied or we don't know the encoding, the raw bytes are all we can offer
+                ui.colored_label(Color32::YELLOW, format!("Unable to decode body ({}), showing raw bytes", e));
+                self.hex.show(ui, key, body);
+            }
+        }
+        self.decoded.insert(key, (body.len(), decoded));
+    }
+
+    fn pick_tab(&self, kind: BodyKind) -> BodyTab {
+        self.chosen.filter(|tab| kind.supports(*tab)).unwrap_or_else(|| kind.default_tab())
+    }
+
+    fn show_decoded(&mut self, ui: &mut Ui, frame: Option<&epi::Frame>, key: PreviewKey, headers: &HeaderMap<HeaderValue>, body: &[u8]) {
+        let kind = BodyKind::detect(headers, body);
+        let mut tab = self.pick_tab(kind);
+        ui.horizontal(|ui| {
+            for candidate in BodyTab::ALL.iter().filter(|candidate| kind.supports(**candidate)) {
+                if ui.selectable_label(tab == *candidate, candidate.name()).clicked() {
+                    tab = *candidate;
+                    self.chosen = Some(*candidate);
+                }
+            }
+        });
+        // The preview, tree and frames need the whole body and hex only lays out what's on screen, only the text views get cut short
+        let shown = if !matches!(tab, BodyTab::Preview | BodyTab::Tree | BodyTab::Frames | BodyTab::Hex) && body.len() > RENDER_LIMIT {
+            ui.label(format!("Body is {} bytes, showing the first {}", body.len(), RENDER_LIMIT));
+            &body[..RENDER_LIMIT]
+        } else {
+            body
+        };
+        if !self.search.query.is_empty() && !matches!(tab, BodyTab::Pretty | BodyTab::Raw) {
+            ui.label("Find only searches the Pretty and Raw views");
+        }
+        match tab {
+            BodyTab::Pretty => match pretty(kind, shown) {
+                Some(text) => self.show_text(ui, &text),
+                None => self.show_text(ui, &String::from_utf8_lossy(shown)),
+            },
+            BodyTab::Tree => self.show_tree(ui, key, body),
+            BodyTab::Frames => show_grpc(ui, key, headers, body),
+            BodyTab::Raw => self.show_text(ui, &String::from_utf8_lossy(shown)),
+            BodyTab::Hex => self.hex.show(ui, key, body),
+            BodyTab::Preview => self.show_preview(ui, frame, key, body),
+        }
+    }
+
+    // Monospace text with any matches for the search picked out, the current one brighter than the rest
+    fn show_text(&mut self, ui: &mut Ui, text: &str) {
+        let search = &mut self.search;
+        if search.query.is_empty() {
+            ui.label(RichText::new(text).monospace());
+            return;
+        }
+        let plain = TextFormat { style: TextStyle::Monospace, color: ui.visuals().text_color(), ..Default::default() };
+        let mut job = LayoutJob { wrap_width: ui.available_width(), ..Default::default() };
+        let (mut last, mut current) = (0, None);
+        for start in search.matches(text) {
+            let end = start + search.query.len();
+            let background = if search.counted == search.current {
+                current = Some(start);
+                Color32::from_rgb(255, 140, 0)
+            } else {
+                Color32::YELLOW
+            };
+            job.append(&text[last..start], 0.0, plain);
+            job.append(&text[start..end], 0.0, TextFormat { color: Color32::BLACK, background, ..plain });
+            search.counted += 1;
+            last = end;
+        }
+        job.append(&text[last..], 0.0, plain);
+        let galley = ui.fonts().layout_job(job);
+        let mut response = ui.add(Label::new(galley.clone()));
+        if let (Some(start), true) = (current, search.scroll) {
+            let cursor = galley.from_ccursor(CCursor::new(text[..start].chars().count()));
+            response.rect = galley.pos_from_cursor(&cursor).translate(response.rect.min.to_vec2());
+            response.scroll_to_me(Align::Center);
+            search.scroll = false;
+        }
+    }
+
+    // Cache indices get reused after the store is cleared, so nothing cached against them can be trusted past that
+    pub fn forget_cached(&mut self, frame: Option<&epi::Frame>) {
+        self.decoded.clear();
+        self.spooled.clear();
+        self.parsed.clear();
+        self.hex.forget();
+        if let Some((_, Some((texture, _)))) = self.preview.take() {
+            if let Some(frame) = frame {
+                frame.free_texture(texture);
+            }
+        }
+    }
+
+    fn show_tree(&mut self, ui: &mut Ui, key: PreviewKey, body: &[u8]) {
+        self.parsed.retain(|cached, _| cached.0 == key.0);
+        let parsed = match self.parsed.remove(&key) {
+            Some((len, parsed)) if len == body.len() => parsed,
+            _ => serde_json::from_slice(body).map_err(|e| e.to_string()),
+        };
+        match &parsed {
+            Ok(value) => show_json(ui, "$", "$", value),
+            Err(e) => {
+                ui.colored_label(Color32::YELLOW, format!("Invalid JSON ({}), showing text", e));
+                self.show_text(ui, &String::from_utf8_lossy(&body[..body.len().min(RENDER_LIMIT)]));
+            }
+        }
+        self.parsed.insert(key, (body.len(), parsed));
+    }
+
+    fn show_preview(&mut self, ui: &mut Ui, frame: Option<&epi::Frame>, key: PreviewKey, body: &[u8]) {
+        let frame = match frame {
+            Some(frame) => frame,
+            None => return,
+        };
+        if self.preview.as_ref().map(|(cached, _)| *cached != key).unwrap_or(true) {
+            if let Some((_, Some((texture, _)))) = self.preview.take() {
+                frame.free_texture(texture);
+            }
+            let texture = image::load_from_memory(body).ok().map(|decoded| {
+                let rgba = decoded.to_rgba8();
+                let size = [rgba.width() as usize, rgba.height() as usize];
+                let texture = frame.alloc_texture(epi::Image::from_rgba_unmultiplied(size, rgba.as_raw()));
+                (texture, Vec2::new(size[0] as f32, size[1] as f32))
+            });
+            self.preview = Some((key, texture));
+        }
+        match &self.preview {
+            Some((_, Some((texture, size)))) => {
+                // Thumbnail: scale down to fit the panel width and a fixed height, never up
+                let scale = (ui.available_width() / size.x).min(THUMBNAIL_HEIGHT / size.y).min(1.0);
+                ui.image(*texture, *size * scale);
+            }
+            _ => { ui.label("Unable to decode image"); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    fn typed(content_type: &str) -> HeaderMap<HeaderValue> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+        headers
+    }
+
+    fn default_tab(headers: &HeaderMap<HeaderValue>, body: &[u8]) -> BodyTab {
+        BodyView::new().pick_tab(BodyKind::detect(headers, body))
+    }
+
+    #[test]
+    fn pretty_for_json() {
+        assert_eq!(default_tab(&typed("application/json; charset=utf-8"), b"{}"), BodyTab::Pretty);
+        assert_eq!(default_tab(&typed("application/problem+json"), b"{}"), BodyTab::Pretty);
+        // Sniffed when the Content-Type doesn't say
+        assert_eq!(default_tab(&HeaderMap::new(), br#" {"a": [1, 2]}"#), BodyTab::Pretty);
+    }
+
+    #[test]
+    fn preview_for_images() {
+        assert_eq!(default_tab(&typed("image/png"), PNG), BodyTab::Preview);
+        assert_eq!(default_tab(&typed("application/octet-stream"), PNG), BodyTab::Preview);
+    }
+
+    #[test]
+    fn chosen_tab_sticks_where_it_can() {
+        let mut view = BodyView::new();
+        view.chosen = Some(BodyTab::Hex);
+        assert_eq!(view.pick_tab(BodyKind::Json), BodyTab::Hex);
+        view.chosen = Some(BodyTab::Tree);
+        assert_eq!(view.pick_tab(BodyKind::Json), BodyTab::Tree);
+        assert_eq!(view.pick_tab(BodyKind::Image), BodyTab::Preview);
+    }
+}
@@ -0,0 +1,296 @@
+use std::io::IoSlice;
+
+use eframe::egui::{Ui, RichText, Color32};
+
+use super::view;
+use super::storable::{Storable, Fields, put, put_u32, put_option, invalid};
+
+// A single message can't grow past this, it would take a broken or hostile stream to get there
+const MESSAGE_LIMIT: usize = 16 * 1024 * 1024;
+
+// Messages listed under a capture, the most recent ones
+const SHOWN_LIMIT: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+    Reserved(u8),
+}
+
+impl Opcode {
+    fn from_u8(opcode: u8) -> Self {
+        match opcode {
+            0x0 => Self::Continuation,
+            0x1 => Self::Text,
+            0x2 => Self::Binary,
+            0x8 => Self::Close,
+            0x9 => Self::Ping,
+            0xa => Self::Pong,
+            other => Self::Reserved(other),
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Continuation => 0x0,
+            Self::Text => 0x1,
+            Self::Binary => 0x2,
+            Self::Close => 0x8,
+            Self::Ping => 0x9,
+            Self::Pong => 0xa,
+            Self::Reserved(opcode) => opcode,
+        }
+    }
+
+    fn is_control(&self) -> bool {
+        match self {
+            Self::Close | Self::Ping | Self::Pong => true,
+            Self::Reserved(opcode) => *opcode >= 0x8,
+            _ => false,
+        }
+    }
+}
+
+// One message, put back together from however many frames it was sent in
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub outbound: bool, // Client to origin
+    pub opcode: Opcode,
+    pub payload: Vec<u8>, // Unmasked
+    pub frames: usize,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: Opcode,
+    payload: Vec<u8>,
+}
+
+// RFC 6455 5.2. Ok(None) means the frame isn't all here yet.
+fn parse_frame(buf: &[u8]) -> Result<Option<(Frame, usize)>, String> {
+    let (first, second) = match buf {
+        [first, second, ..] => (*first, *second),
+        _ => return Ok(None),
+    };
+    if first & 0x70 != 0 {
+        return Err(format!("Reserved bits set ({:#04x}), an extension we don't know is in use", first));
+    }
+    let (fin, opcode, masked) = (first & 0x80 != 0, Opcode::from_u8(first & 0x0f), second & 0x80 != 0);
+    let (len, mut offset) = match second & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    if opcode.is_control() && (!fin || len > 125) {
+        return Err(format!("Control frame {:?} is fragmented or longer than 125 bytes", opcode));
+    }
+    if len > MESSAGE_LIMIT as u64 {
+        return Err(format!("Frame of {} bytes is past the {} byte limit", len, MESSAGE_LIMIT));
+    }
+    let key = if masked {
+        match buf.get(offset..offset + 4) {
+            Some(key) => {
+                offset += 4;
+                Some([key[0], key[1], key[2], key[3]])
+            },
+            None => return Ok(None),
+        }
+    } else {
+        None
+    };
+    let end = offset + len as usize;
+    let mut payload = match buf.get(offset..end) {
+        Some(payload) => payload.to_vec(),
+        None => return Ok(None),
+    };
+    if let Some(key) = key {
+        for (idx, byte) in payload.iter_mut().enumerate() {
+            *byte ^= key[idx % 4];
+        }
+    }
+    Ok(Some((Frame { fin, opcode, payload }, end)))
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Direction {
+    buf: Vec<u8>, // Read but not yet a whole frame
+    partial: Option<Message>, // Data frames so far of a fragmented message
+    failed: Option<String>, // Once set, everything else from this side is kept as raw chunks
+}
+
+// Both sides of a WebSocket connection, parsed as the chunks come in. Reads don't line up with frames, so each
+// side buffers until it has a whole one, and fragmented messages are held until their last frame.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WebSocket {
+    pub messages: Vec<Message>,
+    outbound: Direction,
+    inbound: Direction,
+}
+
+impl Direction {
+    // Fold a frame into the messages, or say why it doesn't follow from what came before
+    fn take(&mut self, messages: &mut Vec<Message>, outbound: bool, frame: Frame) -> Result<(), String> {
+        let message = match (frame.opcode, self.partial.take()) {
+            (opcode, partial) if opcode.is_control() => {
+                // Control frames can turn up between the fragments of a message
+                self.partial = partial;
+                messages.push(Message { outbound, opcode, payload: frame.payload, frames: 1 });
+                return Ok(());
+            },
+            (Opcode::Continuation, Some(mut message)) => {
+                message.payload.extend_from_slice(&frame.payload);
+                message.frames += 1;
+                if message.payload.len() > MESSAGE_LIMIT {
+                    return Err(format!("Message past the {} byte limit", MESSAGE_LIMIT));
+                }
+                message
+            },
+            (Opcode::Continuation, None) => return Err("Continuation frame without a message to continue".to_string()),
+            (_, Some(_)) => return Err("New message started before the last one finished".to_string()),
+            (opcode, None) => Message { outbound, opcode, payload: frame.payload, frames: 1 },
+        };
+        if frame.fin {
+            messages.push(message);
+        } else {
+            self.partial = Some(message);
+        }
+        Ok(())
+    }
+}
+
+impl WebSocket {
+    // Hands back whatever couldn't be parsed, for the caller to keep as is
+    pub fn feed(&mut self, outbound: bool, chunk: &[u8]) -> Option<Vec<u8>> {
+        let side = if outbound { &mut self.outbound } else { &mut self.inbound };
+        if side.failed.is_some() {
+            return Some(chunk.to_vec());
+        }
+        side.buf.extend_from_slice(chunk);
+        let mut consumed = 0;
+        let result = loop {
+            match parse_frame(&side.buf[consumed..]) {
+                Ok(Some((frame, len))) => match side.take(&mut self.messages, outbound, frame) {
+                    Ok(()) => consumed += len,
+                    Err(e) => break Err(e),
+                },
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        let rest = side.buf.split_off(consumed);
+        match result {
+            Ok(()) => {
+                side.buf = rest;
+                None
+            },
+            Err(e) => {
+                // The frame that broke it is handed back with everything after it
+                side.failed = Some(e);
+                side.buf.clear();
+                Some(rest)
+            },
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        let side = |side: &Direction| side.buf.len() + side.partial.as_ref().map(|message| message.payload.len()).unwrap_or(0);
+        self.messages.iter().map(|message| message.payload.len()).sum::<usize>() + side(&self.outbound) + side(&self.inbound)
+    }
+
+    pub fn draw(&self, ui: &mut Ui) {
+        for (side, name) in [(&self.outbound, "client"), (&self.inbound, "origin")] {
+            if let Some(e) = &side.failed {
+                ui.colored_label(Color32::YELLOW, format!("Stopped parsing frames from the {} ({}), the rest is under upgrade frames", name, e));
+            }
+        }
+        if self.messages.len() > SHOWN_LIMIT {
+            ui.label(format!("... {} earlier messages", self.messages.len() - SHOWN_LIMIT));
+        }
+        for message in self.messages.iter().skip(self.messages.len().saturating_sub(SHOWN_LIMIT)) {
+            let arrow = if message.outbound { "->" } else { "<-" };
+            let frames = if message.frames > 1 { format!(" ({} frames)", message.frames) } else { String::new() };
+            let payload = &message.payload;
+            let text = match message.opcode {
+                Opcode::Text => String::from_utf8_lossy(payload).into_owned(),
+                Opcode::Binary => format!("binary, {} bytes\n{}", payload.len(), view::hex_dump(payload)),
+                Opcode::Close => match payload.as_slice() {
+                    [a, b, reason @ ..] => format!("CLOSE {} {}", u16::from_be_bytes([*a, *b]), String::from_utf8_lossy(reason)),
+                    _ => "CLOSE".to_string(),
+                },
+                Opcode::Ping => format!("PING {}", String::from_utf8_lossy(payload)),
+                Opcode::Pong => format!("PONG {}", String::from_utf8_lossy(payload)),
+                Opcode::Continuation | Opcode::Reserved(_) => format!("{:?}, {} bytes\n{}", message.opcode, payload.len(), view::hex_dump(payload)),
+            };
+            let mut text = RichText::new(format!("{} {}{}", arrow, text, frames)).monospace();
+            if matches!(message.opcode, Opcode::Close | Opcode::Ping | Opcode::Pong) {
+                text = text.italics();
+            }
+            ui.label(text);
+        }
+    }
+}
+
+fn put_message(buf: &mut Vec<u8>, message: &Message) {
+    put(buf, &[message.outbound as u8, message.opcode.to_u8()]);
+    put(buf, &message.payload);
+    put_u32(buf, message.frames as u32);
+}
+
+fn take_message(fields: &mut Fields) -> std::io::Result<Message> {
+    let (outbound, opcode) = match fields.take()? {
+        [outbound, opcode] => (*outbound != 0, Opcode::from_u8(*opcode)),
+        other => return Err(invalid(format!("Expected outbound and opcode bytes, got {} bytes", other.len()))),
+    };
+    let payload = fields.take()?.to_vec();
+    let frames = fields.u32()? as usize;
+    Ok(Message { outbound, opcode, payload, frames })
+}
+
+// Parse state goes along with the messages, so frames still coming in when it was packed pick up where they left off
+impl Storable for WebSocket {
+    type Header = usize; // Bytes in the pack
+
+    fn pack<'a>(&self, buf: &'a mut Vec<u8>) -> (usize, Vec<IoSlice<'a>>) {
+        buf.clear();
+        put_u32(buf, self.messages.len() as u32);
+        for message in &self.messages {
+            put_message(buf, message);
+        }
+        for side in [&self.outbound, &self.inbound] {
+            put(buf, &side.buf);
+            put_option(buf, side.partial.as_ref(), put_message);
+            put_option(buf, side.failed.as_ref(), |buf, e| put(buf, e.as_bytes()));
+        }
+        (buf.len(), vec![IoSlice::new(buf)])
+    }
+
+    fn pack_size(header: &usize) -> usize {
+        *header
+    }
+
+    // Even one with nothing in it has the message count
+    fn has_body(_header: &usize) -> bool {
+        true
+    }
+
+    fn unpack(_hdr: &usize, pack: &[u8]) -> std::io::Result<Self> {
+        let mut fields = Fields(pack);
+        let messages = (0..fields.u32()?).map(|_| take_message(&mut fields)).collect::<std::io::Result<_>>()?;
+        let mut side = || -> std::io::Result<Direction> {
+            Ok(Direction {
+                buf: fields.take()?.to_vec(),
+                partial: fields.option(take_message)?,
+                failed: fields.option(Fields::string)?,
+            })
+        };
+        let (outbound, inbound) = (side()?, side()?);
+        fields.finish()?;
+        Ok(Self { messages, outbound, inbound })
+    }
+}
@@ -1,53 +1,113 @@
 use std::{
     fs::File,
     io::{Read, Write},
-    sync::Arc,
+    collections::HashMap,
+    sync::{Arc, Mutex, atomic::AtomicUsize},
+    time::{Duration, Instant},
 };
 
 use openssl::{
     asn1::{Asn1Integer, Asn1Time},
     bn::{BigNum, MsbOption},
     conf::Conf,
+    ec::{EcGroup, EcKey},
     hash::MessageDigest,
+    nid::Nid,
     pkey::{PKey, Private},
     rsa::Rsa,
     x509::{extension, X509Builder, X509Extension, X509NameBuilder, X509},
 };
 
 use lazy_static::lazy_static;
-use rustls::{server::ResolvesServerCert, sign::RsaSigningKey, client::ServerCertVerifier};
+use rustls::{server::ResolvesServerCert, sign::SigningKey, client::ServerCertVerifier};
 use tokio::sync::mpsc::Sender;
 use rustls::client::WebPkiVerifier;
+use tracing::{error, info};
 
 use crate::proxy::ProxyEvent;
+use serde::{Deserialize, Serialize};
 
 lazy_static! {
     pub static ref SSL_CONF: Conf = Conf::new(openssl::conf::ConfMethod::default()).unwrap();
 }
 
+#[allow(dead_code)] // Only Rsa2048 is picked by the default config
+#[derive(Clone, Copy, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub enum KeyAlgorithm {
+    #[default]
+    Rsa2048,
+    Rsa4096,
+    EcdsaP256,
+}
+
+impl KeyAlgorithm {
+    fn generate(&self) -> Option<PKey<Private>> {
+        match self {
+            Self::Rsa2048 => PKey::from_rsa(Rsa::generate(2048).ok()?).ok(),
+            Self::Rsa4096 => PKey::from_rsa(Rsa::generate(4096).ok()?).ok(),
+            Self::EcdsaP256 => {
+                let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).ok()?;
+                PKey::from_ec_key(EcKey::generate(&group).ok()?).ok()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CertOptions {
+    pub ca_not_after_days: u32,
+    pub leaf_not_after_days: u32,
+    pub key_algorithm: KeyAlgorithm,
+}
+
+impl Default for CertOptions {
+    fn default() -> Self {
+        Self { ca_not_after_days: 365, leaf_not_after_days: 365, key_algorithm: KeyAlgorithm::default() }
+    }
+}
+
 pub struct CertStore {
     privkey: PKey<Private>,
     pubkey: X509,
+    leaf_key: PKey<Private>, // Shared by every leaf, only the CA key needs to stay stable across runs
+    leaf_signing_key: Arc<dyn SigningKey>,
+    leaf_not_after_days: u32,
+    leaf_cache: LeafCache, // Handed to every resolver, there's a new one for each connection
 }
 
+type LeafCache = Arc<Mutex<HashMap<String, (Instant, Arc<rustls::sign::CertifiedKey>)>>>; // Hostname -> (evict at, cert)
+
 impl CertStore {
-    pub fn load_or_create(pubkey_path: &str, privkey_path: &str) -> Self {
-        Self::try_load_or_create(pubkey_path, privkey_path)
-            .expect("Unable to load or create cert store")
+    pub fn load_or_create(pubkey_path: &str, privkey_path: &str, options: CertOptions) -> Result<Self, String> {
+        match CertStore::try_load(pubkey_path, privkey_path, options)? {
+            Some(store) => Ok(store),
+            None => CertStore::try_new(pubkey_path, privkey_path, options)
+                .ok_or_else(|| format!("Unable to create a CA in {} and {}", pubkey_path, privkey_path)),
+        }
     }
 
-    pub fn try_load_or_create(pubkey_path: &str, privkey_path: &str) -> Option<Self> {
-        CertStore::try_load(pubkey_path, privkey_path)
-            .or_else(|| CertStore::try_new(pubkey_path, privkey_path))
+    fn from_ca(privkey: PKey<Private>, pubkey: X509, options: CertOptions) -> Option<Self> {
+        let leaf_key = options.key_algorithm.generate()?;
+        // rustls wants PKCS8 for anything that isn't RSA, openssl only hands that out as PEM
+        let leaf_der = pem_to_der(&leaf_key.private_key_to_pem_pkcs8().ok()?)?;
+        let leaf_signing_key = rustls::sign::any_supported_type(&rustls::PrivateKey(leaf_der)).ok()?;
+        Some(Self {
+            privkey,
+            pubkey,
+            leaf_key,
+            leaf_signing_key,
+            leaf_not_after_days: options.leaf_not_after_days,
+            leaf_cache: Arc::new(Mutex::new(HashMap::new())),
+        })
     }
 
-    pub fn try_new(pubkey_path: &str, privkey_path: &str) -> Option<Self> {
-        println!("Creating new cert");
+    pub fn try_new(pubkey_path: &str, privkey_path: &str, options: CertOptions) -> Option<Self> {
+        info!("Creating new cert");
         let mut cert = X509Builder::new().ok()?;
         cert.set_version(2).ok()?;
         cert.set_not_before(Asn1Time::days_from_now(0).ok()?.as_ref())
             .ok()?;
-        cert.set_not_after(Asn1Time::days_from_now(365).ok()?.as_ref())
+        cert.set_not_after(Asn1Time::days_from_now(options.ca_not_after_days).ok()?.as_ref())
             .ok()?;
         let mut name = X509NameBuilder::new().ok()?;
         name.append_entry_by_text("CN", "localhost").ok()?;
@@ -55,19 +115,15 @@ impl CertStore {
         let name = name.build();
         cert.set_issuer_name(&name).unwrap();
         cert.set_subject_name(&name).unwrap();
-        let key = PKey::from_rsa(Rsa::generate(2048).ok()?).ok()?;
+        let key = options.key_algorithm.generate()?;
+        // Before the extensions, the subject key identifier is a hash of it
+        cert.set_pubkey(&key).unwrap();
+        // Only what a CA needs. Server auth belongs on the leaves, some validators refuse a CA that looks like one.
         cert.append_extension(
             extension::KeyUsage::new()
                 .critical()
-                .digital_signature()
                 .key_cert_sign()
-                .build()
-                .ok()?,
-        )
-        .unwrap();
-        cert.append_extension(
-            extension::ExtendedKeyUsage::new()
-                .server_auth()
+                .crl_sign()
                 .build()
                 .ok()?,
         )
@@ -90,7 +146,6 @@ impl CertStore {
             )
             .unwrap();
         }
-        cert.set_pubkey(&key).unwrap();
         cert.sign(&key, MessageDigest::sha512()).ok()?;
         let cert = cert.build();
 
@@ -98,23 +153,70 @@ impl CertStore {
         cert_file.write(&cert.to_pem().ok()?[..]).ok()?;
         let mut key_file = File::create(privkey_path).ok()?;
         key_file.write(&key.private_key_to_der().ok()?[..]).ok()?;
-        Some(CertStore {
-            privkey: key,
-            pubkey: cert,
-        })
+        CertStore::from_ca(key, cert, options)
     }
 
-    fn try_load(pubkey_path: &str, privkey_path: &str) -> Option<Self> {
-        let mut cert_file: File = File::open(pubkey_path).ok()?;
-        let mut key_file: File = File::open(privkey_path).ok()?;
+    // Missing files mean there's nothing to load yet. Files that are there but unusable are an error,
+    // falling back to creating a new CA would overwrite them.
+    fn try_load(pubkey_path: &str, privkey_path: &str, options: CertOptions) -> Result<Option<Self>, String> {
+        let (mut cert_file, mut key_file) = match (File::open(pubkey_path), File::open(privkey_path)) {
+            (Ok(cert_file), Ok(key_file)) => (cert_file, key_file),
+            _ => return Ok(None),
+        };
         let mut cert: Vec<u8> = Vec::new();
-        cert_file.read_to_end(&mut cert).unwrap();
+        cert_file.read_to_end(&mut cert).map_err(|e| format!("Unable to read {}: {}", pubkey_path, e))?;
         let mut key: Vec<u8> = Vec::new();
-        key_file.read_to_end(&mut key).unwrap();
-        Some(Self {
-            pubkey: X509::from_pem(&cert[..]).unwrap(),
-            privkey: PKey::private_key_from_der(&key[..]).unwrap(),
-        })
+        key_file.read_to_end(&mut key).map_err(|e| format!("Unable to read {}: {}", privkey_path, e))?;
+        CertStore::import(&cert, &key, options)
+            .map(Some)
+            .map_err(|e| format!("Unable to use the CA in {} and {}: {}", pubkey_path, privkey_path, e))
+    }
+
+    // Use an existing CA, each of the cert and key can be either PEM or DER
+    pub fn import(cert: &[u8], key: &[u8], options: CertOptions) -> Result<Self, String> {
+        let cert = if is_pem(cert) { X509::from_pem(cert) } else { X509::from_der(cert) }
+            .map_err(|e| format!("Invalid CA certificate: {}", e))?;
+        let key = if is_pem(key) { PKey::private_key_from_pem(key) } else { PKey::private_key_from_der(key) }
+            .map_err(|e| format!("Invalid CA private key: {}", e))?;
+        Self::from_ca_checked(cert, key, options)
+    }
+
+    // An existing CA held in memory, e.g. embedded in the binary, with the default leaf options. Nothing touches the disk.
+    pub fn from_bytes(cert: &[u8], key: &[u8]) -> Result<Self, String> {
+        Self::import(cert, key, CertOptions::default())
+    }
+
+    pub fn from_pem(cert_pem: &[u8], key_pem: &[u8], options: CertOptions) -> Result<Self, String> {
+        let cert = X509::from_pem(cert_pem).map_err(|e| format!("Invalid CA certificate: {}", e))?;
+        let key = PKey::private_key_from_pem(key_pem).map_err(|e| format!("Invalid CA private key: {}", e))?;
+        Self::from_ca_checked(cert, key, options)
+    }
+
+    pub fn from_der(cert_der: &[u8], key_der: &[u8], options: CertOptions) -> Result<Self, String> {
+        let cert = X509::from_der(cert_der).map_err(|e| format!("Invalid CA certificate: {}", e))?;
+        let key = PKey::private_key_from_der(key_der).map_err(|e| format!("Invalid CA private key: {}", e))?;
+        Self::from_ca_checked(cert, key, options)
+    }
+
+    // Leaves signed by something that isn't a CA, or with a key that doesn't go with the cert, fail in every client
+    fn from_ca_checked(cert: X509, key: PKey<Private>, options: CertOptions) -> Result<Self, String> {
+        if !is_ca(&cert.to_der().map_err(|e| e.to_string())?) {
+            return Err("Certificate is not a CA, it needs basicConstraints with CA:TRUE".to_string());
+        }
+        let public = cert.public_key().map_err(|e| format!("Unable to read the certificate's public key: {}", e))?;
+        if !public.public_eq(&key) {
+            return Err("Private key doesn't match the certificate".to_string());
+        }
+        CertStore::from_ca(key, cert, options).ok_or_else(|| "Unable to set up leaf signing".to_string())
+    }
+
+    pub fn export_ca_pem(&self) -> Vec<u8> {
+        self.pubkey.to_pem().unwrap()
+    }
+
+    // Most OSes only recognise a double-clicked cert file if it's DER
+    pub fn export_ca_der(&self) -> Vec<u8> {
+        self.pubkey.to_der().unwrap()
     }
 
     #[allow(dead_code)]
@@ -122,6 +224,7 @@ impl CertStore {
         Arc::new(CertResolver {
             cert_store: store.to_owned(),
             fallback_host: hostname,
+            leaf_cache: store.leaf_cache.clone(),
         })
     }
 }
@@ -129,6 +232,7 @@ impl CertStore {
 pub struct CertResolver {
     cert_store: Arc<CertStore>,
     fallback_host: Option<String>,
+    leaf_cache: LeafCache,
 }
 
 impl ResolvesServerCert for CertResolver {
@@ -136,30 +240,55 @@ impl ResolvesServerCert for CertResolver {
         &self,
         client_hello: rustls::server::ClientHello,
     ) -> Option<Arc<rustls::sign::CertifiedKey>> {
-        let privkey = &self.cert_store.privkey;
-        let pubkey = &self.cert_store.pubkey;
         let hostname = client_hello
             .server_name()
             .map(|host| host.to_owned())
             .or(self.fallback_host.to_owned())?;
 
+        self.leaf_cert(&hostname)
+    }
+}
+
+impl CertResolver {
+    // Signing a leaf is the expensive part of a handshake, so reuse them across connections until they're close to expiring
+    fn leaf_cert(&self, hostname: &str) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        let now = Instant::now();
+        if let Some((expires, cert)) = self.leaf_cache.lock().unwrap().get(hostname) {
+            if *expires > now {
+                return Some(cert.clone());
+            }
+        }
+        let cert = self.cert_store.build_leaf_cert(hostname)?;
+        // Drop it a day early so we never hand out a cert that expires mid-connection
+        let lifetime = Duration::from_secs(86400 * self.cert_store.leaf_not_after_days.saturating_sub(1) as u64);
+        if !lifetime.is_zero() {
+            self.leaf_cache.lock().unwrap().insert(hostname.to_string(), (now + lifetime, cert.clone()));
+        }
+        Some(cert)
+    }
+}
+
+impl CertStore {
+    fn build_leaf_cert(&self, hostname: &str) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        let privkey = &self.privkey;
+        let pubkey = &self.pubkey;
         if let Ok(mut cert) = X509::builder() {
             let mut serial = BigNum::new().unwrap();
             if let Err(e) = serial.rand(128, MsbOption::MAYBE_ZERO, true) {
-                eprintln!("Error generating cert serial: {}", e);
+                error!("Error generating cert serial: {}", e);
                 return None;
             }
             let serial = Asn1Integer::from_bn(&serial).unwrap();
 
             cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
                 .unwrap();
-            cert.set_not_after(&Asn1Time::days_from_now(365).unwrap())
+            cert.set_not_after(&Asn1Time::days_from_now(self.leaf_not_after_days).unwrap())
                 .unwrap();
             cert.set_version(2).unwrap();
             cert.set_serial_number(&serial).unwrap();
 
             let mut x509_name = X509NameBuilder::new().unwrap();
-            x509_name.append_entry_by_text("CN", &hostname).unwrap();
+            x509_name.append_entry_by_text("CN", hostname).unwrap();
             let x509_name = x509_name.build();
             cert.set_issuer_name(pubkey.subject_name()).unwrap();
             cert.set_subject_name(&x509_name).unwrap();
@@ -175,25 +304,25 @@ impl ResolvesServerCert for CertResolver {
                 let ctx = cert.x509v3_context(Some(&pubkey), Some(&SSL_CONF));
                 // Rust gets angy abut this but there's not much we can do about it
                 #[allow(mutable_borrow_reservation_conflict)]
-                cert.append_extension(
-                    extension::SubjectAlternativeName::new()
-                        .critical()
-                        .uri(&hostname)
-                        .build(&ctx)
-                        .unwrap(),
-                )
-                .unwrap();
+                // Browsers match on the DNS/IP SAN, not the CN. Not critical since the subject isn't empty.
+                let mut san = extension::SubjectAlternativeName::new();
+                if hostname.parse::<std::net::IpAddr>().is_ok() {
+                    san.ip(hostname);
+                } else {
+                    san.dns(hostname);
+                }
+                cert.append_extension(san.build(&ctx).unwrap())
+                    .unwrap();
             }
-            cert.set_pubkey(&privkey).unwrap();
-            cert.sign(&privkey, MessageDigest::sha512()).unwrap();
+            cert.set_pubkey(&self.leaf_key).unwrap();
+            cert.sign(privkey, MessageDigest::sha512()).unwrap();
             let cert = cert.build();
-            let privkey = rustls::PrivateKey(privkey.private_key_to_der().unwrap());
             Some(Arc::new(rustls::sign::CertifiedKey {
                 cert: vec![
                     rustls::Certificate(cert.to_der().unwrap()),
                     rustls::Certificate(pubkey.to_der().unwrap()),
                 ],
-                key: Arc::new(RsaSigningKey::new(&privkey).unwrap()),
+                key: self.leaf_signing_key.clone(),
                 ocsp: None,
                 sct_list: None,
             }))
@@ -203,13 +332,63 @@ impl ResolvesServerCert for CertResolver {
     }
 }
 
+fn is_pem(bytes: &[u8]) -> bool {
+    bytes.iter().skip_while(|byte| byte.is_ascii_whitespace()).take(10).eq(b"-----BEGIN".iter())
+}
+
+// openssl-sys doesn't bind X509_check_ca, so find basicConstraints (OID 2.5.29.19) in the DER ourselves. What follows the
+// OID is an optional critical flag, then an octet string wrapping SEQUENCE { cA BOOLEAN DEFAULT FALSE, ... }.
+fn is_ca(der: &[u8]) -> bool {
+    const BASIC_CONSTRAINTS: [u8; 5] = [0x06, 0x03, 0x55, 0x1d, 0x13];
+    let start = match der.windows(BASIC_CONSTRAINTS.len()).position(|window| window == BASIC_CONSTRAINTS) {
+        Some(pos) => pos + BASIC_CONSTRAINTS.len(),
+        None => return false,
+    };
+    let mut rest = &der[start..];
+    if rest.starts_with(&[0x01, 0x01]) {
+        rest = rest.get(3..).unwrap_or_default();
+    }
+    // Octet string, then the sequence inside it. Both are tiny, so their lengths always fit in the short form.
+    match rest {
+        [0x04, _, 0x30, _, 0x01, 0x01, ca, ..] => *ca != 0,
+        _ => false,
+    }
+}
+
+fn pem_to_der(pem: &[u8]) -> Option<Vec<u8>> {
+    let pem = std::str::from_utf8(pem).ok()?;
+    let body: String = pem.lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    base64::decode(body).ok()
+}
+
+// Extra roots to trust for origins, e.g. an internal CA. Each one is checked the way the verifier will take it.
+pub fn load_roots(path: &str) -> Result<Vec<rustls::Certificate>, String> {
+    let pem = std::fs::read(path).map_err(|e| format!("Unable to read root bundle {}: {}", path, e))?;
+    let certs = X509::stack_from_pem(&pem).map_err(|e| format!("Invalid root bundle {}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates in root bundle {}", path));
+    }
+    let mut check = rustls::RootCertStore::empty();
+    certs.iter()
+        .map(|cert| {
+            let cert = rustls::Certificate(cert.to_der().map_err(|e| e.to_string())?);
+            check.add(&cert).map_err(|e| format!("Unusable root in {}: {:?}", path, e))?;
+            Ok(cert)
+        })
+        .collect()
+}
+
 pub struct CertVerifier {
     channel: Sender<ProxyEvent>,
+    dropped: Arc<AtomicUsize>, // Notices we couldn't queue because the channel was full
+    accept_invalid: bool, // Let certs that don't verify through anyway, with a notice instead of a failed request
     inner: WebPkiVerifier
 }
 
 impl CertVerifier {
-    pub fn new(channel: Sender<ProxyEvent>) -> Self {
+    pub fn new(channel: Sender<ProxyEvent>, dropped: Arc<AtomicUsize>, accept_invalid: bool, extra_roots: &[rustls::Certificate]) -> Self {
         let mut store = rustls::RootCertStore::empty();
         store.add_server_trust_anchors(
             webpki_roots::TLS_SERVER_ROOTS
@@ -223,8 +402,14 @@ impl CertVerifier {
                 )
             })
         );
+        for root in extra_roots {
+            // Already checked by load_roots
+            let _ = store.add(root);
+        }
         Self {
             channel,
+            dropped,
+            accept_invalid,
             inner: WebPkiVerifier::new(store, None)
         }
     }
@@ -240,9 +425,107 @@ impl ServerCertVerifier for CertVerifier {
         ocsp_response: &[u8],
         now: std::time::SystemTime,
     ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
-        if let Err(e) = self.inner.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now) {
-            self.channel.try_send(crate::proxy::ProxyEvent::msg(e.to_string())).unwrap();
+        match self.inner.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now) {
+            Err(e) if self.accept_invalid => {
+                // We can't wait for room here, and a notice isn't worth stalling the handshake over
+                if self.channel.try_send(crate::proxy::ProxyEvent::msg(e.to_string())).is_err() {
+                    self.dropped.fetch_add(1, crate::ORDERING);
+                }
+                Ok(rustls::client::ServerCertVerified::assertion())
+            },
+            // Fails the handshake, the request it was for gets the error
+            result => result,
         }
-        Ok(rustls::client::ServerCertVerified::assertion())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str, leaf_not_after_days: u32) -> Arc<CertStore> {
+        let dir = std::env::temp_dir().join(format!("stain-tls-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let options = CertOptions { leaf_not_after_days, key_algorithm: KeyAlgorithm::EcdsaP256, ..CertOptions::default() };
+        let store = CertStore::try_new(
+            dir.join("cert.pem").to_str().unwrap(),
+            dir.join("key.der").to_str().unwrap(),
+            options,
+        ).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        Arc::new(store)
+    }
+
+    #[test]
+    fn second_resolve_reuses_the_leaf() {
+        let store = store("leaf-cache", 30);
+        let resolver = CertStore::build_cert(&store, None);
+        let first = resolver.leaf_cert("example.com").unwrap();
+        assert!(Arc::ptr_eq(&first, &resolver.leaf_cert("example.com").unwrap()));
+        // A later connection gets its own resolver, and still the same leaf
+        let other = CertStore::build_cert(&store, None);
+        assert!(Arc::ptr_eq(&first, &other.leaf_cert("example.com").unwrap()));
+        assert!(!Arc::ptr_eq(&first, &other.leaf_cert("example.org").unwrap()));
+    }
+
+    #[test]
+    fn short_lived_leaves_are_not_cached() {
+        let store = store("leaf-no-cache", 1);
+        let resolver = CertStore::build_cert(&store, None);
+        let first = resolver.leaf_cert("example.com").unwrap();
+        assert!(!Arc::ptr_eq(&first, &resolver.leaf_cert("example.com").unwrap()));
+    }
+
+    fn leaf(resolver: &CertResolver, hostname: &str) -> X509 {
+        X509::from_der(&resolver.leaf_cert(hostname).unwrap().cert[0].0).unwrap()
+    }
+
+    #[test]
+    fn leaves_carry_a_dns_or_ip_san() {
+        let store = store("leaf-san", 30);
+        let resolver = CertStore::build_cert(&store, None);
+        let names = leaf(&resolver, "example.com").subject_alt_names().unwrap();
+        assert_eq!(names.len(), 1);
+        assert_eq!(names.get(0).unwrap().dnsname(), Some("example.com"));
+        let names = leaf(&resolver, "127.0.0.1").subject_alt_names().unwrap();
+        assert_eq!(names.len(), 1);
+        assert_eq!(names.get(0).unwrap().ipaddress(), Some(&[127, 0, 0, 1][..]));
+        let names = leaf(&resolver, "::1").subject_alt_names().unwrap();
+        assert_eq!(names.get(0).unwrap().ipaddress(), Some(&std::net::Ipv6Addr::LOCALHOST.octets()[..]));
+    }
+
+    #[test]
+    fn unusable_ca_files_are_an_error() {
+        let dir = std::env::temp_dir().join(format!("stain-tls-test-bad-ca-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.der"));
+        std::fs::write(&cert, b"not a cert").unwrap();
+        std::fs::write(&key, b"not a key").unwrap();
+        let loaded = CertStore::load_or_create(cert.to_str().unwrap(), key.to_str().unwrap(), CertOptions::default());
+        assert!(loaded.err().unwrap().contains("Invalid CA certificate"));
+        // Left alone rather than replaced with a new CA
+        assert_eq!(std::fs::read(&cert).unwrap(), b"not a cert");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn contains(der: &[u8], bytes: &[u8]) -> bool {
+        der.windows(bytes.len()).any(|window| window == bytes)
+    }
+
+    // Extensions by their DER, there's no getter for them in openssl
+    const EXTENDED_KEY_USAGE: [u8; 5] = [0x06, 0x03, 0x55, 0x1d, 0x25];
+    const SERVER_AUTH: [u8; 10] = [0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01];
+
+    #[test]
+    fn ca_only_signs() {
+        let store = store("ca-extensions", 30);
+        let ca = store.export_ca_der();
+        assert!(is_ca(&ca));
+        // keyUsage, critical, keyCertSign and cRLSign only
+        assert!(contains(&ca, &[0x06, 0x03, 0x55, 0x1d, 0x0f, 0x01, 0x01, 0xff, 0x04, 0x04, 0x03, 0x02, 0x01, 0x06]));
+        assert!(!contains(&ca, &EXTENDED_KEY_USAGE));
+        let leaf = leaf(&CertStore::build_cert(&store, None), "example.com").to_der().unwrap();
+        assert!(!is_ca(&leaf));
+        assert!(contains(&leaf, &EXTENDED_KEY_USAGE) && contains(&leaf, &SERVER_AUTH));
+    }
+}
@@ -1,7 +1,6 @@
-use std::{task::Waker, sync::Arc};
+use std::{task::{Waker, Poll}, sync::{Arc, Mutex}};
 
 use futures::Future;
-use tokio::sync::Mutex;
 
 #[derive(Debug)]
 pub struct WaitpointInner {
@@ -9,6 +8,7 @@ pub struct WaitpointInner {
     waker: Option<Waker>
 }
 
+// Parks a task until someone else (usually the GUI) calls `complete`. Clones share the same state.
 #[derive(Clone, Debug)]
 pub struct Waitpoint(Arc<Mutex<WaitpointInner>>);
 
@@ -23,11 +23,15 @@ impl Waitpoint {
         )
     }
 
-    pub async fn complete(&mut self) {
-        let mut inner = self.0.lock().await;
-        inner.completed = true;
-        if let Some(waker) = &inner.waker {
-            waker.wake_by_ref()
+    pub fn complete(&self) {
+        let waker = {
+            let mut inner = self.0.lock().unwrap();
+            inner.completed = true;
+            inner.waker.take()
+        };
+        // Wake outside the lock, the woken task may get polled on another thread straight away
+        if let Some(waker) = waker {
+            waker.wake()
         }
     }
 }
@@ -35,18 +39,64 @@ impl Waitpoint {
 impl Future for Waitpoint {
     type Output = ();
 
-    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
-        let mut lock = Box::pin(self.0.lock());
-        match Future::poll(lock.as_mut(), cx) {
-            std::task::Poll::Ready(mut lock) => {
-                if lock.completed {
-                    std::task::Poll::Ready(())
-                } else {
-                    lock.waker = Some(cx.waker().clone());
-                    std::task::Poll::Pending
-                }
-            },
-            std::task::Poll::Pending => std::task::Poll::Pending,
+    // The check and the waker registration happen under the same lock, so a `complete` can't slip in between them
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.0.lock().unwrap();
+        if inner.completed {
+            Poll::Ready(())
+        } else {
+            match &inner.waker {
+                Some(waker) if waker.will_wake(cx.waker()) => {},
+                _ => inner.waker = Some(cx.waker().clone()),
+            }
+            Poll::Pending
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Context;
+
+    use futures::task::{waker, ArcWake};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl ArcWake for CountingWaker {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn pending_waitpoint_wakes_once() {
+        let wakes = Arc::new(CountingWaker::default());
+        let waker = waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut waitpoint = Waitpoint::new();
+        // Polling again with the same waker doesn't queue up a second wake
+        assert!(std::pin::Pin::new(&mut waitpoint).poll(&mut cx).is_pending());
+        assert!(std::pin::Pin::new(&mut waitpoint).poll(&mut cx).is_pending());
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+        waitpoint.clone().complete();
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        waitpoint.complete();
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        assert!(std::pin::Pin::new(&mut waitpoint).poll(&mut cx).is_ready());
+    }
+
+    #[tokio::test]
+    async fn completed_from_another_task() {
+        let waitpoint = Waitpoint::new();
+        let resume = waitpoint.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            resume.complete();
+        });
+        tokio::time::timeout(std::time::Duration::from_secs(5), waitpoint).await.unwrap();
+    }
+}